@@ -0,0 +1,427 @@
+//! Derive `PopVariable` and `PushVariable` for Dyon.
+//!
+//! ```ignore
+//! #[macro_use]
+//! extern crate dyon_derive;
+//!
+//! #[derive(PopVariable, PushVariable)]
+//! struct Person {
+//!     #[dyon(rename = "first-name")]
+//!     first_name: String,
+//!     #[dyon(default)]
+//!     age: f64,
+//!     #[dyon(skip)]
+//!     id: u32,
+//! }
+//! ```
+//!
+//! Conversions:
+//!
+//! - Structs with named fields are objects with a key per field
+//! - Tuple structs are arrays, or `vec4` with `#[dyon(vec4)]`
+//! - Newtype structs are converted as the inner value
+//! - Unit structs are empty objects
+//! - Unit variants of enums are text, e.g. `"Red"`
+//! - Other variants are objects with the variant as the only key, e.g. `{Rgb: [1, 0, 0]}`
+//! - Fields use their own `PopVariable`/`PushVariable`, e.g. `Option` is `opt`
+//!   and `Result` is `res`
+//!
+//! Attributes on fields and variants:
+//!
+//! - `#[dyon(rename = "name")]` uses another key or variant name
+//! - `#[dyon(skip)]` leaves the field out, and uses `Default` when popping
+//! - `#[dyon(default)]` uses `Default` when the key is missing
+//! - `#[dyon(default = "path")]` calls a function when the key is missing
+//!
+//! Fields of type `Option` are `none()` when the key is missing.
+//!
+//! Errors name the path of the field that failed, e.g. ``In field `pos.x`:``.
+
+extern crate proc_macro;
+extern crate syn;
+#[macro_use]
+extern crate quote;
+
+use proc_macro::TokenStream;
+use quote::Tokens;
+use syn::{Body, Field, Ident, Lit, MetaItem, NestedMetaItem, VariantData};
+
+#[proc_macro_derive(PopVariable, attributes(dyon))]
+pub fn derive_pop_variable(input: TokenStream) -> TokenStream {
+    let ast = syn::parse_derive_input(&input.to_string()).unwrap();
+    impl_pop_variable(&ast).parse().unwrap()
+}
+
+#[proc_macro_derive(PushVariable, attributes(dyon))]
+pub fn derive_push_variable(input: TokenStream) -> TokenStream {
+    let ast = syn::parse_derive_input(&input.to_string()).unwrap();
+    impl_push_variable(&ast).parse().unwrap()
+}
+
+/// Options set with `#[dyon(...)]`.
+#[derive(Default)]
+struct Attrs {
+    rename: Option<String>,
+    skip: bool,
+    /// Set to `Some(None)` for `Default::default()`.
+    default: Option<Option<String>>,
+    vec4: bool,
+}
+
+impl Attrs {
+    fn from_attributes(attrs: &[syn::Attribute]) -> Attrs {
+        let mut res = Attrs::default();
+        for attr in attrs {
+            let items = match attr.value {
+                MetaItem::List(ref name, ref items) if name.as_ref() == "dyon" => items,
+                _ => continue,
+            };
+            for item in items {
+                match *item {
+                    NestedMetaItem::MetaItem(MetaItem::Word(ref name)) => {
+                        match name.as_ref() {
+                            "skip" => res.skip = true,
+                            "default" => res.default = Some(None),
+                            "vec4" => res.vec4 = true,
+                            x => panic!("Unknown attribute `dyon({})`", x),
+                        }
+                    }
+                    NestedMetaItem::MetaItem(
+                        MetaItem::NameValue(ref name, Lit::Str(ref val, _))
+                    ) => {
+                        match name.as_ref() {
+                            "rename" => res.rename = Some(val.clone()),
+                            "default" => res.default = Some(Some(val.clone())),
+                            x => panic!("Unknown attribute `dyon({} = ...)`", x),
+                        }
+                    }
+                    _ => panic!("Expected `#[dyon(name)]` or `#[dyon(name = \"value\")]`"),
+                }
+            }
+        }
+        res
+    }
+
+    /// Returns the key of a field or variant.
+    fn key(&self, ident: &Ident) -> String {
+        match self.rename {
+            Some(ref name) => name.clone(),
+            None => ident.as_ref().into(),
+        }
+    }
+
+    /// Returns the expression for a missing or skipped value.
+    fn default_value(&self) -> Tokens {
+        match self.default {
+            Some(Some(ref path)) => {
+                let path = syn::parse_path(path).unwrap();
+                quote! { #path() }
+            }
+            _ => quote! { ::std::default::Default::default() }
+        }
+    }
+}
+
+/// Returns `true` if the type of a field is `Option<_>`.
+fn is_option(field: &Field) -> bool {
+    if let syn::Ty::Path(None, ref path) = field.ty {
+        if let Some(segment) = path.segments.last() {
+            return segment.ident.as_ref() == "Option";
+        }
+    }
+    false
+}
+
+/// The fields that are converted, with their attributes.
+fn used_fields(fields: &[Field]) -> Vec<(&Field, Attrs)> {
+    fields.iter()
+        .map(|f| (f, Attrs::from_attributes(&f.attrs)))
+        .filter(|&(_, ref attrs)| !attrs.skip)
+        .collect()
+}
+
+fn impl_pop_variable(ast: &syn::DeriveInput) -> Tokens {
+    let name = &ast.ident;
+    let type_name = name.as_ref();
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+    let body = match ast.body {
+        Body::Struct(ref data) => {
+            let attrs = Attrs::from_attributes(&ast.attrs);
+            pop_data(quote! { #name }, data, attrs.vec4, type_name)
+        }
+        Body::Enum(ref variants) => {
+            let mut units = vec![];
+            let mut others = vec![];
+            for variant in variants {
+                let attrs = Attrs::from_attributes(&variant.attrs);
+                let ident = &variant.ident;
+                let key = attrs.key(ident);
+                if let VariantData::Unit = variant.data {
+                    units.push(quote! { #key => Ok(#name::#ident), });
+                } else {
+                    let pop = pop_data(quote! { #name::#ident }, &variant.data,
+                                       attrs.vec4, &key);
+                    others.push(quote! {
+                        #key => (|| -> Result<Self, ::dyon::error::Error> { #pop })()
+                            .map_err(|err| ::dyon::embed::field_error(#key, err)),
+                    });
+                }
+            }
+            quote! {
+                match var {
+                    &::dyon::Variable::Text(ref text) => match text.as_str() {
+                        #(#units)*
+                        _ => Err(::dyon::error::Error::new(::dyon::error::ErrorKind::Type,
+                            format!("Unknown variant `{}` of `{}`", text, #type_name))),
+                    },
+                    &::dyon::Variable::Object(ref obj) if obj.len() == 1 => {
+                        let (key, var) = obj.iter().next().unwrap();
+                        let var = rt.resolve(var);
+                        match key.as_str() {
+                            #(#others)*
+                            _ => Err(::dyon::error::Error::new(::dyon::error::ErrorKind::Type,
+                                format!("Unknown variant `{}` of `{}`", key, #type_name))),
+                        }
+                    }
+                    _ => Err(rt.expected_error(var, #type_name)),
+                }
+            }
+        }
+    };
+    quote! {
+        impl #impl_generics ::dyon::embed::PopVariable for #name #ty_generics #where_clause {
+            fn pop_var(
+                rt: &::dyon::Runtime,
+                var: &::dyon::Variable
+            ) -> Result<Self, ::dyon::error::Error> {
+                let var = rt.resolve(var);
+                #body
+            }
+        }
+    }
+}
+
+/// Generates an expression that pops `var` into a struct or variant.
+fn pop_data(ctor: Tokens, data: &VariantData, vec4: bool, type_name: &str) -> Tokens {
+    match *data {
+        VariantData::Struct(ref fields) => {
+            let values: Vec<Tokens> = fields.iter().map(|f| {
+                let attrs = Attrs::from_attributes(&f.attrs);
+                let ident = f.ident.as_ref().unwrap();
+                let key = attrs.key(ident);
+                if attrs.skip {
+                    let default = attrs.default_value();
+                    return quote! { #ident: #default };
+                }
+                let missing = if attrs.default.is_some() {
+                    attrs.default_value()
+                } else if is_option(f) {
+                    quote! { None }
+                } else {
+                    return quote! {
+                        #ident: try!(::dyon::embed::obj_field_path(rt, obj, #key))
+                    };
+                };
+                quote! {
+                    #ident: match obj.get(&::std::sync::Arc::new(#key.into())) {
+                        Some(x) => try!(::dyon::embed::pop_field(rt, #key, x)),
+                        None => #missing,
+                    }
+                }
+            }).collect();
+            quote! {
+                if let &::dyon::Variable::Object(ref obj) = var {
+                    Ok(#ctor { #(#values),* })
+                } else {
+                    Err(rt.expected_error(var, "object"))
+                }
+            }
+        }
+        VariantData::Tuple(ref fields) if vec4 => {
+            if fields.len() > 4 {
+                panic!("`{}` has more than 4 fields to fit in `vec4`", type_name);
+            }
+            let values: Vec<Tokens> = (0..fields.len())
+                .map(|i| quote! { v[#i] as _ })
+                .collect();
+            quote! {
+                if let &::dyon::Variable::Vec4(v) = var {
+                    Ok(#ctor(#(#values),*))
+                } else {
+                    Err(rt.expected_error(var, "vec4"))
+                }
+            }
+        }
+        VariantData::Tuple(ref fields) => {
+            let n = used_fields(fields).len();
+            if n == 1 && fields.len() == 1 {
+                return quote! {
+                    ::dyon::embed::PopVariable::pop_var(rt, var).map(#ctor)
+                };
+            }
+            let mut i: usize = 0;
+            let values: Vec<Tokens> = fields.iter().map(|f| {
+                let attrs = Attrs::from_attributes(&f.attrs);
+                if attrs.skip { return attrs.default_value(); }
+                let key = i.to_string();
+                let value = quote! { try!(::dyon::embed::pop_field(rt, #key, &arr[#i])) };
+                i += 1;
+                value
+            }).collect();
+            quote! {
+                if let &::dyon::Variable::Array(ref arr) = var {
+                    if arr.len() != #n {
+                        return Err(::dyon::error::Error::new(::dyon::error::ErrorKind::Type,
+                            format!("Expected array of length {}, found length {}",
+                                    #n, arr.len())));
+                    }
+                    Ok(#ctor(#(#values),*))
+                } else {
+                    Err(rt.expected_error(var, "array"))
+                }
+            }
+        }
+        VariantData::Unit => quote! {
+            if let &::dyon::Variable::Object(_) = var {
+                Ok(#ctor)
+            } else {
+                Err(rt.expected_error(var, "object"))
+            }
+        }
+    }
+}
+
+fn impl_push_variable(ast: &syn::DeriveInput) -> Tokens {
+    let name = &ast.ident;
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+    let body = match ast.body {
+        Body::Struct(ref data) => {
+            let attrs = Attrs::from_attributes(&ast.attrs);
+            let values: Vec<Tokens> = match *data {
+                VariantData::Struct(ref fields) => fields.iter()
+                    .map(|f| { let ident = &f.ident; quote! { &self.#ident } })
+                    .collect(),
+                VariantData::Tuple(ref fields) => (0..fields.len())
+                    .map(|i| { let i = Ident::new(i.to_string()); quote! { &self.#i } })
+                    .collect(),
+                VariantData::Unit => vec![],
+            };
+            push_data(data, &values, attrs.vec4)
+        }
+        Body::Enum(ref variants) => {
+            let arms: Vec<Tokens> = variants.iter().map(|variant| {
+                let attrs = Attrs::from_attributes(&variant.attrs);
+                let ident = &variant.ident;
+                let key = attrs.key(ident);
+                match variant.data {
+                    VariantData::Unit => quote! {
+                        #name::#ident => ::dyon::Variable::Text(
+                            ::std::sync::Arc::new(#key.into())),
+                    },
+                    ref data => {
+                        let (pattern, values) = bindings(data);
+                        let payload = push_data(data, &values, attrs.vec4);
+                        quote! {
+                            #name::#ident #pattern => {
+                                let mut obj = ::std::collections::HashMap::new();
+                                obj.insert(::std::sync::Arc::new(#key.into()), #payload);
+                                ::dyon::Variable::Object(::std::sync::Arc::new(obj))
+                            }
+                        }
+                    }
+                }
+            }).collect();
+            quote! {
+                match *self {
+                    #(#arms)*
+                }
+            }
+        }
+    };
+    quote! {
+        impl #impl_generics ::dyon::embed::PushVariable for #name #ty_generics #where_clause {
+            fn push_var(&self) -> ::dyon::Variable {
+                #body
+            }
+        }
+    }
+}
+
+/// Returns a pattern that binds the fields of a variant by reference,
+/// together with the bound names.
+///
+/// Skipped fields are not bound.
+fn bindings(data: &VariantData) -> (Tokens, Vec<Tokens>) {
+    let mut patterns = vec![];
+    let mut values = vec![];
+    for (i, f) in data.fields().iter().enumerate() {
+        let binding = match f.ident {
+            Some(ref ident) => ident.clone(),
+            None => Ident::new(format!("__field{}", i)),
+        };
+        let skip = Attrs::from_attributes(&f.attrs).skip;
+        patterns.push(match (&f.ident, skip) {
+            (&Some(ref ident), true) => quote! { #ident: _ },
+            (&None, true) => quote! { _ },
+            (_, false) => quote! { ref #binding },
+        });
+        values.push(quote! { #binding });
+    }
+    match *data {
+        VariantData::Struct(_) => (quote! { { #(#patterns),* } }, values),
+        VariantData::Tuple(_) => (quote! { ( #(#patterns),* ) }, values),
+        VariantData::Unit => (quote! {}, values),
+    }
+}
+
+/// Generates an expression that pushes a struct or variant.
+///
+/// The values are expressions that borrow each field.
+fn push_data(data: &VariantData, values: &[Tokens], vec4: bool) -> Tokens {
+    let fields = match *data {
+        VariantData::Struct(ref fields) => fields,
+        VariantData::Tuple(ref fields) => fields,
+        VariantData::Unit => return quote! {
+            ::dyon::Variable::Object(::std::sync::Arc::new(::std::collections::HashMap::new()))
+        }
+    };
+    let used: Vec<(&Tokens, &Field, Attrs)> = values.iter().zip(fields.iter())
+        .map(|(value, f)| (value, f, Attrs::from_attributes(&f.attrs)))
+        .filter(|&(_, _, ref attrs)| !attrs.skip)
+        .collect();
+    match *data {
+        VariantData::Struct(_) => {
+            let inserts: Vec<Tokens> = used.iter()
+                .map(|&(value, f, ref attrs)| {
+                    let key = attrs.key(f.ident.as_ref().unwrap());
+                    quote! {
+                        obj.insert(::std::sync::Arc::new(#key.into()),
+                                   ::dyon::embed::PushVariable::push_var(#value));
+                    }
+                })
+                .collect();
+            quote! {{
+                let mut obj = ::std::collections::HashMap::new();
+                #(#inserts)*
+                ::dyon::Variable::Object(::std::sync::Arc::new(obj))
+            }}
+        }
+        _ if vec4 => {
+            let mut components: Vec<Tokens> = values.iter()
+                .map(|value| quote! { *#value as f32 })
+                .collect();
+            while components.len() < 4 { components.push(quote! { 0.0 }); }
+            quote! { ::dyon::Variable::Vec4([#(#components),*]) }
+        }
+        _ => {
+            if used.len() == 1 && fields.len() == 1 {
+                let value = used[0].0;
+                return quote! { ::dyon::embed::PushVariable::push_var(#value) };
+            }
+            let items: Vec<Tokens> = used.iter()
+                .map(|&(value, _, _)| quote! { ::dyon::embed::PushVariable::push_var(#value) })
+                .collect();
+            quote! { ::dyon::Variable::Array(::std::sync::Arc::new(vec![#(#items),*])) }
+        }
+    }
+}
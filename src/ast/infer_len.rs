@@ -1,4 +1,3 @@
-use std::cell::Cell;
 use std::sync::Arc;
 
 use FnIndex;
@@ -8,6 +7,7 @@ use super::{
     Call,
     CallClosure,
     Expression,
+    FnIndexCell,
     ForN,
     Id,
     Item,
@@ -21,7 +21,7 @@ pub fn infer(block: &Block, name: &str) -> Option<Expression> {
         Expression::Call(Call {
             alias: None,
             name: Arc::new("len".into()),
-            f_index: Cell::new(FnIndex::None),
+            f_index: FnIndexCell::new(FnIndex::None),
             args: vec![
                 Expression::Item(item)
             ],
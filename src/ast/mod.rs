@@ -1,10 +1,12 @@
 use std::collections::HashMap;
-use std::sync::Arc;
-use std::cell::Cell;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
 use range::Range;
 use piston_meta::bootstrap::Convert;
 use piston_meta::MetaData;
 
+use bytecode;
+use FnExternalRef;
 use FnIndex;
 use Module;
 use Prelude;
@@ -14,13 +16,16 @@ use Variable;
 mod infer_len;
 mod replace;
 
+/// Converts meta data to AST and registers the functions in the module.
+///
+/// Returns the range of the first meta data that could not be converted on failure.
 pub fn convert(
     file: Arc<String>,
     source: Arc<String>,
     data: &[Range<MetaData>],
     ignored: &mut Vec<Range>,
     module: &mut Module
-) -> Result<(), ()> {
+) -> Result<(), Range> {
     let mut convert = Convert::new(data);
 
     let namespace = if let Ok((range, val)) = Namespace::from_meta_data(convert, ignored) {
@@ -43,7 +48,8 @@ pub fn convert(
             convert.update(range);
             module.register(function);
         } else if convert.remaining_data_len() > 0 {
-            return Err(());
+            // Report the range of the first meta data that was not converted.
+            return Err(data[data.len() - convert.remaining_data_len()].range());
         } else {
             break;
         }
@@ -51,6 +57,16 @@ pub fn convert(
     for (i, f) in module.functions.iter().enumerate() {
         f.resolve_locals(i, module, &use_lookup);
     }
+    // Imported functions are compiled again, since calls might resolve differently.
+    let returns: Vec<bool> = module.functions.iter().map(|f| f.returns()).collect();
+    for (i, f) in module.functions.iter_mut().enumerate() {
+        let functions = bytecode::Functions {
+            relative: i,
+            returns: &returns,
+        };
+        bytecode::compile_closures(&mut f.block, &functions);
+        f.code = Some(Arc::new(bytecode::compile_function(f, &functions)));
+    }
     Ok(())
 }
 
@@ -281,6 +297,22 @@ impl UseImport {
     }
 }
 
+/// Stores whether locals of a function are resolved, which can be shared between threads.
+#[derive(Debug)]
+pub struct Resolved(AtomicBool);
+
+impl Resolved {
+    pub fn new() -> Resolved { Resolved(AtomicBool::new(false)) }
+
+    pub fn get(&self) -> bool { self.0.load(Ordering::Acquire) }
+
+    pub fn set(&self) { self.0.store(true, Ordering::Release) }
+}
+
+impl Clone for Resolved {
+    fn clone(&self) -> Resolved { Resolved(AtomicBool::new(self.get())) }
+}
+
 #[derive(Debug, Clone)]
 pub struct Function {
     pub namespace: Arc<Vec<Arc<String>>>,
@@ -291,8 +323,10 @@ pub struct Function {
     pub currents: Vec<Current>,
     pub block: Block,
     pub ret: Type,
-    pub resolved: Cell<bool>,
+    pub resolved: Resolved,
     pub source_range: Range,
+    /// Compiled body, set after resolving locals.
+    pub code: Option<Arc<bytecode::Code>>,
 }
 
 impl Function {
@@ -379,7 +413,7 @@ impl Function {
         let ret = try!(ret.ok_or(()));
         Ok((convert.subtract(start), Function {
             namespace: namespace.clone(),
-            resolved: Cell::new(false),
+            resolved: Resolved::new(),
             name: name,
             file: file.clone(),
             source: source.clone(),
@@ -388,6 +422,7 @@ impl Function {
             block: block,
             ret: ret,
             source_range: convert.source(start).unwrap(),
+            code: None,
         }))
     }
 
@@ -407,7 +442,7 @@ impl Function {
             stack.push(Some(current.name.clone()));
         }
         self.block.resolve_locals(relative, &mut stack, &mut closure_stack, module, use_lookup);
-        self.resolved.set(true);
+        self.resolved.set();
     }
 }
 
@@ -420,6 +455,8 @@ pub struct Closure {
     pub expr: Expression,
     pub ret: Type,
     pub source_range: Range,
+    /// Compiled body, set when compiling the function containing the closure.
+    pub code: Option<Arc<bytecode::Code>>,
 }
 
 impl Closure {
@@ -479,6 +516,7 @@ impl Closure {
             expr: expr,
             ret: ret,
             source_range: convert.source(start).unwrap(),
+            code: None,
         }))
     }
 
@@ -701,6 +739,8 @@ impl Arg {
 #[derive(Debug, Clone)]
 pub struct Current {
     pub name: Arc<String>,
+    /// The declared type, not checked yet.
+    pub ty: Type,
     pub source_range: Range,
     pub mutable: bool,
 }
@@ -714,6 +754,7 @@ impl Current {
         convert.update(start_range);
 
         let mut name: Option<Arc<String>> = None;
+        let mut ty = Type::Any;
         let mut mutable = false;
         loop {
             if let Ok(range) = convert.end_node(node) {
@@ -725,10 +766,10 @@ impl Current {
             } else if let Ok((range, val)) = convert.meta_string("name") {
                 convert.update(range);
                 name = Some(val);
-            } else if let Ok((range, _)) = Type::from_meta_data(
+            } else if let Ok((range, val)) = Type::from_meta_data(
                     "type", convert, ignored) {
                 convert.update(range);
-                // Just ignore type for now.
+                ty = val;
             } else {
                 let range = convert.ignore();
                 convert.update(range);
@@ -739,6 +780,7 @@ impl Current {
         let name = try!(name.ok_or(()));
         Ok((convert.subtract(start), Current {
             name: name,
+            ty: ty,
             source_range: convert.source(start).unwrap(),
             mutable: mutable,
         }))
@@ -1789,11 +1831,36 @@ impl Id {
     }
 }
 
+/// Stores a resolved stack id, which can be shared between threads.
+#[derive(Debug)]
+pub struct StackId(AtomicUsize);
+
+impl StackId {
+    pub fn new(val: Option<usize>) -> StackId {
+        StackId(AtomicUsize::new(val.map(|x| x + 1).unwrap_or(0)))
+    }
+
+    pub fn get(&self) -> Option<usize> {
+        match self.0.load(Ordering::Relaxed) {
+            0 => None,
+            x => Some(x - 1),
+        }
+    }
+
+    pub fn set(&self, val: Option<usize>) {
+        self.0.store(val.map(|x| x + 1).unwrap_or(0), Ordering::Relaxed);
+    }
+}
+
+impl Clone for StackId {
+    fn clone(&self) -> StackId { StackId::new(self.get()) }
+}
+
 #[derive(Debug, Clone)]
 pub struct Item {
     pub name: Arc<String>,
-    pub stack_id: Cell<Option<usize>>,
-    pub static_stack_id: Cell<Option<usize>>,
+    pub stack_id: StackId,
+    pub static_stack_id: StackId,
     pub current: bool,
     pub try: bool,
     pub ids: Vec<Id>,
@@ -1807,8 +1874,8 @@ impl Item {
         Item {
             name: name,
             current: false,
-            stack_id: Cell::new(None),
-            static_stack_id: Cell::new(None),
+            stack_id: StackId::new(None),
+            static_stack_id: StackId::new(None),
             try: false,
             ids: vec![],
             try_ids: vec![],
@@ -1821,8 +1888,8 @@ impl Item {
         Item {
             name: self.name.clone(),
             current: self.current,
-            stack_id: Cell::new(None),
-            static_stack_id: Cell::new(None),
+            stack_id: StackId::new(None),
+            static_stack_id: StackId::new(None),
             try: self.try,
             ids: self.ids.iter().take(n).map(|id| id.clone()).collect(),
             try_ids: {
@@ -1897,8 +1964,8 @@ impl Item {
         let name = try!(name.ok_or(()));
         Ok((convert.subtract(start), Item {
             name: name,
-            stack_id: Cell::new(None),
-            static_stack_id: Cell::new(None),
+            stack_id: StackId::new(None),
+            static_stack_id: StackId::new(None),
             current: current,
             try: try,
             ids: ids,
@@ -1998,12 +2065,83 @@ impl Go {
     }
 }
 
+/// Stores the function index of a call, which is set when resolving locals.
+///
+/// Calls to loaded and intrinsic functions read the index without locking.
+/// External functions are stored behind a lock,
+/// such that a loaded module can be shared between threads.
+#[derive(Debug)]
+pub struct FnIndexCell {
+    kind: AtomicUsize,
+    index: AtomicIsize,
+    external: Mutex<Option<FnExternalRef>>,
+}
+
+const FN_NONE: usize = 0;
+const FN_INTRINSIC: usize = 1;
+const FN_LOADED: usize = 2;
+const FN_EXTERNAL_VOID: usize = 3;
+const FN_EXTERNAL_RETURN: usize = 4;
+
+impl FnIndexCell {
+    pub fn new(f_index: FnIndex) -> FnIndexCell {
+        let cell = FnIndexCell {
+            kind: AtomicUsize::new(FN_NONE),
+            index: AtomicIsize::new(0),
+            external: Mutex::new(None),
+        };
+        cell.set(f_index);
+        cell
+    }
+
+    pub fn get(&self) -> FnIndex {
+        match self.kind.load(Ordering::Acquire) {
+            FN_INTRINSIC => FnIndex::Intrinsic(self.index.load(Ordering::Relaxed) as usize),
+            FN_LOADED => FnIndex::Loaded(self.index.load(Ordering::Relaxed)),
+            FN_EXTERNAL_VOID => FnIndex::ExternalVoid(self.external()),
+            FN_EXTERNAL_RETURN => FnIndex::ExternalReturn(self.external()),
+            _ => FnIndex::None,
+        }
+    }
+
+    pub fn set(&self, f_index: FnIndex) {
+        let kind = match f_index {
+            FnIndex::None => FN_NONE,
+            FnIndex::Intrinsic(index) => {
+                self.index.store(index as isize, Ordering::Relaxed);
+                FN_INTRINSIC
+            }
+            FnIndex::Loaded(index) => {
+                self.index.store(index, Ordering::Relaxed);
+                FN_LOADED
+            }
+            FnIndex::ExternalVoid(f) => {
+                *self.external.lock().unwrap() = Some(f);
+                FN_EXTERNAL_VOID
+            }
+            FnIndex::ExternalReturn(f) => {
+                *self.external.lock().unwrap() = Some(f);
+                FN_EXTERNAL_RETURN
+            }
+        };
+        self.kind.store(kind, Ordering::Release);
+    }
+
+    fn external(&self) -> FnExternalRef {
+        self.external.lock().unwrap().clone().expect("Expected external function")
+    }
+}
+
+impl Clone for FnIndexCell {
+    fn clone(&self) -> FnIndexCell { FnIndexCell::new(self.get()) }
+}
+
 #[derive(Debug, Clone)]
 pub struct Call {
     pub alias: Option<Arc<String>>,
     pub name: Arc<String>,
     pub args: Vec<Expression>,
-    pub f_index: Cell<FnIndex>,
+    pub f_index: FnIndexCell,
     /// A custom source, such as when calling a function inside a loaded module.
     pub custom_source: Option<Arc<String>>,
     pub source_range: Range,
@@ -2074,7 +2212,7 @@ impl Call {
             alias: alias,
             name: name,
             args: args,
-            f_index: Cell::new(FnIndex::None),
+            f_index: FnIndexCell::new(FnIndex::None),
             custom_source: None,
             source_range: convert.source(start).unwrap(),
         }))
@@ -2146,7 +2284,7 @@ impl Call {
             alias: alias,
             name: Arc::new(name),
             args: args,
-            f_index: Cell::new(FnIndex::None),
+            f_index: FnIndexCell::new(FnIndex::None),
             custom_source: None,
             source_range: convert.source(start).unwrap(),
         }))
@@ -2170,7 +2308,7 @@ impl Call {
         } else {
             module.find_function(&self.name, relative)
         };
-        self.f_index.set(f_index);
+        self.f_index.set(f_index.clone());
         match f_index {
             FnIndex::Loaded(f_index) => {
                 let index = (f_index + relative as isize) as usize;
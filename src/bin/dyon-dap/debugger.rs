@@ -0,0 +1,248 @@
+//! Debugger attached to each script thread.
+
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver};
+
+use dyon::{Debugger, Module, Runtime};
+use dyon::runtime::Call;
+use dyon::write::{write_variable, EscapeString};
+use range::Range;
+
+use json::Json;
+use lines::line_of;
+use session::{self, Command, Session, ThreadInfo};
+
+enum Mode {
+    StepIn,
+    /// Stop at a new line with call depth less or equal.
+    Next(usize),
+    /// Stop when call depth is less.
+    StepOut(usize),
+    Continue,
+}
+
+pub struct DapDebugger {
+    id: usize,
+    session: Arc<Mutex<Session>>,
+    commands: Receiver<Command>,
+    mode: Mode,
+    /// File, line and call depth of the last visited expression.
+    last: Option<(Arc<String>, usize, usize)>,
+    /// Range of the last visited expression for each call frame.
+    ranges: Vec<Range>,
+    /// Whether execution has stopped before.
+    has_stopped: bool,
+}
+
+impl DapDebugger {
+    /// Registers a new thread in the session.
+    pub fn new(session: Arc<Mutex<Session>>, stop_on_entry: bool) -> DapDebugger {
+        let (tx, rx) = channel();
+        let id = {
+            let mut s = session.lock().unwrap();
+            let id = s.next_thread_id;
+            s.next_thread_id += 1;
+            s.threads.push(ThreadInfo {
+                id: id,
+                name: if id == 1 { "main".into() } else { format!("go {}", id) },
+                commands: tx,
+                stopped: false,
+                pause: false,
+            });
+            s.event("thread", Json::object(vec![
+                ("reason", Json::str("started")),
+                ("threadId", Json::Number(id as f64)),
+            ]));
+            id
+        };
+        DapDebugger {
+            id: id,
+            session: session,
+            commands: rx,
+            mode: if stop_on_entry { Mode::StepIn } else { Mode::Continue },
+            last: None,
+            ranges: vec![],
+            has_stopped: false,
+        }
+    }
+
+    /// Reports a stop and handles commands until execution should continue.
+    fn stopped(
+        &mut self,
+        reason: &str,
+        rt: &Runtime,
+        module: &Module,
+        depth: usize
+    ) -> Result<(), String> {
+        self.has_stopped = true;
+        {
+            let mut s = self.session.lock().unwrap();
+            if let Some(t) = s.thread(self.id) { t.stopped = true; }
+            s.event("stopped", Json::object(vec![
+                ("reason", Json::str(reason)),
+                ("threadId", Json::Number(self.id as f64)),
+                ("allThreadsStopped", Json::Bool(false)),
+            ]));
+        }
+        loop {
+            let cmd = match self.commands.recv() {
+                Ok(cmd) => cmd,
+                Err(_) => return Err("Debugger disconnected".into()),
+            };
+            match cmd {
+                Command::Continue => self.mode = Mode::Continue,
+                Command::Next => self.mode = Mode::Next(depth),
+                Command::StepIn => self.mode = Mode::StepIn,
+                Command::StepOut => self.mode = Mode::StepOut(depth),
+                Command::StackTrace(req) => {
+                    let body = self.stack_trace(rt, module);
+                    self.session.lock().unwrap().respond(&req, body);
+                    continue;
+                }
+                Command::Variables(req) => {
+                    let body = self.variables(&req, rt);
+                    self.session.lock().unwrap().respond(&req, body);
+                    continue;
+                }
+            }
+            let mut s = self.session.lock().unwrap();
+            if let Some(t) = s.thread(self.id) { t.stopped = false; }
+            return Ok(());
+        }
+    }
+
+    fn stack_trace(&self, rt: &Runtime, module: &Module) -> Json {
+        let mut frames = vec![];
+        for (i, call) in rt.call_stack.iter().enumerate().rev() {
+            let f = &module.functions[call.index];
+            let line = match self.ranges.get(i) {
+                Some(range) => line_of(&f.source, range.offset).0,
+                None => 0,
+            };
+            frames.push(Json::object(vec![
+                ("id", Json::Number(session::frame_id(self.id, i) as f64)),
+                ("name", Json::str(&call.fn_name)),
+                ("source", Json::object(vec![
+                    ("path", Json::str(&f.file)),
+                ])),
+                ("line", Json::Number(line as f64)),
+                ("column", Json::Number(1.0)),
+            ]));
+        }
+        let n = frames.len();
+        Json::object(vec![
+            ("stackFrames", Json::Array(frames)),
+            ("totalFrames", Json::Number(n as f64)),
+        ])
+    }
+
+    fn variables(&self, req: &Json, rt: &Runtime) -> Json {
+        let r = req.get("arguments").get("variablesReference").as_f64().unwrap_or(0.0) as usize;
+        let mut variables = vec![];
+        if r > 0 {
+            let (frame_id, currents) = session::decode_variables_ref(r);
+            let (_, i) = session::decode_frame_id(frame_id);
+            if let Some(call) = rt.call_stack.get(i) {
+                let next = rt.call_stack.get(i + 1);
+                let (list, start, end) = if currents {
+                    (&rt.current_stack, call.current_len,
+                     next.map(|c| c.current_len).unwrap_or(rt.current_stack.len()))
+                } else {
+                    (&rt.local_stack, call.local_len,
+                     next.map(|c| c.local_len).unwrap_or(rt.local_stack.len()))
+                };
+                for &(ref name, ind) in &list[start..end] {
+                    if &**name == "return" { continue; }
+                    let v = rt.resolve(&rt.stack[ind]);
+                    let mut w: Vec<u8> = vec![];
+                    write_variable(&mut w, rt, v, EscapeString::Json, 0).unwrap();
+                    variables.push(Json::object(vec![
+                        ("name", Json::str(name)),
+                        ("value", Json::Str(String::from_utf8(w).unwrap())),
+                        ("type", Json::str(&rt.typeof_var(v))),
+                        ("variablesReference", Json::Number(0.0)),
+                    ]));
+                }
+            }
+        }
+        Json::object(vec![("variables", Json::Array(variables))])
+    }
+}
+
+impl Debugger for DapDebugger {
+    fn before(
+        &mut self,
+        rt: &Runtime,
+        range: Range,
+        call: &Call,
+        module: &Module
+    ) -> Result<(), String> {
+        let depth = rt.call_stack.len();
+        self.ranges.truncate(depth);
+        while self.ranges.len() < depth { self.ranges.push(range); }
+        self.ranges[depth - 1] = range;
+
+        let f = &module.functions[call.index];
+        let (line, _) = line_of(&f.source, range.offset);
+        let new_line = match self.last {
+            Some((ref file, l, d)) => file != &f.file || l != line || d != depth,
+            None => true,
+        };
+        self.last = Some((f.file.clone(), line, depth));
+
+        let (breakpoint, pause) = {
+            let mut s = self.session.lock().unwrap();
+            let breakpoint = new_line && match s.breakpoints.get(&**f.file) {
+                Some(list) => list.iter().any(|&(_, r)| {
+                    r.offset <= range.offset && range.offset <= r.offset + r.length
+                }),
+                None => false,
+            };
+            let pause = match s.thread(self.id) {
+                Some(t) => { let pause = t.pause; t.pause = false; pause }
+                None => false,
+            };
+            (breakpoint, pause)
+        };
+        if pause {
+            return self.stopped("pause", rt, module, depth);
+        }
+        if breakpoint {
+            return self.stopped("breakpoint", rt, module, depth);
+        }
+        if !new_line { return Ok(()); }
+        let step = match self.mode {
+            Mode::StepIn => true,
+            Mode::Next(d) => depth <= d,
+            Mode::StepOut(d) => depth < d,
+            Mode::Continue => false,
+        };
+        if step {
+            let reason = if self.has_stopped { "step" } else { "entry" };
+            return self.stopped(reason, rt, module, depth);
+        }
+        Ok(())
+    }
+
+    fn thread(&mut self) -> Option<Box<Debugger>> {
+        Some(Box::new(DapDebugger::new(self.session.clone(), false)))
+    }
+
+    fn output(&mut self, text: &str) {
+        self.session.lock().unwrap().event("output", Json::object(vec![
+            ("category", Json::str("stdout")),
+            ("output", Json::str(text)),
+        ]));
+    }
+}
+
+impl Drop for DapDebugger {
+    fn drop(&mut self) {
+        let mut s = self.session.lock().unwrap();
+        s.threads.retain(|t| t.id != self.id);
+        s.event("thread", Json::object(vec![
+            ("reason", Json::str("exited")),
+            ("threadId", Json::Number(self.id as f64)),
+        ]));
+    }
+}
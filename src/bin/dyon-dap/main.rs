@@ -0,0 +1,217 @@
+//! Debug Adapter Protocol server for Dyon scripts.
+//!
+//! Reads requests from standard input and writes responses and events
+//! to standard output. Each script thread, including `go` threads,
+//! gets its own debugger that blocks the thread while it is stopped.
+
+extern crate dyon;
+extern crate range;
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use dyon::{load, Module, Runtime, Variable};
+
+use json::{read_message, Json};
+use session::{Command, Session};
+
+mod debugger;
+#[path = "../shared/json.rs"]
+mod json;
+#[path = "../shared/lines.rs"]
+mod lines;
+mod session;
+
+/// Canonicalizes paths so breakpoints match the files loaded by the runtime.
+fn canonical(path: &str) -> String {
+    Path::new(path).canonicalize()
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or(path.into())
+}
+
+struct Launch {
+    program: String,
+    args: Vec<String>,
+    stop_on_entry: bool,
+}
+
+fn main() {
+    let session = Arc::new(Mutex::new(Session::new()));
+    let stdin = io::stdin();
+    let mut stdin = stdin.lock();
+    let mut launch: Option<Launch> = None;
+    loop {
+        let req = match read_message(&mut stdin) {
+            Ok(Some(req)) => req,
+            Ok(None) => break,
+            Err(err) => {
+                session.lock().unwrap().event("output", Json::object(vec![
+                    ("category", Json::str("stderr")),
+                    ("output", Json::Str(format!("{}\n", err))),
+                ]));
+                break;
+            }
+        };
+        let args = req.get("arguments");
+        let thread_id = args.get("threadId").as_f64().unwrap_or(0.0) as usize;
+        match req.get("command").as_str().unwrap_or("") {
+            "initialize" => {
+                let mut s = session.lock().unwrap();
+                s.respond(&req, Json::object(vec![
+                    ("supportsConfigurationDoneRequest", Json::Bool(true)),
+                ]));
+                s.event("initialized", Json::object(vec![]));
+            }
+            "launch" => {
+                let program = match args.get("program").as_str() {
+                    Some(program) => canonical(program),
+                    None => {
+                        session.lock().unwrap().respond_error(&req, "Expected `program`");
+                        continue;
+                    }
+                };
+                launch = Some(Launch {
+                    program: program,
+                    args: args.get("args").as_array().iter()
+                        .filter_map(|a| a.as_str().map(|a| a.to_string())).collect(),
+                    stop_on_entry: args.get("stopOnEntry").as_bool().unwrap_or(false),
+                });
+                session.lock().unwrap().respond(&req, Json::object(vec![]));
+            }
+            "setBreakpoints" => {
+                let path = canonical(args.get("source").get("path").as_str().unwrap_or(""));
+                let mut source = String::new();
+                let _ = File::open(&path).and_then(|mut f| f.read_to_string(&mut source));
+                let mut list = vec![];
+                let mut breakpoints = vec![];
+                for bp in args.get("breakpoints").as_array() {
+                    let line = bp.get("line").as_f64().unwrap_or(0.0) as usize;
+                    let range = session::line_range(&source, line);
+                    if let Some(range) = range {
+                        list.push((line, range));
+                    }
+                    breakpoints.push(Json::object(vec![
+                        ("verified", Json::Bool(range.is_some())),
+                        ("line", Json::Number(line as f64)),
+                    ]));
+                }
+                let mut s = session.lock().unwrap();
+                s.breakpoints.insert(path, list);
+                s.respond(&req, Json::object(vec![
+                    ("breakpoints", Json::Array(breakpoints)),
+                ]));
+            }
+            "configurationDone" => {
+                session.lock().unwrap().respond(&req, Json::object(vec![]));
+                if let Some(launch) = launch.take() {
+                    let session = session.clone();
+                    thread::spawn(move || run(session, launch));
+                }
+            }
+            "threads" => {
+                let mut s = session.lock().unwrap();
+                let threads = s.threads.iter().map(|t| Json::object(vec![
+                    ("id", Json::Number(t.id as f64)),
+                    ("name", Json::str(&t.name)),
+                ])).collect();
+                s.respond(&req, Json::object(vec![("threads", Json::Array(threads))]));
+            }
+            "stackTrace" => forward(&session, &req, thread_id, Command::StackTrace(req.clone())),
+            "scopes" => {
+                let frame_id = args.get("frameId").as_f64().unwrap_or(0.0) as usize;
+                let scope = |name: &str, currents: bool| Json::object(vec![
+                    ("name", Json::str(name)),
+                    ("variablesReference",
+                     Json::Number(session::variables_ref(frame_id, currents) as f64)),
+                    ("expensive", Json::Bool(false)),
+                ]);
+                session.lock().unwrap().respond(&req, Json::object(vec![
+                    ("scopes", Json::Array(vec![
+                        scope("Locals", false),
+                        scope("Currents", true),
+                    ])),
+                ]));
+            }
+            "variables" => {
+                let r = args.get("variablesReference").as_f64().unwrap_or(0.0) as usize;
+                let thread_id = if r > 0 {
+                    session::decode_frame_id(session::decode_variables_ref(r).0).0
+                } else { 0 };
+                forward(&session, &req, thread_id, Command::Variables(req.clone()))
+            }
+            "continue" => resume(&session, &req, thread_id, Command::Continue),
+            "next" => resume(&session, &req, thread_id, Command::Next),
+            "stepIn" => resume(&session, &req, thread_id, Command::StepIn),
+            "stepOut" => resume(&session, &req, thread_id, Command::StepOut),
+            "pause" => {
+                let mut s = session.lock().unwrap();
+                if let Some(t) = s.thread(thread_id) { t.pause = true; }
+                s.respond(&req, Json::object(vec![]));
+            }
+            "disconnect" => {
+                session.lock().unwrap().respond(&req, Json::object(vec![]));
+                break;
+            }
+            cmd => {
+                let msg = format!("Unsupported request `{}`", cmd);
+                session.lock().unwrap().respond_error(&req, &msg);
+            }
+        }
+    }
+}
+
+/// Sends an inspection request to a stopped thread, which responds to it.
+fn forward(session: &Arc<Mutex<Session>>, req: &Json, thread_id: usize, cmd: Command) {
+    let mut s = session.lock().unwrap();
+    let sent = match s.thread(thread_id) {
+        Some(ref t) if t.stopped => t.commands.send(cmd).is_ok(),
+        _ => false,
+    };
+    if !sent {
+        s.respond_error(req, "Thread is not stopped");
+    }
+}
+
+/// Resumes a stopped thread.
+fn resume(session: &Arc<Mutex<Session>>, req: &Json, thread_id: usize, cmd: Command) {
+    let mut s = session.lock().unwrap();
+    let sent = match s.thread(thread_id) {
+        Some(ref t) if t.stopped => t.commands.send(cmd).is_ok(),
+        _ => false,
+    };
+    if sent {
+        s.respond(req, Json::object(vec![]));
+    } else {
+        s.respond_error(req, "Thread is not stopped");
+    }
+}
+
+/// Runs the launched program and reports when it ends.
+fn run(session: Arc<Mutex<Session>>, launch: Launch) {
+    let exit_code = {
+        let mut module = Module::new();
+        let res = load(&launch.program, &mut module).and_then(|()| {
+            let mut rt = Runtime::new();
+            rt.debugger = Some(Box::new(debugger::DapDebugger::new(
+                session.clone(), launch.stop_on_entry)));
+            rt.run_args(&Arc::new(module), &launch.args)
+        });
+        match res {
+            Ok(Some(Variable::Result(Err(_)))) => 1,
+            Ok(_) => 0,
+            Err(err) => {
+                session.lock().unwrap().event("output", Json::object(vec![
+                    ("category", Json::str("stderr")),
+                    ("output", Json::Str(format!("{}\n", err))),
+                ]));
+                1
+            }
+        }
+    };
+    let mut s = session.lock().unwrap();
+    s.event("exited", Json::object(vec![("exitCode", Json::Number(exit_code as f64))]));
+    s.event("terminated", Json::object(vec![]));
+}
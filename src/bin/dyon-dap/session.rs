@@ -0,0 +1,117 @@
+//! State shared between the protocol loop and the script threads.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::mpsc::Sender;
+
+use range::Range;
+
+use json::{write_message, Json};
+
+/// Sent from the protocol loop to a script thread.
+pub enum Command {
+    Continue,
+    Next,
+    StepIn,
+    StepOut,
+    /// Answered by a stopped thread, since only it can read its runtime.
+    StackTrace(Json),
+    Variables(Json),
+}
+
+pub struct ThreadInfo {
+    pub id: usize,
+    pub name: String,
+    pub commands: Sender<Command>,
+    pub stopped: bool,
+    /// Set by a `pause` request.
+    pub pause: bool,
+}
+
+pub struct Session {
+    seq: u64,
+    /// Breakpoint lines and their source ranges by file.
+    pub breakpoints: HashMap<String, Vec<(usize, Range)>>,
+    pub threads: Vec<ThreadInfo>,
+    pub next_thread_id: usize,
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session {
+            seq: 0,
+            breakpoints: HashMap::new(),
+            threads: vec![],
+            next_thread_id: 1,
+        }
+    }
+
+    pub fn thread(&mut self, id: usize) -> Option<&mut ThreadInfo> {
+        self.threads.iter_mut().find(|t| t.id == id)
+    }
+
+    /// Writes a protocol message to standard output.
+    pub fn send(&mut self, ty: &str, mut msg: Vec<(&str, Json)>) {
+        self.seq += 1;
+        msg.insert(0, ("seq", Json::Number(self.seq as f64)));
+        msg.insert(1, ("type", Json::str(ty)));
+        let stdout = io::stdout();
+        write_message(&mut stdout.lock(), &Json::object(msg)).unwrap();
+    }
+
+    pub fn event(&mut self, event: &str, body: Json) {
+        self.send("event", vec![("event", Json::str(event)), ("body", body)]);
+    }
+
+    pub fn respond(&mut self, request: &Json, body: Json) {
+        self.send("response", vec![
+            ("request_seq", request.get("seq").clone()),
+            ("success", Json::Bool(true)),
+            ("command", request.get("command").clone()),
+            ("body", body),
+        ]);
+    }
+
+    pub fn respond_error(&mut self, request: &Json, message: &str) {
+        self.send("response", vec![
+            ("request_seq", request.get("seq").clone()),
+            ("success", Json::Bool(false)),
+            ("command", request.get("command").clone()),
+            ("message", Json::str(message)),
+        ]);
+    }
+}
+
+/// Frames and variable references encode the thread id and frame index.
+const FRAMES_PER_THREAD: usize = 100000;
+
+pub fn frame_id(thread: usize, frame: usize) -> usize {
+    thread * FRAMES_PER_THREAD + frame
+}
+
+/// Returns thread id and frame index.
+pub fn decode_frame_id(id: usize) -> (usize, usize) {
+    (id / FRAMES_PER_THREAD, id % FRAMES_PER_THREAD)
+}
+
+/// Variable references are `frame_id * 2 + 1` for locals and `frame_id * 2 + 2` for currents.
+pub fn variables_ref(frame_id: usize, currents: bool) -> usize {
+    frame_id * 2 + if currents { 2 } else { 1 }
+}
+
+/// Returns frame id and whether the reference is to current objects.
+pub fn decode_variables_ref(r: usize) -> (usize, bool) {
+    ((r - 1) / 2, (r - 1) % 2 == 1)
+}
+
+/// Returns the byte range of a line, starting at 1.
+pub fn line_range(source: &str, line: usize) -> Option<Range> {
+    let mut start = 0;
+    for (i, text) in source.split('\n').enumerate() {
+        if i + 1 == line {
+            return Some(Range::new(start, text.len()));
+        }
+        start += text.len() + 1;
+    }
+    None
+}
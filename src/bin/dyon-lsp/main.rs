@@ -0,0 +1,398 @@
+//! Language Server Protocol server for Dyon scripts.
+//!
+//! Each edit re-runs parsing, lifetime checking and type checking.
+//! The node tree from the lifetime checker is kept to answer hover,
+//! goto definition and completion requests.
+
+extern crate dyon;
+extern crate piston_meta;
+extern crate range;
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+
+use dyon::{load, syntax_rules, Module, Prelude};
+use dyon::ast::UseLookup;
+use dyon::lifetime::{build_use_lookup, check_all};
+use dyon::lifetime::kind::Kind;
+use dyon::lifetime::node::Node;
+use range::Range;
+
+use json::{read_message, write_message, Json};
+
+#[path = "../shared/json.rs"]
+mod json;
+
+fn send(msg: Vec<(&str, Json)>) {
+    let mut msg = msg;
+    msg.insert(0, ("jsonrpc", Json::str("2.0")));
+    let stdout = io::stdout();
+    write_message(&mut stdout.lock(), &Json::object(msg)).unwrap();
+}
+
+fn respond(req: &Json, result: Json) {
+    send(vec![("id", req.get("id").clone()), ("result", result)]);
+}
+
+fn notify(method: &str, params: Json) {
+    send(vec![("method", Json::str(method)), ("params", params)]);
+}
+
+/// Converts a byte offset to a zero based line and UTF-16 character.
+fn position(text: &str, offset: usize) -> Json {
+    let mut offset = if offset > text.len() { text.len() } else { offset };
+    while !text.is_char_boundary(offset) { offset -= 1; }
+    let start = text[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line = text[..offset].matches('\n').count();
+    let character: usize = text[start..offset].chars().map(|c| c.len_utf16()).sum();
+    Json::object(vec![
+        ("line", Json::Number(line as f64)),
+        ("character", Json::Number(character as f64)),
+    ])
+}
+
+/// Converts a zero based line and UTF-16 character to a byte offset.
+fn offset(text: &str, pos: &Json) -> usize {
+    let line = pos.get("line").as_f64().unwrap_or(0.0) as usize;
+    let character = pos.get("character").as_f64().unwrap_or(0.0) as usize;
+    let mut start = 0;
+    for (i, l) in text.split('\n').enumerate() {
+        if i == line {
+            let mut n = 0;
+            for (j, c) in l.char_indices() {
+                if n >= character { return start + j; }
+                n += c.len_utf16();
+            }
+            return start + l.len();
+        }
+        start += l.len() + 1;
+    }
+    text.len()
+}
+
+fn range_json(text: &str, range: Range) -> Json {
+    Json::object(vec![
+        ("start", position(text, range.offset)),
+        ("end", position(text, range.offset + range.length)),
+    ])
+}
+
+fn location(uri: &str, text: &str, range: Range) -> Json {
+    Json::object(vec![
+        ("uri", Json::str(uri)),
+        ("range", range_json(text, range)),
+    ])
+}
+
+fn file_uri(path: &str) -> String {
+    use std::path::Path;
+
+    let path = Path::new(path).canonicalize()
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or(path.into());
+    format!("file://{}", path)
+}
+
+/// Strips mutability information, e.g. `foo(mut,_)` becomes `foo`.
+fn base_name(name: &str) -> &str {
+    name.split('(').next().unwrap_or(name)
+}
+
+struct Document {
+    text: String,
+    nodes: Vec<Node>,
+    use_lookup: UseLookup,
+}
+
+struct Server {
+    /// Modules loaded from `initializationOptions.modules`, used by `use` imports.
+    module: Module,
+    prelude: Prelude,
+    documents: HashMap<String, Document>,
+}
+
+impl Server {
+    fn new(module: Module) -> Server {
+        Server {
+            prelude: Prelude::from_module(&module),
+            module: module,
+            documents: HashMap::new(),
+        }
+    }
+
+    /// Parses and checks a document, then publishes diagnostics.
+    fn update(&mut self, uri: &str, text: String) {
+        let mut nodes = vec![];
+        let mut use_lookup = UseLookup::new();
+        let mut diagnostics = vec![];
+        match syntax_rules() {
+            Ok(rules) => {
+                let mut data = vec![];
+                match piston_meta::parse(rules, &text, &mut data) {
+                    Ok(()) => {
+                        if let Err((_, errors)) = check_all(&mut nodes, &data, &self.prelude) {
+                            diagnostics.extend(errors.into_iter().map(|err| err.decouple()));
+                        }
+                        use_lookup = build_use_lookup(&nodes, &data, &self.prelude);
+                    }
+                    Err(err) => {
+                        let (range, err) = err.decouple();
+                        diagnostics.push((range, format!("{}", err)));
+                    }
+                }
+            }
+            Err(err) => diagnostics.push((Range::empty(0), err)),
+        }
+
+        notify("textDocument/publishDiagnostics", Json::object(vec![
+            ("uri", Json::str(uri)),
+            ("diagnostics", Json::Array(diagnostics.into_iter().map(|(range, msg)| {
+                Json::object(vec![
+                    ("range", range_json(&text, range)),
+                    ("severity", Json::Number(1.0)),
+                    ("source", Json::str("dyon")),
+                    ("message", Json::Str(msg)),
+                ])
+            }).collect())),
+        ]));
+        self.documents.insert(uri.into(), Document {
+            text: text,
+            nodes: nodes,
+            use_lookup: use_lookup,
+        });
+    }
+
+    /// Returns the index of the module function a call resolves to.
+    fn module_function(&self, doc: &Document, node: &Node) -> Option<usize> {
+        let name = match node.name() {
+            Some(name) => name,
+            None => return None,
+        };
+        let p = match node.alias {
+            Some(ref alias) => doc.use_lookup.aliases.get(alias)
+                .and_then(|fns| fns.get(name)).cloned(),
+            None => self.prelude.functions.get(name).cloned(),
+        };
+        // Loaded functions come after intrinsics and external functions in the prelude.
+        let offset = self.prelude.list.len() - self.module.functions.len();
+        match p {
+            Some(p) if p >= offset => Some(p - offset),
+            _ => None,
+        }
+    }
+
+    /// Describes the signature of a called function.
+    fn signature(&self, doc: &Document, node: &Node) -> Option<String> {
+        let name = base_name(node.name().map(|n| &***n).unwrap_or(""));
+        if let Some(decl) = node.declaration {
+            let f = &doc.nodes[decl];
+            let args: Vec<String> = f.children.iter()
+                .filter(|&&c| doc.nodes[c].kind == Kind::Arg)
+                .map(|&c| {
+                    let arg = &doc.nodes[c];
+                    format!("{}: {}", arg.name().map(|n| &***n).unwrap_or("_"),
+                            arg.ty.as_ref().map(|ty| ty.description())
+                                .unwrap_or("any".into()))
+                })
+                .collect();
+            let ret = f.ty.as_ref().map(|ty| ty.description()).unwrap_or("any".into());
+            return Some(format!("fn {}({}) -> {}", name, args.join(", "), ret));
+        }
+        let p = match node.alias {
+            Some(ref alias) => node.name().and_then(|n| doc.use_lookup.aliases.get(alias)
+                .and_then(|fns| fns.get(n)).cloned()),
+            None => node.name().and_then(|n| self.prelude.functions.get(n).cloned()),
+        };
+        p.map(|p| {
+            let f = &self.prelude.list[p];
+            let args: Vec<String> = f.tys.iter().map(|ty| ty.description()).collect();
+            format!("fn {}({}) -> {}", name, args.join(", "), f.ret.description())
+        })
+    }
+
+    fn hover(&self, req: &Json) -> Json {
+        let params = req.get("params");
+        let uri = params.get("textDocument").get("uri").as_str().unwrap_or("");
+        let doc = match self.documents.get(uri) {
+            Some(doc) => doc,
+            None => return Json::Null,
+        };
+        let offset = offset(&doc.text, params.get("position"));
+        let i = match find_node(&doc.nodes, offset, |n| {
+            n.ty.is_some() || n.kind == Kind::Call || n.kind == Kind::Item
+        }) {
+            Some(i) => i,
+            None => return Json::Null,
+        };
+        let node = &doc.nodes[i];
+        let text = if node.kind == Kind::Call {
+            self.signature(doc, node)
+        } else {
+            let ty = node.ty.as_ref().map(|ty| ty.description()).unwrap_or("any".into());
+            Some(match node.name() {
+                Some(name) => format!("{}: {}", base_name(name), ty),
+                None => ty,
+            })
+        };
+        match text {
+            Some(text) => Json::object(vec![
+                ("contents", Json::object(vec![
+                    ("kind", Json::str("markdown")),
+                    ("value", Json::Str(format!("```dyon\n{}\n```", text))),
+                ])),
+                ("range", range_json(&doc.text, node.source)),
+            ]),
+            None => Json::Null,
+        }
+    }
+
+    fn definition(&self, req: &Json) -> Json {
+        let params = req.get("params");
+        let uri = params.get("textDocument").get("uri").as_str().unwrap_or("");
+        let doc = match self.documents.get(uri) {
+            Some(doc) => doc,
+            None => return Json::Null,
+        };
+        let offset = offset(&doc.text, params.get("position"));
+        let i = match find_node(&doc.nodes, offset, |n| {
+            n.kind == Kind::Call || (n.kind == Kind::Item && n.declaration.is_some())
+        }) {
+            Some(i) => i,
+            None => return Json::Null,
+        };
+        let node = &doc.nodes[i];
+        if let Some(decl) = node.declaration {
+            return location(uri, &doc.text, doc.nodes[decl].source);
+        }
+        match self.module_function(doc, node) {
+            Some(f_index) => {
+                let f = &self.module.functions[f_index];
+                location(&file_uri(&f.file), &f.source, f.source_range)
+            }
+            None => Json::Null,
+        }
+    }
+
+    fn completion(&self, req: &Json) -> Json {
+        let uri = req.get("params").get("textDocument").get("uri").as_str().unwrap_or("");
+        let mut items = vec![];
+        let mut labels = HashSet::new();
+        let mut add = |label: &str, detail: String| {
+            if labels.insert(label.to_string()) {
+                items.push(Json::object(vec![
+                    ("label", Json::str(label)),
+                    // Function.
+                    ("kind", Json::Number(3.0)),
+                    ("detail", Json::Str(detail)),
+                ]));
+            }
+        };
+        if let Some(doc) = self.documents.get(uri) {
+            for node in doc.nodes.iter().filter(|n| n.kind == Kind::Fn) {
+                if let Some(name) = node.name() {
+                    let ty = node.ty.as_ref().map(|ty| ty.description());
+                    add(base_name(name), ty.unwrap_or("any".into()));
+                }
+            }
+        }
+        for (name, &p) in &self.prelude.functions {
+            if self.prelude.disabled_capability(p).is_some() { continue; }
+            add(base_name(name), self.prelude.list[p].ret.description());
+        }
+        Json::object(vec![
+            ("isIncomplete", Json::Bool(false)),
+            ("items", Json::Array(items)),
+        ])
+    }
+}
+
+/// Finds the smallest node containing the offset that satisfies the filter.
+fn find_node<F: Fn(&Node) -> bool>(nodes: &[Node], offset: usize, f: F) -> Option<usize> {
+    let mut found: Option<usize> = None;
+    for (i, n) in nodes.iter().enumerate() {
+        if n.source.offset <= offset && offset <= n.source.offset + n.source.length && f(n) {
+            match found {
+                // Nodes come after their parents, so the inner node wins a tie.
+                Some(j) if nodes[j].source.length < n.source.length => {}
+                _ => found = Some(i),
+            }
+        }
+    }
+    found
+}
+
+fn main() {
+    let stdin = io::stdin();
+    let mut stdin = stdin.lock();
+    let mut server = Server::new(Module::new());
+    loop {
+        let req = match read_message(&mut stdin) {
+            Ok(Some(req)) => req,
+            Ok(None) => break,
+            Err(err) => {
+                writeln!(&mut io::stderr(), "{}", err).unwrap();
+                break;
+            }
+        };
+        let params = req.get("params");
+        match req.get("method").as_str().unwrap_or("") {
+            "initialize" => {
+                let mut module = Module::new();
+                for file in params.get("initializationOptions").get("modules").as_array() {
+                    if let Some(file) = file.as_str() {
+                        if let Err(err) = load(file, &mut module) {
+                            writeln!(&mut io::stderr(), "{}", err).unwrap();
+                        }
+                    }
+                }
+                server = Server::new(module);
+                respond(&req, Json::object(vec![
+                    ("capabilities", Json::object(vec![
+                        // Full document sync.
+                        ("textDocumentSync", Json::Number(1.0)),
+                        ("hoverProvider", Json::Bool(true)),
+                        ("definitionProvider", Json::Bool(true)),
+                        ("completionProvider", Json::object(vec![])),
+                    ])),
+                ]));
+            }
+            "textDocument/didOpen" => {
+                let doc = params.get("textDocument");
+                if let (Some(uri), Some(text)) = (doc.get("uri").as_str(),
+                                                  doc.get("text").as_str()) {
+                    server.update(uri, text.into());
+                }
+            }
+            "textDocument/didChange" => {
+                let uri = params.get("textDocument").get("uri").as_str();
+                let text = params.get("contentChanges").as_array().last()
+                    .and_then(|change| change.get("text").as_str());
+                if let (Some(uri), Some(text)) = (uri, text) {
+                    server.update(uri, text.into());
+                }
+            }
+            "textDocument/didClose" => {
+                if let Some(uri) = params.get("textDocument").get("uri").as_str() {
+                    server.documents.remove(uri);
+                }
+            }
+            "textDocument/hover" => respond(&req, server.hover(&req)),
+            "textDocument/definition" => respond(&req, server.definition(&req)),
+            "textDocument/completion" => respond(&req, server.completion(&req)),
+            "shutdown" => respond(&req, Json::Null),
+            "exit" => break,
+            method => {
+                // Requests have an id and need a response, notifications do not.
+                if *req.get("id") != Json::Null {
+                    send(vec![
+                        ("id", req.get("id").clone()),
+                        ("error", Json::object(vec![
+                            ("code", Json::Number(-32601.0)),
+                            ("message", Json::Str(format!("Unknown method `{}`", method))),
+                        ])),
+                    ]);
+                }
+            }
+        }
+    }
+}
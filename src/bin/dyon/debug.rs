@@ -0,0 +1,185 @@
+//! Terminal debugger.
+
+use std::io::{self, BufRead, Write};
+use std::sync::Arc;
+
+use dyon::{Debugger, Module, Runtime};
+use dyon::runtime::Call;
+use dyon::write::{write_variable, EscapeString};
+use range::Range;
+
+use lines::line_of;
+
+const HELP: &'static str = "\
+Commands:
+    break <file>:<line>  Set breakpoint (b)
+    delete <file>:<line> Remove breakpoint (d)
+    step                 Stop at next line (s)
+    next                 Stop at next line in this function (n)
+    finish               Stop after returning from function (f)
+    continue             Run to next breakpoint (c)
+    locals               Print local and current variables (l)
+    print <name>         Print variable (p)
+    backtrace            Print call stack (bt)
+    quit                 Stop the script (q)";
+
+enum Mode {
+    Step,
+    /// Stop at a new line with call depth less or equal.
+    Next(usize),
+    /// Stop when call depth is less.
+    Finish(usize),
+    Continue,
+}
+
+/// Stops at breakpoints and reads commands from standard input.
+pub struct TerminalDebugger {
+    breakpoints: Vec<(String, usize)>,
+    mode: Mode,
+    /// File, line and call depth of the last visited expression.
+    last: Option<(Arc<String>, usize, usize)>,
+}
+
+impl TerminalDebugger {
+    pub fn new() -> TerminalDebugger {
+        TerminalDebugger {
+            breakpoints: vec![],
+            // Stop before the first expression to let the user set breakpoints.
+            mode: Mode::Step,
+            last: None,
+        }
+    }
+
+    fn is_breakpoint(&self, file: &str, line: usize) -> bool {
+        self.breakpoints.iter().any(|&(ref f, l)| {
+            l == line && (f == file || file.ends_with(&format!("/{}", f)))
+        })
+    }
+
+    /// Reads commands until execution should continue.
+    fn prompt(&mut self, rt: &Runtime, call: &Call, depth: usize) -> Result<(), String> {
+        let stdin = io::stdin();
+        loop {
+            print!("(dyon) ");
+            io::stdout().flush().unwrap();
+            let mut line = String::new();
+            match stdin.lock().read_line(&mut line) {
+                Ok(0) | Err(_) => return Err("Debugger quit".into()),
+                Ok(_) => {}
+            }
+            let line = line.trim();
+            let (cmd, arg) = match line.find(char::is_whitespace) {
+                Some(i) => (&line[..i], line[i..].trim()),
+                None => (line, ""),
+            };
+            match cmd {
+                "s" | "step" => { self.mode = Mode::Step; return Ok(()); }
+                "n" | "next" => { self.mode = Mode::Next(depth); return Ok(()); }
+                "f" | "finish" => { self.mode = Mode::Finish(depth); return Ok(()); }
+                "c" | "continue" => { self.mode = Mode::Continue; return Ok(()); }
+                "q" | "quit" => return Err("Debugger quit".into()),
+                "b" | "break" | "d" | "delete" => {
+                    match parse_location(arg) {
+                        Some(bp) => {
+                            if cmd == "b" || cmd == "break" {
+                                println!("Breakpoint at {}:{}", bp.0, bp.1);
+                                self.breakpoints.push(bp);
+                            } else {
+                                self.breakpoints.retain(|x| x != &bp);
+                            }
+                        }
+                        None => println!("Expected `<file>:<line>`"),
+                    }
+                }
+                "l" | "locals" => print_locals(rt, call, None),
+                "p" | "print" => print_locals(rt, call, Some(arg)),
+                "bt" | "backtrace" => {
+                    for call in rt.call_stack.iter().rev() {
+                        match call.file {
+                            Some(ref file) => println!("{} ({})", call.fn_name, file),
+                            None => println!("{}", call.fn_name),
+                        }
+                    }
+                }
+                "h" | "help" => println!("{}", HELP),
+                "" => {}
+                _ => println!("Unknown command `{}`, type `help` for help", cmd),
+            }
+        }
+    }
+}
+
+impl Debugger for TerminalDebugger {
+    fn before(
+        &mut self,
+        rt: &Runtime,
+        range: Range,
+        call: &Call,
+        module: &Module
+    ) -> Result<(), String> {
+        let f = &module.functions[call.index];
+        let (line, text) = line_of(&f.source, range.offset);
+        let text = f.source[text.iter()].trim_right();
+        let depth = rt.call_stack.len();
+        let new_line = match self.last {
+            Some((ref file, l, d)) => file != &f.file || l != line || d != depth,
+            None => true,
+        };
+        self.last = Some((f.file.clone(), line, depth));
+        if !new_line { return Ok(()); }
+
+        let stop = match self.mode {
+            Mode::Step => true,
+            Mode::Next(d) => depth <= d,
+            Mode::Finish(d) => depth < d,
+            Mode::Continue => false,
+        } || self.is_breakpoint(&f.file, line);
+        if !stop { return Ok(()); }
+
+        println!("{}:{} in `{}`", f.file, line, call.fn_name);
+        println!("{:>5} | {}", line, text);
+        self.prompt(rt, call, depth)
+    }
+}
+
+fn parse_location(arg: &str) -> Option<(String, usize)> {
+    let i = match arg.rfind(':') {
+        Some(i) => i,
+        None => return None,
+    };
+    match arg[i + 1..].parse() {
+        Ok(line) => Some((arg[..i].into(), line)),
+        Err(_) => None,
+    }
+}
+
+/// Prints variables of a call frame, or a single variable by name.
+fn print_locals(rt: &Runtime, call: &Call, name: Option<&str>) {
+    let mut stdout = io::stdout();
+    let mut found = false;
+    // Later locals shadow earlier ones, so search for a name in reverse.
+    for &(ref n, ind) in rt.local_stack[call.local_len..].iter().rev() {
+        if name.map(|name| name == &**n).unwrap_or(true) {
+            found = true;
+            if &**n == "return" { continue; }
+            write!(&mut stdout, "{} = ", n).unwrap();
+            write_variable(&mut stdout, rt, rt.resolve(&rt.stack[ind]),
+                           EscapeString::Json, 0).unwrap();
+            writeln!(&mut stdout, "").unwrap();
+            if name.is_some() { return; }
+        }
+    }
+    for &(ref n, ind) in rt.current_stack[call.current_len..].iter().rev() {
+        if name.map(|name| name == &**n).unwrap_or(true) {
+            found = true;
+            write!(&mut stdout, "~ {} = ", n).unwrap();
+            write_variable(&mut stdout, rt, rt.resolve(&rt.stack[ind]),
+                           EscapeString::Json, 0).unwrap();
+            writeln!(&mut stdout, "").unwrap();
+            if name.is_some() { return; }
+        }
+    }
+    if let (false, Some(name)) = (found, name) {
+        println!("Could not find variable `{}`", name);
+    }
+}
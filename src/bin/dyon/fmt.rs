@@ -0,0 +1,73 @@
+//! Formats source files from the command line.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+use dyon::format::{format_str, Config};
+
+const USAGE: &'static str = "Usage: dyon fmt [--check] [--width <n>] <file>...";
+
+/// Runs `dyon fmt [--check] [--width <n>] <file>...`, returning the exit status.
+///
+/// Rewrites the files in place.
+/// With `--check`, lists the files that are not formatted instead,
+/// and fails if there are any.
+pub fn run(args: &[String]) -> i32 {
+    let mut config = Config::default();
+    let mut check = false;
+    let mut files = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match &**arg {
+            "--check" => check = true,
+            "--width" => {
+                match args.next().and_then(|n| n.parse().ok()) {
+                    Some(width) => config.width = width,
+                    None => {
+                        writeln!(&mut io::stderr(), "{}", USAGE).unwrap();
+                        return 2;
+                    }
+                }
+            }
+            _ => files.push(arg),
+        }
+    }
+    if files.len() == 0 {
+        writeln!(&mut io::stderr(), "{}", USAGE).unwrap();
+        return 2;
+    }
+
+    let mut status = 0;
+    for file in files {
+        match format_file(file, &config, check) {
+            Ok(true) => {}
+            Ok(false) => {
+                println!("{}", file);
+                status = 1;
+            }
+            Err(err) => {
+                writeln!(&mut io::stderr(), "{}", err).unwrap();
+                status = 1;
+            }
+        }
+    }
+    status
+}
+
+/// Formats a file, returning `false` if it was not formatted when checking.
+fn format_file(file: &str, config: &Config, check: bool) -> Result<bool, String> {
+    let mut source = String::new();
+    let mut data_file = try!(File::open(file).map_err(|err|
+        format!("Could not open `{}`, {}", file, err)));
+    try!(data_file.read_to_string(&mut source).map_err(|err|
+        format!("Could not read `{}`, {}", file, err)));
+
+    let formatted = try!(format_str(file, &source, config));
+    if formatted == source { return Ok(true); }
+    if check { return Ok(false); }
+    let mut data_file = try!(File::create(file).map_err(|err|
+        format!("Could not create `{}`, {}", file, err)));
+    try!(data_file.write_all(formatted.as_bytes()).map_err(|err|
+        format!("Could not write `{}`, {}", file, err)));
+    Ok(true)
+}
@@ -0,0 +1,87 @@
+//! Lints source files from the command line.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use dyon::Module;
+use dyon::diagnostic::render;
+use dyon::lint::{apply_fixes, automatic_fixes, lint_str, Config, Rule, RULES};
+
+const USAGE: &'static str = "Usage: dyon lint [--fix] [--allow <rule>]... <file>...";
+
+/// Runs `dyon lint [--fix] [--allow <rule>]... <file>...`, returning the exit status.
+///
+/// Prints the lints of the files and fails if there are any.
+/// With `--fix`, rewrites the files with the fixes that keep the behavior of the program,
+/// prints each applied fix, and then the lints that are left.
+pub fn run(args: &[String]) -> i32 {
+    let mut config = Config::default();
+    let mut fix = false;
+    let mut files = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match &**arg {
+            "--fix" => fix = true,
+            "--allow" => {
+                match args.next().and_then(|name| Rule::from_name(name)) {
+                    Some(rule) => config.allow(rule),
+                    None => {
+                        let rules: Vec<&str> = RULES.iter().map(|rule| rule.name()).collect();
+                        writeln!(&mut io::stderr(), "{}\nRules: {}",
+                                 USAGE, rules.join(", ")).unwrap();
+                        return 2;
+                    }
+                }
+            }
+            _ => files.push(arg),
+        }
+    }
+    if files.len() == 0 {
+        writeln!(&mut io::stderr(), "{}", USAGE).unwrap();
+        return 2;
+    }
+
+    let mut status = 0;
+    for file in files {
+        match lint_file(file, &config, fix) {
+            Ok(true) => {}
+            Ok(false) => status = 1,
+            Err(err) => {
+                writeln!(&mut io::stderr(), "{}", err).unwrap();
+                status = 1;
+            }
+        }
+    }
+    status
+}
+
+/// Lints a file, returning `false` if there are lints left.
+fn lint_file(file: &str, config: &Config, fix: bool) -> Result<bool, String> {
+    let mut source = String::new();
+    let mut data_file = try!(File::open(file).map_err(|err|
+        format!("Could not open `{}`, {}", file, err)));
+    try!(data_file.read_to_string(&mut source).map_err(|err|
+        format!("Could not read `{}`, {}", file, err)));
+
+    let module = Module::new();
+    let mut lints = try!(lint_str(file, &source, &module, config));
+    if fix && automatic_fixes(&lints).len() > 0 {
+        for fix in automatic_fixes(&lints) {
+            let line = source[..fix.range.offset].matches('\n').count() + 1;
+            writeln!(&mut io::stderr(), "{}:{}: fixed, {}", file, line, fix.description).unwrap();
+        }
+        source = apply_fixes(&source, &lints);
+        let mut data_file = try!(File::create(file).map_err(|err|
+            format!("Could not create `{}`, {}", file, err)));
+        try!(data_file.write_all(source.as_bytes()).map_err(|err|
+            format!("Could not write `{}`, {}", file, err)));
+        lints = try!(lint_str(file, &source, &module, config));
+    }
+    if lints.len() == 0 { return Ok(true); }
+
+    let file = Arc::new(file.to_string());
+    let diagnostics: Vec<_> = lints.iter().map(|lint| lint.diagnostic(file.clone())).collect();
+    writeln!(&mut io::stderr(), "{}", render(&diagnostics, &source)).unwrap();
+    Ok(false)
+}
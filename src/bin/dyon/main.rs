@@ -0,0 +1,46 @@
+extern crate dyon;
+extern crate piston_meta;
+extern crate range;
+
+use std::env;
+use std::io::{self, Write};
+use std::process;
+
+mod debug;
+#[path = "../shared/lines.rs"]
+mod lines;
+mod fmt;
+mod lint;
+mod repl;
+mod run;
+mod testing;
+
+const USAGE: &'static str = "\
+Usage:
+    dyon [repl]
+    dyon run <file> [-- args...]
+    dyon debug <file> [-- args...]
+    dyon profile [--folded <out>] <file> [-- args...]
+    dyon fmt [--check] [--width <n>] <file>...
+    dyon lint [--fix] [--allow <rule>]... <file>...
+    dyon test [--filter <text>] [<path>...]";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.get(0).map(|s| &**s) {
+        None | Some("repl") => repl::run(),
+        Some("run") => process::exit(run::run(&args[1..], None)),
+        Some("debug") => {
+            let debugger = Box::new(debug::TerminalDebugger::new());
+            process::exit(run::run(&args[1..], Some(debugger)))
+        }
+        Some("profile") => process::exit(run::profile(&args[1..])),
+        Some("fmt") => process::exit(fmt::run(&args[1..])),
+        Some("lint") => process::exit(lint::run(&args[1..])),
+        Some("test") => process::exit(testing::run(&args[1..])),
+        Some(cmd) => {
+            writeln!(&mut io::stderr(), "Unknown command `{}`\n{}", cmd, USAGE).unwrap();
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,362 @@
+//! Interactive read-eval-print loop.
+//!
+//! Entries that parse as a module are function declarations,
+//! which are added to a persistent module.
+//! Other entries are wrapped in a function that takes the session variables
+//! as mutable arguments, and the body is evaluated in a frame on a persistent runtime.
+//! Variables declared at the top level of an entry are kept for later entries.
+
+use std::io::{self, BufRead, Write};
+use std::sync::Arc;
+
+use dyon::{load, load_str, syntax_rules, Module, Runtime, Variable};
+use dyon::intrinsics::functions::list_functions;
+use dyon::runtime::{Flow, Side};
+use dyon::write::{write_variable, EscapeString};
+
+const SOURCE: &'static str = "repl";
+const WRAPPER: &'static str = "__repl";
+const HELP: &'static str = "\
+Enter an expression, statements or a function declaration.
+Commands:
+    :type <expr>     Show the type of an expression
+    :functions       List available functions
+    :load <file>     Load functions from a source file
+    :help            Show this message
+    :quit            Exit";
+
+/// Runs the REPL on standard input and output.
+pub fn run() {
+    let stdin = io::stdin();
+    let mut repl = Repl::new();
+    let mut input = String::new();
+    println!("Dyon {} (type `:help` for help)", env!("CARGO_PKG_VERSION"));
+    loop {
+        print!("{}", if input.len() == 0 { "> " } else { "... " });
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        input.push_str(&line);
+        if depth(&input) > 0 { continue; }
+
+        let entry = input.trim().to_string();
+        input.clear();
+        if entry.len() == 0 { continue; }
+        if entry == ":quit" || entry == ":q" { break; }
+        match repl.entry(&entry) {
+            Ok(ref output) if output.len() == 0 => {}
+            Ok(output) => println!("{}", output),
+            Err(err) => println!("{}", err),
+        }
+    }
+}
+
+/// Returns the number of unclosed brackets, ignoring strings and comments.
+fn depth(input: &str) -> i32 {
+    let mut depth = 0;
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => { chars.next(); }
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                while let Some(c) = chars.next() {
+                    if c == '\n' { break; }
+                }
+            }
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    depth
+}
+
+struct Repl {
+    module: Module,
+    runtime: Runtime,
+    /// Variables declared in previous entries.
+    variables: Vec<(Arc<String>, Variable)>,
+}
+
+impl Repl {
+    fn new() -> Repl {
+        Repl {
+            module: Module::new(),
+            runtime: Runtime::new(),
+            variables: vec![],
+        }
+    }
+
+    /// Runs an entry, returning the text to print.
+    fn entry(&mut self, entry: &str) -> Result<String, String> {
+        if entry.starts_with(':') {
+            let (cmd, arg) = match entry.find(char::is_whitespace) {
+                Some(i) => (&entry[..i], entry[i..].trim()),
+                None => (entry, ""),
+            };
+            match cmd {
+                ":help" => Ok(HELP.into()),
+                ":type" => self.type_of(arg),
+                ":functions" => Ok(self.functions()),
+                ":load" => {
+                    let mut module = self.module.clone();
+                    try!(load(arg, &mut module));
+                    self.module = module;
+                    Ok(String::new())
+                }
+                _ => Err(format!("Unknown command `{}`, type `:help` for help", cmd))
+            }
+        } else if is_declaration(entry) {
+            self.declare(entry)
+        } else {
+            self.eval(entry)
+        }
+    }
+
+    /// Registers function declarations in the persistent module,
+    /// returning their names.
+    fn declare(&mut self, entry: &str) -> Result<String, String> {
+        let mut module = self.module.clone();
+        let n = module.functions.len();
+        try!(load_str(SOURCE, Arc::new(entry.into()), &mut module));
+        let mut names = vec![];
+        for f in module.functions.drain(n..) {
+            names.push(f.name.to_string());
+            self.module.register(f);
+        }
+        Ok(names.join("\n"))
+    }
+
+    /// Returns the session variables as arguments of the wrapper function.
+    ///
+    /// When `typed` is `true`, the arguments are declared with the type of their values.
+    fn args(&self, typed: bool) -> String {
+        let mut args = String::new();
+        for (i, &(ref name, ref val)) in self.variables.iter().enumerate() {
+            if i > 0 { args.push_str(", "); }
+            args.push_str(&format!("mut {}: 'return", name));
+            if typed {
+                if let Some(ty) = type_name(val) {
+                    args.push_str(&format!(" {}", ty));
+                }
+            }
+        }
+        args
+    }
+
+    /// Loads an entry wrapped in a function into a copy of the module.
+    ///
+    /// When `returns` is `true`, the entry must be a single expression.
+    fn wrap(&self, entry: &str, returns: bool) -> Result<Module, String> {
+        let mut source = format!("fn {}({}", WRAPPER, self.args(false));
+        // Dyon functions return values with `return`.
+        source.push_str(if returns { ") -> {\nreturn " } else { ") {\n" });
+        source.push_str(entry);
+        source.push_str("\n}\n");
+
+        let mut module = self.module.clone();
+        try!(load_str(SOURCE, Arc::new(source), &mut module));
+        Ok(module)
+    }
+
+    /// Returns the type of an expression inferred by the type checker.
+    fn type_of(&self, entry: &str) -> Result<String, String> {
+        // The return type of a function declared with `=` is inferred from the expression.
+        let source = format!("{}({}) = {}\n", WRAPPER, self.args(true), entry);
+        let mut module = self.module.clone();
+        try!(load_str(SOURCE, Arc::new(source), &mut module));
+        Ok(module.functions.last().unwrap().ret.description())
+    }
+
+    /// Evaluates statements and returns the value of the last expression.
+    fn eval(&mut self, entry: &str) -> Result<String, String> {
+        // Try statements first, then an expression with a value.
+        let module = match self.wrap(entry, false) {
+            Ok(module) => module,
+            Err(err) => match self.wrap(entry, true) {
+                Ok(module) => module,
+                Err(_) => return Err(err),
+            }
+        };
+        let module = Arc::new(module);
+        let f_index = module.functions.len() - 1;
+
+        let rt = &mut self.runtime;
+        let cl = rt.call_stack.len();
+        let st = rt.stack.len();
+        let lc = rt.local_stack.len();
+        let cu = rt.current_stack.len();
+        let res = {
+            let f = &module.functions[f_index];
+            if f.returns() {
+                rt.stack.push(Variable::Return);
+            }
+            let st_args = rt.stack.len();
+            for &(_, ref val) in &self.variables {
+                rt.stack.push(val.clone());
+            }
+            rt.push_fn(f.name.clone(), f_index, Some(f.file.clone()), st_args, lc, cu);
+            if f.returns() {
+                rt.local_stack.push((Arc::new("return".into()), st_args - 1));
+            }
+            for (i, arg) in f.args.iter().enumerate() {
+                rt.local_stack.push((arg.name.clone(), st_args + i));
+            }
+
+            // Evaluate top level expressions without a block,
+            // such that declared variables stay on the stack.
+            let mut res = Ok(None);
+            for e in &f.block.expressions {
+                match rt.expression(e, Side::Right, &module) {
+                    Ok((x, Flow::Continue)) => res = Ok(x),
+                    Ok((x, _)) => { res = Ok(x); break; }
+                    Err(err) => { res = Err(err); break; }
+                }
+            }
+            res
+        };
+
+        if res.is_ok() {
+            let mut variables: Vec<(Arc<String>, Variable)> = vec![];
+            for &(ref name, ind) in &rt.local_stack[lc..] {
+                if &**name == "return" { continue; }
+                let val = rt.resolve(&rt.stack[ind]).clone();
+                match variables.iter().position(|v| &v.0 == name) {
+                    Some(i) => variables[i].1 = val,
+                    None => variables.push((name.clone(), val)),
+                }
+            }
+            self.variables = variables;
+        }
+        let res = res.map(|x| x.map(|x| rt.resolve(&x).clone()));
+
+        rt.call_stack.truncate(cl);
+        rt.stack.truncate(st);
+        rt.local_stack.truncate(lc);
+        rt.current_stack.truncate(cu);
+
+        let mut w: Vec<u8> = vec![];
+        if let Some(x) = try!(res) {
+            write_variable(&mut w, rt, &x, EscapeString::Json, 0).unwrap();
+        }
+        Ok(String::from_utf8(w).unwrap())
+    }
+
+    /// Returns signatures of all available functions, one per line.
+    fn functions(&self) -> String {
+        let mut list: Vec<String> = list_functions(&self.module).iter()
+            .filter_map(|f| signature(f)).collect();
+        list.sort();
+        list.dedup();
+        list.join("\n")
+    }
+}
+
+/// Returns `true` if the entry parses as a module,
+/// which contains function declarations, e.g. `fn f(x) -> { ... }` or `f(x) = x + 1`.
+fn is_declaration(entry: &str) -> bool {
+    let syntax_rules = match syntax_rules() {
+        Ok(x) => x,
+        Err(_) => return false,
+    };
+    let mut data = vec![];
+    piston_meta::parse(syntax_rules, entry, &mut data).is_ok()
+}
+
+/// Returns the name of the type of a value, when it can be declared.
+fn type_name(val: &Variable) -> Option<&'static str> {
+    match *val {
+        Variable::Bool(_, None) => Some("bool"),
+        Variable::Bool(_, Some(_)) => Some("sec[bool]"),
+        Variable::F64(_, None) => Some("f64"),
+        Variable::F64(_, Some(_)) => Some("sec[f64]"),
+        Variable::Vec4(_) => Some("vec4"),
+        Variable::Text(_) => Some("str"),
+        Variable::Link(_) => Some("link"),
+        Variable::Array(_) => Some("[]"),
+        Variable::Object(_) => Some("{}"),
+        Variable::Option(_) => Some("opt"),
+        Variable::Result(_) => Some("res"),
+        _ => None,
+    }
+}
+
+/// Formats the signature of a function object from `list_functions`.
+fn signature(f: &Variable) -> Option<String> {
+    let obj = match *f {
+        Variable::Object(ref obj) => obj,
+        _ => return None
+    };
+    let text = |obj: &::std::collections::HashMap<Arc<String>, Variable>, key: &str| {
+        match obj.get(&Arc::new(key.into())) {
+            Some(&Variable::Text(ref t)) => t.to_string(),
+            _ => String::new(),
+        }
+    };
+    let mut s = text(obj, "name");
+    if s.starts_with(WRAPPER) { return None; }
+    s.push('(');
+    if let Some(&Variable::Array(ref args)) = obj.get(&Arc::new("arguments".into())) {
+        for (i, arg) in args.iter().enumerate() {
+            if let Variable::Object(ref arg) = *arg {
+                if i > 0 { s.push_str(", "); }
+                s.push_str(&format!("{}: {}", text(arg, "name"), text(arg, "takes")));
+            }
+        }
+    }
+    s.push(')');
+    let ret = text(obj, "returns");
+    if ret != "void" {
+        s.push_str(&format!(" -> {}", ret));
+    }
+    Some(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn declarations() {
+        assert!(is_declaration("fn f(x) -> { return x + 1 }"));
+        assert!(is_declaration("f(x) = x + 1"));
+        assert!(is_declaration("fn a() {}\nfn b() {}"));
+        assert!(!is_declaration("x := 1"));
+        assert!(!is_declaration("f(2)"));
+        assert!(!is_declaration("x = f(2)"));
+    }
+
+    #[test]
+    fn state() {
+        let mut repl = Repl::new();
+        assert_eq!(repl.entry("f(x) = x + 1").unwrap(), "f");
+        assert_eq!(repl.entry("x := f(2)").unwrap(), "");
+        assert_eq!(repl.entry("x += 1").unwrap(), "");
+        assert_eq!(repl.entry("x").unwrap(), "4");
+        assert_eq!(repl.entry("1 + 2").unwrap(), "3");
+        assert_eq!(repl.entry("\"a\"").unwrap(), "\"a\"");
+        assert!(repl.entry("y").is_err());
+        assert_eq!(repl.entry("x").unwrap(), "4");
+    }
+
+    #[test]
+    fn types() {
+        let mut repl = Repl::new();
+        assert_eq!(repl.entry(":type 1 + 2").unwrap(), "f64");
+        assert_eq!(repl.entry(":type \"a\"").unwrap(), "str");
+        assert_eq!(repl.entry(":type [1, 2]").unwrap(), "[]");
+        assert_eq!(repl.entry("x := 1").unwrap(), "");
+        assert_eq!(repl.entry(":type x + 2").unwrap(), "f64");
+    }
+}
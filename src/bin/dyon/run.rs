@@ -0,0 +1,127 @@
+//! Runs a script from the command line.
+
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use dyon::{load_str, Debugger, FnIndex, Module, Profiler, Runtime, Variable};
+
+/// Runs `dyon run <file> [-- args...]`, returning the exit status.
+///
+/// Reads the script from standard input when the file is `-`.
+pub fn run(args: &[String], debugger: Option<Box<Debugger>>) -> i32 {
+    let file = match args.get(0) {
+        Some(file) => file,
+        None => {
+            writeln!(&mut io::stderr(), "Usage: dyon run|debug <file> [-- args...]").unwrap();
+            return 2;
+        }
+    };
+    let script_args = match args.get(1).map(|s| &**s) {
+        Some("--") => &args[2..],
+        _ => &args[1..],
+    };
+    let mut rt = Runtime::new();
+    rt.debugger = debugger;
+    match load_file(file).and_then(|module| run_module(&module, &mut rt, script_args)) {
+        Ok(status) => status,
+        Err(err) => {
+            writeln!(&mut io::stderr(), "{}", err).unwrap();
+            1
+        }
+    }
+}
+
+/// Runs `dyon profile [--folded <out>] <file> [-- args...]`, returning the exit status.
+///
+/// Prints the time spent per function and source line when the script ends.
+/// With `--folded`, writes the sampled call stacks for flamegraph tools to `out`.
+pub fn profile(args: &[String]) -> i32 {
+    const USAGE: &'static str = "Usage: dyon profile [--folded <out>] <file> [-- args...]";
+
+    let (folded, args) = match args.get(0).map(|s| &**s) {
+        Some("--folded") => match args.get(1) {
+            Some(out) => (Some(out), &args[2..]),
+            None => {
+                writeln!(&mut io::stderr(), "{}", USAGE).unwrap();
+                return 2;
+            }
+        },
+        _ => (None, args),
+    };
+    let file = match args.get(0) {
+        Some(file) => file,
+        None => {
+            writeln!(&mut io::stderr(), "{}", USAGE).unwrap();
+            return 2;
+        }
+    };
+    let script_args = match args.get(1).map(|s| &**s) {
+        Some("--") => &args[2..],
+        _ => &args[1..],
+    };
+    let module = match load_file(file) {
+        Ok(module) => module,
+        Err(err) => {
+            writeln!(&mut io::stderr(), "{}", err).unwrap();
+            return 1;
+        }
+    };
+
+    let mut rt = Runtime::new();
+    rt.profiler = Some(Profiler::new());
+    let mut status = match run_module(&module, &mut rt, script_args) {
+        Ok(status) => status,
+        Err(err) => {
+            writeln!(&mut io::stderr(), "{}", err).unwrap();
+            1
+        }
+    };
+    let profiler = rt.profiler.take().unwrap();
+    writeln!(&mut io::stderr(), "\n{}", profiler.report(&module, 20)).unwrap();
+    if let Some(out) = folded {
+        use std::fs::File;
+
+        let res = File::create(out).and_then(|mut f| f.write_all(profiler.folded(&module).as_bytes()));
+        if let Err(err) = res {
+            writeln!(&mut io::stderr(), "Could not write `{}`, {}", out, err).unwrap();
+            status = 1;
+        }
+    }
+    status
+}
+
+fn load_file(file: &str) -> Result<Arc<Module>, String> {
+    let mut source = String::new();
+    if file == "-" {
+        try!(io::stdin().read_to_string(&mut source).map_err(|err|
+            format!("Could not read from stdin, {}", err)));
+    } else {
+        use std::fs::File;
+
+        let mut data_file = try!(File::open(file).map_err(|err|
+            format!("Could not open `{}`, {}", file, err)));
+        try!(data_file.read_to_string(&mut source).map_err(|err|
+            format!("Could not read `{}`, {}", file, err)));
+    }
+
+    let mut module = Module::new();
+    try!(load_str(file, Arc::new(source), &mut module));
+    Ok(Arc::new(module))
+}
+
+fn run_module(
+    module: &Arc<Module>,
+    rt: &mut Runtime,
+    args: &[String]
+) -> Result<i32, String> {
+    match try!(rt.run_args(module, args)) {
+        Some(Variable::Result(Err(ref err))) => {
+            let f_index = match module.find_function(&Arc::new("main".into()), 0) {
+                FnIndex::Loaded(f_index) => f_index as usize,
+                _ => unreachable!()
+            };
+            Err(rt.returned_error(err, f_index, module).message)
+        }
+        _ => Ok(0)
+    }
+}
@@ -0,0 +1,94 @@
+//! Runs tests written in Dyon from the command line.
+
+use std::io::{self, Write};
+use std::panic;
+use std::path::Path;
+
+use dyon::testing::{find_files, run_file, Outcome};
+
+const USAGE: &'static str = "Usage: dyon test [--filter <text>] [<path>...]";
+
+/// Runs `dyon test [--filter <text>] [<path>...]`, returning the exit status.
+///
+/// Runs the tests of the source files in the paths,
+/// or in the current directory when there are no paths.
+/// Fails if a test fails or a source file does not load.
+pub fn run(args: &[String]) -> i32 {
+    let mut filter = "";
+    let mut paths = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match &**arg {
+            "--filter" => {
+                match args.next() {
+                    Some(text) => filter = &**text,
+                    None => {
+                        writeln!(&mut io::stderr(), "{}", USAGE).unwrap();
+                        return 2;
+                    }
+                }
+            }
+            _ => paths.push(&**arg),
+        }
+    }
+    if paths.len() == 0 { paths.push("."); }
+
+    let mut files = vec![];
+    for path in paths {
+        match find_files(Path::new(path)) {
+            Ok(x) => files.extend(x),
+            Err(err) => {
+                writeln!(&mut io::stderr(), "Could not read `{}`, {}", path, err).unwrap();
+                return 1;
+            }
+        }
+    }
+
+    // Panics are reported with the failed test instead.
+    panic::set_hook(Box::new(|_| {}));
+    let mut passed = 0;
+    let mut failures = vec![];
+    let mut load_errors = 0;
+    for file in &files {
+        let file = file.to_string_lossy();
+        let results = match run_file(&file, filter) {
+            Ok(x) => x,
+            Err(err) => {
+                println!("load {} ... FAILED", file);
+                failures.push(format!("{}", err));
+                load_errors += 1;
+                continue;
+            }
+        };
+        for res in results {
+            let msg = match res.outcome {
+                Outcome::Passed => None,
+                Outcome::Failed(ref err) => Some(format!("{}", err)),
+                Outcome::Panicked(ref msg) => Some(format!("Panicked: {}", msg)),
+            };
+            match msg {
+                None => {
+                    println!("test {}::{} ... ok", res.file, res.name);
+                    passed += 1;
+                }
+                Some(msg) => {
+                    println!("test {}::{} ... FAILED", res.file, res.name);
+                    failures.push(format!("--- {}::{} ---\n{}", res.file, res.name, msg));
+                }
+            }
+        }
+    }
+    let _ = panic::take_hook();
+
+    if failures.len() > 0 {
+        println!("\nfailures:\n");
+        for failure in &failures {
+            println!("{}\n", failure);
+        }
+    }
+    let failed = failures.len() - load_errors;
+    println!("\ntest result: {}. {} passed; {} failed; {} files did not load",
+             if failures.len() == 0 { "ok" } else { "FAILED" },
+             passed, failed, load_errors);
+    if failures.len() == 0 { 0 } else { 1 }
+}
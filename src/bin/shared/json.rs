@@ -0,0 +1,274 @@
+//! Minimal JSON values and message framing,
+//! used by the language server and the debug adapter.
+//!
+//! Messages are sent with a `Content-Length` header followed by a JSON body.
+
+// Each binary uses a part of this module.
+#![allow(dead_code)]
+
+use std::fmt;
+use std::io::{BufRead, Write};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+    Array(Vec<Json>),
+    /// Keys are kept in insertion order.
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Creates an object from key-value pairs.
+    pub fn object(pairs: Vec<(&str, Json)>) -> Json {
+        Json::Object(pairs.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+
+    pub fn str(s: &str) -> Json { Json::Str(s.into()) }
+
+    /// Returns `null` when the key is missing.
+    pub fn get(&self, key: &str) -> &Json {
+        static NULL: Json = Json::Null;
+
+        match *self {
+            Json::Object(ref obj) => {
+                obj.iter().find(|&&(ref k, _)| k == key).map(|&(_, ref v)| v).unwrap_or(&NULL)
+            }
+            _ => &NULL
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        if let Json::Str(ref s) = *self { Some(s) } else { None }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        if let Json::Number(x) = *self { Some(x) } else { None }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        if let Json::Bool(x) = *self { Some(x) } else { None }
+    }
+
+    pub fn as_array(&self) -> &[Json] {
+        if let Json::Array(ref arr) = *self { arr } else { &[] }
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(x) => write!(f, "{}", x),
+            Json::Number(x) => write!(f, "{}", x),
+            Json::Str(ref s) => write_string(f, s),
+            Json::Array(ref arr) => {
+                try!(write!(f, "["));
+                for (i, v) in arr.iter().enumerate() {
+                    if i > 0 { try!(write!(f, ",")); }
+                    try!(write!(f, "{}", v));
+                }
+                write!(f, "]")
+            }
+            Json::Object(ref obj) => {
+                try!(write!(f, "{{"));
+                for (i, &(ref k, ref v)) in obj.iter().enumerate() {
+                    if i > 0 { try!(write!(f, ",")); }
+                    try!(write_string(f, k));
+                    try!(write!(f, ":{}", v));
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    try!(write!(f, "\""));
+    for c in s.chars() {
+        match c {
+            '"' => try!(write!(f, "\\\"")),
+            '\\' => try!(write!(f, "\\\\")),
+            '\n' => try!(write!(f, "\\n")),
+            '\r' => try!(write!(f, "\\r")),
+            '\t' => try!(write!(f, "\\t")),
+            c if (c as u32) < 0x20 => try!(write!(f, "\\u{:04x}", c as u32)),
+            c => try!(write!(f, "{}", c)),
+        }
+    }
+    write!(f, "\"")
+}
+
+/// Reads a message with a `Content-Length` header.
+///
+/// Returns `None` at the end of input.
+pub fn read_message<R: BufRead>(r: &mut R) -> Result<Option<Json>, String> {
+    let mut len = None;
+    loop {
+        let mut line = String::new();
+        if try!(r.read_line(&mut line).map_err(|err| format!("{}", err))) == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.len() == 0 { break; }
+        if line.starts_with("Content-Length:") {
+            len = line["Content-Length:".len()..].trim().parse().ok();
+        }
+    }
+    let len: usize = try!(len.ok_or("Expected `Content-Length` header".to_string()));
+    let mut buf = vec![0; len];
+    try!(r.read_exact(&mut buf).map_err(|err| format!("{}", err)));
+    let data = try!(String::from_utf8(buf).map_err(|err| format!("{}", err)));
+    parse(&data).map(Some)
+}
+
+/// Writes a message with a `Content-Length` header.
+pub fn write_message<W: Write>(w: &mut W, msg: &Json) -> Result<(), String> {
+    let body = format!("{}", msg);
+    try!(write!(w, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+        .map_err(|err| format!("{}", err)));
+    w.flush().map_err(|err| format!("{}", err))
+}
+
+/// Parses a JSON value.
+pub fn parse(data: &str) -> Result<Json, String> {
+    let mut parser = Parser { chars: data.chars().collect(), pos: 0 };
+    let val = try!(parser.value());
+    parser.whitespace();
+    if parser.pos < parser.chars.len() {
+        return Err(parser.error("Expected end of JSON"));
+    }
+    Ok(val)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn error(&self, msg: &str) -> String {
+        format!("{} at character {}", msg, self.pos)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if !c.is_whitespace() { break; }
+            self.pos += 1;
+        }
+    }
+
+    fn tag(&mut self, tag: &str) -> bool {
+        let n = tag.chars().count();
+        if self.pos + n <= self.chars.len() &&
+           self.chars[self.pos..self.pos + n].iter().cloned().eq(tag.chars()) {
+            self.pos += n;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.whitespace();
+        match self.peek() {
+            Some('{') => {
+                self.pos += 1;
+                let mut obj = vec![];
+                self.whitespace();
+                if self.tag("}") { return Ok(Json::Object(obj)); }
+                loop {
+                    self.whitespace();
+                    let key = try!(self.string());
+                    self.whitespace();
+                    if !self.tag(":") { return Err(self.error("Expected `:`")); }
+                    let val = try!(self.value());
+                    obj.push((key, val));
+                    self.whitespace();
+                    if self.tag("}") { return Ok(Json::Object(obj)); }
+                    if !self.tag(",") { return Err(self.error("Expected `,` or `}`")); }
+                }
+            }
+            Some('[') => {
+                self.pos += 1;
+                let mut arr = vec![];
+                self.whitespace();
+                if self.tag("]") { return Ok(Json::Array(arr)); }
+                loop {
+                    arr.push(try!(self.value()));
+                    self.whitespace();
+                    if self.tag("]") { return Ok(Json::Array(arr)); }
+                    if !self.tag(",") { return Err(self.error("Expected `,` or `]`")); }
+                }
+            }
+            Some('"') => Ok(Json::Str(try!(self.string()))),
+            Some(_) if self.tag("null") => Ok(Json::Null),
+            Some(_) if self.tag("true") => Ok(Json::Bool(true)),
+            Some(_) if self.tag("false") => Ok(Json::Bool(false)),
+            Some(_) => {
+                let start = self.pos;
+                while let Some(c) = self.peek() {
+                    if c.is_digit(10) || "-+.eE".contains(c) {
+                        self.pos += 1;
+                    } else {
+                        break;
+                    }
+                }
+                let s: String = self.chars[start..self.pos].iter().cloned().collect();
+                s.parse().map(Json::Number).map_err(|_| {
+                    self.pos = start;
+                    self.error("Expected JSON value")
+                })
+            }
+            None => Err(self.error("Unexpected end of JSON")),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if !self.tag("\"") { return Err(self.error("Expected string")); }
+        let mut s = String::new();
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return Err(self.error("Unexpected end of string")),
+            };
+            self.pos += 1;
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let c = match self.peek() {
+                        Some(c) => c,
+                        None => return Err(self.error("Unexpected end of string")),
+                    };
+                    self.pos += 1;
+                    match c {
+                        'n' => s.push('\n'),
+                        'r' => s.push('\r'),
+                        't' => s.push('\t'),
+                        'b' => s.push('\u{8}'),
+                        'f' => s.push('\u{c}'),
+                        'u' => {
+                            if self.pos + 4 > self.chars.len() {
+                                return Err(self.error("Expected 4 hex digits"));
+                            }
+                            let hex: String = self.chars[self.pos..self.pos + 4]
+                                .iter().cloned().collect();
+                            self.pos += 4;
+                            let code = try!(u32::from_str_radix(&hex, 16)
+                                .map_err(|_| self.error("Expected 4 hex digits")));
+                            s.push(::std::char::from_u32(code).unwrap_or('\u{fffd}'));
+                        }
+                        c => s.push(c),
+                    }
+                }
+                c => s.push(c),
+            }
+        }
+    }
+}
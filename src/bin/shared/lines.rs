@@ -0,0 +1,13 @@
+//! Line numbers of source offsets, shared by the debuggers.
+
+use range::Range;
+
+/// Returns the line number, starting at 1, and the byte range of the line.
+pub fn line_of(source: &str, offset: usize) -> (usize, Range) {
+    let mut offset = if offset > source.len() { source.len() } else { offset };
+    while !source.is_char_boundary(offset) { offset -= 1; }
+    let start = source[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let end = source[offset..].find('\n').map(|i| offset + i).unwrap_or(source.len());
+    let line = source[..offset].matches('\n').count() + 1;
+    (line, Range::new(start, end - start))
+}
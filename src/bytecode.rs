@@ -0,0 +1,1194 @@
+//! Compiles function and closure bodies to bytecode.
+//!
+//! The bytecode is register based, with local variables resolved to slots
+//! relative to the start of the call frame on the stack.
+//! Control flow, arithmetic, comparisons and local variables are compiled
+//! to instructions, while the remaining expressions are evaluated by
+//! the AST interpreter through `Op::Eval`.
+//!
+//! The compiler keeps the same stack layout as the AST interpreter,
+//! such that evaluated expressions can look up locals as usual.
+//! Evaluated expressions are referenced by their path from the root
+//! of the compiled body, such that the code can be shared between
+//! closures created from the same closure expression.
+
+use std::sync::Arc;
+use range::Range;
+
+use ast;
+use FnIndex;
+
+/// Register index, relative to the registers of the call frame.
+pub type Reg = usize;
+
+/// Accumulates the value of a `for` loop body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Accumulate {
+    /// Ignores the value.
+    None,
+    /// Adds numbers, used by `sum`.
+    Sum,
+    /// Pushes values into an array, used by `sift`.
+    Sift,
+}
+
+/// A bytecode instruction.
+#[derive(Debug, Clone)]
+pub enum Op {
+    /// Sets register to no value.
+    Empty(Reg),
+    /// Sets register to number.
+    Number(Reg, f64),
+    /// Sets register to bool.
+    Bool(Reg, bool),
+    /// Sets register to text.
+    Text(Reg, Arc<String>),
+    /// Sets register to a reference to local variable in frame slot.
+    Local(Reg, usize),
+    /// Looks up item of local variable in frame slot.
+    Item {
+        dst: Reg,
+        slot: usize,
+        /// First register of computed ids.
+        ids: Reg,
+        /// Number of computed ids.
+        len: usize,
+        /// Index of item path.
+        expr: usize,
+    },
+    /// Evaluates an expression with the AST interpreter.
+    Eval {
+        dst: Reg,
+        /// Index of expression path.
+        expr: usize,
+        /// Index of enclosing loops, used when expression breaks or continues.
+        loops: usize,
+    },
+    /// Pushes return value slot of a call to the stack.
+    PushReturn,
+    /// Pushes argument of a call to the stack.
+    Push(Reg),
+    /// Calls loaded function with return value slot and arguments on the stack.
+    Call {
+        dst: Reg,
+        /// The call without arguments.
+        call: Box<ast::Call>,
+        /// Number of arguments.
+        len: usize,
+    },
+    /// Reports an error when register has no value.
+    Expect {
+        src: Reg,
+        source_range: Range,
+        msg: &'static str,
+    },
+    /// Reports an error when register has a value.
+    ExpectNone {
+        src: Reg,
+        source_range: Range,
+        msg: &'static str,
+    },
+    /// Converts register to number.
+    ToNumber {
+        src: Reg,
+        source_range: Range,
+    },
+    /// Binary operator.
+    BinOp {
+        dst: Reg,
+        op: ast::BinOp,
+        left: Reg,
+        right: Reg,
+        source_range: Range,
+    },
+    /// Skips the right argument of lazy operator,
+    /// when left argument is a bool with same value.
+    Lazy {
+        dst: Reg,
+        left: Reg,
+        val: bool,
+        jump: usize,
+    },
+    /// Comparison operator.
+    Compare {
+        dst: Reg,
+        op: ast::CompareOp,
+        left: Reg,
+        right: Reg,
+        source_range: Range,
+    },
+    /// Unary operator.
+    UnOp {
+        dst: Reg,
+        op: ast::UnOp,
+        src: Reg,
+        source_range: Range,
+    },
+    /// Computes length of vector.
+    Norm {
+        dst: Reg,
+        src: Reg,
+        source_range: Range,
+    },
+    /// Declares a new local variable.
+    Declare {
+        name: Arc<String>,
+        current: bool,
+        src: Reg,
+    },
+    /// Assigns to local variable in frame slot.
+    Assign {
+        op: ast::AssignOp,
+        slot: usize,
+        src: Reg,
+        source_range: Range,
+    },
+    /// Assigns to item of local variable in frame slot.
+    AssignItem {
+        op: ast::AssignOp,
+        slot: usize,
+        /// First register of computed ids.
+        ids: Reg,
+        /// Number of computed ids.
+        len: usize,
+        /// Index of item path.
+        expr: usize,
+        src: Reg,
+        source_range: Range,
+    },
+    /// Truncates locals and currents to lengths relative to frame.
+    Truncate {
+        locals: usize,
+        currents: usize,
+    },
+    /// Jumps to instruction.
+    Jump(usize),
+    /// Counts an evaluation step, used at the start of each loop iteration.
+    Step(Range),
+    /// Jumps to instruction when condition is `false`.
+    JumpIfNot {
+        cond: Reg,
+        jump: usize,
+        source_range: Range,
+        msg: &'static str,
+    },
+    /// Declares counter of `for` loop, starting at zero if no start is set.
+    Counter {
+        name: Arc<String>,
+        start: Option<Reg>,
+    },
+    /// Jumps to instruction when counter is not less than end.
+    CounterCond {
+        slot: usize,
+        end: Reg,
+        jump: usize,
+        source_range: Range,
+    },
+    /// Increases counter by one.
+    Increment {
+        slot: usize,
+        source_range: Range,
+    },
+    /// Initializes accumulator of loop.
+    Init {
+        dst: Reg,
+        acc: Accumulate,
+    },
+    /// Accumulates value of loop body.
+    Accumulate {
+        dst: Reg,
+        acc: Accumulate,
+        src: Reg,
+        source_range: Range,
+    },
+    /// Returns value.
+    Return(Reg),
+    /// Returns without value.
+    ReturnVoid,
+    /// Breaks out of a loop outside the function body.
+    Break(Option<Arc<String>>),
+    /// Continues a loop outside the function body.
+    Continue(Option<Arc<String>>),
+}
+
+/// Stores jump targets of a loop.
+#[derive(Debug, Clone)]
+pub struct Loop {
+    pub label: Option<Arc<String>>,
+    /// Instruction to jump to on `break`.
+    pub break_jump: usize,
+    /// Instruction to jump to on `continue`.
+    pub continue_jump: usize,
+}
+
+/// Compiled function or closure body.
+#[derive(Debug, Clone)]
+pub struct Code {
+    pub ops: Vec<Op>,
+    /// Paths to expressions evaluated by the AST interpreter, see `Node::find`.
+    pub paths: Vec<Vec<usize>>,
+    pub loops: Vec<Loop>,
+    /// Enclosing loops of evaluated expressions, innermost last.
+    pub contexts: Vec<Vec<usize>>,
+    /// Number of registers used.
+    pub registers: usize,
+    /// Number of locals when entering the frame,
+    /// counting return value, arguments and currents.
+    pub frame: usize,
+}
+
+impl Code {
+    /// Finds instruction to jump to on `break` or `continue`
+    /// from an evaluated expression.
+    pub fn jump(&self, loops: usize, label: &Option<Arc<String>>, is_break: bool) -> Option<usize> {
+        for &id in self.contexts[loops].iter().rev() {
+            let found = match *label {
+                None => true,
+                Some(ref label) => self.loops[id].label.as_ref() == Some(label),
+            };
+            if found {
+                return Some(if is_break {
+                    self.loops[id].break_jump
+                } else {
+                    self.loops[id].continue_jump
+                });
+            }
+        }
+        None
+    }
+}
+
+/// Root or inner node of a compiled body.
+#[derive(Clone, Copy)]
+pub enum Node<'a> {
+    Block(&'a ast::Block),
+    Expr(&'a ast::Expression),
+}
+
+impl<'a> Node<'a> {
+    /// Finds expression evaluated by the AST interpreter.
+    pub fn find(self, path: &[usize]) -> &'a ast::Expression {
+        let mut node = self;
+        for &i in path {
+            node = node.child(i);
+        }
+        match node {
+            Node::Expr(expr) => expr,
+            Node::Block(_) => panic!("Expected expression"),
+        }
+    }
+
+    /// Returns child node, numbered in the order the compiler visits them.
+    fn child(self, i: usize) -> Node<'a> {
+        use ast::Expression as E;
+
+        let expr = match self {
+            Node::Block(block) => return Node::Expr(&block.expressions[i]),
+            Node::Expr(expr) => expr,
+        };
+        match *expr {
+            E::Block(ref block) => Node::Expr(&block.expressions[i]),
+            E::Return(ref expr) => Node::Expr(expr),
+            E::BinOp(ref binop) => Node::Expr(if i == 0 { &binop.left } else { &binop.right }),
+            E::Compare(ref compare) =>
+                Node::Expr(if i == 0 { &compare.left } else { &compare.right }),
+            E::UnOp(ref unop) => Node::Expr(&unop.expr),
+            E::Norm(ref norm) => Node::Expr(&norm.expr),
+            E::Call(ref call) => Node::Expr(&call.args[i]),
+            E::Item(ref item) => match item.ids[i] {
+                ast::Id::Expression(ref expr) => Node::Expr(expr),
+                _ => panic!("Expected computed id"),
+            },
+            E::Assign(ref assign) =>
+                Node::Expr(if i == 0 { &assign.left } else { &assign.right }),
+            E::If(ref if_expr) => {
+                let n = if_expr.else_if_conds.len();
+                match i {
+                    0 => Node::Expr(&if_expr.cond),
+                    1 => Node::Block(&if_expr.true_block),
+                    i if i < 2 + 2 * n => {
+                        let k = (i - 2) / 2;
+                        if i % 2 == 0 {
+                            Node::Expr(&if_expr.else_if_conds[k])
+                        } else {
+                            Node::Block(&if_expr.else_if_blocks[k])
+                        }
+                    }
+                    _ => Node::Block(if_expr.else_block.as_ref().expect("Expected else block")),
+                }
+            }
+            E::For(ref for_expr) => match i {
+                0 => Node::Expr(&for_expr.init),
+                1 => Node::Expr(&for_expr.cond),
+                2 => Node::Expr(&for_expr.step),
+                _ => Node::Block(&for_expr.block),
+            },
+            E::ForN(ref for_n_expr) |
+            E::Sum(ref for_n_expr) |
+            E::Sift(ref for_n_expr) => match i {
+                0 => Node::Expr(for_n_expr.start.as_ref().expect("Expected for start")),
+                1 => Node::Expr(&for_n_expr.end),
+                _ => Node::Block(&for_n_expr.block),
+            },
+            _ => panic!("Expected compiled expression"),
+        }
+    }
+}
+
+/// Loaded functions of a module, used to compile calls.
+pub struct Functions<'a> {
+    /// Index of the function being compiled.
+    pub relative: usize,
+    /// Whether each function returns a value.
+    pub returns: &'a [bool],
+}
+
+/// Compiles closures in function body and stores the code on each closure.
+///
+/// Closures created at runtime share the code of their closure expression.
+pub fn compile_closures(block: &mut ast::Block, functions: &Functions) {
+    for expr in &mut block.expressions {
+        closures_in_expr(expr, functions);
+    }
+}
+
+fn closures_in_expr(expr: &mut ast::Expression, functions: &Functions) {
+    use ast::Expression as E;
+
+    match *expr {
+        E::Closure(ref mut closure) => {
+            let closure = Arc::make_mut(closure);
+            closures_in_expr(&mut closure.expr, functions);
+            closure.code = Some(Arc::new(compile_closure(closure, functions)));
+        }
+        E::Link(ref mut link) => {
+            for item in &mut link.items { closures_in_expr(item, functions) }
+        }
+        E::Object(ref mut obj) => {
+            for key_value in &mut obj.key_values { closures_in_expr(&mut key_value.1, functions) }
+        }
+        E::Array(ref mut arr) => {
+            for item in &mut arr.items { closures_in_expr(item, functions) }
+        }
+        E::ArrayFill(ref mut arr_fill) => {
+            closures_in_expr(&mut arr_fill.fill, functions);
+            closures_in_expr(&mut arr_fill.n, functions);
+        }
+        E::Return(ref mut expr) |
+        E::Try(ref mut expr) => closures_in_expr(expr, functions),
+        E::Block(ref mut block) => compile_closures(block, functions),
+        E::Go(ref mut go) => {
+            for arg in &mut go.call.args { closures_in_expr(arg, functions) }
+        }
+        E::Call(ref mut call) => {
+            for arg in &mut call.args { closures_in_expr(arg, functions) }
+        }
+        E::Item(ref mut item) => closures_in_item(item, functions),
+        E::BinOp(ref mut binop) => {
+            closures_in_expr(&mut binop.left, functions);
+            closures_in_expr(&mut binop.right, functions);
+        }
+        E::Assign(ref mut assign) => {
+            closures_in_expr(&mut assign.left, functions);
+            closures_in_expr(&mut assign.right, functions);
+        }
+        E::Vec4(ref mut vec4) => {
+            for arg in &mut vec4.args { closures_in_expr(arg, functions) }
+        }
+        E::For(ref mut for_expr) => {
+            closures_in_expr(&mut for_expr.init, functions);
+            closures_in_expr(&mut for_expr.cond, functions);
+            closures_in_expr(&mut for_expr.step, functions);
+            compile_closures(&mut for_expr.block, functions);
+        }
+        E::ForN(ref mut for_n_expr) |
+        E::Sum(ref mut for_n_expr) |
+        E::SumVec4(ref mut for_n_expr) |
+        E::Prod(ref mut for_n_expr) |
+        E::ProdVec4(ref mut for_n_expr) |
+        E::Min(ref mut for_n_expr) |
+        E::Max(ref mut for_n_expr) |
+        E::Sift(ref mut for_n_expr) |
+        E::Any(ref mut for_n_expr) |
+        E::All(ref mut for_n_expr) |
+        E::LinkFor(ref mut for_n_expr) => {
+            if let Some(ref mut start) = for_n_expr.start { closures_in_expr(start, functions) }
+            closures_in_expr(&mut for_n_expr.end, functions);
+            compile_closures(&mut for_n_expr.block, functions);
+        }
+        E::If(ref mut if_expr) => {
+            closures_in_expr(&mut if_expr.cond, functions);
+            compile_closures(&mut if_expr.true_block, functions);
+            for cond in &mut if_expr.else_if_conds { closures_in_expr(cond, functions) }
+            for block in &mut if_expr.else_if_blocks { compile_closures(block, functions) }
+            if let Some(ref mut block) = if_expr.else_block { compile_closures(block, functions) }
+        }
+        E::Compare(ref mut compare) => {
+            closures_in_expr(&mut compare.left, functions);
+            closures_in_expr(&mut compare.right, functions);
+        }
+        E::UnOp(ref mut unop) => closures_in_expr(&mut unop.expr, functions),
+        E::Norm(ref mut norm) => closures_in_expr(&mut norm.expr, functions),
+        E::Swizzle(ref mut swizzle) => closures_in_expr(&mut swizzle.expr, functions),
+        E::CallClosure(ref mut call) => {
+            closures_in_item(&mut call.item, functions);
+            for arg in &mut call.args { closures_in_expr(arg, functions) }
+        }
+        E::Grab(ref mut grab) => closures_in_expr(&mut grab.expr, functions),
+        E::TryExpr(ref mut try_expr) => closures_in_expr(&mut try_expr.expr, functions),
+        E::ReturnVoid(_) |
+        E::Break(_) |
+        E::Continue(_) |
+        E::Text(_) |
+        E::Number(_) |
+        E::Bool(_) |
+        E::Variable(_, _) => {}
+    }
+}
+
+fn closures_in_item(item: &mut ast::Item, functions: &Functions) {
+    for id in &mut item.ids {
+        if let ast::Id::Expression(ref mut expr) = *id {
+            closures_in_expr(expr, functions);
+        }
+    }
+}
+
+/// Compiles function body.
+pub fn compile_function(f: &ast::Function, functions: &Functions) -> Code {
+    let frame = if f.returns() { 1 } else { 0 } + f.args.len() + f.currents.len();
+    let mut c = Compiler::new(frame, functions);
+    let dst = c.reg();
+    c.block(&f.block, dst);
+    c.finish()
+}
+
+/// Compiles closure body.
+pub fn compile_closure(closure: &ast::Closure, functions: &Functions) -> Code {
+    let frame = if closure.returns() { 1 } else { 0 } +
+                closure.args.len() + closure.currents.len();
+    let mut c = Compiler::new(frame, functions);
+    let dst = c.reg();
+    c.expr(&closure.expr, dst);
+    c.finish()
+}
+
+struct Compiler<'a> {
+    functions: &'a Functions<'a>,
+    ops: Vec<Op>,
+    paths: Vec<Vec<usize>>,
+    /// Path from the root to the expression being compiled.
+    path: Vec<usize>,
+    loops: Vec<Loop>,
+    contexts: Vec<Vec<usize>>,
+    /// Loops being compiled, innermost last.
+    loop_stack: Vec<usize>,
+    /// Last jump target, where a register might be set by another instruction.
+    label: Option<usize>,
+    /// Jumps to patch when loop is compiled: loop, instruction, is break.
+    patches: Vec<(usize, usize, bool)>,
+    /// Number of locals in scope, relative to frame.
+    locals: usize,
+    /// Number of currents in scope, relative to frame.
+    currents: usize,
+    next_reg: Reg,
+    registers: usize,
+    frame: usize,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(frame: usize, functions: &'a Functions<'a>) -> Compiler<'a> {
+        Compiler {
+            functions: functions,
+            ops: vec![],
+            paths: vec![],
+            path: vec![],
+            loops: vec![],
+            contexts: vec![],
+            loop_stack: vec![],
+            label: None,
+            patches: vec![],
+            locals: frame,
+            currents: 0,
+            next_reg: 0,
+            registers: 0,
+            frame: frame,
+        }
+    }
+
+    fn finish(self) -> Code {
+        Code {
+            ops: self.ops,
+            paths: self.paths,
+            loops: self.loops,
+            contexts: self.contexts,
+            registers: self.registers,
+            frame: self.frame,
+        }
+    }
+
+    /// Allocates a new register.
+    fn reg(&mut self) -> Reg {
+        let r = self.next_reg;
+        self.next_reg += 1;
+        if self.next_reg > self.registers {
+            self.registers = self.next_reg;
+        }
+        r
+    }
+
+    /// Pushes jump instruction to be patched later.
+    fn jump_placeholder(&mut self) -> usize {
+        let ind = self.ops.len();
+        self.ops.push(Op::Jump(0));
+        ind
+    }
+
+    /// Sets jump target of instruction.
+    fn patch(&mut self, ind: usize, target: usize) {
+        if self.label.map(|label| label < target).unwrap_or(true) {
+            self.label = Some(target);
+        }
+        match self.ops[ind] {
+            Op::Jump(ref mut jump) |
+            Op::JumpIfNot { ref mut jump, .. } |
+            Op::Lazy { ref mut jump, .. } |
+            Op::CounterCond { ref mut jump, .. } => *jump = target,
+            _ => panic!("Expected jump instruction"),
+        }
+    }
+
+    /// Returns frame slot of a local variable,
+    /// if the item refers to a local without extra lookup.
+    fn slot(&self, item: &ast::Item) -> Option<usize> {
+        if item.ids.len() != 0 { return None; }
+        self.item_slot(item)
+    }
+
+    /// Returns frame slot of the local variable of an item with ids,
+    /// if the ids can be looked up without checking for errors.
+    fn item_slot(&self, item: &ast::Item) -> Option<usize> {
+        if item.try || item.try_ids.len() != 0 { return None; }
+        match item.static_stack_id.get() {
+            Some(id) if id > 0 && id <= self.locals => Some(self.locals - id),
+            _ => None
+        }
+    }
+
+    /// Compiles computed ids of item to registers in order.
+    /// Returns the first register and the number of computed ids.
+    fn ids(&mut self, item: &ast::Item) -> (Reg, usize) {
+        let first = self.next_reg;
+        let mut len = 0;
+        for (i, id) in item.ids.iter().enumerate() {
+            if let ast::Id::Expression(ref expr) = *id {
+                let r = self.reg();
+                self.child(i, expr, r);
+                self.expect(r, expr.source_range(), "Expected something for index");
+                len += 1;
+            }
+        }
+        (first, len)
+    }
+
+    /// Returns index of the path to the expression being compiled.
+    fn path_index(&mut self) -> usize {
+        self.paths.push(self.path.clone());
+        self.paths.len() - 1
+    }
+
+    fn eval(&mut self, dst: Reg) {
+        let loops = if self.contexts.last() == Some(&self.loop_stack) {
+            self.contexts.len() - 1
+        } else {
+            self.contexts.push(self.loop_stack.clone());
+            self.contexts.len() - 1
+        };
+        let expr = self.path_index();
+        self.ops.push(Op::Eval {
+            dst: dst,
+            expr: expr,
+            loops: loops,
+        });
+    }
+
+    /// Compiles child expression, see `Node::child`.
+    fn child(&mut self, i: usize, expr: &ast::Expression, dst: Reg) {
+        self.path.push(i);
+        self.expr(expr, dst);
+        self.path.pop();
+    }
+
+    /// Compiles child block, see `Node::child`.
+    fn child_block(&mut self, i: usize, block: &ast::Block, dst: Reg) {
+        self.path.push(i);
+        self.block(block, dst);
+        self.path.pop();
+    }
+
+    /// Reports an error when register has no value,
+    /// unless the last instruction always sets the register.
+    fn expect(&mut self, src: Reg, source_range: Range, msg: &'static str) {
+        if self.label != Some(self.ops.len()) {
+            let set = match self.ops.last() {
+                Some(&Op::Number(dst, _)) |
+                Some(&Op::Bool(dst, _)) |
+                Some(&Op::Text(dst, _)) |
+                Some(&Op::Local(dst, _)) |
+                Some(&Op::Item { dst, .. }) |
+                Some(&Op::BinOp { dst, .. }) |
+                Some(&Op::Compare { dst, .. }) |
+                Some(&Op::UnOp { dst, .. }) |
+                Some(&Op::Norm { dst, .. }) => dst == src,
+                _ => false
+            };
+            if set { return; }
+        }
+        self.ops.push(Op::Expect {
+            src: src,
+            source_range: source_range,
+            msg: msg,
+        });
+    }
+
+    fn expr(&mut self, expr: &ast::Expression, dst: Reg) {
+        use ast::Expression as E;
+
+        match *expr {
+            E::Number(ref num) => self.ops.push(Op::Number(dst, num.num)),
+            E::Bool(ref b) => self.ops.push(Op::Bool(dst, b.val)),
+            E::Text(ref text) => self.ops.push(Op::Text(dst, text.text.clone())),
+            E::Item(ref item) => {
+                if let Some(slot) = self.slot(item) {
+                    self.ops.push(Op::Local(dst, slot));
+                } else if let Some(slot) = self.item_slot(item) {
+                    let st = self.next_reg;
+                    let (ids, len) = self.ids(item);
+                    let expr = self.path_index();
+                    self.ops.push(Op::Item {
+                        dst: dst,
+                        slot: slot,
+                        ids: ids,
+                        len: len,
+                        expr: expr,
+                    });
+                    self.next_reg = st;
+                } else {
+                    self.eval(dst);
+                }
+            }
+            E::Block(ref block) => self.block(block, dst),
+            E::Call(ref call) => self.call(call, dst),
+            E::BinOp(ref binop) => self.binop(binop, dst),
+            E::Compare(ref compare) => self.compare(compare, dst),
+            E::UnOp(ref unop) => {
+                let st = self.next_reg;
+                let src = self.reg();
+                self.child(0, &unop.expr, src);
+                self.expect(src, unop.source_range,
+                            "Expected something from unary argument");
+                self.ops.push(Op::UnOp {
+                    dst: dst,
+                    op: unop.op,
+                    src: src,
+                    source_range: unop.source_range,
+                });
+                self.next_reg = st;
+            }
+            E::Norm(ref norm) => {
+                let st = self.next_reg;
+                let src = self.reg();
+                self.child(0, &norm.expr, src);
+                self.expect(src, norm.source_range, "Expected something from unary argument");
+                self.ops.push(Op::Norm {
+                    dst: dst,
+                    src: src,
+                    source_range: norm.source_range,
+                });
+                self.next_reg = st;
+            }
+            E::Assign(ref assign) => {
+                if !self.assign(assign) {
+                    self.eval(dst);
+                } else {
+                    self.ops.push(Op::Empty(dst));
+                }
+            }
+            E::If(ref if_expr) => self.if_expr(if_expr, dst),
+            E::For(ref for_expr) => self.for_expr(for_expr, dst),
+            E::ForN(ref for_n_expr) => self.for_n_expr(for_n_expr, Accumulate::None, dst),
+            E::Sum(ref for_n_expr) => self.for_n_expr(for_n_expr, Accumulate::Sum, dst),
+            E::Sift(ref for_n_expr) => self.for_n_expr(for_n_expr, Accumulate::Sift, dst),
+            E::Return(ref ret) => {
+                self.child(0, ret, dst);
+                self.expect(dst, expr.source_range(), "Expected something");
+                self.ops.push(Op::Return(dst));
+            }
+            E::ReturnVoid(_) => self.ops.push(Op::ReturnVoid),
+            E::Break(ref b) => {
+                match self.find_loop(&b.label) {
+                    Some(id) => {
+                        let ind = self.jump_placeholder();
+                        self.patches.push((id, ind, true));
+                    }
+                    None => self.ops.push(Op::Break(b.label.clone())),
+                }
+            }
+            E::Continue(ref c) => {
+                match self.find_loop(&c.label) {
+                    Some(id) => {
+                        let ind = self.jump_placeholder();
+                        self.patches.push((id, ind, false));
+                    }
+                    None => self.ops.push(Op::Continue(c.label.clone())),
+                }
+            }
+            _ => self.eval(dst),
+        }
+    }
+
+    fn block(&mut self, block: &ast::Block, dst: Reg) {
+        let locals = self.locals;
+        let currents = self.currents;
+        if block.expressions.len() == 0 {
+            self.ops.push(Op::Empty(dst));
+        }
+        let n = block.expressions.len();
+        for (i, e) in block.expressions.iter().enumerate() {
+            if i + 1 == n {
+                self.child(i, e, dst);
+            } else {
+                let st = self.next_reg;
+                let r = self.reg();
+                self.child(i, e, r);
+                // The value is not used.
+                if self.label != Some(self.ops.len()) {
+                    if let Some(&Op::Empty(src)) = self.ops.last() {
+                        if src == r { self.ops.pop(); }
+                    }
+                }
+                self.next_reg = st;
+            }
+        }
+        if self.locals != locals || self.currents != currents {
+            self.ops.push(Op::Truncate {
+                locals: locals,
+                currents: currents,
+            });
+        }
+        self.locals = locals;
+        self.currents = currents;
+    }
+
+    /// Compiles call to loaded function, evaluating other calls.
+    ///
+    /// The return value slot and arguments are pushed to the stack,
+    /// where the locals of the arguments were resolved.
+    fn call(&mut self, call: &ast::Call, dst: Reg) {
+        let returns = match call.f_index.get() {
+            FnIndex::Loaded(f_index) => {
+                let index = (f_index + self.functions.relative as isize) as usize;
+                self.functions.returns[index]
+            }
+            _ => {
+                self.eval(dst);
+                return;
+            }
+        };
+        // Swizzled arguments are pushed directly to the stack.
+        let swizzle = call.args.iter().any(|arg| {
+            if let ast::Expression::Swizzle(_) = *arg { true } else { false }
+        });
+        if swizzle {
+            self.eval(dst);
+            return;
+        }
+        let locals = self.locals;
+        if returns {
+            self.ops.push(Op::PushReturn);
+            self.locals += 1;
+        }
+        let st = self.next_reg;
+        for (i, arg) in call.args.iter().enumerate() {
+            let r = self.reg();
+            self.child(i, arg, r);
+            self.expect(r, arg.source_range(),
+                        "Expected something. Check that expression returns a value.");
+            self.ops.push(Op::Push(r));
+            self.locals += 1;
+            self.next_reg = st;
+        }
+        self.ops.push(Op::Call {
+            dst: dst,
+            call: Box::new(ast::Call {
+                alias: call.alias.clone(),
+                name: call.name.clone(),
+                args: vec![],
+                f_index: call.f_index.clone(),
+                custom_source: call.custom_source.clone(),
+                source_range: call.source_range,
+            }),
+            len: call.args.len(),
+        });
+        self.locals = locals;
+    }
+
+    fn binop(&mut self, binop: &ast::BinOpExpression, dst: Reg) {
+        use ast::BinOp::*;
+
+        let st = self.next_reg;
+        let left = self.reg();
+        self.child(0, &binop.left, left);
+        self.expect(left, binop.source_range, "Expected something from left argument");
+        let lazy = match binop.op {
+            OrElse => Some(true),
+            AndAlso => Some(false),
+            _ => None
+        };
+        let lazy = lazy.map(|val| {
+            let ind = self.ops.len();
+            self.ops.push(Op::Lazy {
+                dst: dst,
+                left: left,
+                val: val,
+                jump: 0,
+            });
+            ind
+        });
+        let right = self.reg();
+        self.child(1, &binop.right, right);
+        self.expect(right, binop.source_range, "Expected something from right argument");
+        self.ops.push(Op::BinOp {
+            dst: dst,
+            op: binop.op,
+            left: left,
+            right: right,
+            source_range: binop.source_range,
+        });
+        if let Some(ind) = lazy {
+            let target = self.ops.len();
+            self.patch(ind, target);
+        }
+        self.next_reg = st;
+    }
+
+    fn compare(&mut self, compare: &ast::Compare, dst: Reg) {
+        let st = self.next_reg;
+        let left = self.reg();
+        self.child(0, &compare.left, left);
+        self.expect(left, compare.left.source_range(),
+                    "Expected something from the left argument");
+        let right = self.reg();
+        self.child(1, &compare.right, right);
+        self.expect(right, compare.right.source_range(),
+                    "Expected something from the right argument");
+        self.ops.push(Op::Compare {
+            dst: dst,
+            op: compare.op,
+            left: left,
+            right: right,
+            source_range: compare.source_range,
+        });
+        self.next_reg = st;
+    }
+
+    /// Compiles assignment to local variable or its item.
+    /// Returns `false` if the assignment must be evaluated.
+    fn assign(&mut self, assign: &ast::Assign) -> bool {
+        use ast::AssignOp;
+
+        let item = match assign.left {
+            ast::Expression::Item(ref item) => item,
+            _ => return false
+        };
+        if item.ids.len() != 0 {
+            let slot = match self.item_slot(item) {
+                Some(slot) => slot,
+                None => return false
+            };
+            // The right side is evaluated before the ids of the left side.
+            let st = self.next_reg;
+            let src = self.reg();
+            self.child(1, &assign.right, src);
+            self.expect(src, assign.right.source_range(),
+                        "Expected something from the right side");
+            self.path.push(0);
+            let (ids, len) = self.ids(item);
+            let expr = self.path_index();
+            self.path.pop();
+            self.ops.push(Op::AssignItem {
+                op: assign.op,
+                slot: slot,
+                ids: ids,
+                len: len,
+                expr: expr,
+                src: src,
+                source_range: assign.left.source_range(),
+            });
+            self.next_reg = st;
+            true
+        } else if assign.op == AssignOp::Assign {
+            let st = self.next_reg;
+            let src = self.reg();
+            self.child(1, &assign.right, src);
+            self.expect(src, assign.right.source_range(),
+                        "Expected something from the right side");
+            self.ops.push(Op::Declare {
+                name: item.name.clone(),
+                current: item.current,
+                src: src,
+            });
+            self.next_reg = st;
+            self.locals += 1;
+            if item.current { self.currents += 1; }
+            true
+        } else {
+            let slot = match self.slot(item) {
+                Some(slot) => slot,
+                None => return false
+            };
+            let st = self.next_reg;
+            let src = self.reg();
+            self.child(1, &assign.right, src);
+            self.expect(src, assign.right.source_range(),
+                        "Expected something from the right side");
+            self.ops.push(Op::Assign {
+                op: assign.op,
+                slot: slot,
+                src: src,
+                source_range: assign.left.source_range(),
+            });
+            self.next_reg = st;
+            true
+        }
+    }
+
+    fn if_expr(&mut self, if_expr: &ast::If, dst: Reg) {
+        let mut ends = vec![];
+        let st = self.next_reg;
+        let cond = self.reg();
+        self.child(0, &if_expr.cond, cond);
+        let mut next = self.ops.len();
+        self.ops.push(Op::JumpIfNot {
+            cond: cond,
+            jump: 0,
+            source_range: if_expr.cond.source_range(),
+            msg: "Expected bool from if condition",
+        });
+        self.next_reg = st;
+        self.child_block(1, &if_expr.true_block, dst);
+        ends.push(self.jump_placeholder());
+        for (k, (cond_expr, body)) in if_expr.else_if_conds.iter()
+            .zip(if_expr.else_if_blocks.iter()).enumerate() {
+            let target = self.ops.len();
+            self.patch(next, target);
+            let cond = self.reg();
+            self.child(2 + 2 * k, cond_expr, cond);
+            next = self.ops.len();
+            self.ops.push(Op::JumpIfNot {
+                cond: cond,
+                jump: 0,
+                source_range: cond_expr.source_range(),
+                msg: "Expected bool from else if condition",
+            });
+            self.next_reg = st;
+            self.child_block(3 + 2 * k, body, dst);
+            ends.push(self.jump_placeholder());
+        }
+        let target = self.ops.len();
+        self.patch(next, target);
+        if let Some(ref else_block) = if_expr.else_block {
+            let i = 2 + 2 * if_expr.else_if_conds.len();
+            self.child_block(i, else_block, dst);
+        } else {
+            self.ops.push(Op::Empty(dst));
+        }
+        let target = self.ops.len();
+        for ind in ends {
+            self.patch(ind, target);
+        }
+    }
+
+    fn for_expr(&mut self, for_expr: &ast::For, dst: Reg) {
+        let prev_locals = self.locals;
+        let prev_currents = self.currents;
+        let st = self.next_reg;
+
+        let r = self.reg();
+        self.child(0, &for_expr.init, r);
+        self.ops.push(Op::ExpectNone {
+            src: r,
+            source_range: for_expr.init.source_range(),
+            msg: "Expected nothing from for init",
+        });
+        let locals = self.locals;
+        let currents = self.currents;
+
+        let id = self.begin_loop(&for_expr.label);
+        let top = self.ops.len();
+        self.ops.push(Op::Step(for_expr.source_range));
+        self.child(1, &for_expr.cond, r);
+        self.expect(r, for_expr.cond.source_range(), "Expected bool from for condition");
+        let exit = self.ops.len();
+        self.ops.push(Op::JumpIfNot {
+            cond: r,
+            jump: 0,
+            source_range: for_expr.cond.source_range(),
+            msg: "Expected bool",
+        });
+        self.child_block(3, &for_expr.block, r);
+        // Jumps from nested blocks must remove their locals before the step.
+        let continue_jump = self.ops.len();
+        self.ops.push(Op::Truncate {
+            locals: locals,
+            currents: currents,
+        });
+        self.child(2, &for_expr.step, r);
+        self.ops.push(Op::ExpectNone {
+            src: r,
+            source_range: for_expr.step.source_range(),
+            msg: "Expected nothing from for step",
+        });
+        self.ops.push(Op::Truncate {
+            locals: locals,
+            currents: currents,
+        });
+        self.ops.push(Op::Jump(top));
+        let break_jump = self.ops.len();
+        self.patch(exit, break_jump);
+        self.ops.push(Op::Truncate {
+            locals: prev_locals,
+            currents: prev_currents,
+        });
+        self.end_loop(id, break_jump, continue_jump);
+
+        self.locals = prev_locals;
+        self.currents = prev_currents;
+        self.next_reg = st;
+        self.ops.push(Op::Empty(dst));
+    }
+
+    fn for_n_expr(&mut self, for_n_expr: &ast::ForN, acc: Accumulate, dst: Reg) {
+        let prev_locals = self.locals;
+        let prev_currents = self.currents;
+        let st = self.next_reg;
+
+        let start = if let Some(ref start) = for_n_expr.start {
+            let r = self.reg();
+            self.child(0, start, r);
+            self.expect(r, for_n_expr.end.source_range(), "Expected number from for start");
+            self.ops.push(Op::ToNumber {
+                src: r,
+                source_range: for_n_expr.end.source_range(),
+            });
+            Some(r)
+        } else { None };
+        let end = self.reg();
+        self.child(1, &for_n_expr.end, end);
+        self.expect(end, for_n_expr.end.source_range(), "Expected number from for end");
+        self.ops.push(Op::ToNumber {
+            src: end,
+            source_range: for_n_expr.end.source_range(),
+        });
+
+        // Initialize counter.
+        self.ops.push(Op::Counter {
+            name: for_n_expr.name.clone(),
+            start: start,
+        });
+        let slot = self.locals;
+        self.locals += 1;
+        let locals = self.locals;
+        let currents = self.currents;
+        if acc != Accumulate::None {
+            self.ops.push(Op::Init {
+                dst: dst,
+                acc: acc,
+            });
+        }
+
+        let id = self.begin_loop(&for_n_expr.label);
+        let step = self.ops.len();
+        self.ops.push(Op::Step(for_n_expr.source_range));
+        let top = self.ops.len();
+        self.ops.push(Op::CounterCond {
+            slot: slot,
+            end: end,
+            jump: 0,
+            source_range: for_n_expr.source_range,
+        });
+        let val = self.reg();
+        self.child_block(2, &for_n_expr.block, val);
+        if acc != Accumulate::None {
+            self.ops.push(Op::Accumulate {
+                dst: dst,
+                acc: acc,
+                src: val,
+                source_range: for_n_expr.block.source_range,
+            });
+        }
+        // Jumps from nested blocks must remove their locals.
+        let continue_jump = self.ops.len();
+        self.ops.push(Op::Truncate {
+            locals: locals,
+            currents: currents,
+        });
+        self.ops.push(Op::Increment {
+            slot: slot,
+            source_range: for_n_expr.source_range,
+        });
+        self.ops.push(Op::Jump(step));
+        let break_jump = self.ops.len();
+        self.patch(top, break_jump);
+        self.ops.push(Op::Truncate {
+            locals: prev_locals,
+            currents: prev_currents,
+        });
+        self.end_loop(id, break_jump, continue_jump);
+
+        self.locals = prev_locals;
+        self.currents = prev_currents;
+        self.next_reg = st;
+        if acc == Accumulate::None {
+            self.ops.push(Op::Empty(dst));
+        }
+    }
+
+    fn begin_loop(&mut self, label: &Option<Arc<String>>) -> usize {
+        let id = self.loops.len();
+        self.loops.push(Loop {
+            label: label.clone(),
+            break_jump: 0,
+            continue_jump: 0,
+        });
+        self.loop_stack.push(id);
+        id
+    }
+
+    fn end_loop(&mut self, id: usize, break_jump: usize, continue_jump: usize) {
+        self.loops[id].break_jump = break_jump;
+        self.loops[id].continue_jump = continue_jump;
+        self.loop_stack.pop();
+        let patches: Vec<(usize, usize, bool)> = self.patches.iter()
+            .filter(|&&(loop_id, _, _)| loop_id == id).map(|&x| x).collect();
+        self.patches.retain(|&(loop_id, _, _)| loop_id != id);
+        for (_, ind, is_break) in patches {
+            self.patch(ind, if is_break { break_jump } else { continue_jump });
+        }
+    }
+
+    /// Finds loop compiled in this function, innermost first.
+    fn find_loop(&self, label: &Option<Arc<String>>) -> Option<usize> {
+        for &id in self.loop_stack.iter().rev() {
+            match *label {
+                None => return Some(id),
+                Some(ref label) => {
+                    if self.loops[id].label.as_ref() == Some(label) {
+                        return Some(id);
+                    }
+                }
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,279 @@
+//! Caches checked sources on disk, such that loading skips parsing and lifetime checking.
+//!
+//! A cache file stores the meta data of a source that passed the lifetime checker,
+//! together with the refined return types.
+//! The meta data is converted to AST when loading, like `load_meta`.
+//!
+//! Each file is keyed by a hash of the source, the syntax rules,
+//! the prelude of the module and the version of Dyon.
+//! When any of these change, the cache is stale and the source is loaded again.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use piston_meta::MetaData;
+use range::Range;
+
+use error::Error;
+use diagnostics_error;
+use load_meta;
+use load_str_checked;
+use read_source;
+use refine_returns;
+use Dfn;
+use Lt;
+use Module;
+use Prelude;
+use Type;
+
+/// Increase this when changing the format of cache files.
+const CACHE_VERSION: u32 = 1;
+
+/// Returns `None` from the function when the value is `None`.
+macro_rules! try_opt {
+    ($e:expr) => (match $e { Some(x) => x, None => return None })
+}
+
+/// Loads a source file, using a cached version in `cache_dir` when it is up to date.
+///
+/// Falls back to a full load when the cache is missing or stale,
+/// and then writes a new cache file.
+/// Failing to write the cache is not an error.
+pub fn load_cached(source: &str, cache_dir: &str, module: &mut Module) -> Result<(), Error> {
+    let d = try!(read_source(source));
+    let key = key(&d, module);
+    let path = cache_path(source, cache_dir);
+
+    if let Some((data, refined_rets)) = read(&path, &key) {
+        let n = module.functions.len();
+        if load_meta(source, d.clone(), &data, module).is_ok() {
+            refine_returns(module, &refined_rets);
+            return Ok(());
+        }
+        // Remove functions from the failed attempt.
+        module.functions.truncate(n);
+    }
+
+    let (data, refined_rets) = try!(load_str_checked(source, d.clone(), module)
+        .map_err(|diagnostics| diagnostics_error(&diagnostics, &d)));
+    let _ = fs::create_dir_all(cache_dir)
+        .and_then(|()| write(&path, &key, &data, &refined_rets));
+    Ok(())
+}
+
+/// Returns the cache file of a source.
+fn cache_path(source: &str, cache_dir: &str) -> PathBuf {
+    Path::new(cache_dir).join(format!("{:016x}.dyonc", hash(source.as_bytes())))
+}
+
+/// FNV-1a, which gives the same hash on every platform and Rust version.
+fn hash(data: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for &b in data {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
+/// Returns the header that must match for the cache to be used.
+fn key(d: &str, module: &Module) -> String {
+    let prelude = Prelude::from_module(module);
+    let mut functions: Vec<(&Arc<String>, &usize)> = prelude.functions.iter().collect();
+    functions.sort();
+    let prelude = format!("{:?}{:?}{:?}{:?}",
+        functions, prelude.list, prelude.namespaces, prelude.capabilities);
+    format!("dyon-cache {} {}\nsyntax {:016x}\nprelude {:016x}\nsource {:016x}\n",
+        CACHE_VERSION,
+        env!("CARGO_PKG_VERSION"),
+        hash(include_str!("../assets/syntax.txt").as_bytes()),
+        hash(prelude.as_bytes()),
+        hash(d.as_bytes()))
+}
+
+fn write(
+    path: &Path,
+    key: &str,
+    data: &[Range<MetaData>],
+    refined_rets: &HashMap<Arc<String>, Type>
+) -> ::std::io::Result<()> {
+    let mut s = String::from(key);
+    for (name, ty) in refined_rets {
+        s.push_str("ret ");
+        s.push_str(name);
+        write_type(&mut s, ty);
+        s.push('\n');
+    }
+    for d in data {
+        let range = d.range();
+        s.push_str(&format!("{} {} ", range.offset, range.length));
+        match d.data {
+            MetaData::StartNode(ref name) => s.push_str(&format!("s {}", name)),
+            MetaData::EndNode(ref name) => s.push_str(&format!("e {}", name)),
+            MetaData::Bool(ref name, val) => s.push_str(&format!("b {} {}", name, val)),
+            MetaData::F64(ref name, val) => s.push_str(&format!("f {} {}", name, val)),
+            MetaData::String(ref name, ref val) => {
+                s.push_str(&format!("t {} ", name));
+                escape(&mut s, val);
+            }
+        }
+        s.push('\n');
+    }
+    let mut file = try!(File::create(path));
+    file.write_all(s.as_bytes())
+}
+
+/// Reads a cache file, returning `None` when it is missing, stale or corrupt.
+fn read(
+    path: &Path,
+    key: &str
+) -> Option<(Vec<Range<MetaData>>, HashMap<Arc<String>, Type>)> {
+    let mut s = String::new();
+    if File::open(path).and_then(|mut f| f.read_to_string(&mut s)).is_err() {
+        return None;
+    }
+    if !s.starts_with(key) { return None; }
+
+    let mut data = vec![];
+    let mut refined_rets = HashMap::new();
+    for line in s[key.len()..].lines() {
+        let mut tokens = line.splitn(4, ' ');
+        let (a, b, c) = (tokens.next(), tokens.next(), tokens.next());
+        if a == Some("ret") {
+            let name = try_opt!(b);
+            let mut rest = line["ret ".len() + name.len()..].split_whitespace();
+            let ty = try_opt!(read_type(&mut rest));
+            refined_rets.insert(Arc::new(name.into()), ty);
+            continue;
+        }
+        let offset = try_opt!(a.and_then(|x| x.parse().ok()));
+        let length = try_opt!(b.and_then(|x| x.parse().ok()));
+        let rest = try_opt!(tokens.next());
+        let (name, val) = match rest.find(' ') {
+            Some(i) => (&rest[..i], &rest[i + 1..]),
+            None => (rest, ""),
+        };
+        let name = Arc::new(name.to_string());
+        let meta = match c {
+            Some("s") => MetaData::StartNode(name),
+            Some("e") => MetaData::EndNode(name),
+            Some("b") => MetaData::Bool(name, val == "true"),
+            Some("f") => MetaData::F64(name, try_opt!(val.parse().ok())),
+            Some("t") => MetaData::String(name, Arc::new(try_opt!(unescape(val)))),
+            _ => return None,
+        };
+        data.push(Range::new(offset, length).wrap(meta));
+    }
+    Some((data, refined_rets))
+}
+
+/// Escapes line breaks, such that each meta data fits on one line.
+fn escape(s: &mut String, val: &str) {
+    for c in val.chars() {
+        match c {
+            '\\' => s.push_str("\\\\"),
+            '\n' => s.push_str("\\n"),
+            '\r' => s.push_str("\\r"),
+            c => s.push(c),
+        }
+    }
+}
+
+fn unescape(val: &str) -> Option<String> {
+    let mut s = String::new();
+    let mut chars = val.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            s.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => s.push('\\'),
+            Some('n') => s.push('\n'),
+            Some('r') => s.push('\r'),
+            _ => return None,
+        }
+    }
+    Some(s)
+}
+
+/// Writes a type as space separated tokens in prefix notation.
+fn write_type(s: &mut String, ty: &Type) {
+    match *ty {
+        Type::Unreachable => s.push_str(" unreachable"),
+        Type::Void => s.push_str(" void"),
+        Type::Any => s.push_str(" any"),
+        Type::Bool => s.push_str(" bool"),
+        Type::F64 => s.push_str(" f64"),
+        Type::Vec4 => s.push_str(" vec4"),
+        Type::Text => s.push_str(" str"),
+        Type::Link => s.push_str(" link"),
+        Type::Object => s.push_str(" obj"),
+        Type::Array(ref ty) => { s.push_str(" arr"); write_type(s, ty) }
+        Type::Option(ref ty) => { s.push_str(" opt"); write_type(s, ty) }
+        Type::Result(ref ty) => { s.push_str(" res"); write_type(s, ty) }
+        Type::Secret(ref ty) => { s.push_str(" sec"); write_type(s, ty) }
+        Type::Thread(ref ty) => { s.push_str(" thr"); write_type(s, ty) }
+        Type::Channel(ref ty) => { s.push_str(" chan"); write_type(s, ty) }
+        Type::AdHoc(ref name, ref ty) => {
+            s.push_str(" ad ");
+            s.push_str(name);
+            write_type(s, ty);
+        }
+        Type::Closure(ref dfn) => {
+            s.push_str(&format!(" cl {}", dfn.tys.len()));
+            for (lt, ty) in dfn.lts.iter().zip(dfn.tys.iter()) {
+                match *lt {
+                    Lt::Default => s.push_str(" d"),
+                    Lt::Return => s.push_str(" r"),
+                    Lt::Arg(i) => s.push_str(&format!(" a{}", i)),
+                }
+                write_type(s, ty);
+            }
+            write_type(s, &dfn.ret);
+        }
+    }
+}
+
+fn read_type<'a, I: Iterator<Item = &'a str>>(tokens: &mut I) -> Option<Type> {
+    Some(match tokens.next() {
+        Some("unreachable") => Type::Unreachable,
+        Some("void") => Type::Void,
+        Some("any") => Type::Any,
+        Some("bool") => Type::Bool,
+        Some("f64") => Type::F64,
+        Some("vec4") => Type::Vec4,
+        Some("str") => Type::Text,
+        Some("link") => Type::Link,
+        Some("obj") => Type::Object,
+        Some("arr") => Type::Array(Box::new(try_opt!(read_type(tokens)))),
+        Some("opt") => Type::Option(Box::new(try_opt!(read_type(tokens)))),
+        Some("res") => Type::Result(Box::new(try_opt!(read_type(tokens)))),
+        Some("sec") => Type::Secret(Box::new(try_opt!(read_type(tokens)))),
+        Some("thr") => Type::Thread(Box::new(try_opt!(read_type(tokens)))),
+        Some("chan") => Type::Channel(Box::new(try_opt!(read_type(tokens)))),
+        Some("ad") => {
+            let name = Arc::new(try_opt!(tokens.next()).to_string());
+            Type::AdHoc(name, Box::new(try_opt!(read_type(tokens))))
+        }
+        Some("cl") => {
+            let n: usize = try_opt!(try_opt!(tokens.next()).parse().ok());
+            let mut lts = vec![];
+            let mut tys = vec![];
+            for _ in 0..n {
+                lts.push(match try_opt!(tokens.next()) {
+                    "d" => Lt::Default,
+                    "r" => Lt::Return,
+                    x if x.starts_with('a') => Lt::Arg(try_opt!(x[1..].parse().ok())),
+                    _ => return None,
+                });
+                tys.push(try_opt!(read_type(tokens)));
+            }
+            let ret = try_opt!(read_type(tokens));
+            Type::Closure(Box::new(Dfn { lts: lts, tys: tys, ret: ret }))
+        }
+        _ => return None,
+    })
+}
@@ -0,0 +1,102 @@
+//! Channels for passing messages between `go` threads.
+//!
+//! A channel is a queue shared by all copies of it.
+//! Values are deep cloned when sent, so they never contain references.
+//!
+//! Receiving blocks until a value is sent or the channel is closed.
+//! A worker that blocks is replaced by a helper thread while waiting,
+//! such that a consumer does not wait for a producer that never starts.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+
+use pool;
+use Variable;
+
+struct Queue {
+    values: VecDeque<Variable>,
+    closed: bool,
+}
+
+struct Inner {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+/// A handle to a channel, cloning it shares the same queue.
+#[derive(Clone)]
+pub struct Channel(Arc<Inner>);
+
+impl Channel {
+    /// Creates a new open channel.
+    pub fn new() -> Channel {
+        Channel(Arc::new(Inner {
+            queue: Mutex::new(Queue {
+                values: VecDeque::new(),
+                closed: false,
+            }),
+            ready: Condvar::new(),
+        }))
+    }
+
+    /// Sends a value.
+    ///
+    /// Returns `false` when the channel is closed.
+    pub fn send(&self, value: Variable) -> bool {
+        {
+            let mut queue = self.0.queue.lock().unwrap();
+            if queue.closed { return false; }
+            queue.values.push_back(value);
+        }
+        self.0.ready.notify_one();
+        true
+    }
+
+    /// Waits for a value.
+    ///
+    /// Returns `None` when the channel is closed and empty.
+    pub fn recv(&self) -> Option<Variable> {
+        if let Some(x) = take(&mut self.0.queue.lock().unwrap()) { return x; }
+        pool::blocking(|| {
+            let mut queue = self.0.queue.lock().unwrap();
+            loop {
+                if let Some(x) = take(&mut queue) { return x; }
+                queue = self.0.ready.wait(queue).unwrap();
+            }
+        })
+    }
+
+    /// Returns a value if there is one, without waiting.
+    pub fn try_recv(&self) -> Option<Variable> {
+        self.0.queue.lock().unwrap().values.pop_front()
+    }
+
+    /// Closes the channel.
+    ///
+    /// Values sent before closing can still be received.
+    pub fn close(&self) {
+        self.0.queue.lock().unwrap().closed = true;
+        self.0.ready.notify_all();
+    }
+
+    /// Returns `true` if the channel is closed.
+    pub fn is_closed(&self) -> bool {
+        self.0.queue.lock().unwrap().closed
+    }
+}
+
+/// Returns `Some` when `recv` should stop waiting.
+fn take(queue: &mut Queue) -> Option<Option<Variable>> {
+    match queue.values.pop_front() {
+        Some(x) => Some(Some(x)),
+        None if queue.closed => Some(None),
+        None => None,
+    }
+}
+
+impl fmt::Debug for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "channel")
+    }
+}
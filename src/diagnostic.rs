@@ -0,0 +1,83 @@
+use std::sync::Arc;
+use range::Range;
+
+use error::ErrorKind;
+
+/// The severity of a diagnostic.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found when loading a source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub kind: ErrorKind,
+    /// The name of the source file.
+    pub file: Arc<String>,
+    /// The range in the source.
+    pub range: Range,
+    pub severity: Severity,
+    pub message: String,
+    /// Extra information that is not part of the message.
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    /// Creates a new error.
+    pub fn error(
+        kind: ErrorKind,
+        file: Arc<String>,
+        range: Range,
+        message: String
+    ) -> Diagnostic {
+        Diagnostic {
+            kind: kind,
+            file: file,
+            range: range,
+            severity: Severity::Error,
+            message: message,
+            notes: vec![],
+        }
+    }
+
+    /// Creates a new warning.
+    pub fn warning(
+        kind: ErrorKind,
+        file: Arc<String>,
+        range: Range,
+        message: String
+    ) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Diagnostic::error(kind, file, range, message)
+        }
+    }
+
+    /// Renders the diagnostic with the source, like the errors returned from `load_str`.
+    pub fn render(&self, source: &str) -> String {
+        use std::io::Write;
+        use piston_meta::ParseErrorHandler;
+
+        let mut buf: Vec<u8> = vec![];
+        writeln!(&mut buf, "In `{}`:\n", self.file).unwrap();
+        ParseErrorHandler::new(source)
+            .write_msg(&mut buf, self.range, &self.message)
+            .unwrap();
+        for note in &self.notes {
+            writeln!(&mut buf, "note: {}", note).unwrap();
+        }
+        String::from_utf8(buf).unwrap()
+    }
+}
+
+/// Renders a list of diagnostics with the source.
+pub fn render(diagnostics: &[Diagnostic], source: &str) -> String {
+    let mut s = String::new();
+    for (i, d) in diagnostics.iter().enumerate() {
+        if i > 0 { s.push('\n'); }
+        s.push_str(&d.render(source));
+    }
+    s
+}
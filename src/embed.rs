@@ -1,22 +1,66 @@
+use std::marker::PhantomData;
 use std::sync::Arc;
 
+use error::{self, ErrorKind};
 use Error;
+use Module;
 use Object;
 use Runtime;
+use Type;
 use Variable;
 use RustObject;
 
-pub fn obj_field<T: PopVariable>(rt: &Runtime, obj: &Object, name: &str) -> Result<T, String> {
+pub fn obj_field<T: PopVariable>(
+    rt: &Runtime,
+    obj: &Object,
+    name: &str
+) -> Result<T, error::Error> {
     let var = try!(obj.get(&Arc::new(name.into()))
-        .ok_or_else(|| format!("Object has no key `{}`", name)));
+        .ok_or_else(|| error::Error::new(ErrorKind::Type,
+            format!("Object has no key `{}`", name))));
     PopVariable::pop_var(rt, var)
 }
 
+/// Like `obj_field`, but adds the field to the path of errors.
+///
+/// Used by `#[derive(PopVariable)]`.
+pub fn obj_field_path<T: PopVariable>(
+    rt: &Runtime,
+    obj: &Object,
+    name: &str
+) -> Result<T, error::Error> {
+    match obj.get(&Arc::new(name.into())) {
+        Some(var) => pop_field(rt, name, var),
+        None => Err(field_error(name, error::Error::new(ErrorKind::Type,
+            format!("Object has no key `{}`", name)))),
+    }
+}
+
+/// Converts the field of an object or array, adding the field to the path of errors.
+pub fn pop_field<T: PopVariable>(
+    rt: &Runtime,
+    name: &str,
+    var: &Variable
+) -> Result<T, error::Error> {
+    PopVariable::pop_var(rt, rt.resolve(var)).map_err(|err| field_error(name, err))
+}
+
+/// Adds a field to the path of an error, e.g. ``In field `pos.x`:``.
+pub fn field_error(name: &str, mut err: error::Error) -> error::Error {
+    const PREFIX: &'static str = "In field `";
+    err.message = if err.message.starts_with(PREFIX) {
+        format!("{}{}.{}", PREFIX, name, &err.message[PREFIX.len()..])
+    } else {
+        format!("{}{}`:\n{}", PREFIX, name, err.message)
+    };
+    err
+}
+
 /// Implemented by types that can be popped from the runtime stack.
 pub trait PopVariable: Sized {
     /// Converts variable to self.
     /// The variable should be resolved before call.
-    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, String>;
+    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, error::Error>;
 }
 
 /// Implemented by types that can be pushed to the runtime stack.
@@ -25,6 +69,13 @@ pub trait PushVariable {
     fn push_var(&self) -> Variable;
 }
 
+/// Implemented by types that have a corresponding Dyon type.
+///
+/// Used to check the signature of functions called from Rust.
+pub trait TypeOf {
+    fn type_of() -> Type;
+}
+
 /// Implemented by types that can be converted to and from vec4.
 pub trait ConvertVec4: Sized {
     /// Converts vec4 to self.
@@ -33,132 +84,132 @@ pub trait ConvertVec4: Sized {
 }
 
 impl PopVariable for Variable {
-    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, String> {
+    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, error::Error> {
         Ok(var.deep_clone(&rt.stack))
     }
 }
 
 impl PopVariable for RustObject {
-    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, String> {
+    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, error::Error> {
         if let &Variable::RustObject(ref robj) = var {
             Ok(robj.clone())
         } else {
-            Err(rt.expected(var, "rust_object"))
+            Err(rt.expected_error(var, "rust_object"))
         }
     }
 }
 
 impl PopVariable for bool {
-    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, String> {
+    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, error::Error> {
         if let &Variable::Bool(b, _) = var {
             Ok(b)
         } else {
-            Err(rt.expected(var, "bool"))
+            Err(rt.expected_error(var, "bool"))
         }
     }
 }
 
 impl PopVariable for String {
-    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, String> {
+    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, error::Error> {
         if let &Variable::Text(ref s) = var {
             Ok((&**s).clone())
         } else {
-            Err(rt.expected(var, "string"))
+            Err(rt.expected_error(var, "string"))
         }
     }
 }
 
 impl PopVariable for Arc<String> {
-    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, String> {
+    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, error::Error> {
         if let &Variable::Text(ref s) = var {
             Ok(s.clone())
         } else {
-            Err(rt.expected(var, "string"))
+            Err(rt.expected_error(var, "string"))
         }
     }
 }
 
 impl PopVariable for u32 {
-    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, String> {
+    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, error::Error> {
         if let &Variable::F64(n, _) = var {
             Ok(n as u32)
         } else {
-            Err(rt.expected(var, "number"))
+            Err(rt.expected_error(var, "number"))
         }
     }
 }
 
 impl PopVariable for usize {
-    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, String> {
+    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, error::Error> {
         if let &Variable::F64(n, _) = var {
             Ok(n as usize)
         } else {
-            Err(rt.expected(var, "number"))
+            Err(rt.expected_error(var, "number"))
         }
     }
 }
 
 impl PopVariable for f32 {
-    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, String> {
+    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, error::Error> {
         if let &Variable::F64(n, _) = var {
             Ok(n as f32)
         } else {
-            Err(rt.expected(var, "number"))
+            Err(rt.expected_error(var, "number"))
         }
     }
 }
 
 impl PopVariable for f64 {
-    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, String> {
+    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, error::Error> {
         if let &Variable::F64(n, _) = var {
             Ok(n)
         } else {
-            Err(rt.expected(var, "number"))
+            Err(rt.expected_error(var, "number"))
         }
     }
 }
 
 impl<T: PopVariable> PopVariable for Option<T> {
-    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, String> {
+    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, error::Error> {
         if let &Variable::Option(ref s) = var {
             Ok(match *s {
                 Some(ref s) => Some(try!(PopVariable::pop_var(rt, rt.resolve(s)))),
                 None => None
             })
         } else {
-            Err(rt.expected(var, "option"))
+            Err(rt.expected_error(var, "option"))
         }
     }
 }
 
 impl<T: PopVariable, U: PopVariable> PopVariable for Result<T, U> {
-    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, String> {
+    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, error::Error> {
         if let &Variable::Result(ref s) = var {
             Ok(match *s {
                 Ok(ref s) => Ok(try!(PopVariable::pop_var(rt, rt.resolve(s)))),
                 Err(ref err) => Err(try!(PopVariable::pop_var(rt, rt.resolve(&err.message))))
             })
         } else {
-            Err(rt.expected(var, "result"))
+            Err(rt.expected_error(var, "result"))
         }
     }
 }
 
 impl<T: PopVariable> PopVariable for [T; 2] {
-    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, String> {
+    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, error::Error> {
         if let &Variable::Array(ref arr) = var {
             Ok([
                 try!(PopVariable::pop_var(rt, rt.resolve(&arr[0]))),
                 try!(PopVariable::pop_var(rt, rt.resolve(&arr[1])))
             ])
         } else {
-            Err(rt.expected(var, "[_; 2]"))
+            Err(rt.expected_error(var, "[_; 2]"))
         }
     }
 }
 
 impl<T: PopVariable> PopVariable for [T; 3] {
-    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, String> {
+    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, error::Error> {
         if let &Variable::Array(ref arr) = var {
             Ok([
                 try!(PopVariable::pop_var(rt, rt.resolve(&arr[0]))),
@@ -166,13 +217,13 @@ impl<T: PopVariable> PopVariable for [T; 3] {
                 try!(PopVariable::pop_var(rt, rt.resolve(&arr[2])))
             ])
         } else {
-            Err(rt.expected(var, "[_; 3]"))
+            Err(rt.expected_error(var, "[_; 3]"))
         }
     }
 }
 
 impl<T: PopVariable> PopVariable for [T; 4] {
-    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, String> {
+    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, error::Error> {
         if let &Variable::Array(ref arr) = var {
             Ok([
                 try!(PopVariable::pop_var(rt, rt.resolve(&arr[0]))),
@@ -181,26 +232,26 @@ impl<T: PopVariable> PopVariable for [T; 4] {
                 try!(PopVariable::pop_var(rt, rt.resolve(&arr[3])))
             ])
         } else {
-            Err(rt.expected(var, "[_; 4]"))
+            Err(rt.expected_error(var, "[_; 4]"))
         }
     }
 }
 
 impl<T: PopVariable, U: PopVariable> PopVariable for (T, U) {
-    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, String> {
+    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, error::Error> {
         if let &Variable::Array(ref arr) = var {
             Ok((
                 try!(PopVariable::pop_var(rt, rt.resolve(&arr[0]))),
                 try!(PopVariable::pop_var(rt, rt.resolve(&arr[1])))
             ))
         } else {
-            Err(rt.expected(var, "[_; 2]"))
+            Err(rt.expected_error(var, "[_; 2]"))
         }
     }
 }
 
 impl<T: PopVariable, U: PopVariable, V: PopVariable> PopVariable for (T, U, V) {
-    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, String> {
+    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, error::Error> {
         if let &Variable::Array(ref arr) = var {
             Ok((
                 try!(PopVariable::pop_var(rt, rt.resolve(&arr[0]))),
@@ -208,13 +259,13 @@ impl<T: PopVariable, U: PopVariable, V: PopVariable> PopVariable for (T, U, V) {
                 try!(PopVariable::pop_var(rt, rt.resolve(&arr[2])))
             ))
         } else {
-            Err(rt.expected(var, "[_; 3]"))
+            Err(rt.expected_error(var, "[_; 3]"))
         }
     }
 }
 
 impl<T: PopVariable, U: PopVariable, V: PopVariable, W: PopVariable> PopVariable for (T, U, V, W) {
-    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, String> {
+    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, error::Error> {
         if let &Variable::Array(ref arr) = var {
             Ok((
                 try!(PopVariable::pop_var(rt, rt.resolve(&arr[0]))),
@@ -223,13 +274,13 @@ impl<T: PopVariable, U: PopVariable, V: PopVariable, W: PopVariable> PopVariable
                 try!(PopVariable::pop_var(rt, rt.resolve(&arr[3])))
             ))
         } else {
-            Err(rt.expected(var, "[_; 4]"))
+            Err(rt.expected_error(var, "[_; 4]"))
         }
     }
 }
 
 impl<T: PopVariable> PopVariable for Vec<T> {
-    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, String> {
+    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, error::Error> {
         if let &Variable::Array(ref arr) = var {
             let mut res = Vec::with_capacity(arr.len());
             for it in &**arr {
@@ -237,7 +288,7 @@ impl<T: PopVariable> PopVariable for Vec<T> {
             }
             Ok(res)
         } else {
-            Err(rt.expected(var, "array"))
+            Err(rt.expected_error(var, "array"))
         }
     }
 }
@@ -394,3 +445,203 @@ impl ConvertVec4 for [f64; 4] {
     fn from(val: [f32; 4]) -> Self { [val[0] as f64, val[1] as f64, val[2] as f64, val[3] as f64] }
     fn to(&self) -> [f32; 4] { [self[0] as f32, self[1] as f32, self[2] as f32, self[3] as f32] }
 }
+
+impl TypeOf for Variable {
+    fn type_of() -> Type { Type::Any }
+}
+
+impl TypeOf for RustObject {
+    fn type_of() -> Type { Type::Any }
+}
+
+impl TypeOf for bool {
+    fn type_of() -> Type { Type::Bool }
+}
+
+impl TypeOf for u32 {
+    fn type_of() -> Type { Type::F64 }
+}
+
+impl TypeOf for usize {
+    fn type_of() -> Type { Type::F64 }
+}
+
+impl TypeOf for f32 {
+    fn type_of() -> Type { Type::F64 }
+}
+
+impl TypeOf for f64 {
+    fn type_of() -> Type { Type::F64 }
+}
+
+impl TypeOf for String {
+    fn type_of() -> Type { Type::Text }
+}
+
+impl TypeOf for Arc<String> {
+    fn type_of() -> Type { Type::Text }
+}
+
+impl<T: TypeOf> TypeOf for Option<T> {
+    fn type_of() -> Type { Type::Option(Box::new(T::type_of())) }
+}
+
+impl<T: TypeOf, U> TypeOf for Result<T, U> {
+    fn type_of() -> Type { Type::Result(Box::new(T::type_of())) }
+}
+
+impl<T: TypeOf> TypeOf for [T; 2] {
+    fn type_of() -> Type { Type::Array(Box::new(T::type_of())) }
+}
+
+impl<T: TypeOf> TypeOf for [T; 3] {
+    fn type_of() -> Type { Type::Array(Box::new(T::type_of())) }
+}
+
+impl<T: TypeOf> TypeOf for [T; 4] {
+    fn type_of() -> Type { Type::Array(Box::new(T::type_of())) }
+}
+
+impl<T: TypeOf> TypeOf for Vec<T> {
+    fn type_of() -> Type { Type::Array(Box::new(T::type_of())) }
+}
+
+impl<T, U> TypeOf for (T, U) {
+    fn type_of() -> Type { Type::Array(Box::new(Type::Any)) }
+}
+
+impl<T, U, V> TypeOf for (T, U, V) {
+    fn type_of() -> Type { Type::Array(Box::new(Type::Any)) }
+}
+
+impl<T, U, V, W> TypeOf for (T, U, V, W) {
+    fn type_of() -> Type { Type::Array(Box::new(Type::Any)) }
+}
+
+/// Implemented by tuples of arguments to functions called from Rust.
+pub trait FnArgs {
+    /// Returns the types of the arguments.
+    fn types() -> Vec<Type>;
+    /// Converts the arguments to variables.
+    fn push_args(&self) -> Vec<Variable>;
+}
+
+macro_rules! fn_args {
+    ($($t:ident: $i:tt),*) => {
+        impl<$($t: PushVariable + TypeOf),*> FnArgs for ($($t,)*) {
+            fn types() -> Vec<Type> { vec![$($t::type_of()),*] }
+            fn push_args(&self) -> Vec<Variable> { vec![$(self.$i.push_var()),*] }
+        }
+    }
+}
+
+fn_args!();
+fn_args!(A: 0);
+fn_args!(A: 0, B: 1);
+fn_args!(A: 0, B: 1, C: 2);
+fn_args!(A: 0, B: 1, C: 2, D: 3);
+fn_args!(A: 0, B: 1, C: 2, D: 3, E: 4);
+fn_args!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5);
+
+/// Implemented by return types of functions called from Rust.
+///
+/// Use `()` for functions that do not return a value.
+pub trait FnRet: Sized {
+    /// Returns the type of the return value.
+    fn ret_type() -> Type;
+    /// Converts the returned value.
+    fn pop_ret(rt: &Runtime, var: Option<Variable>) -> Result<Self, error::Error>;
+}
+
+impl FnRet for () {
+    fn ret_type() -> Type { Type::Void }
+    fn pop_ret(_rt: &Runtime, _var: Option<Variable>) -> Result<(), error::Error> { Ok(()) }
+}
+
+impl<T: PopVariable + TypeOf> FnRet for T {
+    fn ret_type() -> Type { T::type_of() }
+    fn pop_ret(rt: &Runtime, var: Option<Variable>) -> Result<T, error::Error> {
+        match var {
+            Some(ref var) => T::pop_var(rt, var),
+            None => Err(error::Error::new(ErrorKind::Runtime,
+                                          "Expected function to return a value".into())),
+        }
+    }
+}
+
+/// A function in a module with a checked signature, created by `Module::get_fn`.
+pub struct FnHandle<A, R> {
+    name: Arc<String>,
+    f_index: usize,
+    _types: PhantomData<fn(A) -> R>,
+}
+
+impl<A, R> Clone for FnHandle<A, R> {
+    fn clone(&self) -> FnHandle<A, R> {
+        FnHandle {
+            name: self.name.clone(),
+            f_index: self.f_index,
+            _types: PhantomData,
+        }
+    }
+}
+
+impl<A: FnArgs, R: FnRet> FnHandle<A, R> {
+    /// Looks up a function and checks its signature against the Rust types.
+    pub fn new(module: &Module, name: &str) -> Result<FnHandle<A, R>, error::Error> {
+        use FnIndex;
+
+        let name: Arc<String> = Arc::new(name.into());
+        let f_index = match module.find_function(&name, 0) {
+            FnIndex::Loaded(f_index) => f_index as usize,
+            _ => return Err(error::Error::new(ErrorKind::Runtime,
+                            format!("Could not find function `{}`", name)))
+        };
+        let f = &module.functions[f_index];
+        let type_error = |range, msg: String| error::Error {
+            file: Some(f.file.clone()),
+            range: Some(range),
+            ..error::Error::new(ErrorKind::Type, module.error_fnindex(range, &msg, f_index))
+        };
+        let tys = A::types();
+        if tys.len() != f.args.len() {
+            return Err(type_error(f.source_range, format!(
+                "`{}`: Expected {} arguments, found {}", name, f.args.len(), tys.len())));
+        }
+        for (arg, ty) in f.args.iter().zip(tys.iter()) {
+            if !arg.ty.goes_with(ty) {
+                return Err(type_error(arg.source_range, format!(
+                    "`{}`: Expected `{}`, found `{}`",
+                    name, arg.ty.description(), ty.description())));
+            }
+        }
+        let ret = R::ret_type();
+        if !ret.goes_with(&f.ret) {
+            return Err(type_error(f.source_range, format!(
+                "`{}`: Expected return type `{}`, found `{}`",
+                name, ret.description(), f.ret.description())));
+        }
+        Ok(FnHandle {
+            name: name,
+            f_index: f_index,
+            _types: PhantomData,
+        })
+    }
+
+    /// Calls the function.
+    ///
+    /// The module must be the one the handle was created from, or a clone of it.
+    pub fn call(
+        &self,
+        rt: &mut Runtime,
+        module: &Arc<Module>,
+        args: A
+    ) -> Result<R, error::Error> {
+        if module.functions.get(self.f_index).map(|f| &f.name) != Some(&self.name) {
+            return Err(error::Error::new(ErrorKind::Runtime, format!(
+                "Function `{}` is not in module", self.name)));
+        }
+        let x = try!(rt.call_index(self.f_index, args.push_args(), module));
+        R::pop_ret(rt, x)
+    }
+}
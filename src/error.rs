@@ -0,0 +1,83 @@
+//! Errors returned to the host when loading or running scripts.
+//!
+//! This is not the same as `dyon::Error`, which is the error value of `Variable::Result`.
+
+use std::error;
+use std::fmt;
+use std::sync::Arc;
+use range::Range;
+
+/// The kind of error.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Could not read a source file.
+    Io,
+    /// The source does not match the syntax or could not be converted to AST.
+    Syntax,
+    /// Failed the lifetime checker.
+    Lifetime,
+    /// Failed the type checker,
+    /// or a value did not have the expected type when converted to Rust.
+    Type,
+    /// Failed while running a script.
+    Runtime,
+    /// Returned from an external function.
+    External,
+    /// Found by the linter, see `lint`.
+    Lint,
+}
+
+/// A function call in the stack trace of an error.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub fn_name: Arc<String>,
+    pub file: Option<Arc<String>>,
+}
+
+/// An error from loading or running a script.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Error {
+    pub kind: ErrorKind,
+    /// The source file where the error happened.
+    pub file: Option<Arc<String>>,
+    /// The range in the source file.
+    ///
+    /// For runtime errors, this is the expression where the error was created.
+    pub range: Option<Range>,
+    /// The call stack when a runtime error happened, innermost call last.
+    pub trace: Vec<Frame>,
+    /// The formatted message, including source and stack trace.
+    pub message: String,
+}
+
+impl Error {
+    /// Creates an error without source location.
+    pub fn new(kind: ErrorKind, message: String) -> Error {
+        Error {
+            kind: kind,
+            file: None,
+            range: None,
+            trace: vec![],
+            message: message,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+/// Makes it possible to use `try!` in functions that return `Result<_, String>`.
+impl From<Error> for String {
+    fn from(err: Error) -> String {
+        err.message
+    }
+}
@@ -0,0 +1,676 @@
+//! Formats Dyon source files.
+//!
+//! Expressions are written with `write::write_expr` when they fit on a line.
+//! The formatter lays out functions and blocks, breaks calls, arrays and objects
+//! that are too wide into one item per line,
+//! and keeps comments and blank lines, which the syntax rules throw away.
+//!
+//! A comment inside an expression that is written on one line
+//! is moved to its own line before the expression.
+
+use std::sync::Arc;
+use piston_meta;
+use piston_meta::MetaData;
+use piston_meta::bootstrap::Convert;
+use range::Range;
+
+use ast;
+use diagnostic::Diagnostic;
+use error::{Error, ErrorKind};
+use write;
+use conversion_diagnostics;
+use diagnostics_error;
+use syntax_rules;
+use Runtime;
+use Type;
+
+/// Formatting options.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The maximum width of lines.
+    ///
+    /// Calls, arrays, objects and function arguments that do not fit
+    /// are written with one item per line.
+    pub width: usize,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config { width: 100 }
+    }
+}
+
+/// Formats source.
+///
+/// - source - The name of source file
+/// - d - The data of source file
+///
+/// Returns an error if the source does not parse.
+/// Formatting is idempotent, so formatting the result gives the same text.
+pub fn format_str(source: &str, d: &str, config: &Config) -> Result<String, Error> {
+    let file = Arc::new(source.to_string());
+    let d = Arc::new(d.to_string());
+    let syntax_rules = try!(syntax_rules().map_err(|err| Error::new(ErrorKind::Syntax, err)));
+
+    let mut data = vec![];
+    try!(piston_meta::parse(syntax_rules, &d, &mut data).map_err(|err| {
+        let (range, err) = err.decouple();
+        let diagnostic = Diagnostic::error(ErrorKind::Syntax, file.clone(), range,
+                                           format!("{}", err));
+        diagnostics_error(&[diagnostic], &d)
+    }));
+
+    // Parse numbers again to get the nearest value, e.g. for `.3`,
+    // such that they are written like in source.
+    for item in &mut data {
+        if let MetaData::F64(_, ref mut val) = item.data {
+            let text = &d[item.offset..item.offset + item.length];
+            if let Ok(x) = text.replace("_", "").parse() { *val = x; }
+        }
+    }
+
+    let mut ignored = vec![];
+    let mut items = vec![];
+    let mut convert = Convert::new(&data);
+    let start = convert;
+    if let Ok((range, val)) = ast::Namespace::from_meta_data(convert, &mut ignored) {
+        convert.update(range);
+        items.push(Item::Namespace(convert.source(start).unwrap(), val));
+    }
+    let start = convert;
+    if let Ok((range, val)) = ast::Uses::from_meta_data(convert, &mut ignored) {
+        convert.update(range);
+        if val.use_imports.len() > 0 {
+            items.push(Item::Uses(convert.source(start).unwrap(), val));
+        }
+    }
+    let namespace = Arc::new(vec![]);
+    let mut conv_res = Ok(());
+    loop {
+        if let Ok((range, f)) = ast::Function::from_meta_data(
+                &namespace, &file, &d, "fn", convert, &mut ignored) {
+            convert.update(range);
+            items.push(Item::Function(f));
+        } else if convert.remaining_data_len() > 0 {
+            conv_res = Err(data[data.len() - convert.remaining_data_len()].range());
+            break;
+        } else {
+            break;
+        }
+    }
+    let diagnostics = conversion_diagnostics(&file, &conv_res, &data, &ignored);
+    if diagnostics.len() > 0 {
+        return Err(diagnostics_error(&diagnostics, &d));
+    }
+
+    let mut printer = Printer {
+        d: &d,
+        config: config,
+        rt: Runtime::new(),
+        comments: comments(&d),
+        out: String::new(),
+    };
+    printer.lines(Lines::File(&items), 0, d.len(), 0);
+    if printer.out.len() > 0 {
+        printer.out.push('\n');
+    }
+    Ok(printer.out)
+}
+
+/// An item at the top of a file.
+enum Item {
+    Namespace(Range, ast::Namespace),
+    Uses(Range, ast::Uses),
+    Function(ast::Function),
+}
+
+impl Item {
+    fn source_range(&self) -> Range {
+        match *self {
+            Item::Namespace(range, _) => range,
+            Item::Uses(range, _) => range,
+            Item::Function(ref f) => f.source_range,
+        }
+    }
+}
+
+/// Things written on separate lines.
+#[derive(Copy, Clone)]
+enum Lines<'a> {
+    File(&'a [Item]),
+    Block(&'a [ast::Expression]),
+}
+
+impl<'a> Lines<'a> {
+    fn len(&self) -> usize {
+        match *self {
+            Lines::File(items) => items.len(),
+            Lines::Block(exprs) => exprs.len(),
+        }
+    }
+
+    fn source_range(&self, i: usize) -> Range {
+        match *self {
+            Lines::File(items) => items[i].source_range(),
+            Lines::Block(exprs) => exprs[i].source_range(),
+        }
+    }
+}
+
+struct Comment {
+    start: usize,
+    end: usize,
+    text: String,
+    used: bool,
+}
+
+/// Finds comments in source, skipping text in strings.
+fn comments(d: &str) -> Vec<Comment> {
+    let bytes = d.as_bytes();
+    let n = bytes.len();
+    let mut res = vec![];
+    let mut i = 0;
+    while i < n {
+        match (bytes[i], if i + 1 < n { bytes[i + 1] } else { 0 }) {
+            (b'"', _) => {
+                i += 1;
+                while i < n && bytes[i] != b'"' {
+                    if bytes[i] == b'\\' { i += 1; }
+                    i += 1;
+                }
+                i += 1;
+            }
+            (b'/', b'/') => {
+                let end = d[i..].find('\n').map(|j| i + j).unwrap_or(n);
+                res.push(Comment {
+                    start: i,
+                    end: end,
+                    text: d[i..end].trim_right().into(),
+                    used: false,
+                });
+                i = end;
+            }
+            (b'/', b'*') => {
+                let mut depth = 0;
+                let mut end = i;
+                while end < n {
+                    if d[end..].starts_with("/*") {
+                        depth += 1;
+                        end += 2;
+                    } else if d[end..].starts_with("*/") {
+                        depth -= 1;
+                        end += 2;
+                        if depth == 0 { break; }
+                    } else {
+                        end += 1;
+                    }
+                }
+                res.push(Comment {
+                    start: i,
+                    end: end,
+                    text: d[i..end].into(),
+                    used: false,
+                });
+                i = end;
+            }
+            _ => i += 1,
+        }
+    }
+    res
+}
+
+/// What was written before on the same level.
+#[derive(Copy, Clone, PartialEq)]
+enum Prev {
+    Nothing,
+    Comment,
+    Line,
+}
+
+struct Printer<'a> {
+    d: &'a str,
+    config: &'a Config,
+    rt: Runtime,
+    comments: Vec<Comment>,
+    out: String,
+}
+
+impl<'a> Printer<'a> {
+    fn column(&self) -> usize {
+        let start = self.out.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        self.out[start..].chars().count()
+    }
+
+    fn fits(&self, text: &str) -> bool {
+        !text.contains('\n') && self.column() + text.chars().count() <= self.config.width
+    }
+
+    fn indent(&mut self, tabs: u32) {
+        for _ in 0..tabs {
+            self.out.push_str("    ");
+        }
+    }
+
+    fn newline(&mut self, tabs: u32) {
+        self.out.push('\n');
+        self.indent(tabs);
+    }
+
+    fn flat(&self, expr: &ast::Expression, tabs: u32) -> String {
+        let mut buf: Vec<u8> = vec![];
+        write::write_expr(&mut buf, &self.rt, expr, tabs).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    /// Returns `true` if there are comments left between two positions.
+    fn has_comments(&self, start: usize, end: usize) -> bool {
+        self.comments.iter().any(|c| !c.used && c.start >= start && c.end <= end)
+    }
+
+    /// Writes line breaks and indention before a line.
+    fn separate(&mut self, prev: Prev, gap: &str, file: bool, tabs: u32) {
+        match prev {
+            Prev::Nothing => {
+                if !file { self.out.push('\n'); }
+            }
+            _ => {
+                self.out.push('\n');
+                // Keep blank lines, but at most one in a row.
+                if gap.matches('\n').count() >= 2 {
+                    self.out.push('\n');
+                }
+            }
+        }
+        self.indent(tabs);
+    }
+
+    /// Writes things on separate lines, with the comments between them.
+    fn lines(&mut self, lines: Lines, start: usize, end: usize, tabs: u32) {
+        let d = self.d;
+        let file = if let Lines::File(_) = lines { true } else { false };
+        let mut prev = Prev::Nothing;
+        let mut prev_end = start;
+        let n = lines.len();
+        for i in 0..n + 1 {
+            let next = if i < n { lines.source_range(i).offset } else { end };
+            for j in 0..self.comments.len() {
+                let (c_start, c_end) = (self.comments[j].start, self.comments[j].end);
+                if self.comments[j].used || c_start < prev_end || c_end > next { continue; }
+                let gap = &d[prev_end..c_start];
+                if prev != Prev::Nothing && !gap.contains('\n') {
+                    // Keep comments at the end of a line.
+                    self.out.push(' ');
+                } else {
+                    self.separate(prev, gap, file, tabs);
+                }
+                self.out.push_str(&self.comments[j].text);
+                self.comments[j].used = true;
+                prev = Prev::Comment;
+                prev_end = c_end;
+            }
+            if i == n { break; }
+
+            let range = lines.source_range(i);
+            let gap = if prev_end < range.offset { &d[prev_end..range.offset] } else { "" };
+            self.separate(prev, gap, file, tabs);
+            let pos = self.out.len();
+            match lines {
+                Lines::File(items) => self.item(&items[i]),
+                Lines::Block(exprs) => self.expr(&exprs[i], tabs),
+            }
+            // Move comments that were not written to the line before.
+            let mut before = String::new();
+            for c in &mut self.comments {
+                if !c.used && c.start >= range.offset && c.end <= range.offset + range.length {
+                    before.push_str(&c.text);
+                    before.push('\n');
+                    for _ in 0..tabs { before.push_str("    "); }
+                    c.used = true;
+                }
+            }
+            self.out.insert_str(pos, &before);
+            prev = Prev::Line;
+            prev_end = range.offset + range.length;
+        }
+    }
+
+    fn item(&mut self, item: &Item) {
+        match *item {
+            Item::Namespace(_, ref ns) => {
+                let names: Vec<&str> = ns.names.iter().map(|n| &***n).collect();
+                self.out.push_str("ns ");
+                self.out.push_str(&names.join("::"));
+            }
+            Item::Uses(_, ref uses) => {
+                for (i, use_import) in uses.use_imports.iter().enumerate() {
+                    if i > 0 { self.out.push('\n'); }
+                    let names: Vec<&str> = use_import.names.iter().map(|n| &***n).collect();
+                    self.out.push_str("use ");
+                    self.out.push_str(&names.join("::"));
+                    if use_import.fns.len() > 0 {
+                        let fns: Vec<String> = use_import.fns.iter().map(|&(ref f, ref alias)| {
+                            match *alias {
+                                None => (**f).clone(),
+                                Some(ref alias) => format!("{} as {}", f, alias),
+                            }
+                        }).collect();
+                        self.out.push_str("::{");
+                        self.out.push_str(&fns.join(", "));
+                        self.out.push('}');
+                    }
+                    self.out.push_str(" as ");
+                    self.out.push_str(&use_import.alias);
+                }
+            }
+            Item::Function(ref f) => self.function(f),
+        }
+    }
+
+    fn function(&mut self, f: &ast::Function) {
+        // Functions declared with `=` return the expression.
+        let math_expr = if f.block.expressions.len() == 1 {
+            match f.block.expressions[0] {
+                ast::Expression::Return(ref expr) => {
+                    let range = expr.source_range();
+                    if range.offset == f.block.source_range.offset &&
+                       range.length == f.block.source_range.length {
+                        Some(expr)
+                    } else {
+                        None
+                    }
+                }
+                _ => None
+            }
+        } else {
+            None
+        };
+
+        let name = match f.name.find('(') {
+            Some(i) => &f.name[..i],
+            None => &f.name[..],
+        };
+        if math_expr.is_none() {
+            self.out.push_str("fn ");
+        }
+        self.out.push_str(name);
+        self.out.push('(');
+
+        let args: Vec<String> = f.args.iter().map(|arg| {
+            let mut buf: Vec<u8> = vec![];
+            write::write_arg(&mut buf, arg).unwrap();
+            String::from_utf8(buf).unwrap()
+        }).collect();
+        let mut rest: Vec<u8> = vec![];
+        write::write_currents(&mut rest, &f.currents).unwrap();
+        let mut rest = String::from_utf8(rest).unwrap();
+        if math_expr.is_some() {
+            rest.push_str("= ");
+        } else {
+            match f.ret {
+                Type::Void => {}
+                Type::Any => rest.push_str("-> "),
+                ref ty => {
+                    let mut buf: Vec<u8> = vec![];
+                    write::write_type(&mut buf, ty).unwrap();
+                    rest.push_str("-> ");
+                    rest.push_str(&String::from_utf8(buf).unwrap());
+                    rest.push(' ');
+                }
+            }
+            rest.push('{');
+        }
+        let flat = format!("{}) {}", args.join(", "), rest);
+        if args.len() == 0 || self.fits(&flat) {
+            self.out.push_str(&args.join(", "));
+        } else {
+            for (i, arg) in args.iter().enumerate() {
+                self.newline(1);
+                self.out.push_str(arg);
+                if i + 1 < args.len() { self.out.push(','); }
+            }
+            self.out.push('\n');
+        }
+        self.out.push_str(") ");
+
+        match math_expr {
+            Some(expr) => {
+                self.out.push_str(&rest);
+                self.expr(expr, 0);
+            }
+            None => {
+                // The block is written with its opening brace.
+                rest.pop();
+                self.out.push_str(&rest);
+                self.block(&f.block, 0);
+            }
+        }
+    }
+
+    fn block(&mut self, block: &ast::Block, tabs: u32) {
+        let start = block.source_range.offset;
+        let end = start + block.source_range.length;
+        let has_comments = self.has_comments(start, end);
+        if block.expressions.len() == 0 && !has_comments {
+            self.out.push_str("{}");
+            return;
+        }
+        // Keep blocks with one expression on one line when they were written like that.
+        if block.expressions.len() == 1 && !has_comments && !self.d[start..end].contains('\n') {
+            let text = format!("{{ {} }}", self.flat(&block.expressions[0], tabs + 1));
+            if self.fits(&text) {
+                self.out.push_str(&text);
+                return;
+            }
+        }
+        self.out.push('{');
+        self.lines(Lines::Block(&block.expressions), start, end, tabs + 1);
+        self.newline(tabs);
+        self.out.push('}');
+    }
+
+    fn expr(&mut self, expr: &ast::Expression, tabs: u32) {
+        use ast::Expression as E;
+
+        match *expr {
+            E::Block(ref block) => self.block(block, tabs),
+            E::If(ref if_expr) => {
+                self.out.push_str("if ");
+                let cond = self.flat(&if_expr.cond, tabs);
+                self.out.push_str(&cond);
+                self.out.push(' ');
+                self.block(&if_expr.true_block, tabs);
+                for (cond, block) in if_expr.else_if_conds.iter()
+                    .zip(if_expr.else_if_blocks.iter()) {
+                    self.out.push_str(" else if ");
+                    let cond = self.flat(cond, tabs);
+                    self.out.push_str(&cond);
+                    self.out.push(' ');
+                    self.block(block, tabs);
+                }
+                if let Some(ref else_block) = if_expr.else_block {
+                    self.out.push_str(" else ");
+                    self.block(else_block, tabs);
+                }
+            }
+            E::For(ref f) => {
+                let mut buf: Vec<u8> = vec![];
+                write::write_for_head(&mut buf, &self.rt, f, tabs).unwrap();
+                self.out.push_str(&String::from_utf8(buf).unwrap());
+                self.block(&f.block, tabs);
+            }
+            E::ForN(ref f) => self.for_n("for", f, tabs),
+            E::Sum(ref f) => self.for_n("sum", f, tabs),
+            E::SumVec4(ref f) => self.for_n("sum_vec4", f, tabs),
+            E::Prod(ref f) => self.for_n("prod", f, tabs),
+            E::ProdVec4(ref f) => self.for_n("prod_vec4", f, tabs),
+            E::Min(ref f) => self.for_n("min", f, tabs),
+            E::Max(ref f) => self.for_n("max", f, tabs),
+            E::Sift(ref f) => self.for_n("sift", f, tabs),
+            E::Any(ref f) => self.for_n("any", f, tabs),
+            E::All(ref f) => self.for_n("all", f, tabs),
+            E::Link(ref link) => {
+                self.out.push_str("link ");
+                self.link(link, tabs);
+            }
+            E::LinkFor(ref f) => {
+                let mut buf: Vec<u8> = vec![];
+                let body = write::write_for_n_head(&mut buf, &self.rt, "link", f, tabs).unwrap();
+                self.out.push_str(&String::from_utf8(buf).unwrap());
+                match write::link_body(body) {
+                    Some(link) => self.link(link, tabs),
+                    None => self.block(&body.block, tabs),
+                }
+            }
+            E::Closure(ref closure) => {
+                let mut buf: Vec<u8> = vec![];
+                write::write_closure_head(&mut buf, closure).unwrap();
+                self.out.push_str(&String::from_utf8(buf).unwrap());
+                self.expr(&closure.expr, tabs);
+            }
+            E::Assign(ref assign) => {
+                let left = self.flat(&assign.left, tabs);
+                self.out.push_str(&left);
+                self.out.push(' ');
+                self.out.push_str(assign.op.symbol());
+                self.out.push(' ');
+                self.expr(&assign.right, tabs);
+            }
+            E::Return(ref expr) => {
+                self.out.push_str("return ");
+                self.expr(expr, tabs);
+            }
+            E::Go(ref go) => {
+                self.out.push_str("go ");
+                self.call(&go.call, tabs);
+            }
+            E::Call(ref call) => self.call(call, tabs),
+            E::Array(ref arr) => {
+                if !self.write_flat(expr, tabs) {
+                    self.out.push('[');
+                    let items = arr.items.iter().map(|item| (String::new(), item)).collect();
+                    self.list(items, "]", tabs);
+                }
+            }
+            E::Object(ref obj) => {
+                if !self.write_flat(expr, tabs) {
+                    self.out.push('{');
+                    let items = obj.key_values.iter().map(|&(ref key, ref val)| {
+                        let mut buf: Vec<u8> = vec![];
+                        write::write_key(&mut buf, key).unwrap();
+                        (String::from_utf8(buf).unwrap(), val)
+                    }).collect();
+                    self.list(items, "}", tabs);
+                }
+            }
+            _ => {
+                let text = self.flat(expr, tabs);
+                self.out.push_str(&text);
+            }
+        }
+    }
+
+    /// Writes an expression on one line if it fits.
+    ///
+    /// Expressions written on several lines in source are kept on several lines
+    /// when they contain braces, such that their blocks are not joined.
+    fn write_flat(&mut self, expr: &ast::Expression, tabs: u32) -> bool {
+        let range = expr.source_range();
+        let d = self.d;
+        let source = &d[range.offset..range.offset + range.length];
+        if source.contains('\n') && source.contains('{') { return false; }
+        let text = self.flat(expr, tabs);
+        if self.fits(&text) {
+            self.out.push_str(&text);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Writes the items of a link, on separate lines when they were written like that.
+    fn link(&mut self, link: &ast::Link, tabs: u32) {
+        let start = link.source_range.offset;
+        let end = start + link.source_range.length;
+        if !self.d[start..end].contains('\n') && !self.has_comments(start, end) {
+            let mut buf: Vec<u8> = vec![];
+            write::write_link_body(&mut buf, &self.rt, link, tabs).unwrap();
+            let text = String::from_utf8(buf).unwrap();
+            if self.fits(&text) {
+                self.out.push_str(&text);
+                return;
+            }
+        }
+        self.out.push('{');
+        self.lines(Lines::Block(&link.items), start, end, tabs + 1);
+        self.newline(tabs);
+        self.out.push('}');
+    }
+
+    fn for_n(&mut self, keyword: &str, for_n: &ast::ForN, tabs: u32) {
+        let mut buf: Vec<u8> = vec![];
+        let body = write::write_for_n_head(&mut buf, &self.rt, keyword, for_n, tabs).unwrap();
+        self.out.push_str(&String::from_utf8(buf).unwrap());
+        self.block(&body.block, tabs);
+    }
+
+    fn call(&mut self, call: &ast::Call, tabs: u32) {
+        let expr = ast::Expression::Call(call.clone());
+        if self.write_flat(&expr, tabs) { return; }
+        let mut buf: Vec<u8> = vec![];
+        let prefixes = write::write_call_head(&mut buf, &call.alias, &call.name, call.args.len())
+            .unwrap();
+        self.out.push_str(&String::from_utf8(buf).unwrap());
+        let items = prefixes.into_iter().zip(call.args.iter()).collect();
+        self.list(items, ")", tabs);
+    }
+
+    /// Writes the items of a call, array or object that does not fit on one line.
+    ///
+    /// When the last item ends with brackets, the other items are kept on the first line
+    /// and only the last item is broken into lines.
+    /// Otherwise, each item is written on its own line.
+    fn list(&mut self, items: Vec<(String, &ast::Expression)>, close: &str, tabs: u32) {
+        if let Some(&(ref prefix, last)) = items.last() {
+            if can_hug(last) {
+                let mut line = String::new();
+                for &(ref prefix, item) in &items[..items.len() - 1] {
+                    line.push_str(prefix);
+                    line.push_str(&self.flat(item, tabs));
+                    line.push_str(", ");
+                }
+                line.push_str(prefix);
+                let last_flat = self.flat(last, tabs);
+                line.push_str(last_flat.lines().next().unwrap_or(""));
+                if self.fits(&line) {
+                    let n = line.len() - last_flat.lines().next().unwrap_or("").len();
+                    self.out.push_str(&line[..n]);
+                    self.expr(last, tabs);
+                    self.out.push_str(close);
+                    return;
+                }
+            }
+        }
+        for (i, &(ref prefix, item)) in items.iter().enumerate() {
+            self.newline(tabs + 1);
+            self.out.push_str(prefix);
+            self.expr(item, tabs + 1);
+            if i + 1 < items.len() { self.out.push(','); }
+        }
+        self.newline(tabs);
+        self.out.push_str(close);
+    }
+}
+
+/// Returns `true` if an expression ends with brackets that can be broken into lines.
+fn can_hug(expr: &ast::Expression) -> bool {
+    use ast::Expression as E;
+
+    match *expr {
+        E::Block(_) | E::If(_) | E::For(_) | E::ForN(_) | E::Sum(_) | E::SumVec4(_) |
+        E::Prod(_) | E::ProdVec4(_) | E::Min(_) | E::Max(_) | E::Sift(_) |
+        E::Any(_) | E::All(_) | E::Link(_) | E::LinkFor(_) |
+        E::Array(_) | E::Object(_) | E::Call(_) => true,
+        E::Closure(ref closure) => can_hug(&closure.expr),
+        _ => false
+    }
+}
@@ -92,6 +92,7 @@ pub fn grab_expr(
                 },
                 ret: closure.ret.clone(),
                 source_range: closure.source_range.clone(),
+                code: closure.code.clone(),
             }))), Flow::Continue))
         }
         &E::Item(ref item) => match grab_item(level, rt, item, side, module) {
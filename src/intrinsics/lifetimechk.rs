@@ -67,6 +67,7 @@ fn min_ref(v: &Variable, min: &mut Option<usize>) {
         &Variable::Option(_) => {}
         &Variable::Result(_) => {}
         &Variable::Thread(_) => {}
+        &Variable::Channel(_) => {}
         &Variable::Array(ref arr) => {
             for v in arr.iter() {
                 min_ref(v, min);
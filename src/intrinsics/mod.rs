@@ -5,7 +5,7 @@ use rand::Rng;
 
 use runtime::{Flow, Runtime, Side};
 use ast;
-use prelude::{Lt, Prelude, Dfn};
+use prelude::{Capabilities, Lt, Prelude, Dfn};
 
 use FnIndex;
 use Error;
@@ -18,7 +18,7 @@ mod io;
 mod meta;
 mod data;
 mod lifetimechk;
-mod functions;
+pub mod functions;
 
 const X: usize = 0;
 const Y: usize = 1;
@@ -113,6 +113,13 @@ const META__SYNTAX_IN_STRING: usize = 89;
 const MODULE__IN_STRING_IMPORTS: usize = 90;
 const LOAD_STRING__URL: usize = 91;
 const PARSE_NUMBER: usize = 92;
+const CHANNEL: usize = 93;
+const SEND: usize = 94;
+const RECV: usize = 95;
+const TRY_RECV: usize = 96;
+const CLOSE: usize = 97;
+const ASSERT: usize = 98;
+const ASSERT_EQ: usize = 99;
 
 const TABLE: &'static [(usize, fn(
         &mut Runtime,
@@ -213,8 +220,41 @@ const TABLE: &'static [(usize, fn(
     (MODULE__IN_STRING_IMPORTS, module__in_string_imports),
     (LOAD_STRING__URL, load_string__url),
     (PARSE_NUMBER, parse_number),
+    (CHANNEL, channel),
+    (SEND, send),
+    (RECV, recv),
+    (TRY_RECV, try_recv),
+    (CLOSE, close),
+    (ASSERT, assert),
+    (ASSERT_EQ, assert_eq),
 ];
 
+/// Returns the capability that an intrinsic requires, if it is disabled.
+pub fn disabled_capability(index: usize, capabilities: &Capabilities) -> Option<&'static str> {
+    let required: Vec<(bool, &'static str)> = match index {
+        LOAD_STRING__FILE | LOAD_DATA__FILE | LOAD__META_FILE =>
+            vec![(capabilities.fs_read, "fs_read")],
+        SAVE__STRING_FILE | SAVE__DATA_FILE =>
+            vec![(capabilities.fs_write, "fs_write")],
+        LOAD_STRING__URL | LOAD__META_URL =>
+            vec![(capabilities.http, "http")],
+        DOWNLOAD__URL_FILE =>
+            vec![(capabilities.http, "http"), (capabilities.fs_write, "fs_write")],
+        READ_LINE | READ_NUMBER =>
+            vec![(capabilities.stdin, "stdin")],
+        JOIN__THREAD =>
+            vec![(capabilities.threads, "threads")],
+        SLEEP =>
+            vec![(capabilities.sleep, "sleep")],
+        LOAD | LOAD__SOURCE_IMPORTS =>
+            vec![(capabilities.load, "load"), (capabilities.fs_read, "fs_read")],
+        MODULE__IN_STRING_IMPORTS =>
+            vec![(capabilities.load, "load")],
+        _ => vec![]
+    };
+    required.into_iter().find(|&(enabled, _)| !enabled).map(|(_, name)| name)
+}
+
 pub fn standard(f: &mut Prelude) {
     let sarg = |f: &mut Prelude, name: &str, index: usize, ty: Type, ret: Type| {
         f.intrinsic(Arc::new(name.into()), index, Dfn {
@@ -444,6 +484,29 @@ pub fn standard(f: &mut Prelude) {
     });
     sarg(f, "load_string__url", LOAD_STRING__URL, Type::Text, Type::Result(Box::new(Type::Text)));
     sarg(f, "parse_number", PARSE_NUMBER, Type::Text, Type::Option(Box::new(Type::F64)));
+    f.intrinsic(Arc::new("channel".into()), CHANNEL, Dfn {
+        lts: vec![],
+        tys: vec![],
+        ret: Type::channel()
+    });
+    f.intrinsic(Arc::new("send(mut,_)".into()), SEND, Dfn {
+        lts: vec![Lt::Default; 2],
+        tys: vec![Type::channel(), Type::Any],
+        ret: Type::Bool
+    });
+    sarg(f, "recv", RECV, Type::channel(), Type::option());
+    sarg(f, "try_recv", TRY_RECV, Type::channel(), Type::option());
+    f.intrinsic(Arc::new("close(mut)".into()), CLOSE, Dfn {
+        lts: vec![Lt::Default],
+        tys: vec![Type::channel()],
+        ret: Type::Void
+    });
+    sarg(f, "assert", ASSERT, Type::Bool, Type::Void);
+    f.intrinsic(Arc::new("assert_eq".into()), ASSERT_EQ, Dfn {
+        lts: vec![Lt::Default; 2],
+        tys: vec![Type::Any; 2],
+        ret: Type::Void
+    });
 }
 
 pub fn call_standard(
@@ -663,16 +726,32 @@ fn explain_where(
     Ok(Some(Variable::F64(val, Some(wh))))
 }
 
+/// Prints a variable, or sends it to the debugger when one is attached.
+fn print_output(rt: &mut Runtime, x: &Variable, newline: bool) {
+    use write::{print_variable, write_variable, EscapeString};
+
+    match rt.debugger.take() {
+        Some(mut debugger) => {
+            let mut w: Vec<u8> = vec![];
+            write_variable(&mut w, rt, x, EscapeString::None, 0).unwrap();
+            if newline { w.push(b'\n'); }
+            debugger.output(&String::from_utf8(w).unwrap());
+            rt.debugger = Some(debugger);
+        }
+        None => {
+            print_variable(rt, x, EscapeString::None);
+            if newline { println!(""); }
+        }
+    }
+}
+
 fn println(
     rt: &mut Runtime,
     _call: &ast::Call,
     _module: &Arc<Module>,
 ) -> Result<Option<Variable>, String> {
-    use write::{print_variable, EscapeString};
-
     let x = rt.stack.pop().expect(TINVOTS);
-    print_variable(rt, &x, EscapeString::None);
-    println!("");
+    print_output(rt, &x, true);
     Ok(None)
 }
 
@@ -681,10 +760,8 @@ fn print(
     _call: &ast::Call,
     _module: &Arc<Module>,
 ) -> Result<Option<Variable>, String> {
-    use write::{print_variable, EscapeString};
-
     let x = rt.stack.pop().expect(TINVOTS);
-    print_variable(rt, &x, EscapeString::None);
+    print_output(rt, &x, false);
     Ok(None)
 }
 
@@ -966,6 +1043,10 @@ fn push_ref(
                 &format!("{}\nExpected reference to array",
                     rt.stack_trace()), rt));
         }
+        let bytes = ::std::mem::size_of::<Variable>();
+        if let Some(msg) = rt.check_alloc(&rt.stack[ind], bytes) {
+            return Err(rt.limit_error(call.source_range, msg, module));
+        }
     } else {
         return Err(module.error(call.args[0].source_range(),
             &format!("{}\nExpected reference to array",
@@ -995,6 +1076,10 @@ fn push(
                 &format!("{}\nExpected reference to array",
                     rt.stack_trace()), rt));
         }
+        let bytes = ::std::mem::size_of::<Variable>();
+        if let Some(msg) = rt.check_alloc(&rt.stack[ind], bytes) {
+            return Err(rt.limit_error(call.source_range, msg, module));
+        }
     } else {
         return Err(module.error(call.args[0].source_range(),
             &format!("{}\nExpected reference to array",
@@ -1208,6 +1293,117 @@ fn parse_number(
     })))
 }
 
+fn channel(
+    _rt: &mut Runtime,
+    _call: &ast::Call,
+    _module: &Arc<Module>,
+) -> Result<Option<Variable>, String> {
+    use Channel;
+
+    Ok(Some(Variable::Channel(Channel::new())))
+}
+
+fn send(
+    rt: &mut Runtime,
+    call: &ast::Call,
+    module: &Arc<Module>,
+) -> Result<Option<Variable>, String> {
+    let item = rt.stack.pop().expect(TINVOTS);
+    let item = rt.resolve(&item).deep_clone(&rt.stack);
+    let ch = rt.stack.pop().expect(TINVOTS);
+    let sent = match rt.resolve(&ch) {
+        &Variable::Channel(ref ch) => ch.send(item),
+        x => return Err(module.error(call.args[0].source_range(),
+                        &rt.expected(x, "channel"), rt))
+    };
+    Ok(Some(Variable::bool(sent)))
+}
+
+fn recv(
+    rt: &mut Runtime,
+    call: &ast::Call,
+    module: &Arc<Module>,
+) -> Result<Option<Variable>, String> {
+    let ch = rt.stack.pop().expect(TINVOTS);
+    let ch = match rt.resolve(&ch) {
+        &Variable::Channel(ref ch) => ch.clone(),
+        x => return Err(module.error(call.args[0].source_range(),
+                        &rt.expected(x, "channel"), rt))
+    };
+    Ok(Some(Variable::Option(ch.recv().map(Box::new))))
+}
+
+fn try_recv(
+    rt: &mut Runtime,
+    call: &ast::Call,
+    module: &Arc<Module>,
+) -> Result<Option<Variable>, String> {
+    let ch = rt.stack.pop().expect(TINVOTS);
+    let v = match rt.resolve(&ch) {
+        &Variable::Channel(ref ch) => ch.try_recv(),
+        x => return Err(module.error(call.args[0].source_range(),
+                        &rt.expected(x, "channel"), rt))
+    };
+    Ok(Some(Variable::Option(v.map(Box::new))))
+}
+
+fn close(
+    rt: &mut Runtime,
+    call: &ast::Call,
+    module: &Arc<Module>,
+) -> Result<Option<Variable>, String> {
+    let ch = rt.stack.pop().expect(TINVOTS);
+    match rt.resolve(&ch) {
+        &Variable::Channel(ref ch) => ch.close(),
+        x => return Err(module.error(call.args[0].source_range(),
+                        &rt.expected(x, "channel"), rt))
+    };
+    Ok(None)
+}
+
+fn assert(
+    rt: &mut Runtime,
+    call: &ast::Call,
+    module: &Arc<Module>,
+) -> Result<Option<Variable>, String> {
+    let v = rt.stack.pop().expect(TINVOTS);
+    match rt.resolve(&v) {
+        &Variable::Bool(true, _) => {}
+        &Variable::Bool(false, _) => {
+            return Err(module.error(call.args[0].source_range(),
+                &format!("{}\nAssertion failed", rt.stack_trace()), rt));
+        }
+        x => return Err(module.error(call.args[0].source_range(),
+                        &rt.expected(x, "bool"), rt))
+    }
+    Ok(None)
+}
+
+fn assert_eq(
+    rt: &mut Runtime,
+    call: &ast::Call,
+    module: &Arc<Module>,
+) -> Result<Option<Variable>, String> {
+    use std::io::Write;
+    use write::{write_variable, EscapeString};
+
+    let b = rt.stack.pop().expect(TINVOTS);
+    let a = rt.stack.pop().expect(TINVOTS);
+    let a = rt.resolve(&a).deep_clone(&rt.stack);
+    let b = rt.resolve(&b).deep_clone(&rt.stack);
+    if a != b {
+        let mut w: Vec<u8> = vec![];
+        write!(&mut w, "{}\nAssertion failed: `left == right`\n  left: ",
+               rt.stack_trace()).unwrap();
+        write_variable(&mut w, rt, &a, EscapeString::Json, 0).unwrap();
+        write!(&mut w, "\n right: ").unwrap();
+        write_variable(&mut w, rt, &b, EscapeString::Json, 0).unwrap();
+        return Err(module.error(call.source_range,
+                                &String::from_utf8(w).unwrap(), rt));
+    }
+    Ok(None)
+}
+
 fn trim(
     rt: &mut Runtime,
     call: &ast::Call,
@@ -1387,6 +1583,7 @@ fn _typeof(
         &Variable::Option(_) => rt.option_type.clone(),
         &Variable::Result(_) => rt.result_type.clone(),
         &Variable::Thread(_) => rt.thread_type.clone(),
+        &Variable::Channel(_) => rt.channel_type.clone(),
         &Variable::Closure(_, _) => rt.closure_type.clone(),
     }))
 }
@@ -1422,15 +1619,17 @@ fn load(
     let v = match rt.resolve(&v) {
         &Variable::Text(ref text) => {
             let mut m = Module::new_intrinsics(module.intrinsics.clone());
+            m.capabilities = module.capabilities;
             for f in &module.ext_prelude {
-                m.add(f.name.clone(), f.f, f.p.clone());
+                m.ext_prelude.push(f.clone());
             }
             if let Err(err) = load(text, &mut m) {
                 Variable::Result(Err(Box::new(Error {
                     message: Variable::Text(Arc::new(
                         format!("{}\n{}\n{}", rt.stack_trace(), err,
-                            module.error(call.args[0].source_range(),
-                            "When attempting to load module:", rt)))),
+                            module.error_fnindex(call.args[0].source_range(),
+                            "When attempting to load module:",
+                            rt.call_stack.last().unwrap().index)))),
                     trace: vec![]
                 })))
             } else {
@@ -1454,8 +1653,9 @@ fn load__source_imports(
     let modules = rt.stack.pop().expect(TINVOTS);
     let source = rt.stack.pop().expect(TINVOTS);
     let mut new_module = Module::new_intrinsics(module.intrinsics.clone());
+    new_module.capabilities = module.capabilities;
     for f in &module.ext_prelude {
-        new_module.add(f.name.clone(), f.f, f.p.clone());
+        new_module.ext_prelude.push(f.clone());
     }
     match rt.resolve(&modules) {
         &Variable::Array(ref array) => {
@@ -1469,7 +1669,7 @@ fn load__source_imports(
                                     let has_external = new_module.ext_prelude.iter()
                                         .any(|a| a.name == f.name);
                                     if !has_external {
-                                        new_module.add(f.name.clone(), f.f, f.p.clone());
+                                        new_module.ext_prelude.push(f.clone());
                                     }
                                 }
                                 // Register loaded functions from imports.
@@ -1498,8 +1698,9 @@ fn load__source_imports(
                 Variable::Result(Err(Box::new(Error {
                     message: Variable::Text(Arc::new(
                         format!("{}\n{}\n{}", rt.stack_trace(), err,
-                            module.error(call.args[0].source_range(),
-                            "When attempting to load module:", rt)))),
+                            module.error_fnindex(call.args[0].source_range(),
+                            "When attempting to load module:",
+                            rt.call_stack.last().unwrap().index)))),
                     trace: vec![]
                 })))
             } else {
@@ -1535,8 +1736,9 @@ fn module__in_string_imports(
                 &rt.expected(x, "str"), rt))
     };
     let mut new_module = Module::new_intrinsics(module.intrinsics.clone());
+    new_module.capabilities = module.capabilities;
     for f in &module.ext_prelude {
-        new_module.add(f.name.clone(), f.f, f.p.clone());
+        new_module.ext_prelude.push(f.clone());
     }
     match rt.resolve(&modules) {
         &Variable::Array(ref array) => {
@@ -1550,7 +1752,7 @@ fn module__in_string_imports(
                                     let has_external = new_module.ext_prelude.iter()
                                         .any(|a| a.name == f.name);
                                     if !has_external {
-                                        new_module.add(f.name.clone(), f.f, f.p.clone());
+                                        new_module.ext_prelude.push(f.clone());
                                     }
                                 }
                                 // Register loaded functions from imports.
@@ -1577,8 +1779,9 @@ fn module__in_string_imports(
             Variable::Result(Err(Box::new(Error {
                 message: Variable::Text(Arc::new(
                     format!("{}\n{}\n{}", rt.stack_trace(), err,
-                        module.error(call.args[0].source_range(),
-                        "When attempting to load module:", rt)))),
+                        module.error_fnindex(call.args[0].source_range(),
+                        "When attempting to load module:",
+                        rt.call_stack.last().unwrap().index)))),
                 trace: vec![]
             })))
         } else {
@@ -1618,7 +1821,6 @@ fn _call(
     match obj.lock().unwrap()
         .downcast_ref::<Arc<Module>>() {
         Some(m) => {
-            use std::cell::Cell;
 
             let f_index = m.find_function(&fn_name, 0);
             match f_index {
@@ -1648,7 +1850,7 @@ fn _call(
             let call = ast::Call {
                 alias: None,
                 name: fn_name.clone(),
-                f_index: Cell::new(f_index),
+                f_index: ast::FnIndexCell::new(f_index),
                 args: args.iter().map(|arg|
                     ast::Expression::Variable(
                         call.source_range, arg.clone())).collect(),
@@ -1695,7 +1897,6 @@ fn call_ret(
     let v = match obj.lock().unwrap()
         .downcast_ref::<Arc<Module>>() {
         Some(m) => {
-            use std::cell::Cell;
 
             let f_index = m.find_function(&fn_name, 0);
             match f_index {
@@ -1725,7 +1926,7 @@ fn call_ret(
             let call = ast::Call {
                 alias: None,
                 name: fn_name.clone(),
-                f_index: Cell::new(f_index),
+                f_index: ast::FnIndexCell::new(f_index),
                 args: args.iter().map(|arg|
                     ast::Expression::Variable(
                         call.source_range, arg.clone())).collect(),
@@ -11,13 +11,13 @@ extern crate lazy_static;
 
 use std::any::Any;
 use std::fmt;
-use std::thread::JoinHandle;
 use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
 use range::Range;
 use piston_meta::MetaData;
 
 pub mod ast;
+pub mod bytecode;
 pub mod runtime;
 pub mod lifetime;
 pub mod intrinsics;
@@ -28,14 +28,30 @@ pub mod link;
 pub mod macros;
 pub mod vec4;
 pub mod write;
+pub mod format;
+pub mod lint;
+pub mod testing;
+pub mod diagnostic;
+pub mod error;
+pub mod cache;
+pub mod watcher;
+pub mod pool;
+pub mod channel;
+pub mod profiler;
 
 mod grab;
 
-pub use runtime::Runtime;
-pub use prelude::{Lt, Prelude, Dfn};
+pub use runtime::{CancelHandle, Debugger, Runtime, RuntimeLimits};
+pub use prelude::{Capabilities, Lt, Prelude, Dfn};
 pub use ty::Type;
 pub use link::Link;
 pub use vec4::Vec4;
+pub use diagnostic::{Diagnostic, Severity};
+pub use cache::load_cached;
+pub use watcher::ModuleWatcher;
+pub use pool::TaskHandle;
+pub use channel::Channel;
+pub use profiler::Profiler;
 
 /// A common error message when there is no value on the stack.
 pub const TINVOTS: &'static str = "There is no value on the stack";
@@ -54,11 +70,11 @@ pub struct Error {
 
 #[derive(Clone)]
 pub struct Thread {
-    pub handle: Option<Arc<Mutex<JoinHandle<Result<Variable, String>>>>>,
+    pub handle: Option<Arc<Mutex<TaskHandle>>>,
 }
 
 impl Thread {
-    pub fn new(handle: JoinHandle<Result<Variable, String>>) -> Thread {
+    pub fn new(handle: TaskHandle) -> Thread {
         Thread {
             handle: Some(Arc::new(Mutex::new(handle)))
         }
@@ -69,7 +85,7 @@ impl Thread {
     pub fn invalidate_handle(
         rt: &mut Runtime,
         var: Variable
-    ) -> Result<JoinHandle<Result<Variable, String>>, String> {
+    ) -> Result<TaskHandle, String> {
         use std::error::Error;
 
         let thread = match var {
@@ -134,6 +150,7 @@ pub enum Variable {
     Option(Option<Box<Variable>>),
     Result(Result<Box<Variable>, Box<Error>>),
     Thread(Thread),
+    Channel(Channel),
     // Stores closure AST, relative function index.
     Closure(Arc<ast::Closure>, Box<ClosureEnvironment>),
 }
@@ -189,6 +206,7 @@ impl Variable {
             // `err(x)` always uses deep clone, so it does not contain references.
             Result(Err(ref err)) => Result(Err(err.clone())),
             Thread(_) => self.clone(),
+            Channel(_) => self.clone(),
             Closure(_, _) => self.clone(),
         }
     }
@@ -203,6 +221,8 @@ impl PartialEq for Variable {
             (&Variable::Text(ref a), &Variable::Text(ref b)) => a == b,
             (&Variable::Object(ref a), &Variable::Object(ref b)) => a == b,
             (&Variable::Array(ref a), &Variable::Array(ref b)) => a == b,
+            (&Variable::Vec4(a), &Variable::Vec4(b)) => a == b,
+            (&Variable::Option(ref a), &Variable::Option(ref b)) => a == b,
             (&Variable::Ref(_), _) => false,
             (&Variable::UnsafeRef(_), _) => false,
             (&Variable::RustObject(_), _) => false,
@@ -211,7 +231,7 @@ impl PartialEq for Variable {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub enum FnIndex {
     None,
     Intrinsic(usize),
@@ -222,12 +242,21 @@ pub enum FnIndex {
 }
 
 /// Used to store direct reference to external function.
-#[derive(Copy)]
-pub struct FnExternalRef(pub fn(&mut Runtime) -> Result<(), String>);
+#[derive(Clone)]
+pub enum FnExternalRef {
+    /// A function pointer.
+    Fn(fn(&mut Runtime) -> Result<(), String>),
+    /// A closure, which can capture state from the host.
+    Closure(Arc<Fn(&mut Runtime) -> Result<(), String> + Send + Sync>),
+}
 
-impl Clone for FnExternalRef {
-    fn clone(&self) -> FnExternalRef {
-        *self
+impl FnExternalRef {
+    /// Calls the external function.
+    pub fn call(&self, rt: &mut Runtime) -> Result<(), String> {
+        match *self {
+            FnExternalRef::Fn(f) => f(rt),
+            FnExternalRef::Closure(ref f) => f(rt),
+        }
     }
 }
 
@@ -237,27 +266,19 @@ impl fmt::Debug for FnExternalRef {
     }
 }
 
+#[derive(Clone)]
 pub struct FnExternal {
     pub name: Arc<String>,
-    pub f: fn(&mut Runtime) -> Result<(), String>,
+    pub f: FnExternalRef,
     pub p: Dfn,
 }
 
-impl Clone for FnExternal {
-    fn clone(&self) -> FnExternal {
-        FnExternal {
-            name: self.name.clone(),
-            f: self.f,
-            p: self.p.clone(),
-        }
-    }
-}
-
 #[derive(Clone)]
 pub struct Module {
     pub functions: Vec<ast::Function>,
     pub ext_prelude: Vec<FnExternal>,
     pub intrinsics: Arc<HashMap<Arc<String>, usize>>,
+    pub capabilities: Capabilities,
 }
 
 impl Module {
@@ -270,9 +291,22 @@ impl Module {
             functions: vec![],
             ext_prelude: vec![],
             intrinsics: intrinsics,
+            capabilities: Capabilities::all(),
         }
     }
 
+    /// Creates a new module without intrinsics of disabled capabilities.
+    pub fn with_capabilities(capabilities: Capabilities) -> Module {
+        let prelude = Prelude::with_capabilities(capabilities);
+        let intrinsics = prelude.functions.iter()
+            .filter(|&(_, &ind)| prelude.disabled_capability(ind).is_none())
+            .map(|(name, &ind)| (name.clone(), ind))
+            .collect();
+        let mut module = Module::new_intrinsics(Arc::new(intrinsics));
+        module.capabilities = capabilities;
+        module
+    }
+
     pub fn register(&mut self, function: ast::Function) {
         self.functions.push(function);
     }
@@ -287,9 +321,9 @@ impl Module {
         for f in self.ext_prelude.iter().rev() {
             if &f.name == name {
                 return if f.p.returns() {
-                    FnIndex::ExternalReturn(FnExternalRef(f.f))
+                    FnIndex::ExternalReturn(f.f.clone())
                 } else {
-                    FnIndex::ExternalVoid(FnExternalRef(f.f))
+                    FnIndex::ExternalVoid(f.f.clone())
                 };
             }
         }
@@ -299,7 +333,20 @@ impl Module {
         }
     }
 
+    /// Looks up a loaded function and checks its signature against Rust types.
+    ///
+    /// The arguments are given as a tuple, e.g. `module.get_fn::<(f64, String), Vec4>("foo")`.
+    /// Use `()` as return type for functions that do not return a value.
+    pub fn get_fn<A, R>(&self, name: &str) -> Result<embed::FnHandle<A, R>, error::Error>
+        where A: embed::FnArgs, R: embed::FnRet
+    {
+        embed::FnHandle::new(self, name)
+    }
+
+    /// Creates an error message in the function being called,
+    /// and records the location of the error in the runtime.
     pub fn error(&self, range: Range, msg: &str, rt: &Runtime) -> String {
+        rt.record_error(error::ErrorKind::Runtime, range, self);
         self.error_fnindex(range, msg, rt.call_stack.last().unwrap().index)
     }
 
@@ -327,14 +374,31 @@ impl Module {
     ) {
         self.ext_prelude.push(FnExternal {
             name: name.clone(),
-            f: f,
+            f: FnExternalRef::Fn(f),
+            p: prelude_function,
+        });
+    }
+
+    /// Adds a new extended prelude function that can capture state.
+    ///
+    /// The closure is shared with modules created by `load` or `load_source_imports`,
+    /// and with `go` threads.
+    pub fn add_closure(
+        &mut self,
+        name: Arc<String>,
+        f: Arc<Fn(&mut Runtime) -> Result<(), String> + Send + Sync>,
+        prelude_function: Dfn
+    ) {
+        self.ext_prelude.push(FnExternal {
+            name: name.clone(),
+            f: FnExternalRef::Closure(f),
             p: prelude_function,
         });
     }
 }
 
 /// Runs a program using a source file.
-pub fn run(source: &str) -> Result<(), String> {
+pub fn run(source: &str) -> Result<(), error::Error> {
     let mut module = Module::new_intrinsics(Arc::new(Prelude::new_intrinsics().functions));
     try!(load(source, &mut module));
     let mut runtime = runtime::Runtime::new();
@@ -343,7 +407,7 @@ pub fn run(source: &str) -> Result<(), String> {
 }
 
 /// Runs a program from a string.
-pub fn run_str(source: &str, d: Arc<String>) -> Result<(), String> {
+pub fn run_str(source: &str, d: Arc<String>) -> Result<(), error::Error> {
     let mut module = Module::new_intrinsics(Arc::new(Prelude::new_intrinsics().functions));
     try!(load_str(source, d, &mut module));
     let mut runtime = runtime::Runtime::new();
@@ -352,25 +416,29 @@ pub fn run_str(source: &str, d: Arc<String>) -> Result<(), String> {
 }
 
 /// Loads source from file.
-pub fn load(source: &str, module: &mut Module) -> Result<(), String> {
+pub fn load(source: &str, module: &mut Module) -> Result<(), error::Error> {
+    let data = try!(read_source(source));
+    load_str(source, data, module)
+}
+
+/// Reads a source file.
+fn read_source(source: &str) -> Result<Arc<String>, error::Error> {
     use std::fs::File;
     use std::io::Read;
+    use error::{Error, ErrorKind};
 
-    let mut data_file = try!(File::open(source).map_err(|err|
-        format!("Could not open `{}`, {}", source, err)));
+    let mut data_file = try!(File::open(source).map_err(|err| Error {
+        file: Some(Arc::new(source.into())),
+        ..Error::new(ErrorKind::Io, format!("Could not open `{}`, {}", source, err))
+    }));
     let mut data = Arc::new(String::new());
     data_file.read_to_string(Arc::make_mut(&mut data)).unwrap();
-    load_str(source, data, module)
+    Ok(data)
 }
 
-/// Loads a source from string.
-///
-/// - source - The name of source file
-/// - d - The data of source file
-/// - module - The module to load the source
-pub fn load_str(source: &str, d: Arc<String>, module: &mut Module) -> Result<(), String> {
-    use std::thread;
-    use piston_meta::{parse_errstr, syntax_errstr, Syntax};
+/// Returns the syntax rules used to parse Dyon source.
+pub fn syntax_rules() -> Result<&'static piston_meta::Syntax, String> {
+    use piston_meta::{syntax_errstr, Syntax};
 
     lazy_static! {
         static ref SYNTAX_RULES: Result<Syntax, String> = {
@@ -379,13 +447,70 @@ pub fn load_str(source: &str, d: Arc<String>, module: &mut Module) -> Result<(),
         };
     }
 
-    let syntax_rules = try!(SYNTAX_RULES.as_ref()
-        .map_err(|err| err.clone()));
+    SYNTAX_RULES.as_ref().map_err(|err| err.clone())
+}
+
+/// Loads a source from string.
+///
+/// - source - The name of source file
+/// - d - The data of source file
+/// - module - The module to load the source
+///
+/// Reports the kind and location of the first error.
+/// The message contains all errors.
+pub fn load_str(source: &str, d: Arc<String>, module: &mut Module) -> Result<(), error::Error> {
+    load_str_diagnostics(source, d.clone(), module)
+        .map_err(|diagnostics| diagnostics_error(&diagnostics, &d))
+}
+
+/// Creates an error from the first diagnostic, with all diagnostics in the message.
+fn diagnostics_error(diagnostics: &[Diagnostic], d: &str) -> error::Error {
+    error::Error {
+        kind: diagnostics[0].kind,
+        file: Some(diagnostics[0].file.clone()),
+        range: Some(diagnostics[0].range),
+        trace: vec![],
+        message: diagnostic::render(diagnostics, d),
+    }
+}
+
+/// Loads a source from string, reporting all independent errors.
+///
+/// - source - The name of source file
+/// - d - The data of source file
+/// - module - The module to load the source
+///
+/// The lifetime checker reports all errors it finds while linking and checking lifetimes,
+/// but the type checker and the conversion to AST stop at their first error,
+/// since the rest of the source depends on it.
+pub fn load_str_diagnostics(
+    source: &str,
+    d: Arc<String>,
+    module: &mut Module
+) -> Result<(), Vec<Diagnostic>> {
+    load_str_checked(source, d, module).map(|_| ())
+}
+
+/// Loads a source from string.
+///
+/// Returns the meta data and refined return types of the checked source.
+fn load_str_checked(
+    source: &str,
+    d: Arc<String>,
+    module: &mut Module
+) -> Result<(Vec<Range<MetaData>>, HashMap<Arc<String>, Type>), Vec<Diagnostic>> {
+    use std::thread;
+    use error::ErrorKind;
+
+    let file = Arc::new(source.to_string());
+    let syntax_rules = try!(syntax_rules().map_err(|err|
+        vec![Diagnostic::error(ErrorKind::Syntax, file.clone(), Range::empty(0), err)]));
 
     let mut data = vec![];
-    try!(parse_errstr(syntax_rules, &d, &mut data).map_err(
-        |err| format!("In `{}:`\n{}", source, err)
-    ));
+    try!(piston_meta::parse(syntax_rules, &d, &mut data).map_err(|err| {
+        let (range, err) = err.decouple();
+        vec![Diagnostic::error(ErrorKind::Syntax, file.clone(), range, format!("{}", err))]
+    }));
 
     let check_data = data.clone();
     let prelude = Arc::new(Prelude::from_module(module));
@@ -393,39 +518,42 @@ pub fn load_str(source: &str, d: Arc<String>, module: &mut Module) -> Result<(),
     // Do lifetime checking in parallel directly on meta data.
     let handle = thread::spawn(move || {
         let check_data = check_data;
-        lifetime::check(&check_data, &prelude)
+        let mut nodes = vec![];
+        lifetime::check_all(&mut nodes, &check_data, &prelude)
     });
 
     // Convert to AST.
     let mut ignored = vec![];
-    let conv_res = ast::convert(Arc::new(source.into()), d.clone(), &data, &mut ignored, module);
+    let conv_res = ast::convert(file.clone(), d.clone(), &data, &mut ignored, module);
 
     // Check that lifetime checking succeeded.
+    let mut diagnostics = vec![];
+    let mut refined_rets = HashMap::new();
     match handle.join().unwrap() {
-        Ok(refined_rets) => {
-            for (name, ty) in &refined_rets {
-                if let FnIndex::Loaded(f_index) = module.find_function(name, 0) {
-                    let f = &mut module.functions[f_index as usize];
-                    f.ret = ty.clone();
-                }
-            }
+        Ok(rets) => {
+            refine_returns(module, &rets);
+            refined_rets = rets;
         }
-        Err(err_msg) => {
-            use std::io::Write;
-            use piston_meta::ParseErrorHandler;
-
-            let (range, msg) = err_msg.decouple();
-
-            let mut buf: Vec<u8> = vec![];
-            writeln!(&mut buf, "In `{}`:\n", source).unwrap();
-            ParseErrorHandler::new(&d)
-                .write_msg(&mut buf, range, &msg)
-                .unwrap();
-            return Err(String::from_utf8(buf).unwrap())
+        Err((kind, errors)) => {
+            for err in errors {
+                let (range, msg) = err.decouple();
+                diagnostics.push(Diagnostic::error(kind, file.clone(), range, msg));
+            }
         }
     }
 
-    check_ignored_meta_data(&conv_res, source, &d, &data, &ignored)
+    diagnostics.extend(conversion_diagnostics(&file, &conv_res, &data, &ignored));
+    if diagnostics.len() > 0 { Err(diagnostics) } else { Ok((data, refined_rets)) }
+}
+
+/// Sets the return types refined by the type checker.
+fn refine_returns(module: &mut Module, refined_rets: &HashMap<Arc<String>, Type>) {
+    for (name, ty) in refined_rets {
+        if let FnIndex::Loaded(f_index) = module.find_function(name, 0) {
+            let f = &mut module.functions[f_index as usize];
+            f.ret = ty.clone();
+        }
+    }
 }
 
 /// Loads a source from meta data.
@@ -438,49 +566,47 @@ pub fn load_meta(
 ) -> Result<(), String> {
     // Convert to AST.
     let mut ignored = vec![];
-    let conv_res = ast::convert(Arc::new(source.into()), d.clone(), &data, &mut ignored, module);
+    let file = Arc::new(source.to_string());
+    let conv_res = ast::convert(file.clone(), d.clone(), &data, &mut ignored, module);
 
-    check_ignored_meta_data(&conv_res, source, &d, data, &ignored)
+    let diagnostics = conversion_diagnostics(&file, &conv_res, data, &ignored);
+    if diagnostics.len() > 0 {
+        Err(diagnostic::render(&diagnostics, &d))
+    } else {
+        Ok(())
+    }
 }
 
-fn check_ignored_meta_data(
-    conv_res: &Result<(), ()>,
-    source: &str,
-    d: &Arc<String>,
+/// Reports meta data that was ignored or could not be converted to AST.
+fn conversion_diagnostics(
+    file: &Arc<String>,
+    conv_res: &Result<(), Range>,
     data: &[Range<MetaData>],
     ignored: &[Range],
-) -> Result<(), String> {
+) -> Vec<Diagnostic> {
     use piston_meta::json;
+    use error::ErrorKind;
 
-    if ignored.len() > 0 || conv_res.is_err() {
-        use std::io::Write;
-        use piston_meta::ParseErrorHandler;
-
+    let mut diagnostics = vec![];
+    for range in ignored {
         let mut buf: Vec<u8> = vec![];
-        if ignored.len() > 0 {
-            writeln!(&mut buf, "Some meta data was ignored in the syntax").unwrap();
-            writeln!(&mut buf, "START IGNORED").unwrap();
-            json::write(&mut buf, &data[ignored[0].iter()]).unwrap();
-            writeln!(&mut buf, "END IGNORED").unwrap();
-
-            writeln!(&mut buf, "In `{}`:\n", source).unwrap();
-            ParseErrorHandler::new(&d)
-                .write_msg(&mut buf,
-                           data[ignored[0].iter()][0].range(),
-                           "Could not understand this")
-                .unwrap();
-        }
-        if let &Err(()) = conv_res {
-            writeln!(&mut buf, "Conversion error").unwrap();
-        }
-        return Err(String::from_utf8(buf).unwrap());
-    }
-
-    Ok(())
+        json::write(&mut buf, &data[range.iter()]).unwrap();
+        let mut d = Diagnostic::error(ErrorKind::Syntax, file.clone(),
+                                      data[range.iter()][0].range(),
+                                      "Could not understand this".into());
+        d.notes.push(format!("Some meta data was ignored in the syntax\n{}",
+                             String::from_utf8(buf).unwrap()));
+        diagnostics.push(d);
+    }
+    if let &Err(range) = conv_res {
+        diagnostics.push(Diagnostic::error(ErrorKind::Syntax, file.clone(), range,
+                                           "Conversion error".into()));
+    }
+    diagnostics
 }
 
 /// Reports and error to standard output.
-pub fn error(res: Result<(), String>) -> bool {
+pub fn error<E: fmt::Display>(res: Result<(), E>) -> bool {
     match res {
         Err(err) => {
             println!("");
@@ -520,6 +646,7 @@ mod tests {
         Option(Option<Box<Variable>>),
         Result(Result<Box<Variable>, Box<Error>>),
         Thread(Thread),
+        Channel(Channel),
         */
 
         println!("Link {}", size_of::<Box<Link>>());
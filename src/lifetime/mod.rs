@@ -10,15 +10,37 @@ use self::node::{convert_meta_data, Node};
 use self::lt::{arg_lifetime, compare_lifetimes, Lifetime};
 
 use prelude::{Lt, Prelude};
+use error::ErrorKind;
 use ast::{AssignOp, UseLookup};
 
 use Type;
 
-mod kind;
+pub mod kind;
 pub mod node;
 mod lt;
 mod typecheck;
 
+/// Resolves `use` imports against the prelude.
+pub fn build_use_lookup(
+    nodes: &[Node],
+    data: &[Range<MetaData>],
+    prelude: &Prelude
+) -> UseLookup {
+    for node in nodes {
+        if node.kind == Kind::Uses {
+            use piston_meta::bootstrap::Convert;
+            use ast::Uses;
+
+            let convert = Convert::new(&data[node.start..node.end]);
+            if let Ok((_, val)) = Uses::from_meta_data(convert, &mut vec![]) {
+                return UseLookup::from_uses_prelude(&val, prelude);
+            }
+            break;
+        }
+    }
+    UseLookup::new()
+}
+
 /// Checks lifetime constraints and does type checking.
 /// Returns refined return types of functions to put in AST.
 pub fn check(
@@ -26,7 +48,38 @@ pub fn check(
     prelude: &Prelude
 ) -> Result<HashMap<Arc<String>, Type>, Range<String>> {
     let mut nodes: Vec<Node> = vec![];
-    try!(convert_meta_data(&mut nodes, data));
+    check_nodes(&mut nodes, data, prelude)
+}
+
+/// Same as `check`, but keeps the node tree for tools.
+///
+/// When checking fails, the nodes contain the information gathered before the error.
+pub fn check_nodes(
+    nodes: &mut Vec<Node>,
+    data: &[Range<MetaData>],
+    prelude: &Prelude
+) -> Result<HashMap<Arc<String>, Type>, Range<String>> {
+    check_all(nodes, data, prelude).map_err(|(_, mut errors)| errors.swap_remove(0))
+}
+
+/// Same as `check_nodes`, but reports all independent errors.
+///
+/// Errors are collected while linking items and calls to declarations,
+/// and while checking lifetimes and mutability.
+/// Each group stops the check when it has errors, since the next group depends on it.
+/// The first error is the same as the one returned by `check`.
+///
+/// The type checker runs last and stops at its first error,
+/// since the types of the other nodes depend on it.
+///
+/// Returns `ErrorKind::Type` when the type checker fails, otherwise `ErrorKind::Lifetime`.
+pub fn check_all(
+    nodes: &mut Vec<Node>,
+    data: &[Range<MetaData>],
+    prelude: &Prelude
+) -> Result<HashMap<Arc<String>, Type>, (ErrorKind, Vec<Range<String>>)> {
+    let mut errors: Vec<Range<String>> = vec![];
+    try!(convert_meta_data(nodes, data).map_err(|err| (ErrorKind::Lifetime, vec![err])));
 
     // Add mutability information to function names.
     for i in 0..nodes.len() {
@@ -167,7 +220,7 @@ pub fn check(
         .collect();
 
     // Link items to their declaration.
-    for &i in &items {
+    'items: for &i in &items {
         // When `return` is used as variable one does not need to link.
         if nodes[i].name().map(|n| &**n == "return") == Some(true) {
             continue;
@@ -205,10 +258,11 @@ pub fn check(
                 if nodes[item].name() == nodes[i].name() {
                     if nodes[item].item_ids() { continue; }
                     if grab > 0 {
-                        return Err(nodes[i].source.wrap(
+                        errors.push(nodes[i].source.wrap(
                             format!("Grabbed `{}` has same name as variable.\n\
                             Perhaps the grab level is set too high?",
                             nodes[i].name().expect("Expected name"))));
+                        continue 'items;
                     }
                     it = Some(item);
                     break 'search;
@@ -238,9 +292,10 @@ pub fn check(
                             if Some(true) == arg.name().map(|n|
                                     &**n == &**nodes[i].name().unwrap()) {
                                 if grab > 0 {
-                                    return Err(nodes[i].source.wrap(
+                                    errors.push(nodes[i].source.wrap(
                                         format!("Grabbed `{}` has same name as closure argument",
                                         nodes[i].name().expect("Expected name"))));
+                                    continue 'items;
                                 }
                                 it = Some(j);
                                 break 'search;
@@ -283,7 +338,7 @@ pub fn check(
                         nodes[i].declaration = Some(j);
                     }
                     None => {
-                        return Err(nodes[i].source.wrap(
+                        errors.push(nodes[i].source.wrap(
                             format!("Could not find declaration of `{}`",
                             nodes[i].name().expect("Expected name"))));
                     }
@@ -315,7 +370,7 @@ pub fn check(
             }
 
             if !found {
-                return Err(nodes[inf].source.wrap(
+                errors.push(nodes[inf].source.wrap(
                     format!("Can not infer range from body, use `list[i]` syntax")));
             }
         }
@@ -329,7 +384,7 @@ pub fn check(
         for &i in nodes[f].children.iter().filter(|&&i| nodes[i].kind == Kind::Arg) {
             let name = nodes[i].name().expect("Expected name");
             if arg_names.contains(name) {
-                return Err(nodes[i].source.wrap(
+                errors.push(nodes[i].source.wrap(
                     format!("Duplicate argument `{}`", name)));
             } else {
                 arg_names.insert(name.clone());
@@ -344,26 +399,14 @@ pub fn check(
     for (i, &f) in functions.iter().enumerate() {
         let name = nodes[f].name().expect("Expected name");
         if function_lookup.contains_key(name) {
-            return Err(nodes[f].source.wrap(
+            errors.push(nodes[f].source.wrap(
                 format!("Duplicate function `{}`", name)));
         } else {
             function_lookup.insert(name.clone(), i);
         }
     }
 
-    let mut use_lookup: UseLookup = UseLookup::new();
-    for node in nodes.iter() {
-        if node.kind == Kind::Uses {
-            use piston_meta::bootstrap::Convert;
-            use ast::Uses;
-
-            let convert = Convert::new(&data[node.start..node.end]);
-            if let Ok((_, val)) = Uses::from_meta_data(convert, &mut vec![]) {
-                use_lookup = UseLookup::from_uses_prelude(&val, prelude);
-            }
-            break;
-        }
-    }
+    let use_lookup = build_use_lookup(nodes, data, prelude);
 
     // Link call nodes to functions.
     for &c in &calls {
@@ -392,8 +435,9 @@ pub fn check(
                 node.lts = prelude.list[i].lts.clone();
                 continue;
             } else {
-                return Err(node.source.wrap(
+                errors.push(node.source.wrap(
                     format!("Could not find function `{}::{}`", alias, name)));
+                continue;
             }
         }
         let i = match function_lookup.get(&name) {
@@ -402,9 +446,15 @@ pub fn check(
                 // Check whether it is a prelude function.
                 match prelude.functions.get(&name) {
                     Some(&pf) => {
+                        if let Some(capability) = prelude.disabled_capability(pf) {
+                            errors.push(node.source.wrap(
+                                format!("`{}` is disabled, requires capability `{}`",
+                                name, capability)));
+                            continue;
+                        }
                         node.lts = prelude.list[pf].lts.clone();
                         if node.lts.len() != n {
-                            return Err(node.source.wrap(
+                            errors.push(node.source.wrap(
                                 format!("{}: Expected {} arguments, found {}",
                                 name, node.lts.len(), n)));
                         }
@@ -413,16 +463,18 @@ pub fn check(
                     None => {}
                 }
                 let suggestions = suggestions(&**name, &function_lookup, prelude);
-                return Err(node.source.wrap(
+                errors.push(node.source.wrap(
                     format!("Could not find function `{}`{}", name, suggestions)));
+                continue;
             }
         };
         // Check that number of arguments is the same as in declaration.
         if function_args[i] != n {
         let suggestions = suggestions(&**name, &function_lookup, prelude);
-            return Err(node.source.wrap(
+            errors.push(node.source.wrap(
                 format!("{}: Expected {} arguments, found {}{}",
                 name, function_args[i], n, suggestions)));
+            continue;
         }
         node.declaration = Some(functions[i]);
     }
@@ -443,7 +495,7 @@ pub fn check(
             if let Some(ref lt) = nodes[c].lifetime {
                 if &**lt == "return" { continue; }
                 if !arg_names.contains_key(&(f, lt.clone())) {
-                    return Err(nodes[c].source.wrap(
+                    errors.push(nodes[c].source.wrap(
                         format!("Could not find argument `{}`", lt)));
                 }
             }
@@ -459,12 +511,16 @@ pub fn check(
                 // Reset visit flags.
                 for i in 0..visited.len() { visited[i] = false; }
 
-                let (mut arg, mut ind) = *arg_names.get(&(f, lt.clone()))
-                    .expect("Expected argument index");
+                let (mut arg, mut ind) = match arg_names.get(&(f, lt.clone())) {
+                    Some(&x) => x,
+                    // Reported above.
+                    None => continue,
+                };
                 loop {
                     if visited[ind] {
-                        return Err(nodes[arg].source.wrap(
+                        errors.push(nodes[arg].source.wrap(
                                 format!("Cyclic lifetime for `{}`", lt)));
+                        break;
                     }
                     visited[ind] = true;
 
@@ -474,8 +530,10 @@ pub fn check(
                             Some(ref name) => name.clone()
                         };
                     if &**name == "return" { break; }
-                    let (new_arg, new_ind) = *arg_names.get(&(f, name))
-                        .expect("Expected argument");
+                    let (new_arg, new_ind) = match arg_names.get(&(f, name)) {
+                        Some(&x) => x,
+                        None => break,
+                    };
                     arg = new_arg;
                     ind = new_ind;
                 }
@@ -483,6 +541,8 @@ pub fn check(
         }
     }
 
+    if errors.len() > 0 { return Err((ErrorKind::Lifetime, errors)); }
+
     // Check the lifetime of mutated locals.
     for &(a, i) in &mutated_locals {
         // Only `=` needs a lifetime check.
@@ -490,8 +550,9 @@ pub fn check(
         let right = nodes[a].children[1];
         let ref lifetime_left = nodes[i].lifetime(&nodes, &arg_names);
         let ref lifetime_right = nodes[right].lifetime(&nodes, &arg_names);
-        try!(compare_lifetimes(lifetime_left, lifetime_right, &nodes)
-                .map_err(|err| nodes[right].source.wrap(err)));
+        if let Err(err) = compare_lifetimes(lifetime_left, lifetime_right, &nodes) {
+            errors.push(nodes[right].source.wrap(err));
+        }
     }
 
     // Check the lifetime of declared locals.
@@ -499,37 +560,42 @@ pub fn check(
         let right = nodes[a].children[1];
         let ref lifetime_left = Some(Lifetime::Local(i));
         let ref lifetime_right = nodes[right].lifetime(&nodes, &arg_names);
-        try!(compare_lifetimes(lifetime_left, lifetime_right, &nodes)
-                .map_err(|err| nodes[right].source.wrap(err)));
+        if let Err(err) = compare_lifetimes(lifetime_left, lifetime_right, &nodes) {
+            errors.push(nodes[right].source.wrap(err));
+        }
     }
 
     // Check the lifetime of returned values.
+    let mut failed_returns: HashSet<usize> = HashSet::new();
     for &i in &returns {
         let right = nodes[i].children[0];
         let ref lifetime_right = nodes[right].lifetime(&nodes, &arg_names);
-        try!(compare_lifetimes(
-            &Some(Lifetime::Return(vec![])), lifetime_right, &nodes)
-                .map_err(|err| nodes[right].source.wrap(err))
-        );
+        if let Err(err) = compare_lifetimes(&Some(Lifetime::Return(vec![])), lifetime_right, &nodes) {
+            errors.push(nodes[right].source.wrap(err));
+            failed_returns.insert(i);
+        }
     }
 
     // Check the lifetime of expressions that are mathematically declared.
     for &i in &math_expr {
         let ref lifetime_right = nodes[i].lifetime(&nodes, &arg_names);
-        try!(compare_lifetimes(
-            &Some(Lifetime::Return(vec![])), lifetime_right, &nodes)
-                .map_err(|err| nodes[i].source.wrap(err))
-        );
+        if let Err(err) = compare_lifetimes(&Some(Lifetime::Return(vec![])), lifetime_right, &nodes) {
+            errors.push(nodes[i].source.wrap(err));
+        }
     }
 
     // Check the lifetime of expressions at end of blocks.
     for &i in &end_of_blocks {
+        // A return at the end of a block is reported once.
+        if failed_returns.contains(&i) ||
+           nodes[i].children.iter().any(|ch| failed_returns.contains(ch)) { continue; }
         let parent = nodes[i].parent.unwrap();
         // Fake a local variable.
         let ref lifetime_left = Some(Lifetime::Local(parent));
         let ref lifetime_right = nodes[i].lifetime(&nodes, &arg_names);
-        try!(compare_lifetimes(lifetime_left, lifetime_right, &nodes)
-                .map_err(|err| nodes[i].source.wrap(err)));
+        if let Err(err) = compare_lifetimes(lifetime_left, lifetime_right, &nodes) {
+            errors.push(nodes[i].source.wrap(err));
+        }
     }
 
     // Check that calls do not have arguments with shorter lifetime than the call.
@@ -540,8 +606,19 @@ pub fn check(
         for &a in call.children.iter()
             .filter(|&&i| nodes[i].kind == Kind::CallArg)  {
             let ref lifetime_right = nodes[a].lifetime(&nodes, &arg_names);
-            try!(compare_lifetimes(lifetime_left, lifetime_right, &nodes)
-                    .map_err(|err| nodes[a].source.wrap(err)));
+            if let Err(err) = compare_lifetimes(lifetime_left, lifetime_right, &nodes) {
+                errors.push(nodes[a].source.wrap(err));
+            }
+        }
+    }
+
+    // Check that `go` is allowed.
+    if !prelude.capabilities.threads {
+        for node in nodes.iter() {
+            if node.kind == Kind::Go {
+                errors.push(node.source.wrap(
+                    format!("`go` is disabled, requires capability `threads`")));
+            }
         }
     }
 
@@ -560,7 +637,7 @@ pub fn check(
                 .filter(|&(_, &i)| nodes[i].kind == Kind::Arg)  {
                 let arg = &nodes[a];
                 if arg.lifetime.is_some() {
-                    return Err(nodes[call.children[i]].source.wrap(
+                    errors.push(nodes[call.children[i]].source.wrap(
                         format!("Can not use `go` because this argument has a lifetime constraint")));
                 }
             }
@@ -573,7 +650,7 @@ pub fn check(
                 match lt {
                     Lt::Default => {}
                     _ => {
-                        return Err(nodes[call.children[i]].source.wrap(
+                        errors.push(nodes[call.children[i]].source.wrap(
                             format!("Can not use `go` because this argument has a lifetime constraint")));
                     }
                 }
@@ -581,6 +658,26 @@ pub fn check(
         }
     }
 
+    // Check that values sent to channels are not references.
+    for &c in &calls {
+        let call = &nodes[c];
+        if call.declaration.is_some() ||
+           call.name().map(|n| &***n == "send(mut,_)") != Some(true) { continue }
+        if let Some(&a) = call.children.iter()
+            .filter(|&&i| nodes[i].kind == Kind::CallArg).nth(1) {
+            // Arguments with lifetime constraints are references by contract.
+            let constrained = match nodes[a].lifetime(&nodes, &arg_names) {
+                Some(Lifetime::Argument(ref args)) => args.len() > 1,
+                Some(Lifetime::Return(ref args)) => args.len() > 0,
+                _ => false
+            };
+            if constrained {
+                errors.push(nodes[a].source.wrap(
+                    format!("Can not use `send` because this value has a lifetime constraint")));
+            }
+        }
+    }
+
     // Check that calls satisfy the lifetime constraints of arguments.
     for &c in &calls {
         let call = &nodes[c];
@@ -637,8 +734,9 @@ pub fn check(
                     match arg_lifetime {
                         Some(Lifetime::Return(_)) | Some(Lifetime::Argument(_)) => {
                             if !is_reference(i) {
-                                return Err(nodes[call.children[i]].source.wrap(
+                                errors.push(nodes[call.children[i]].source.wrap(
                                     format!("Requires reference to variable")));
+                                continue;
                             }
                         }
                         _ => {}
@@ -652,10 +750,9 @@ pub fn check(
                         let right = call.children[i];
                         let ref lifetime_left = nodes[left].lifetime(&nodes, &arg_names);
                         let ref lifetime_right = nodes[right].lifetime(&nodes, &arg_names);
-                        try!(compare_lifetimes(
-                            lifetime_left, lifetime_right, &nodes)
-                                .map_err(|err| nodes[right].source.wrap(err))
-                        );
+                        if let Err(err) = compare_lifetimes(lifetime_left, lifetime_right, &nodes) {
+                            errors.push(nodes[right].source.wrap(err));
+                        }
                     }
                 }
             }
@@ -669,24 +766,25 @@ pub fn check(
                     Lt::Default => {}
                     Lt::Return => {
                         if !is_reference(i) {
-                            return Err(arg.source.wrap(
+                            errors.push(arg.source.wrap(
                                 format!("Requires reference to variable")));
+                            continue;
                         }
                     }
                     Lt::Arg(ind) => {
                         if !is_reference(i) {
-                            return Err(arg.source.wrap(
+                            errors.push(arg.source.wrap(
                                 format!("Requires reference to variable")));
+                            continue;
                         }
 
                         let left = call.children[ind];
                         let right = call.children[i];
                         let ref lifetime_left = nodes[left].lifetime(&nodes, &arg_names);
                         let ref lifetime_right = nodes[right].lifetime(&nodes, &arg_names);
-                        try!(compare_lifetimes(
-                            lifetime_left, lifetime_right, &nodes)
-                                .map_err(|err| nodes[right].source.wrap(err))
-                        );
+                        if let Err(err) = compare_lifetimes(lifetime_left, lifetime_right, &nodes) {
+                            errors.push(nodes[right].source.wrap(err));
+                        }
                     }
                 }
             }
@@ -699,7 +797,7 @@ pub fn check(
             if nodes[decl].kind == Kind::Arg ||
                nodes[decl].kind == Kind::Current {
                 if !nodes[decl].mutable {
-                    return Err(nodes[i].source.wrap(
+                    errors.push(nodes[i].source.wrap(
                         format!("Requires `mut {}`", nodes[i].name().unwrap())
                     ));
                 }
@@ -731,7 +829,7 @@ pub fn check(
                    if (nodes[decl].kind == Kind::Arg ||
                        nodes[decl].kind == Kind::Current) &&
                        !nodes[decl].mutable {
-                       return Err(nodes[n].source.wrap(
+                       errors.push(nodes[n].source.wrap(
                            format!("Requires `mut {}`", nodes[n].name().unwrap())
                        ));
                    }
@@ -740,7 +838,10 @@ pub fn check(
         }
     }
 
-    try!(typecheck::run(&mut nodes, prelude, &use_lookup));
+    if errors.len() > 0 { return Err((ErrorKind::Lifetime, errors)); }
+
+    try!(typecheck::run(nodes, prelude, &use_lookup)
+        .map_err(|err| (ErrorKind::Type, vec![err])));
 
     // Copy refined return types to use in AST.
     let mut refined_rets: HashMap<Arc<String>, Type> = HashMap::new();
@@ -229,6 +229,28 @@ impl Link {
 
     pub fn is_empty(&self) -> bool { self.slices.len() == 0 }
 
+    /// Returns the number of items.
+    pub fn len(&self) -> usize {
+        self.slices.iter().map(|s| (s.end - s.start) as usize).sum()
+    }
+
+    /// Returns the number of bytes used by blocks and the strings they store.
+    ///
+    /// Blocks shared with other links are counted once per link.
+    pub fn heap_size(&self) -> usize {
+        use std::mem::size_of;
+
+        let mut size = self.slices.len() * (size_of::<Slice>() + size_of::<Block>());
+        for slice in &self.slices {
+            for i in slice.start..slice.end {
+                if let Variable::Text(ref text) = slice.block.var(i) {
+                    size += text.len();
+                }
+            }
+        }
+        size
+    }
+
     pub fn add(&self, other: &Link) -> Link {
         let mut slices = Vec::with_capacity(self.slices.len() + other.slices.len());
         slices.extend_from_slice(&self.slices);
@@ -0,0 +1,452 @@
+//! Finds likely mistakes in Dyon source files.
+//!
+//! The linter walks the node tree of the lifetime checker,
+//! after items are linked to their declarations and the types are checked.
+//! Lints are warnings, so a source that the linter complains about still loads.
+
+use std::sync::Arc;
+use piston_meta;
+use piston_meta::MetaData;
+use piston_meta::bootstrap::Convert;
+use range::Range;
+
+use ast::{AssignOp, Uses};
+use diagnostic::Diagnostic;
+use error::{Error, ErrorKind};
+use lifetime::{build_use_lookup, check_all};
+use lifetime::kind::Kind;
+use lifetime::node::Node;
+use diagnostics_error;
+use syntax_rules;
+use Module;
+use Prelude;
+
+/// A check done by the linter.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Rule {
+    /// A local variable that is declared but never used.
+    UnusedVariable,
+    /// A `use` import that no call refers to.
+    UnusedImport,
+    /// A local variable that hides a variable of an outer scope.
+    ShadowedLocal,
+    /// Code after `return`, `break` or `continue`.
+    UnreachableCode,
+    /// A `:=` that declares an unused variable hiding a variable of an outer scope,
+    /// where `=` was meant to change the outer variable.
+    DeclareInsteadOfSet,
+}
+
+/// All rules, in the order they are documented.
+pub const RULES: &'static [Rule] = &[
+    Rule::UnusedVariable,
+    Rule::UnusedImport,
+    Rule::ShadowedLocal,
+    Rule::UnreachableCode,
+    Rule::DeclareInsteadOfSet,
+];
+
+impl Rule {
+    /// Returns the name used to turn the rule on or off.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Rule::UnusedVariable => "unused_variable",
+            Rule::UnusedImport => "unused_import",
+            Rule::ShadowedLocal => "shadowed_local",
+            Rule::UnreachableCode => "unreachable_code",
+            Rule::DeclareInsteadOfSet => "declare_instead_of_set",
+        }
+    }
+
+    /// Finds a rule by name.
+    pub fn from_name(name: &str) -> Option<Rule> {
+        RULES.iter().find(|rule| rule.name() == name).cloned()
+    }
+}
+
+/// Linting options.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The rules to check, all of them by default.
+    pub rules: Vec<Rule>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config { rules: RULES.to_vec() }
+    }
+}
+
+impl Config {
+    /// Turns off a rule.
+    pub fn allow(&mut self, rule: Rule) {
+        self.rules.retain(|&r| r != rule);
+    }
+
+    /// Turns on a rule.
+    pub fn warn(&mut self, rule: Rule) {
+        if !self.is_enabled(rule) { self.rules.push(rule); }
+    }
+
+    /// Returns `true` if the rule is checked.
+    pub fn is_enabled(&self, rule: Rule) -> bool {
+        self.rules.contains(&rule)
+    }
+}
+
+/// A suggested change to the source that fixes a lint.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fix {
+    /// Describes the change.
+    pub description: String,
+    /// The range to replace.
+    pub range: Range,
+    /// The new text.
+    pub replacement: String,
+    /// Whether the fix keeps the behavior of the program,
+    /// such that it can be applied without review by `apply_fixes`.
+    pub automatic: bool,
+}
+
+/// A problem found by the linter.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lint {
+    pub rule: Rule,
+    /// The range in the source.
+    pub range: Range,
+    pub message: String,
+    pub fix: Option<Fix>,
+}
+
+impl Lint {
+    /// Converts to a warning that can be rendered with the source.
+    pub fn diagnostic(&self, file: Arc<String>) -> Diagnostic {
+        let mut diagnostic = Diagnostic::warning(ErrorKind::Lint, file, self.range,
+                                                 self.message.clone());
+        if let Some(ref fix) = self.fix {
+            diagnostic.notes.push(fix.description.clone());
+        }
+        diagnostic.notes.push(format!("turn off with `--allow {}`", self.rule.name()));
+        diagnostic
+    }
+}
+
+/// Lints source.
+///
+/// - source - The name of source file
+/// - d - The data of source file
+/// - module - The module that `use` imports are resolved against
+///
+/// Returns an error if the source does not pass the lifetime and type checker,
+/// since the rules depend on declarations being linked.
+pub fn lint_str(
+    source: &str,
+    d: &str,
+    module: &Module,
+    config: &Config
+) -> Result<Vec<Lint>, Error> {
+    let file = Arc::new(source.to_string());
+    let syntax_rules = try!(syntax_rules().map_err(|err| Error::new(ErrorKind::Syntax, err)));
+
+    let mut data = vec![];
+    try!(piston_meta::parse(syntax_rules, d, &mut data).map_err(|err| {
+        let (range, err) = err.decouple();
+        let diagnostic = Diagnostic::error(ErrorKind::Syntax, file.clone(), range,
+                                           format!("{}", err));
+        diagnostics_error(&[diagnostic], d)
+    }));
+
+    let prelude = Prelude::from_module(module);
+    let mut nodes = vec![];
+    try!(check_all(&mut nodes, &data, &prelude).map_err(|(kind, errors)| {
+        let diagnostics: Vec<Diagnostic> = errors.into_iter().map(|err| {
+            let (range, msg) = err.decouple();
+            Diagnostic::error(kind, file.clone(), range, msg)
+        }).collect();
+        diagnostics_error(&diagnostics, d)
+    }));
+    Ok(lint_nodes(&nodes, &data, d, &prelude, config))
+}
+
+/// Lints a node tree that passed `lifetime::check_all`.
+///
+/// Returns the lints sorted by their position in the source.
+pub fn lint_nodes(
+    nodes: &[Node],
+    data: &[Range<MetaData>],
+    d: &str,
+    prelude: &Prelude,
+    config: &Config
+) -> Vec<Lint> {
+    let mut lints = vec![];
+    if config.is_enabled(Rule::UnusedImport) {
+        unused_imports(nodes, data, d, prelude, &mut lints);
+    }
+    locals(nodes, d, config, &mut lints);
+    if config.is_enabled(Rule::UnreachableCode) {
+        unreachable_code(d, nodes, &mut lints);
+    }
+    lints.sort_by(|a, b| a.range.offset.cmp(&b.range.offset));
+    lints
+}
+
+/// Returns the fixes that `apply_fixes` applies, sorted by their position in the source.
+///
+/// Fixes that are not automatic or that overlap an earlier fix are skipped,
+/// so linting the result again might find more to fix.
+pub fn automatic_fixes(lints: &[Lint]) -> Vec<&Fix> {
+    let mut fixes: Vec<&Fix> = lints.iter()
+        .filter_map(|lint| lint.fix.as_ref())
+        .filter(|fix| fix.automatic)
+        .collect();
+    fixes.sort_by(|a, b| a.range.offset.cmp(&b.range.offset));
+    let mut pos = 0;
+    fixes.retain(|fix| {
+        if fix.range.offset < pos { return false; }
+        pos = fix.range.offset + fix.range.length;
+        true
+    });
+    fixes
+}
+
+/// Applies the automatic fixes of lints to source, see `automatic_fixes`.
+pub fn apply_fixes(d: &str, lints: &[Lint]) -> String {
+    let fixes = automatic_fixes(lints);
+    let mut s = String::new();
+    let mut pos = 0;
+    for fix in fixes {
+        s.push_str(&d[pos..fix.range.offset]);
+        s.push_str(&fix.replacement);
+        pos = fix.range.offset + fix.range.length;
+    }
+    s.push_str(&d[pos..]);
+    s
+}
+
+/// Strips mutability information, e.g. `foo(mut,_)` becomes `foo`.
+fn base_name(name: &str) -> &str {
+    name.split('(').next().unwrap_or(name)
+}
+
+/// Extends a range over the rest of the line, when there is only white space left.
+fn whole_line(d: &str, range: Range) -> Range {
+    let end = range.offset + range.length;
+    let rest = &d[end..];
+    match rest.find('\n') {
+        Some(n) if rest[..n].trim().len() == 0 => Range::new(range.offset, range.length + n + 1),
+        _ => range
+    }
+}
+
+fn unused_imports(
+    nodes: &[Node],
+    data: &[Range<MetaData>],
+    d: &str,
+    prelude: &Prelude,
+    lints: &mut Vec<Lint>
+) {
+    let uses = match nodes.iter().find(|n| n.kind == Kind::Uses) {
+        None => return,
+        Some(uses) => uses,
+    };
+    let convert = Convert::new(&data[uses.start..uses.end]);
+    let use_imports = match Uses::from_meta_data(convert, &mut vec![]) {
+        Ok((_, val)) => val.use_imports,
+        Err(()) => return,
+    };
+    let use_lookup = build_use_lookup(nodes, data, prelude);
+
+    // Stores alias, name and prelude index of calls to imported functions.
+    let calls: Vec<(&Arc<String>, &str, usize)> = nodes.iter()
+        .filter(|n| n.kind == Kind::Call)
+        .filter_map(|n| {
+            let alias = match n.alias { None => return None, Some(ref alias) => alias };
+            let name = match n.name() { None => return None, Some(name) => name };
+            use_lookup.aliases.get(alias).and_then(|fns| fns.get(name))
+                .map(|&i| (alias, base_name(name), i))
+        })
+        .collect();
+
+    let use_nodes = uses.children.iter().filter(|&&c| nodes[c].kind == Kind::Use);
+    for (&u, use_import) in use_nodes.zip(use_imports.iter()) {
+        let from_import = |&&(alias, _, i): &&(&Arc<String>, &str, usize)| {
+            alias == &use_import.alias &&
+            &*prelude.namespaces[i].0 == &use_import.names
+        };
+        let path = use_import.names.iter().map(|n| &***n).collect::<Vec<&str>>().join("::");
+        let unused: Vec<&Arc<String>> = use_import.fns.iter()
+            .map(|&(ref f, ref f_alias)| f_alias.as_ref().unwrap_or(f))
+            .filter(|&f| !calls.iter().filter(from_import).any(|&(_, name, _)| name == &***f))
+            .collect();
+        let source = nodes[u].source;
+        if use_import.fns.len() == 0 && !calls.iter().any(|c| from_import(&c)) ||
+           use_import.fns.len() > 0 && unused.len() == use_import.fns.len() {
+            lints.push(Lint {
+                rule: Rule::UnusedImport,
+                range: source,
+                message: format!("Unused import `{}` as `{}`", path, use_import.alias),
+                fix: Some(Fix {
+                    description: format!("remove the import"),
+                    range: whole_line(d, source),
+                    replacement: String::new(),
+                    automatic: true,
+                }),
+            });
+        } else {
+            for f in unused {
+                lints.push(Lint {
+                    rule: Rule::UnusedImport,
+                    range: source,
+                    message: format!("Unused import `{}` from `{}`", f, path),
+                    fix: None,
+                });
+            }
+        }
+    }
+}
+
+/// Finds the declaration that a new local variable hides.
+///
+/// Returns the declaration and whether it is in the same block as the local.
+/// The search is the same as when the lifetime checker links items,
+/// except that it stops at closures since they can not declare grabbed variables.
+fn hidden_declaration(nodes: &[Node], item: usize) -> Option<(usize, bool)> {
+    let name = match nodes[item].name() { None => return None, Some(name) => name };
+    let mut child = item;
+    let mut parent = match nodes[item].parent { None => return None, Some(p) => p };
+    let mut outer = false;
+    loop {
+        if nodes[parent].kind.is_decl_loop() || nodes[parent].kind.is_decl_un_loop() {
+            if nodes[parent].names.iter().any(|n| n == name) {
+                return Some((parent, false));
+            }
+        }
+
+        let me = nodes[parent].children.binary_search(&child)
+            .expect("Expected parent to contain child");
+        for &j in nodes[parent].children[..me].iter().rev() {
+            if nodes[j].children.len() == 0 { continue; }
+            // Assign is inside an expression.
+            let j = nodes[j].children[0];
+            if nodes[j].op != Some(AssignOp::Assign) { continue; }
+            let left = nodes[j].children[0];
+            let it = nodes[left].children[0];
+            if nodes[it].name() == Some(name) && !nodes[it].item_ids() {
+                return Some((it, !outer));
+            }
+        }
+        if nodes[parent].kind.is_block() { outer = true; }
+
+        match nodes[parent].kind {
+            Kind::Fn | Kind::Closure => {
+                return nodes[parent].children.iter()
+                    .find(|&&j| match nodes[j].kind {
+                        Kind::Arg | Kind::Current => nodes[j].name() == Some(name),
+                        _ => false
+                    })
+                    .map(|&j| (j, false));
+            }
+            _ => {}
+        }
+        child = parent;
+        parent = match nodes[parent].parent { None => return None, Some(p) => p };
+    }
+}
+
+/// Checks declared locals for unused variables, shadowing and `:=` instead of `=`.
+fn locals(nodes: &[Node], d: &str, config: &Config, lints: &mut Vec<Lint>) {
+    let mut used = vec![false; nodes.len()];
+    for node in nodes {
+        if let Some(decl) = node.declaration { used[decl] = true; }
+    }
+
+    for (a, assign) in nodes.iter().enumerate() {
+        if assign.op != Some(AssignOp::Assign) || assign.children.len() < 2 { continue; }
+        let left = assign.children[0];
+        let item = match nodes[left].children.get(0) { None => continue, Some(&it) => it };
+        if nodes[item].ids != 0 { continue; }
+        let name = match nodes[item].name() { None => continue, Some(name) => name };
+        if name.starts_with('_') || &***name == "return" { continue; }
+
+        let hidden = hidden_declaration(nodes, item);
+        let outer = match hidden { Some((_, same_block)) => !same_block, None => false };
+        let name_range = Range::new(nodes[item].source.offset, name.len());
+        if !used[item] && outer && config.is_enabled(Rule::DeclareInsteadOfSet) {
+            let right = nodes[a].children[1];
+            let start = nodes[left].source.offset + nodes[left].source.length;
+            let fix = d[start..nodes[right].source.offset].find(":=").map(|i| Fix {
+                description: format!("use `=` to change `{}`", name),
+                range: Range::new(start + i, 2),
+                replacement: "=".into(),
+                // Changing the outer variable changes what the program does.
+                automatic: false,
+            });
+            lints.push(Lint {
+                rule: Rule::DeclareInsteadOfSet,
+                range: name_range,
+                message: format!("`{}` is declared with `:=` but never used, \
+                                  hiding `{}` of an outer scope", name, name),
+                fix: fix,
+            });
+        } else if !used[item] && config.is_enabled(Rule::UnusedVariable) {
+            lints.push(Lint {
+                rule: Rule::UnusedVariable,
+                range: name_range,
+                message: format!("Unused variable `{}`", name),
+                fix: Some(Fix {
+                    description: format!("rename to `_{}` if this is intended", name),
+                    range: name_range,
+                    replacement: format!("_{}", name),
+                    automatic: true,
+                }),
+            });
+        } else if used[item] && outer && config.is_enabled(Rule::ShadowedLocal) {
+            lints.push(Lint {
+                rule: Rule::ShadowedLocal,
+                range: name_range,
+                message: format!("`{}` hides a variable of an outer scope", name),
+                fix: None,
+            });
+        }
+    }
+}
+
+/// Returns `true` if the statement never finishes normally.
+///
+/// The type checker marks expressions with `return` as unreachable,
+/// but also an `if` expression where only the true block returns,
+/// so this looks at the statement itself instead.
+fn diverges(nodes: &[Node], expr: usize) -> bool {
+    match nodes[expr].children.get(0).map(|&ch| nodes[ch].kind) {
+        Some(Kind::Return) | Some(Kind::ReturnVoid) |
+        Some(Kind::Break) | Some(Kind::Continue) => true,
+        _ => false
+    }
+}
+
+/// Checks for statements after a statement that diverges.
+fn unreachable_code(d: &str, nodes: &[Node], lints: &mut Vec<Lint>) {
+    for block in nodes.iter().filter(|n| n.kind.is_block()) {
+        let n = block.children.len();
+        let k = match (0..n.saturating_sub(1)).find(|&k| diverges(nodes, block.children[k])) {
+            None => continue,
+            Some(k) => k,
+        };
+        let first = nodes[block.children[k + 1]].source;
+        let last = nodes[block.children[n - 1]].source;
+        // Remove the white space before the unreachable code too.
+        let prev_end = d[..first.offset].trim_right().len();
+        let end = last.offset + last.length;
+        lints.push(Lint {
+            rule: Rule::UnreachableCode,
+            range: Range::new(first.offset, end - first.offset),
+            message: format!("Unreachable code"),
+            fix: Some(Fix {
+                description: format!("remove the unreachable code"),
+                range: Range::new(prev_end, end - prev_end),
+                replacement: String::new(),
+                automatic: true,
+            }),
+        });
+    }
+}
@@ -72,7 +72,10 @@ macro_rules! dyon_obj {
     ($t:tt { $($f:tt),* }) => {
         dyon_macro_items!{
             impl $crate::embed::PopVariable for $t {
-                fn pop_var(rt: &$crate::Runtime, var: &$crate::Variable) -> Result<Self, String> {
+                fn pop_var(
+                    rt: &$crate::Runtime,
+                    var: &$crate::Variable
+                ) -> Result<Self, $crate::error::Error> {
                     use dyon::embed::obj_field;
                     let var = rt.resolve(var);
                     if let &$crate::Variable::Object(ref obj) = var {
@@ -82,7 +85,7 @@ macro_rules! dyon_obj {
                             ),*
                         })
                     } else {
-                        Err(rt.expected(var, stringify!($t)))
+                        Err(rt.expected_error(var, stringify!($t)))
                     }
                 }
             }
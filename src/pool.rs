@@ -0,0 +1,265 @@
+//! Runs `go` tasks on a fixed number of worker threads.
+//!
+//! Each worker has its own queue of tasks.
+//! A worker takes the newest task from its own queue,
+//! and steals the oldest task from other queues when it runs out of work.
+//! Tasks created outside the workers are put in a shared queue.
+//!
+//! Joining a task that has not started runs it on the joining thread,
+//! such that workers waiting for each other do not block the pool.
+//! Other waiting, such as receiving from a channel, must be done with `blocking`,
+//! which wakes a helper thread that takes over the work of the waiting worker.
+//! Helpers are parked when the worker stops waiting, and reused by later calls,
+//! such that new threads are only started when more workers wait at the same time.
+//!
+//! The number of workers is read from the `DYON_WORKERS` environment variable,
+//! and defaults to 4.
+
+use std::any::Any;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::env;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use Variable;
+
+/// The default number of workers.
+const DEFAULT_WORKERS: usize = 4;
+
+/// Work done by a task.
+pub trait Job: Send {
+    fn run(self: Box<Self>) -> Result<Variable, String>;
+}
+
+enum State {
+    Pending(Box<Job>),
+    Running,
+    Done(thread::Result<Result<Variable, String>>),
+    Joined,
+}
+
+struct Task {
+    state: Mutex<State>,
+    done: Condvar,
+}
+
+impl Task {
+    /// Runs the task if it has not started.
+    fn run(&self) {
+        let job = {
+            let mut state = self.state.lock().unwrap();
+            match mem::replace(&mut *state, State::Running) {
+                State::Pending(job) => job,
+                x => {
+                    *state = x;
+                    return;
+                }
+            }
+        };
+        let res = panic::catch_unwind(AssertUnwindSafe(|| job.run()));
+        *self.state.lock().unwrap() = State::Done(res);
+        self.done.notify_all();
+    }
+}
+
+/// Used to join a task, like `std::thread::JoinHandle`.
+pub struct TaskHandle(Arc<Task>);
+
+impl TaskHandle {
+    /// Waits for the task to finish, running it on this thread if it has not started.
+    ///
+    /// Returns an error if the task panicked.
+    pub fn join(self) -> thread::Result<Result<Variable, String>> {
+        self.0.run();
+        let mut state = self.0.state.lock().unwrap();
+        loop {
+            match mem::replace(&mut *state, State::Joined) {
+                State::Done(res) => return res,
+                State::Running => {
+                    *state = State::Running;
+                    state = self.0.done.wait(state).unwrap();
+                }
+                State::Pending(_) | State::Joined => {
+                    let err: Box<Any + Send> = Box::new("The task was already joined");
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+/// A thread that takes over the queue of a waiting worker.
+struct Helper {
+    /// The worker to help and the flag telling when it stops waiting.
+    job: Mutex<Option<(usize, Arc<AtomicBool>)>>,
+    wake: Condvar,
+}
+
+struct Pool {
+    /// One queue per worker.
+    queues: Vec<Mutex<VecDeque<Arc<Task>>>>,
+    /// Tasks created outside the workers.
+    injector: Mutex<VecDeque<Arc<Task>>>,
+    /// Number of tasks that are not taken by a worker.
+    pending: Mutex<usize>,
+    wake: Condvar,
+    /// Helpers that are not in use, at most one per worker.
+    helpers: Mutex<Vec<Arc<Helper>>>,
+}
+
+lazy_static! {
+    static ref POOL: Arc<Pool> = {
+        let n = env::var("DYON_WORKERS").ok()
+            .and_then(|n| n.parse().ok())
+            .map(|n: usize| if n == 0 { 1 } else { n })
+            .unwrap_or(DEFAULT_WORKERS);
+        let pool = Arc::new(Pool {
+            queues: (0..n).map(|_| Mutex::new(VecDeque::new())).collect(),
+            injector: Mutex::new(VecDeque::new()),
+            pending: Mutex::new(0),
+            wake: Condvar::new(),
+            helpers: Mutex::new(vec![]),
+        });
+        for i in 0..n {
+            let pool = pool.clone();
+            thread::Builder::new()
+                .name(format!("dyon-worker-{}", i))
+                .spawn(move || pool.work(i, None))
+                .unwrap();
+        }
+        pool
+    };
+}
+
+thread_local! {
+    /// The index of the worker running on this thread.
+    static WORKER: Cell<Option<usize>> = Cell::new(None);
+}
+
+/// Adds a task to the pool.
+pub fn spawn(job: Box<Job>) -> TaskHandle {
+    let task = Arc::new(Task {
+        state: Mutex::new(State::Pending(job)),
+        done: Condvar::new(),
+    });
+    let pool = &*POOL;
+    let queue = match WORKER.with(|w| w.get()) {
+        Some(i) => &pool.queues[i],
+        None => &pool.injector,
+    };
+    queue.lock().unwrap().push_back(task.clone());
+    *pool.pending.lock().unwrap() += 1;
+    pool.wake.notify_one();
+    TaskHandle(task)
+}
+
+/// Runs a function that blocks the thread, such as waiting for a channel.
+///
+/// When called from a worker, a helper thread takes over the queue of the worker
+/// until the function returns, such that the pool keeps running the same number of tasks.
+/// Without it, workers waiting for tasks that are queued behind them would never wake up.
+/// A parked helper is used when there is one, otherwise a new helper is started.
+pub fn blocking<T, F: FnOnce() -> T>(f: F) -> T {
+    let i = match WORKER.with(|w| w.get()) {
+        None => return f(),
+        Some(i) => i,
+    };
+    let done = Arc::new(AtomicBool::new(false));
+    let parked = POOL.helpers.lock().unwrap().pop();
+    match parked {
+        Some(helper) => {
+            *helper.job.lock().unwrap() = Some((i, done.clone()));
+            helper.wake.notify_one();
+        }
+        None => {
+            let pool = POOL.clone();
+            let helper = Arc::new(Helper {
+                job: Mutex::new(Some((i, done.clone()))),
+                wake: Condvar::new(),
+            });
+            thread::Builder::new()
+                .name("dyon-helper".into())
+                .spawn(move || pool.help(helper))
+                .unwrap();
+        }
+    }
+    let res = f();
+    done.store(true, Ordering::SeqCst);
+    // Wake the helper if it is waiting for tasks.
+    {
+        let _pending = POOL.pending.lock().unwrap();
+        POOL.wake.notify_all();
+    }
+    res
+}
+
+impl Pool {
+    /// Runs tasks, using the queue of worker `i`.
+    ///
+    /// A helper thread stops when `done` is set.
+    fn work(&self, i: usize, done: Option<&AtomicBool>) {
+        let stop = || done.map(|x| x.load(Ordering::SeqCst)).unwrap_or(false);
+        WORKER.with(|w| w.set(Some(i)));
+        loop {
+            {
+                let mut pending = self.pending.lock().unwrap();
+                while *pending == 0 {
+                    if stop() { return; }
+                    pending = self.wake.wait(pending).unwrap();
+                }
+                if stop() { return; }
+                *pending -= 1;
+            }
+            match self.find_task(i) {
+                Some(task) => task.run(),
+                None => {
+                    // Another worker took the task while searching,
+                    // so there is a task left in a queue that was searched.
+                    *self.pending.lock().unwrap() += 1;
+                    thread::yield_now();
+                }
+            }
+        }
+    }
+
+    /// Helps waiting workers, parking between each time.
+    ///
+    /// Stops when there are enough parked helpers.
+    fn help(&self, helper: Arc<Helper>) {
+        loop {
+            let (i, done) = {
+                let mut job = helper.job.lock().unwrap();
+                loop {
+                    match job.take() {
+                        Some(x) => break x,
+                        None => job = helper.wake.wait(job).unwrap(),
+                    }
+                }
+            };
+            self.work(i, Some(&done));
+            let mut parked = self.helpers.lock().unwrap();
+            if parked.len() >= self.queues.len() { return; }
+            parked.push(helper.clone());
+        }
+    }
+
+    fn find_task(&self, i: usize) -> Option<Arc<Task>> {
+        if let Some(task) = self.queues[i].lock().unwrap().pop_back() {
+            return Some(task);
+        }
+        if let Some(task) = self.injector.lock().unwrap().pop_front() {
+            return Some(task);
+        }
+        let n = self.queues.len();
+        for j in 1..n {
+            if let Some(task) = self.queues[(i + j) % n].lock().unwrap().pop_front() {
+                return Some(task);
+            }
+        }
+        None
+    }
+}
@@ -55,10 +55,59 @@ impl Dfn {
     pub fn returns(&self) -> bool { self.ret != Type::Void }
 }
 
+/// Groups of intrinsics that can be turned off to run untrusted scripts.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Capabilities {
+    /// Reading files, e.g. `load_string__file`.
+    pub fs_read: bool,
+    /// Writing files, e.g. `save__string_file`.
+    pub fs_write: bool,
+    /// Network access, e.g. `load_string__url`.
+    pub http: bool,
+    /// Reading from standard input, e.g. `read_line`.
+    pub stdin: bool,
+    /// Starting threads with `go`, and `join__thread`.
+    pub threads: bool,
+    /// Blocking the thread with `sleep`.
+    pub sleep: bool,
+    /// Loading modules dynamically, e.g. `load`.
+    pub load: bool,
+}
+
+impl Capabilities {
+    /// Allows everything.
+    pub fn all() -> Capabilities {
+        Capabilities {
+            fs_read: true,
+            fs_write: true,
+            http: true,
+            stdin: true,
+            threads: true,
+            sleep: true,
+            load: true,
+        }
+    }
+
+    /// Allows nothing.
+    pub fn none() -> Capabilities {
+        Capabilities {
+            fs_read: false,
+            fs_write: false,
+            http: false,
+            stdin: false,
+            threads: false,
+            sleep: false,
+            load: false,
+        }
+    }
+}
+
 pub struct Prelude {
     pub functions: HashMap<Arc<String>, usize>,
     pub list: Vec<Dfn>,
     pub namespaces: Vec<(Arc<Vec<Arc<String>>>, Arc<String>)>,
+    /// Calls to intrinsics that require disabled capabilities are rejected.
+    pub capabilities: Capabilities,
 }
 
 impl Prelude {
@@ -82,18 +131,29 @@ impl Prelude {
             functions: HashMap::new(),
             list: vec![],
             namespaces: vec![],
+            capabilities: Capabilities::all(),
         }
     }
 
     pub fn new_intrinsics() -> Prelude {
+        Prelude::with_capabilities(Capabilities::all())
+    }
+
+    /// Creates a prelude with intrinsics, rejecting those with disabled capabilities.
+    pub fn with_capabilities(capabilities: Capabilities) -> Prelude {
         let mut prelude = Prelude::new();
         intrinsics::standard(&mut prelude);
+        prelude.capabilities = capabilities;
         prelude
     }
 
+    /// Returns the capability that a function requires, if it is disabled.
+    pub fn disabled_capability(&self, index: usize) -> Option<&'static str> {
+        intrinsics::disabled_capability(index, &self.capabilities)
+    }
+
     pub fn from_module(module: &Module) -> Prelude {
-        let mut prelude = Prelude::new();
-        intrinsics::standard(&mut prelude);
+        let mut prelude = Prelude::with_capabilities(module.capabilities);
         for f in &*module.ext_prelude {
             prelude.insert(Arc::new(vec![]), f.name.clone(), f.p.clone());
         }
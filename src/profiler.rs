@@ -0,0 +1,217 @@
+//! Measures where time is spent when running scripts.
+//!
+//! The profiler is attached to `Runtime::profiler` and records every call
+//! to a loaded function or closure, with call counts and inclusive/exclusive time.
+//! It also samples the evaluated expression every `sample_interval` steps,
+//! weighted by the time since the previous sample,
+//! which gives the time per source line and per call stack.
+//!
+//! Intrinsics and external functions count as time of the function calling them.
+//! `go` threads are not profiled.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use range::Range;
+
+use runtime::Call;
+use Module;
+
+/// Identifies a function or closure by the index of the function in module
+/// where it is declared, and its name.
+type FnKey = (usize, Arc<String>);
+
+/// Statistics of a function or closure.
+#[derive(Clone, Debug)]
+pub struct FnStats {
+    /// The number of calls.
+    pub calls: u64,
+    /// The time spent in the function, including the functions it calls.
+    ///
+    /// Recursive calls are only counted once.
+    pub inclusive: Duration,
+    /// The time spent in the function, excluding the functions it calls.
+    pub exclusive: Duration,
+    /// The number of unfinished calls, used to count recursive calls once.
+    active: usize,
+}
+
+/// A call that has not returned yet.
+struct Frame {
+    key: FnKey,
+    start: Instant,
+    /// Time spent in calls made from this call.
+    children: Duration,
+}
+
+/// Records call counts and timing of functions while running a script.
+pub struct Profiler {
+    /// The number of evaluation steps between each sample.
+    pub sample_interval: u64,
+    functions: HashMap<FnKey, FnStats>,
+    stack: Vec<Frame>,
+    /// Time per function index and source offset.
+    offsets: HashMap<(usize, usize), Duration>,
+    /// Time per call stack.
+    stacks: HashMap<Vec<FnKey>, Duration>,
+    steps: u64,
+    last_sample: Instant,
+}
+
+impl Profiler {
+    /// Creates a new profiler that samples every 100 steps.
+    pub fn new() -> Profiler {
+        Profiler {
+            sample_interval: 100,
+            functions: HashMap::new(),
+            stack: vec![],
+            offsets: HashMap::new(),
+            stacks: HashMap::new(),
+            steps: 0,
+            last_sample: Instant::now(),
+        }
+    }
+
+    /// Called when a function is called, with the length of call stack before the call.
+    #[inline(always)]
+    pub fn enter(&mut self, depth: usize, index: usize, name: &Arc<String>) {
+        // Calls that failed with an error do not return.
+        self.stack.truncate(depth);
+        let key = (index, name.clone());
+        {
+            let stats = self.functions.entry(key.clone()).or_insert(FnStats {
+                calls: 0,
+                inclusive: Duration::new(0, 0),
+                exclusive: Duration::new(0, 0),
+                active: 0,
+            });
+            stats.calls += 1;
+            stats.active += 1;
+        }
+        self.stack.push(Frame {
+            key: key,
+            start: Instant::now(),
+            children: Duration::new(0, 0),
+        });
+    }
+
+    /// Called when a function returns, with the length of call stack after returning.
+    #[inline(always)]
+    pub fn exit(&mut self, depth: usize) {
+        // The call started before the profiler was attached.
+        if self.stack.len() <= depth { return; }
+        self.stack.truncate(depth + 1);
+        let frame = match self.stack.pop() {
+            None => return,
+            Some(frame) => frame,
+        };
+        let elapsed = frame.start.elapsed();
+        if let Some(stats) = self.functions.get_mut(&frame.key) {
+            stats.active -= 1;
+            if stats.active == 0 { stats.inclusive += elapsed; }
+            if elapsed > frame.children {
+                stats.exclusive += elapsed - frame.children;
+            }
+        }
+        if let Some(parent) = self.stack.last_mut() {
+            parent.children += elapsed;
+        }
+    }
+
+    /// Called for each evaluation step.
+    #[inline(always)]
+    pub fn step(&mut self, range: Range, call_stack: &[Call]) {
+        self.steps += 1;
+        if self.steps % self.sample_interval.max(1) != 0 { return; }
+        let call = match call_stack.last() {
+            None => return,
+            Some(call) => call,
+        };
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_sample);
+        self.last_sample = now;
+        *self.offsets.entry((call.index, range.offset)).or_insert(Duration::new(0, 0)) += elapsed;
+        let stack: Vec<FnKey> = self.stack.iter().map(|frame| frame.key.clone()).collect();
+        *self.stacks.entry(stack).or_insert(Duration::new(0, 0)) += elapsed;
+    }
+
+    /// Returns the statistics of functions and closures, with their names.
+    ///
+    /// Closures are named after the function they are declared in, e.g. `main::\f`.
+    /// Sorted by exclusive time, most first.
+    pub fn functions(&self, module: &Module) -> Vec<(String, &FnStats)> {
+        let mut list: Vec<(String, &FnStats)> = self.functions.iter()
+            .map(|(key, stats)| (fn_name(module, key), stats))
+            .collect();
+        list.sort_by(|a, b| b.1.exclusive.cmp(&a.1.exclusive).then(a.0.cmp(&b.0)));
+        list
+    }
+
+    /// Returns the sampled time per source line, as file, line and time.
+    ///
+    /// Sorted by time, most first.
+    pub fn lines(&self, module: &Module) -> Vec<(Arc<String>, usize, Duration)> {
+        let mut lines: HashMap<(Arc<String>, usize), Duration> = HashMap::new();
+        for (&(index, offset), &time) in &self.offsets {
+            let f = match module.functions.get(index) { None => continue, Some(f) => f };
+            let end = if offset < f.source.len() { offset } else { f.source.len() };
+            let line = f.source.as_bytes()[..end].iter().filter(|&&c| c == b'\n').count() + 1;
+            *lines.entry((f.file.clone(), line)).or_insert(Duration::new(0, 0)) += time;
+        }
+        let mut lines: Vec<(Arc<String>, usize, Duration)> = lines.into_iter()
+            .map(|((file, line), time)| (file, line, time))
+            .collect();
+        lines.sort_by(|a, b| b.2.cmp(&a.2).then((&a.0, a.1).cmp(&(&b.0, b.1))));
+        lines
+    }
+
+    /// Writes the sampled call stacks in the folded format used by flamegraph tools.
+    ///
+    /// Each line is a call stack separated by `;`, followed by microseconds.
+    pub fn folded(&self, module: &Module) -> String {
+        let mut lines: Vec<String> = self.stacks.iter()
+            .filter(|&(stack, _)| stack.len() > 0)
+            .map(|(stack, time)| {
+                let names: Vec<String> = stack.iter().map(|key| fn_name(module, key)).collect();
+                format!("{} {}", names.join(";"), micros(*time))
+            })
+            .collect();
+        lines.sort();
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+
+    /// Writes a text report of functions sorted by exclusive time,
+    /// followed by the source lines sorted by time.
+    ///
+    /// At most `max_lines` lines are listed.
+    pub fn report(&self, module: &Module, max_lines: usize) -> String {
+        let mut s = String::new();
+        s.push_str(&format!("{:>10} {:>12} {:>12}  {}\n",
+                            "calls", "incl (ms)", "excl (ms)", "function"));
+        for (name, stats) in self.functions(module) {
+            s.push_str(&format!("{:>10} {:>12.3} {:>12.3}  {}\n",
+                                stats.calls, millis(stats.inclusive),
+                                millis(stats.exclusive), name));
+        }
+        s.push_str(&format!("\n{:>12}  {}\n", "time (ms)", "line"));
+        for (file, line, time) in self.lines(module).into_iter().take(max_lines) {
+            s.push_str(&format!("{:>12.3}  {}:{}\n", millis(time), file, line));
+        }
+        s
+    }
+}
+
+fn fn_name(module: &Module, key: &FnKey) -> String {
+    match module.functions.get(key.0) {
+        Some(f) if f.name != key.1 => format!("{}::\\{}", f.name, key.1),
+        _ => format!("{}", key.1),
+    }
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + d.subsec_nanos() as f64 / 1_000_000.0
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + d.subsec_nanos() as u64 / 1000
+}
@@ -1,18 +1,30 @@
+use std::any::{Any, TypeId};
+use std::cell::{Cell, RefCell};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::collections::HashMap;
+use std::mem::size_of;
+use std::time::Instant;
 use rand;
 use range::Range;
 
 use ast;
+use bytecode;
 use intrinsics;
 use embed;
+use pool;
+use profiler::Profiler;
 
 use FnIndex;
 use Module;
 use Variable;
+use error::{Error, ErrorKind, Frame};
 use UnsafeRef;
 use TINVOTS;
 
+/// There is no value in register.
+const TINVIR: &'static str = "There is no value in register";
+
 /// Which side an expression is evalutated.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Side {
@@ -47,12 +59,207 @@ pub struct Call {
     pub current_len: usize,
 }
 
+/// Clones a context value for a `go` thread.
+type CloneContext = fn(&(Any + Send + 'static)) -> Box<Any + Send>;
+
+/// Calls a function in a `go` task.
+struct GoJob {
+    rt: Runtime,
+    call: ast::Call,
+    module: Arc<Module>,
+}
+
+impl pool::Job for GoJob {
+    fn run(self: Box<Self>) -> Result<Variable, String> {
+        let GoJob { mut rt, call, module } = *self;
+        let loader = false;
+        Ok(match try!(rt.call_internal(&call, loader, &module)) {
+            (None, _) => rt.stack.pop().expect(TINVOTS),
+            (Some(x), _) => x,
+        }.deep_clone(&rt.stack))
+    }
+}
+
+/// A value stored by the host in the context of a runtime.
+struct Context {
+    value: Box<Any + Send>,
+    /// Clones the value for `go` threads, `None` when it is local to the runtime.
+    clone: Option<CloneContext>,
+}
+
+fn clone_context<T: Any + Send + Clone>(value: &(Any + Send + 'static)) -> Box<Any + Send> {
+    Box::new(value.downcast_ref::<T>().unwrap().clone())
+}
+
+/// Cancels a running script from another thread.
+///
+/// The script returns an error at the next evaluation step.
+#[derive(Clone, Debug)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    pub fn new() -> CancelHandle {
+        CancelHandle(Arc::new(AtomicBool::new(false)))
+    }
+
+    /// Tells the script to stop.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns `true` if the script is told to stop.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Allows the script to run again.
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Limits resources used by a script.
+///
+/// Violating a limit returns an error with a stack trace.
+#[derive(Clone, Debug)]
+pub struct RuntimeLimits {
+    /// Maximum number of nested function calls.
+    pub max_call_depth: Option<usize>,
+    /// Maximum number of variables on the stack.
+    pub max_stack_len: Option<usize>,
+    /// Maximum length of arrays.
+    pub max_array_len: Option<usize>,
+    /// Maximum length of strings in bytes.
+    pub max_text_len: Option<usize>,
+    /// Maximum number of items in links.
+    pub max_link_len: Option<usize>,
+    /// Approximate maximum number of bytes used by variables on the stack.
+    ///
+    /// Shared data is counted once per reference.
+    /// Bytes are counted when the script creates or grows arrays, objects, strings and links.
+    /// The stack is only measured when the count exceeds the budget,
+    /// to find out how much memory was freed since last time,
+    /// and at most once per `max_heap / 16` bytes counted.
+    /// Memory freed in between is not noticed until the next measure.
+    pub max_heap: Option<usize>,
+}
+
+impl RuntimeLimits {
+    /// Creates limits that allow everything.
+    pub fn new() -> RuntimeLimits {
+        RuntimeLimits {
+            max_call_depth: None,
+            max_stack_len: None,
+            max_array_len: None,
+            max_text_len: None,
+            max_link_len: None,
+            max_heap: None,
+        }
+    }
+
+    /// Creates limits for running untrusted scripts.
+    ///
+    /// The call depth leaves room for expressions nested in each call
+    /// on threads with a 2 MB stack, in release builds.
+    /// The heap budget is 64 MB.
+    pub fn sandbox() -> RuntimeLimits {
+        RuntimeLimits {
+            max_call_depth: Some(256),
+            max_stack_len: Some(100_000),
+            max_array_len: Some(1_000_000),
+            max_text_len: Some(1 << 24),
+            max_link_len: Some(1_000_000),
+            max_heap: Some(1 << 26),
+        }
+    }
+}
+
+/// Number of steps between each time the deadline is checked.
+const CHECK_INTERVAL: u64 = 1024;
+
+/// Returns approximate number of bytes used by variable outside the stack.
+fn heap_size(var: &Variable) -> usize {
+    match *var {
+        Variable::Text(ref text) => text.len(),
+        Variable::Array(ref arr) => {
+            arr.len() * size_of::<Variable>() + arr.iter().map(heap_size).sum::<usize>()
+        }
+        Variable::Object(ref obj) => {
+            obj.iter().map(|(key, val)| {
+                key.len() + size_of::<Variable>() + heap_size(val)
+            }).sum()
+        }
+        Variable::Link(ref link) => link.heap_size(),
+        Variable::Option(Some(ref val)) |
+        Variable::Result(Ok(ref val)) => size_of::<Variable>() + heap_size(val),
+        Variable::Result(Err(ref err)) => size_of::<Variable>() + heap_size(&err.message),
+        _ => 0
+    }
+}
+
+/// Hook for inspecting a script while it runs.
+pub trait Debugger: Send {
+    /// Called before an expression is evaluated.
+    ///
+    /// Returning an error stops the script.
+    fn before(
+        &mut self,
+        rt: &Runtime,
+        range: Range,
+        call: &Call,
+        module: &Module
+    ) -> Result<(), String>;
+
+    /// Called when `go` starts a new thread.
+    ///
+    /// Returns the debugger for the new thread.
+    fn thread(&mut self) -> Option<Box<Debugger>> { None }
+
+    /// Called when the script prints text.
+    fn output(&mut self, text: &str) {
+        use std::io::{self, Write};
+
+        print!("{}", text);
+        io::stdout().flush().unwrap();
+    }
+}
+
 pub struct Runtime {
     pub stack: Vec<Variable>,
     /// name, file, stack_len, local_len.
     pub call_stack: Vec<Call>,
     pub local_stack: Vec<(Arc<String>, usize)>,
     pub current_stack: Vec<(Arc<String>, usize)>,
+    /// Registers used by bytecode.
+    pub registers: Vec<Option<Variable>>,
+    /// Number of evaluation steps.
+    ///
+    /// A step is an evaluated expression or a loop iteration.
+    /// Reset when Rust calls into the runtime, e.g. by `run`, `call_str` or `call_closure_value`.
+    pub steps: u64,
+    /// Maximum number of evaluation steps per call from Rust.
+    pub max_steps: Option<u64>,
+    /// Stops the script when this point in time is reached.
+    pub deadline: Option<Instant>,
+    /// Used to stop the script from another thread.
+    pub cancel: CancelHandle,
+    pub limits: RuntimeLimits,
+    /// Bytes counted against `RuntimeLimits::max_heap`.
+    heap: Cell<usize>,
+    /// The count of bytes when the stack was last measured.
+    heap_measured: Cell<usize>,
+    /// Called before evaluating each expression.
+    ///
+    /// Functions are evaluated by the AST interpreter while a debugger is attached.
+    pub debugger: Option<Box<Debugger>>,
+    /// Records call counts and timing of functions, see `Profiler`.
+    pub profiler: Option<Profiler>,
+    /// Location of the last error, used to create `error::Error`.
+    last_error: RefCell<Option<Error>>,
+    /// Function index and range of the last intrinsic call that returned `err(_)`.
+    last_err_value: Cell<Option<(usize, Range)>>,
+    /// Values stored by the host, by type.
+    ctx: HashMap<TypeId, Context>,
     pub ret: Arc<String>,
     pub rng: rand::StdRng,
     pub text_type: Variable,
@@ -69,6 +276,7 @@ pub struct Runtime {
     pub option_type: Variable,
     pub result_type: Variable,
     pub thread_type: Variable,
+    pub channel_type: Variable,
     pub closure_type: Variable,
 }
 
@@ -213,6 +421,19 @@ impl Runtime {
             call_stack: vec![],
             local_stack: vec![],
             current_stack: vec![],
+            registers: vec![],
+            steps: 0,
+            max_steps: None,
+            deadline: None,
+            cancel: CancelHandle::new(),
+            limits: RuntimeLimits::new(),
+            heap: Cell::new(0),
+            heap_measured: Cell::new(0),
+            debugger: None,
+            profiler: None,
+            last_error: RefCell::new(None),
+            last_err_value: Cell::new(None),
+            ctx: HashMap::new(),
             ret: Arc::new("return".into()),
             rng: rand::StdRng::new().unwrap(),
             text_type: Variable::Text(Arc::new("string".into())),
@@ -229,13 +450,72 @@ impl Runtime {
             option_type: Variable::Text(Arc::new("option".into())),
             result_type: Variable::Text(Arc::new("result".into())),
             thread_type: Variable::Text(Arc::new("thread".into())),
+            channel_type: Variable::Text(Arc::new("channel".into())),
             closure_type: Variable::Text(Arc::new("closure".into())),
         }
     }
 
+    /// Stores a value that external functions can access with `ctx` and `ctx_mut`.
+    ///
+    /// There is one value per type, and the previous value is returned.
+    ///
+    /// A `go` thread receives a clone of the value when the thread starts,
+    /// so changes made by one thread are not seen by others.
+    /// To share state between threads, store e.g. an `Arc<Mutex<T>>`.
+    pub fn set_ctx<T: Any + Send + Clone>(&mut self, value: T) -> Option<T> {
+        self.insert_ctx(value, Some(clone_context::<T>))
+    }
+
+    /// Stores a value that is not passed on to `go` threads.
+    ///
+    /// Use this for state that must stay on the thread of the host, like a renderer.
+    /// External functions called from a `go` thread get `None` from `ctx`.
+    pub fn set_local_ctx<T: Any + Send>(&mut self, value: T) -> Option<T> {
+        self.insert_ctx(value, None)
+    }
+
+    fn insert_ctx<T: Any + Send>(
+        &mut self,
+        value: T,
+        clone: Option<CloneContext>
+    ) -> Option<T> {
+        let old = self.ctx.insert(TypeId::of::<T>(), Context {
+            value: Box::new(value),
+            clone: clone,
+        });
+        old.and_then(|c| c.value.downcast().ok()).map(|x| *x)
+    }
+
+    /// Returns the value of a type stored by the host.
+    pub fn ctx<T: Any>(&self) -> Option<&T> {
+        self.ctx.get(&TypeId::of::<T>()).and_then(|c| c.value.downcast_ref())
+    }
+
+    /// Returns the value of a type stored by the host, for mutation.
+    pub fn ctx_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.ctx.get_mut(&TypeId::of::<T>()).and_then(|c| c.value.downcast_mut())
+    }
+
+    /// Removes the value of a type stored by the host.
+    pub fn remove_ctx<T: Any>(&mut self) -> Option<T> {
+        self.ctx.remove(&TypeId::of::<T>())
+            .and_then(|c| c.value.downcast().ok())
+            .map(|x| *x)
+    }
+
+    /// Returns the values passed on to a `go` thread.
+    fn thread_ctx(&self) -> HashMap<TypeId, Context> {
+        self.ctx.iter()
+            .filter_map(|(&id, c)| c.clone.map(|clone| (id, Context {
+                value: clone(&*c.value),
+                clone: Some(clone),
+            })))
+            .collect()
+    }
+
     pub fn pop<T: embed::PopVariable>(&mut self) -> Result<T, String> {
         let v = self.stack.pop().unwrap_or_else(|| panic!(TINVOTS));
-        T::pop_var(self, self.resolve(&v))
+        T::pop_var(self, self.resolve(&v)).map_err(String::from)
     }
 
     pub fn pop_vec4<T: embed::ConvertVec4>(&mut self) -> Result<T, String> {
@@ -247,7 +527,7 @@ impl Runtime {
     }
 
     pub fn var<T: embed::PopVariable>(&self, var: &Variable) -> Result<T, String> {
-        T::pop_var(self, self.resolve(&var))
+        T::pop_var(self, self.resolve(&var)).map_err(String::from)
     }
 
     pub fn var_vec4<T: embed::ConvertVec4>(&self, var: &Variable) -> Result<T, String> {
@@ -265,11 +545,142 @@ impl Runtime {
         self.stack.push(Variable::Vec4(val.to()))
     }
 
+    /// Returns a handle that can stop the script from another thread.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// Counts an evaluation step.
+    /// Returns an error message if the script should stop.
+    #[inline(always)]
+    pub fn step(&mut self) -> Option<&'static str> {
+        self.steps += 1;
+        if self.cancel.is_cancelled() {
+            return Some("Script was cancelled");
+        }
+        if let Some(max_steps) = self.max_steps {
+            if self.steps > max_steps {
+                return Some("Step budget exceeded");
+            }
+        }
+        if let Some(deadline) = self.deadline {
+            if self.steps % CHECK_INTERVAL == 0 && Instant::now() >= deadline {
+                return Some("Deadline exceeded");
+            }
+        }
+        if let Some(max_stack_len) = self.limits.max_stack_len {
+            if self.stack.len() > max_stack_len {
+                return Some("Maximum stack length exceeded");
+            }
+        }
+        None
+    }
+
+    /// Counts bytes allocated by the script against the heap budget.
+    /// Returns an error message if the budget is exceeded.
+    ///
+    /// Freed memory is not counted, so the stack is measured
+    /// when the count exceeds the budget.
+    /// To avoid measuring on every allocation near the budget,
+    /// the stack is measured again only after `max_heap / 16` more bytes.
+    pub fn alloc(&self, bytes: usize) -> Option<&'static str> {
+        if bytes == 0 { return None; }
+        if let Some(max_heap) = self.limits.max_heap {
+            let mut heap = self.heap.get().saturating_add(bytes);
+            if heap > max_heap && heap - self.heap_measured.get() >= max_heap / 16 {
+                // The new memory might not be on the stack yet.
+                heap = self.heap_size().saturating_add(bytes);
+                self.heap_measured.set(heap);
+            }
+            self.heap.set(heap);
+            if heap > max_heap {
+                return Some("Heap budget exceeded");
+            }
+        }
+        None
+    }
+
+    /// Checks an array, string or link created or grown by `bytes`
+    /// against the length limits and the heap budget.
+    /// Returns an error message if a limit is exceeded.
+    pub fn check_alloc(&self, var: &Variable, bytes: usize) -> Option<&'static str> {
+        self.check_len(var).or_else(|| self.alloc(bytes))
+    }
+
+    /// Checks a link after pushing an item, where `len` is the number of items.
+    /// Returns an error message if a limit is exceeded.
+    fn check_link_push(&self, len: &mut usize, item: &Variable) -> Option<&'static str> {
+        // Each item uses 8 bytes of a block, and pushing a link copies its items.
+        let (n, bytes) = match *item {
+            Variable::Text(ref text) => (1, size_of::<u64>() + text.len()),
+            Variable::Link(ref link) => (link.len(), link.heap_size()),
+            _ => (1, size_of::<u64>()),
+        };
+        *len += n;
+        if let Some(max_link_len) = self.limits.max_link_len {
+            if *len > max_link_len {
+                return Some("Link length exceeds limit");
+            }
+        }
+        self.alloc(bytes)
+    }
+
+    /// Returns approximate number of bytes used by variables on the stack.
+    pub fn heap_size(&self) -> usize {
+        self.stack.len() * size_of::<Variable>() +
+        self.stack.iter().map(heap_size).sum::<usize>()
+    }
+
+    /// Checks length of array, string or link against the limits.
+    /// Returns an error message if the length is exceeded.
+    pub fn check_len(&self, var: &Variable) -> Option<&'static str> {
+        match *self.resolve(var) {
+            Variable::Array(ref arr) => {
+                if let Some(max_array_len) = self.limits.max_array_len {
+                    if arr.len() > max_array_len {
+                        return Some("Array length exceeds limit");
+                    }
+                }
+            }
+            Variable::Text(ref text) => {
+                if let Some(max_text_len) = self.limits.max_text_len {
+                    if text.len() > max_text_len {
+                        return Some("String length exceeds limit");
+                    }
+                }
+            }
+            Variable::Link(ref link) => {
+                if let Some(max_link_len) = self.limits.max_link_len {
+                    if link.len() > max_link_len {
+                        return Some("Link length exceeds limit");
+                    }
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// Creates an error when a limit is violated.
+    pub fn limit_error(&self, range: Range, msg: &str, module: &Module) -> String {
+        if self.call_stack.len() == 0 {
+            msg.into()
+        } else {
+            module.error(range, &format!("{}\n{}", self.stack_trace(), msg), self)
+        }
+    }
+
     pub fn expected(&self, var: &Variable, ty: &str) -> String {
         let found_ty = self.typeof_var(var);
         format!("{}\nExpected `{}`, found `{}`", self.stack_trace(), ty, found_ty)
     }
 
+    /// Creates an error when a variable can not be converted to a Rust type,
+    /// see `embed::PopVariable`.
+    pub fn expected_error(&self, var: &Variable, ty: &str) -> Error {
+        Error::new(ErrorKind::Type, self.expected(var, ty))
+    }
+
     #[inline(always)]
     pub fn resolve<'a>(&'a self, var: &'a Variable) -> &'a Variable {
         resolve(&self.stack, var)
@@ -301,6 +712,9 @@ impl Runtime {
         lc: usize,
         cu: usize,
     ) {
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.enter(self.call_stack.len(), index, &name);
+        }
         self.call_stack.push(Call {
             fn_name: name,
             index: index,
@@ -311,10 +725,18 @@ impl Runtime {
         });
     }
     pub fn pop_fn(&mut self, name: Arc<String>) {
+        self.pop_call(&name)
+    }
+
+    /// Pops function from call stack, without cloning the name.
+    fn pop_call(&mut self, name: &Arc<String>) {
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.exit(self.call_stack.len().saturating_sub(1));
+        }
         match self.call_stack.pop() {
             None => panic!("Did not call `{}`", name),
             Some(Call { fn_name, stack_len: st, local_len: lc, current_len: cu, .. }) => {
-                if name != fn_name {
+                if *name != fn_name {
                     panic!("Calling `{}`, did not call `{}`", fn_name, name);
                 }
                 self.stack.truncate(st);
@@ -324,6 +746,85 @@ impl Runtime {
         }
     }
 
+    /// Records where an error happened, unless a location is already recorded.
+    ///
+    /// Called by `Module::error` when an error is created,
+    /// with the range of the expression that failed.
+    /// The location is used by the entry points to create `error::Error`.
+    pub fn record_error(&self, kind: ErrorKind, range: Range, module: &Module) {
+        let mut last_error = self.last_error.borrow_mut();
+        if last_error.is_some() { return; }
+        let file = self.call_stack.last()
+            .and_then(|call| module.functions.get(call.index))
+            .map(|f| f.file.clone());
+        *last_error = Some(Error {
+            kind: kind,
+            file: file,
+            range: Some(range),
+            trace: self.call_stack.iter().map(|call| Frame {
+                fn_name: call.fn_name.clone(),
+                file: call.file.clone(),
+            }).collect(),
+            message: String::new(),
+        });
+    }
+
+    /// Forgets the recorded error location, when an error is handled by the script.
+    fn clear_error(&self) {
+        *self.last_error.borrow_mut() = None;
+    }
+
+    /// Prepares for a call from Rust.
+    ///
+    /// The step count is reset, unless a script is running and calls Rust
+    /// that calls back into the script, such that callbacks count against `max_steps`.
+    fn enter(&mut self) {
+        self.clear_error();
+        if self.call_stack.len() == 0 {
+            self.steps = 0;
+            self.last_err_value.set(None);
+        }
+    }
+
+    /// Creates an error from a message, using the recorded location.
+    fn take_error(&self, message: String) -> Error {
+        match self.last_error.borrow_mut().take() {
+            Some(mut err) => {
+                err.message = message;
+                err
+            }
+            None => Error::new(ErrorKind::Runtime, message),
+        }
+    }
+
+    /// Creates an error from an `err` value returned to Rust by function `f_index`.
+    ///
+    /// The message includes the trace of the value, at the intrinsic call
+    /// where the value was created, e.g. `err("...")`, when it is known.
+    /// Otherwise the message is at function `f_index`.
+    pub fn returned_error(&self, err: &::Error, f_index: usize, module: &Module) -> Error {
+        use write::{write_variable, EscapeString};
+
+        let mut w: Vec<u8> = vec![];
+        write_variable(&mut w, self, &err.message, EscapeString::None, 0).unwrap();
+        for t in &err.trace {
+            w.extend_from_slice("\n".as_bytes());
+            w.extend_from_slice(t.as_bytes());
+        }
+        let msg = String::from_utf8(w).unwrap();
+        let (index, range) = match self.last_err_value.get() {
+            Some((index, range)) if index < module.functions.len() => (index, range),
+            _ => (f_index, module.functions[f_index].source_range),
+        };
+        Error {
+            kind: ErrorKind::Runtime,
+            file: Some(module.functions[index].file.clone()),
+            range: Some(range),
+            trace: vec![],
+            message: module.error_fnindex(range, &msg, index),
+        }
+    }
+
     pub fn expression(
         &mut self,
         expr: &ast::Expression,
@@ -332,6 +833,20 @@ impl Runtime {
     ) -> Result<(Option<Variable>, Flow), String> {
         use ast::Expression::*;
 
+        if let Some(msg) = self.step() {
+            return Err(self.limit_error(expr.source_range(), msg, module));
+        }
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.step(expr.source_range(), &self.call_stack);
+        }
+        if let Some(mut debugger) = self.debugger.take() {
+            let res = match self.call_stack.last() {
+                Some(call) => debugger.before(self, expr.source_range(), call, module),
+                None => Ok(()),
+            };
+            self.debugger = Some(debugger);
+            try!(res);
+        }
         match *expr {
             Link(ref link) => self.link(link, module),
             Object(ref obj) => self.object(obj, module),
@@ -411,6 +926,7 @@ impl Runtime {
                 &format!("{}\nExpected something", self.stack_trace()), self)),
             Ok((x, flow)) => Ok((x, flow)),
             Err(err) => {
+                self.clear_error();
                 self.call_stack.truncate(cs);
                 self.stack.truncate(st);
                 self.local_stack.truncate(lc);
@@ -443,7 +959,7 @@ impl Runtime {
                                 self.stack_trace()), self))
         };
 
-        Ok((Some(::Variable::Closure(Arc::new(ast::Closure {
+        let new_closure = ast::Closure {
             currents: closure.currents.clone(),
             args: closure.args.clone(),
             source_range: closure.source_range.clone(),
@@ -451,7 +967,10 @@ impl Runtime {
             file: closure.file.clone(),
             source: closure.source.clone(),
             expr: new_expr,
-        }), Box::new(ClosureEnvironment {
+            // Grabbed values replace expressions that the code evaluates.
+            code: closure.code.clone(),
+        };
+        Ok((Some(::Variable::Closure(Arc::new(new_closure), Box::new(ClosureEnvironment {
             module: module.clone(),
             relative: relative
         }))), Flow::Continue))
@@ -569,42 +1088,132 @@ impl Runtime {
                     None => "".into(),
                     Some(ref f) => format!(" ({})", f)
                 };
-                err.trace.push(module.error(expr.source_range(),
+                err.trace.push(module.error_fnindex(expr.source_range(),
                     &format!("In function `{}`{}",
-                    &call.fn_name, file), self));
+                    &call.fn_name, file), call.index));
                 Ok((Some(Variable::Result(Err(err))), Flow::Return))
             }
         }
     }
 
-    pub fn run(&mut self, module: &Arc<Module>) -> Result<(), String> {
-        use std::cell::Cell;
-
+    pub fn run(&mut self, module: &Arc<Module>) -> Result<(), Error> {
         let name: Arc<String> = Arc::new("main".into());
-        let call = ast::Call {
-            alias: None,
-            name: name.clone(),
-            f_index: Cell::new(module.find_function(&name, 0)),
-            args: vec![],
-            custom_source: None,
-            source_range: Range::empty(0),
-        };
-        match call.f_index.get() {
+        match module.find_function(&name, 0) {
             FnIndex::Loaded(f_index) => {
                 let f = &module.functions[f_index as usize];
                 if f.args.len() != 0 {
-                    return Err(module.error(f.args[0].source_range,
-                               "`main` should not have arguments", self))
+                    return Err(Error::new(ErrorKind::Runtime,
+                               module.error_fnindex(f.args[0].source_range,
+                               "`main` should not have arguments", f_index as usize)))
                 }
-                let loader = false;
-                try!(self.call_internal(&call, loader, &module));
+                try!(self.call_index(f_index as usize, vec![], module));
                 Ok(())
             }
-            _ => return Err(module.error(call.source_range,
-                               "Could not find function `main`", self))
+            _ => return Err(Error::new(ErrorKind::Runtime,
+                            "Could not find function `main`".into()))
         }
     }
 
+    /// Runs `main` with command line arguments.
+    ///
+    /// `main` can either take no arguments or `args: [str]`.
+    /// The signature is checked before running `main`,
+    /// and it is an error to pass arguments when `main` takes none.
+    /// Returns the value returned from `main`.
+    pub fn run_args(
+        &mut self,
+        module: &Arc<Module>,
+        args: &[String]
+    ) -> Result<Option<Variable>, Error> {
+        use ty::Type;
+
+        let name: Arc<String> = Arc::new("main".into());
+        let f_index = match module.find_function(&name, 0) {
+            FnIndex::Loaded(f_index) => f_index as usize,
+            _ => return Err(Error::new(ErrorKind::Runtime,
+                            "Could not find function `main`".into()))
+        };
+        let f = &module.functions[f_index];
+        let call_args = match f.args.len() {
+            0 => {
+                if args.len() != 0 {
+                    return Err(Error::new(ErrorKind::Runtime,
+                        module.error_fnindex(f.source_range,
+                        &format!("`main` takes no arguments, found {} command line argument(s)\n\
+                                  Declare `main(args: [str])` to use them", args.len()),
+                        f_index)))
+                }
+                vec![]
+            }
+            1 => {
+                let arg = &f.args[0];
+                if !arg.ty.goes_with(&Type::Array(Box::new(Type::Text))) {
+                    return Err(Error::new(ErrorKind::Type,
+                        module.error_fnindex(arg.source_range,
+                        &format!("`main` should take `args: [str]`, found `{}`",
+                                 arg.ty.description()), f_index)))
+                }
+                let args = args.iter()
+                    .map(|arg| Variable::Text(Arc::new(arg.clone())))
+                    .collect();
+                vec![Variable::Array(Arc::new(args))]
+            }
+            _ => return Err(Error::new(ErrorKind::Runtime,
+                            module.error_fnindex(f.args[1].source_range,
+                            "`main` should take no arguments or `args: [str]`", f_index)))
+        };
+        self.call_index(f_index, call_args, module)
+    }
+
+    /// Calls a loaded function by index in module.
+    ///
+    /// Returns the value returned from the function, if any.
+    /// Does not check the arguments against the function signature,
+    /// see `Module::get_fn` for a checked version.
+    pub fn call_index(
+        &mut self,
+        f_index: usize,
+        args: Vec<Variable>,
+        module: &Arc<Module>
+    ) -> Result<Option<Variable>, Error> {
+        self.enter();
+        let f = match module.functions.get(f_index) {
+            Some(f) => f,
+            None => return Err(Error::new(ErrorKind::Runtime,
+                               format!("There is no function with index {}", f_index)))
+        };
+        let call = ast::Call {
+            alias: None,
+            name: f.name.clone(),
+            f_index: ast::FnIndexCell::new(FnIndex::Loaded(f_index as isize)),
+            args: args.into_iter()
+                    .map(|arg| ast::Expression::Variable(Range::empty(0), arg))
+                    .collect(),
+            custom_source: None,
+            source_range: Range::empty(0),
+        };
+        let cl = self.call_stack.len();
+        let st = self.stack.len();
+        let lc = self.local_stack.len();
+        let cu = self.current_stack.len();
+        // The index is not relative to the current function,
+        // since this can be called from an external function.
+        let loader = true;
+        let (x, _) = match self.call_internal(&call, loader, &module) {
+            Ok(x) => x,
+            Err(err) => {
+                let err = self.take_error(err);
+                // Leave the runtime ready for the next call.
+                self.call_stack.truncate(cl);
+                self.stack.truncate(st);
+                self.local_stack.truncate(lc);
+                self.current_stack.truncate(cu);
+                return Err(err);
+            }
+        };
+        Ok(x.map(|x| self.resolve(&x).deep_clone(&self.stack)))
+    }
+
     fn block(
         &mut self,
         block: &ast::Block,
@@ -632,92 +1241,478 @@ impl Runtime {
         Ok((expect, Flow::Continue))
     }
 
-    pub fn go(&mut self, go: &ast::Go, module: &Arc<Module>) -> Result<(Option<Variable>, Flow), String> {
-        use std::thread::{self, JoinHandle};
-        use std::cell::Cell;
-        use Thread;
-
-        let n = go.call.args.len();
-        let mut stack = vec![];
-        let relative = self.call_stack.last().map(|c| c.index).unwrap();
-        let mut fake_call = ast::Call {
-            alias: go.call.alias.clone(),
-            name: go.call.name.clone(),
-            f_index: Cell::new(module.find_function(&go.call.name, relative)),
-            args: Vec::with_capacity(n),
-            custom_source: None,
-            source_range: go.call.source_range,
-        };
-        // Evaluate the arguments and put a deep clone on the new stack.
-        // This prevents the arguments from containing any reference to other variables.
-        for (i, arg) in go.call.args.iter().enumerate() {
-            let v = match try!(self.expression(arg, Side::Right, module)) {
-                (Some(x), Flow::Continue) => x,
-                (x, Flow::Return) => { return Ok((x, Flow::Return)); }
-                _ => return Err(module.error(arg.source_range(),
-                                &format!("{}\nExpected something. \
-                                Expression did not return a value.",
-                                self.stack_trace()), self))
-            };
-            stack.push(v.deep_clone(&self.stack));
-            fake_call.args.push(ast::Expression::Variable(
-                go.call.args[i].source_range(), Variable::Ref(n-i-1)));
+    /// Runs compiled function or closure body.
+    ///
+    /// Expects the return value, arguments and currents to be on the stack.
+    /// The root is the body the code was compiled from.
+    pub fn run_code(
+        &mut self,
+        code: &bytecode::Code,
+        root: bytecode::Node,
+        module: &Arc<Module>
+    ) -> Result<(Option<Variable>, Flow), String> {
+        let rb = self.registers.len();
+        for _ in 0..code.registers {
+            self.registers.push(None);
         }
-        stack.reverse();
-
-        let last_call = self.call_stack.last().unwrap();
-        let new_rt = Runtime {
-            stack: stack,
-            local_stack: vec![],
-            current_stack: vec![],
-            // Add last call because of loaded functions
-            // use relative index to the function it is calling from.
-            call_stack: vec![Call {
-                fn_name: last_call.fn_name.clone(),
-                index: last_call.index,
-                file: last_call.file.clone(),
-                stack_len: 0,
-                local_len: 0,
-                current_len: 0,
-            }],
-            rng: self.rng.clone(),
-            ret: self.ret.clone(),
-            ref_type: self.ref_type.clone(),
-            option_type: self.option_type.clone(),
-            array_type: self.array_type.clone(),
-            link_type: self.link_type.clone(),
-            bool_type: self.bool_type.clone(),
-            object_type: self.object_type.clone(),
-            text_type: self.text_type.clone(),
-            f64_type: self.f64_type.clone(),
-            thread_type: self.thread_type.clone(),
-            unsafe_ref_type: self.unsafe_ref_type.clone(),
-            return_type: self.return_type.clone(),
-            rust_object_type: self.rust_object_type.clone(),
-            vec4_type: self.vec4_type.clone(),
-            result_type: self.result_type.clone(),
-            closure_type: self.closure_type.clone(),
-        };
-        let new_module: Module = (**module).clone();
-        let handle: JoinHandle<Result<Variable, String>> = thread::spawn(move || {
-            let mut new_rt = new_rt;
-            let new_module = Arc::new(new_module);
-            let fake_call = fake_call;
-            let loader = false;
-            Ok(match new_rt.call_internal(&fake_call, loader, &new_module) {
-                Err(err) => return Err(err),
-                Ok((None, _)) => {
-                    new_rt.stack.pop().expect(TINVOTS)
-                }
-                Ok((Some(x), _)) => x,
-            }.deep_clone(&new_rt.stack))
-        });
-        Ok((Some(Variable::Thread(Thread::new(handle))), Flow::Continue))
+        let res = self.run_ops(code, root, rb, module);
+        self.registers.truncate(rb);
+        res
     }
 
-    pub fn call_closure(
+    fn run_ops(
         &mut self,
-        call: &ast::CallClosure,
+        code: &bytecode::Code,
+        root: bytecode::Node,
+        rb: usize,
+        module: &Arc<Module>
+    ) -> Result<(Option<Variable>, Flow), String> {
+        use bytecode::{Accumulate, Op};
+
+        let base = self.stack.len() - code.frame;
+        let lbase = self.local_stack.len() - code.frame;
+        let cbase = self.current_stack.len();
+        let mut pc = 0;
+        while pc < code.ops.len() {
+            match code.ops[pc] {
+                Op::Empty(dst) => self.registers[rb + dst] = None,
+                Op::Number(dst, val) => self.registers[rb + dst] = Some(Variable::f64(val)),
+                Op::Bool(dst, val) => self.registers[rb + dst] = Some(Variable::bool(val)),
+                Op::Text(dst, ref text) => {
+                    self.registers[rb + dst] = Some(Variable::Text(text.clone()))
+                }
+                Op::Local(dst, slot) => {
+                    let id = base + slot;
+                    let id = if let Variable::Ref(ref_id) = self.stack[id] {
+                        ref_id
+                    } else {
+                        id
+                    };
+                    self.registers[rb + dst] = Some(Variable::Ref(id));
+                }
+                Op::Item { dst, slot, ids, len, expr } => {
+                    let item = match *root.find(&code.paths[expr]) {
+                        ast::Expression::Item(ref item) => item,
+                        _ => panic!("Expected item")
+                    };
+                    let id = base + slot;
+                    let id = if let Variable::Ref(ref_id) = self.stack[id] {
+                        ref_id
+                    } else {
+                        id
+                    };
+                    let st = self.stack.len();
+                    for i in 0..len {
+                        let v = self.registers[rb + ids + i].take().expect(TINVIR);
+                        self.stack.push(v);
+                    }
+                    match try!(self.item_ids(item, id, st, Side::Right, module)) {
+                        (x, Flow::Continue) => self.registers[rb + dst] = x,
+                        (x, flow) => return Ok((x, flow)),
+                    }
+                }
+                Op::Eval { dst, expr, loops } => {
+                    match try!(self.expression(root.find(&code.paths[expr]),
+                                               Side::Right, module)) {
+                        (x, Flow::Continue) => self.registers[rb + dst] = x,
+                        (x, Flow::Return) => return Ok((x, Flow::Return)),
+                        (_, Flow::Break(label)) => {
+                            if let Some(jump) = code.jump(loops, &label, true) {
+                                pc = jump;
+                                continue;
+                            }
+                            return Ok((None, Flow::Break(label)));
+                        }
+                        (_, Flow::ContinueLoop(label)) => {
+                            if let Some(jump) = code.jump(loops, &label, false) {
+                                pc = jump;
+                                continue;
+                            }
+                            return Ok((None, Flow::ContinueLoop(label)));
+                        }
+                    }
+                }
+                Op::PushReturn => self.stack.push(Variable::Return),
+                Op::Push(src) => {
+                    let v = self.registers[rb + src].take().expect(TINVIR);
+                    self.stack.push(v);
+                }
+                Op::Call { dst, ref call, len } => {
+                    if let Some(msg) = self.step() {
+                        return Err(self.limit_error(call.source_range, msg, module));
+                    }
+                    if let Some(ref mut profiler) = self.profiler {
+                        profiler.step(call.source_range, &self.call_stack);
+                    }
+                    let new_index = match call.f_index.get() {
+                        FnIndex::Loaded(f_index) => {
+                            let relative = self.call_stack.last().map(|c| c.index).unwrap_or(0);
+                            (f_index + relative as isize) as usize
+                        }
+                        _ => panic!("Expected loaded function")
+                    };
+                    match try!(self.call_loaded(call, new_index, Some(len), module)) {
+                        (x, Flow::Continue) => self.registers[rb + dst] = x,
+                        (x, flow) => return Ok((x, flow)),
+                    }
+                }
+                Op::Expect { src, source_range, msg } => {
+                    if self.registers[rb + src].is_none() {
+                        return Err(module.error(source_range,
+                            &format!("{}\n{}", self.stack_trace(), msg), self))
+                    }
+                }
+                Op::ExpectNone { src, source_range, msg } => {
+                    if self.registers[rb + src].is_some() {
+                        return Err(module.error(source_range,
+                            &format!("{}\n{}", self.stack_trace(), msg), self))
+                    }
+                }
+                Op::ToNumber { src, source_range } => {
+                    let val = match self.registers[rb + src] {
+                        Some(ref x) => match self.resolve(x) {
+                            &Variable::F64(val, _) => val,
+                            x => return Err(module.error(source_range,
+                                            &self.expected(x, "number"), self))
+                        },
+                        None => return Err(module.error(source_range,
+                            &format!("{}\nExpected number", self.stack_trace()), self))
+                    };
+                    self.registers[rb + src] = Some(Variable::f64(val));
+                }
+                Op::BinOp { dst, op, left, right, source_range } => {
+                    let v = match (&self.registers[rb + left], &self.registers[rb + right]) {
+                        (&Some(ref a), &Some(ref b)) =>
+                            try!(self.binop_values(op, a, b, source_range, module)),
+                        _ => panic!(TINVIR)
+                    };
+                    self.registers[rb + dst] = Some(v);
+                }
+                Op::Lazy { dst, left, val, jump } => {
+                    let sec = match self.registers[rb + left] {
+                        Some(ref x) => match self.resolve(x) {
+                            &Variable::Bool(b, ref sec) if b == val => Some(sec.clone()),
+                            _ => None
+                        },
+                        None => None
+                    };
+                    if let Some(sec) = sec {
+                        self.registers[rb + dst] = Some(Variable::Bool(val, sec));
+                        pc = jump;
+                        continue;
+                    }
+                }
+                Op::Compare { dst, op, left, right, source_range } => {
+                    let v = match (&self.registers[rb + left], &self.registers[rb + right]) {
+                        (&Some(ref a), &Some(ref b)) =>
+                            try!(self.compare_values(op, source_range, a, b, module)),
+                        _ => panic!(TINVIR)
+                    };
+                    self.registers[rb + dst] = Some(v);
+                }
+                Op::UnOp { dst, op, src, source_range } => {
+                    let v = match self.registers[rb + src] {
+                        Some(ref x) => try!(self.unop_value(op, x, source_range, module)),
+                        None => panic!(TINVIR)
+                    };
+                    self.registers[rb + dst] = Some(v);
+                }
+                Op::Norm { dst, src, source_range } => {
+                    let v = match self.registers[rb + src] {
+                        Some(ref x) => match self.resolve(x) {
+                            &Variable::Vec4(b) => Variable::f64(
+                                (b[0] * b[0] + b[1] * b[1] + b[2] * b[2]).sqrt() as f64),
+                            x => return Err(module.error(source_range,
+                                            &self.expected(x, "vec4"), self))
+                        },
+                        None => panic!(TINVIR)
+                    };
+                    self.registers[rb + dst] = Some(v);
+                }
+                Op::Declare { ref name, current, src } => {
+                    let v = match self.registers[rb + src].take().expect(TINVIR) {
+                        // Use a shallow clone of a reference.
+                        Variable::Ref(ind) => self.stack[ind].clone(),
+                        x => x
+                    };
+                    self.local_stack.push((name.clone(), self.stack.len()));
+                    if current {
+                        self.current_stack.push((name.clone(), self.stack.len()));
+                    }
+                    self.stack.push(v);
+                }
+                Op::Assign { op, slot, src, source_range } => {
+                    let b = self.registers[rb + src].take().expect(TINVIR);
+                    let id = base + slot;
+                    let id = if let Variable::Ref(ref_id) = self.stack[id] {
+                        ref_id
+                    } else {
+                        id
+                    };
+                    let r = UnsafeRef(&mut self.stack[id] as *mut Variable);
+                    try!(self.assign_op(op, r, &b, source_range, module));
+                }
+                Op::AssignItem { op, slot, ids, len, expr, src, source_range } => {
+                    let b = match self.registers[rb + src].take().expect(TINVIR) {
+                        // Use a shallow clone of a reference.
+                        Variable::Ref(ind) if op == ast::AssignOp::Assign =>
+                            self.stack[ind].clone(),
+                        x => x
+                    };
+                    let item = match *root.find(&code.paths[expr]) {
+                        ast::Expression::Item(ref item) => item,
+                        _ => panic!("Expected item")
+                    };
+                    let id = base + slot;
+                    let id = if let Variable::Ref(ref_id) = self.stack[id] {
+                        ref_id
+                    } else {
+                        id
+                    };
+                    let st = self.stack.len();
+                    for i in 0..len {
+                        let v = self.registers[rb + ids + i].take().expect(TINVIR);
+                        self.stack.push(v);
+                    }
+                    let side = Side::LeftInsert(op == ast::AssignOp::Assign);
+                    let a = match try!(self.item_ids(item, id, st, side, module)) {
+                        (Some(x), Flow::Continue) => x,
+                        (x, flow) => return Ok((x, flow)),
+                    };
+                    if op == ast::AssignOp::Assign {
+                        match a {
+                            Variable::UnsafeRef(r) => unsafe { *r.0 = b },
+                            _ => panic!("Expected unsafe reference")
+                        }
+                    } else {
+                        try!(self.assign_ref(op, a, &b, source_range, module));
+                    }
+                }
+                Op::Truncate { locals, currents } => {
+                    self.stack.truncate(base + locals);
+                    self.local_stack.truncate(lbase + locals);
+                    self.current_stack.truncate(cbase + currents);
+                }
+                Op::Jump(jump) => {
+                    pc = jump;
+                    continue;
+                }
+                Op::Step(source_range) => {
+                    if let Some(msg) = self.step() {
+                        return Err(self.limit_error(source_range, msg, module));
+                    }
+                    if let Some(ref mut profiler) = self.profiler {
+                        profiler.step(source_range, &self.call_stack);
+                    }
+                }
+                Op::JumpIfNot { cond, jump, source_range, msg } => {
+                    let val = match self.registers[rb + cond] {
+                        Some(ref x) => match self.resolve(x) {
+                            &Variable::Bool(val, _) => Some(val),
+                            _ => None
+                        },
+                        None => None
+                    };
+                    match val {
+                        Some(true) => {}
+                        Some(false) => {
+                            pc = jump;
+                            continue;
+                        }
+                        None => return Err(module.error(source_range,
+                            &format!("{}\n{}", self.stack_trace(), msg), self))
+                    }
+                }
+                Op::Counter { ref name, start } => {
+                    let start = match start.map(|r| &self.registers[rb + r]) {
+                        Some(&Some(Variable::F64(val, _))) => val,
+                        _ => 0.0
+                    };
+                    self.local_stack.push((name.clone(), self.stack.len()));
+                    self.stack.push(Variable::f64(start));
+                }
+                Op::CounterCond { slot, end, jump, source_range } => {
+                    let end = match self.registers[rb + end] {
+                        Some(Variable::F64(end, _)) => end,
+                        _ => panic!(TINVIR)
+                    };
+                    match &self.stack[base + slot] {
+                        &Variable::F64(val, _) => {
+                            if !(val < end) {
+                                pc = jump;
+                                continue;
+                            }
+                        }
+                        x => return Err(module.error(source_range,
+                                        &self.expected(x, "number"), self))
+                    }
+                }
+                Op::Increment { slot, source_range } => {
+                    let error = if let Variable::F64(ref mut val, _) = self.stack[base + slot] {
+                        *val += 1.0;
+                        false
+                    } else { true };
+                    if error {
+                        return Err(module.error(source_range,
+                                   &self.expected(&self.stack[base + slot], "number"), self))
+                    }
+                }
+                Op::Init { dst, acc } => {
+                    self.registers[rb + dst] = match acc {
+                        Accumulate::None => None,
+                        Accumulate::Sum => Some(Variable::f64(0.0)),
+                        Accumulate::Sift => Some(Variable::Array(Arc::new(vec![]))),
+                    };
+                }
+                Op::Accumulate { dst, acc, src, source_range } => {
+                    let x = self.registers[rb + src].take();
+                    match acc {
+                        Accumulate::None => {}
+                        Accumulate::Sum => {
+                            let val = match x {
+                                Some(ref x) => match self.resolve(x) {
+                                    &Variable::F64(val, _) => val,
+                                    x => return Err(module.error(source_range,
+                                            &self.expected(x, "number"), self))
+                                },
+                                None => return Err(module.error(source_range,
+                                            "Expected `number`", self))
+                            };
+                            if let Some(Variable::F64(ref mut sum, _)) =
+                                self.registers[rb + dst] {
+                                *sum += val;
+                            }
+                        }
+                        Accumulate::Sift => {
+                            let x = match x {
+                                Some(x) => x,
+                                None => return Err(module.error(source_range,
+                                            "Expected variable", self))
+                            };
+                            if let Some(Variable::Array(ref mut arr)) =
+                                self.registers[rb + dst] {
+                                Arc::make_mut(arr).push(x);
+                            }
+                            if let Some(msg) = self.registers[rb + dst].as_ref()
+                                .and_then(|v| self.check_alloc(v, size_of::<Variable>())) {
+                                return Err(self.limit_error(source_range, msg, module));
+                            }
+                        }
+                    }
+                }
+                Op::Return(src) => {
+                    return Ok((self.registers[rb + src].take(), Flow::Return));
+                }
+                Op::ReturnVoid => return Ok((None, Flow::Return)),
+                Op::Break(ref label) => return Ok((None, Flow::Break(label.clone()))),
+                Op::Continue(ref label) => {
+                    return Ok((None, Flow::ContinueLoop(label.clone())))
+                }
+            }
+            pc += 1;
+        }
+        Ok((self.registers[rb].take(), Flow::Continue))
+    }
+
+    pub fn go(&mut self, go: &ast::Go, module: &Arc<Module>) -> Result<(Option<Variable>, Flow), String> {
+        use Thread;
+
+        if !module.capabilities.threads {
+            return Err(module.error(go.source_range,
+                &format!("{}\n`go` is disabled, requires capability `threads`",
+                    self.stack_trace()), self));
+        }
+        let n = go.call.args.len();
+        let mut stack = vec![];
+        let relative = self.call_stack.last().map(|c| c.index).unwrap();
+        let mut fake_call = ast::Call {
+            alias: go.call.alias.clone(),
+            name: go.call.name.clone(),
+            f_index: ast::FnIndexCell::new(module.find_function(&go.call.name, relative)),
+            args: Vec::with_capacity(n),
+            custom_source: None,
+            source_range: go.call.source_range,
+        };
+        // Evaluate the arguments and put a deep clone on the new stack.
+        // This prevents the arguments from containing any reference to other variables.
+        for (i, arg) in go.call.args.iter().enumerate() {
+            let v = match try!(self.expression(arg, Side::Right, module)) {
+                (Some(x), Flow::Continue) => x,
+                (x, Flow::Return) => { return Ok((x, Flow::Return)); }
+                _ => return Err(module.error(arg.source_range(),
+                                &format!("{}\nExpected something. \
+                                Expression did not return a value.",
+                                self.stack_trace()), self))
+            };
+            stack.push(v.deep_clone(&self.stack));
+            fake_call.args.push(ast::Expression::Variable(
+                go.call.args[i].source_range(), Variable::Ref(n-i-1)));
+        }
+        stack.reverse();
+
+        let last_call = self.call_stack.last().unwrap();
+        let new_rt = Runtime {
+            stack: stack,
+            local_stack: vec![],
+            current_stack: vec![],
+            registers: vec![],
+            // Threads have their own step counter,
+            // but share deadline and cancel handle.
+            steps: 0,
+            max_steps: self.max_steps,
+            deadline: self.deadline,
+            cancel: self.cancel.clone(),
+            limits: self.limits.clone(),
+            heap: Cell::new(0),
+            heap_measured: Cell::new(0),
+            debugger: match self.debugger {
+                Some(ref mut debugger) => debugger.thread(),
+                None => None,
+            },
+            profiler: None,
+            last_error: RefCell::new(None),
+            last_err_value: Cell::new(None),
+            ctx: self.thread_ctx(),
+            // Add last call because of loaded functions
+            // use relative index to the function it is calling from.
+            call_stack: vec![Call {
+                fn_name: last_call.fn_name.clone(),
+                index: last_call.index,
+                file: last_call.file.clone(),
+                stack_len: 0,
+                local_len: 0,
+                current_len: 0,
+            }],
+            rng: self.rng.clone(),
+            ret: self.ret.clone(),
+            ref_type: self.ref_type.clone(),
+            option_type: self.option_type.clone(),
+            array_type: self.array_type.clone(),
+            link_type: self.link_type.clone(),
+            bool_type: self.bool_type.clone(),
+            object_type: self.object_type.clone(),
+            text_type: self.text_type.clone(),
+            f64_type: self.f64_type.clone(),
+            thread_type: self.thread_type.clone(),
+            channel_type: self.channel_type.clone(),
+            unsafe_ref_type: self.unsafe_ref_type.clone(),
+            return_type: self.return_type.clone(),
+            rust_object_type: self.rust_object_type.clone(),
+            vec4_type: self.vec4_type.clone(),
+            result_type: self.result_type.clone(),
+            closure_type: self.closure_type.clone(),
+        };
+        let handle = pool::spawn(Box::new(GoJob {
+            rt: new_rt,
+            call: fake_call,
+            module: module.clone(),
+        }));
+        Ok((Some(Variable::Thread(Thread::new(handle))), Flow::Continue))
+    }
+
+    pub fn call_closure(
+        &mut self,
+        call: &ast::CallClosure,
         module: &Arc<Module>
     ) -> Result<(Option<Variable>, Flow), String> {
         // Find item.
@@ -743,6 +1738,12 @@ impl Runtime {
                 f.args.len(),
                 call.arg_len()), self));
         }
+        if let Some(max_call_depth) = self.limits.max_call_depth {
+            if self.call_stack.len() >= max_call_depth {
+                return Err(self.limit_error(call.source_range,
+                    "Maximum call depth exceeded", module));
+            }
+        }
         // Arguments must be computed.
         if f.returns() {
             // Add return value before arguments on the stack.
@@ -764,28 +1765,52 @@ impl Runtime {
             };
         }
 
+        self.call_closure_frame(call.item.name.clone(), &f, &env, (st, lc, cu),
+                                Some(call.source_range), module)
+            .map(|x| (x, Flow::Continue))
+    }
+
+    /// Runs the body of a closure, with the arguments on the stack.
+    ///
+    /// Expects the return value and the arguments to be pushed,
+    /// where `st, lc, cu` are the lengths of the stacks before the arguments.
+    /// Errors are reported at the call site, or at the closure when called from Rust.
+    fn call_closure_frame(
+        &mut self,
+        name: Arc<String>,
+        f: &ast::Closure,
+        env: &::ClosureEnvironment,
+        (st, lc, cu): (usize, usize, usize),
+        site: Option<Range>,
+        module: &Arc<Module>
+    ) -> Result<Option<Variable>, String> {
+        let error = |rt: &Runtime, msg: String| {
+            let msg = format!("{}\n{}", rt.stack_trace(), msg);
+            match site {
+                Some(range) => module.error(range, &msg, rt),
+                None => module.error_source(f.source_range, &msg, &f.source),
+            }
+        };
+
         // Look for variable in current stack.
-        if f.currents.len() > 0 {
-            for current in &f.currents {
-                let mut res = None;
-                for &(ref cname, ind) in self.current_stack.iter().rev() {
-                    if cname == &current.name {
-                        res = Some(ind);
-                        break;
-                    }
-                }
-                if let Some(ind) = res {
-                    self.local_stack.push((current.name.clone(), self.stack.len()));
-                    self.stack.push(Variable::Ref(ind));
-                } else {
-                    return Err(module.error(call.source_range, &format!(
-                        "{}\nCould not find current variable `{}`",
-                            self.stack_trace(), current.name), self));
+        for current in &f.currents {
+            let mut res = None;
+            for &(ref cname, ind) in self.current_stack.iter().rev() {
+                if cname == &current.name {
+                    res = Some(ind);
+                    break;
                 }
             }
+            if let Some(ind) = res {
+                self.local_stack.push((current.name.clone(), self.stack.len()));
+                self.stack.push(Variable::Ref(ind));
+            } else {
+                return Err(error(self,
+                    format!("Could not find current variable `{}`", current.name)));
+            }
         }
 
-        self.push_fn(call.item.name.clone(), env.relative, Some(f.file.clone()), st, lc, cu);
+        self.push_fn(name.clone(), env.relative, Some(f.file.clone()), st, lc, cu);
         if f.returns() {
             self.local_stack.push((self.ret.clone(), st - 1));
         }
@@ -793,66 +1818,119 @@ impl Runtime {
             // Do not resolve locals to keep fixed length from end of stack.
             self.local_stack.push((arg.name.clone(), st + i));
         }
-        let (x, flow) = try!(self.expression(&f.expr, Side::Right, &env.module));
+        let (x, flow) = match f.code {
+            Some(ref code) if self.debugger.is_none() =>
+                try!(self.run_code(code, bytecode::Node::Expr(&f.expr), &env.module)),
+            _ => try!(self.expression(&f.expr, Side::Right, &env.module)),
+        };
         match flow {
             Flow::Break(None) =>
-                return Err(module.error(call.source_range,
-                           &format!("{}\nCan not break from function",
-                                self.stack_trace()), self)),
+                return Err(error(self, format!("Can not break from function"))),
             Flow::ContinueLoop(None) =>
-                return Err(module.error(call.source_range,
-                           &format!("{}\nCan not continue from function",
-                                self.stack_trace()), self)),
-            Flow::Break(Some(ref label)) =>
-                return Err(module.error(call.source_range,
-                    &format!("{}\nThere is no loop labeled `{}`",
-                             self.stack_trace(), label), self)),
-            Flow::ContinueLoop(Some(ref label)) =>
-                return Err(module.error(call.source_range,
-                    &format!("{}\nThere is no loop labeled `{}`",
-                            self.stack_trace(), label), self)),
+                return Err(error(self, format!("Can not continue from function"))),
+            Flow::Break(Some(ref label)) | Flow::ContinueLoop(Some(ref label)) =>
+                return Err(error(self, format!("There is no loop labeled `{}`", label))),
             _ => {}
         }
-        self.pop_fn(call.item.name.clone());
+        self.pop_call(&name);
         match (f.returns(), x) {
             (true, None) => {
                 match self.stack.pop().expect(TINVOTS) {
                     Variable::Return => {
-                        return Err(module.error(
-                            call.source_range, &format!(
-                            "{}\nFunction `{}` did not return a value",
-                            self.stack_trace(),
-                            call.item.name), self))
-                    }
-                    x => {
-                        // This happens when return is only
-                        // assigned to `return = x`.
-                        return Ok((Some(x), Flow::Continue))
+                        Err(error(self, format!("Function `{}` did not return a value", name)))
                     }
-                };
+                    // This happens when return is only assigned to `return = x`.
+                    x => Ok(Some(x))
+                }
             }
             (false, Some(_)) => {
-                return Err(module.error(call.source_range,
-                    &format!(
-                        "{}\nFunction `{}` should not return a value",
-                        self.stack_trace(),
-                        call.item.name), self))
+                Err(error(self, format!("Function `{}` should not return a value", name)))
             }
             (true, Some(Variable::Return)) => {
                 // TODO: Could return the last value on the stack.
                 //       Requires .pop_fn delayed after.
-                return Err(module.error(call.source_range,
-                    &format!(
-                    "{}\nFunction `{}` did not return a value. \
-                    Did you forget a `return`?",
-                        self.stack_trace(),
-                        call.item.name), self))
+                Err(error(self, format!(
+                    "Function `{}` did not return a value. Did you forget a `return`?", name)))
             }
             (returns, b) => {
                 if returns { self.stack.pop(); }
-                return Ok((b, Flow::Continue))
+                Ok(b)
+            }
+        }
+    }
+
+    /// Calls a closure from Rust, e.g. a callback registered by a script.
+    ///
+    /// The closure runs in the module it was created in.
+    /// Returns the value returned from the closure, if any.
+    pub fn call_closure_value(
+        &mut self,
+        closure: &Variable,
+        args: Vec<Variable>,
+        module: &Arc<Module>
+    ) -> Result<Option<Variable>, Error> {
+        self.enter();
+        let cl = self.call_stack.len();
+        let st = self.stack.len();
+        let lc = self.local_stack.len();
+        let cu = self.current_stack.len();
+        match self.call_closure_value_internal(closure, args, module) {
+            Ok(x) => Ok(x),
+            Err(err) => {
+                let err = self.take_error(err);
+                // Leave the runtime ready for the next call.
+                self.call_stack.truncate(cl);
+                self.stack.truncate(st);
+                self.local_stack.truncate(lc);
+                self.current_stack.truncate(cu);
+                Err(err)
+            }
+        }
+    }
+
+    /// Calls a closure from Rust with typed arguments and return value.
+    pub fn call_closure_ret<T: embed::PopVariable>(
+        &mut self,
+        closure: &Variable,
+        args: &[&embed::PushVariable],
+        module: &Arc<Module>
+    ) -> Result<T, Error> {
+        let args = args.iter().map(|arg| arg.push_var()).collect();
+        match try!(self.call_closure_value(closure, args, module)) {
+            Some(x) => T::pop_var(self, &x),
+            None => Err(Error::new(ErrorKind::Runtime,
+                                   "Expected closure to return a value".into())),
+        }
+    }
+
+    fn call_closure_value_internal(
+        &mut self,
+        closure: &Variable,
+        args: Vec<Variable>,
+        module: &Arc<Module>
+    ) -> Result<Option<Variable>, String> {
+        let name: Arc<String> = Arc::new("closure".into());
+        let (f, env) = match self.resolve(closure) {
+            &Variable::Closure(ref f, ref env) => (f.clone(), env.clone()),
+            x => return Err(format!("Expected `closure`, found `{}`", self.typeof_var(x)))
+        };
+        if args.len() != f.args.len() {
+            return Err(format!("Expected {} arguments but found {}", f.args.len(), args.len()));
+        }
+        if let Some(max_call_depth) = self.limits.max_call_depth {
+            if self.call_stack.len() >= max_call_depth {
+                return Err(format!("{}\nMaximum call depth exceeded", self.stack_trace()));
             }
         }
+        if f.returns() {
+            self.stack.push(Variable::Return);
+        }
+        let st = self.stack.len();
+        let lc = self.local_stack.len();
+        let cu = self.current_stack.len();
+        self.stack.extend(args);
+        let x = try!(self.call_closure_frame(name, &f, &env, (st, lc, cu), None, module));
+        Ok(x.map(|x| self.resolve(&x).deep_clone(&self.stack)))
     }
 
     /// Called from the outside, e.g. a loader script by `call` or `call_ret` intrinsic.
@@ -875,13 +1953,23 @@ impl Runtime {
         loader: bool,
         module: &Arc<Module>
     ) -> Result<(Option<Variable>, Flow), String> {
-        use FnExternalRef;
-
-        match call.f_index.get() {
+        let f_index = call.f_index.get();
+        match f_index {
             FnIndex::Intrinsic(index) => {
-                intrinsics::call_standard(self, index, call, module)
+                let res = intrinsics::call_standard(self, index, call, module);
+                match res {
+                    // Some intrinsics create errors without a location.
+                    Err(_) => self.record_error(ErrorKind::Runtime, call.source_range, module),
+                    // Remember where `err` values are created, in case they are returned to Rust.
+                    Ok((Some(Variable::Result(Err(_))), _)) => {
+                        let index = self.call_stack.last().map(|c| c.index).unwrap_or(0);
+                        self.last_err_value.set(Some((index, call.source_range)));
+                    }
+                    _ => {}
+                }
+                res
             }
-            FnIndex::ExternalVoid(FnExternalRef(f)) => {
+            FnIndex::ExternalVoid(ref f) => {
                 for arg in &call.args {
                     match try!(self.expression(arg, Side::Right, module)) {
                         (Some(x), Flow::Continue) => self.stack.push(x),
@@ -892,11 +1980,15 @@ impl Runtime {
                                         self.stack_trace()), self))
                     };
                 }
-                try!((f)(self).map_err(|err|
-                    module.error(call.source_range, &err, self)));
+                if let Err(err) = f.call(self) {
+                    self.record_error(ErrorKind::External, call.source_range, module);
+                    return Err(module.error(call.source_range, &err, self));
+                }
+                // The external function handled errors of scripts it called.
+                self.clear_error();
                 return Ok((None, Flow::Continue));
             }
-            FnIndex::ExternalReturn(FnExternalRef(f)) => {
+            FnIndex::ExternalReturn(ref f) => {
                 for arg in &call.args {
                     match try!(self.expression(arg, Side::Right, module)) {
                         (Some(x), Flow::Continue) => self.stack.push(x),
@@ -907,8 +1999,12 @@ impl Runtime {
                                         self.stack_trace()), self))
                     };
                 }
-                try!((f)(self).map_err(|err|
-                    module.error(call.source_range, &err, self)));
+                if let Err(err) = f.call(self) {
+                    self.record_error(ErrorKind::External, call.source_range, module);
+                    return Err(module.error(call.source_range, &err, self));
+                }
+                // The external function handled errors of scripts it called.
+                self.clear_error();
                 return Ok((Some(self.stack.pop().expect(TINVOTS)), Flow::Continue));
             }
             FnIndex::Loaded(f_index) => {
@@ -916,139 +2012,166 @@ impl Runtime {
                     self.call_stack.last().map(|c| c.index).unwrap_or(0)
                 };
                 let new_index = (f_index + relative as isize) as usize;
-                let f = &module.functions[new_index];
-                if call.arg_len() != f.args.len() {
-                    return Err(module.error(call.source_range,
-                        &format!("{}\nExpected {} arguments but found {}",
-                        self.stack_trace(),
-                        f.args.len(),
-                        call.arg_len()), self));
-                }
-                // Arguments must be computed.
-                if f.returns() {
-                    // Add return value before arguments on the stack.
-                    // The stack value should remain, but the local should not.
-                    self.stack.push(Variable::Return);
-                }
-                let st = self.stack.len();
-                let lc = self.local_stack.len();
-                let cu = self.current_stack.len();
-                for arg in &call.args {
-                    match try!(self.expression(arg, Side::Right, module)) {
-                        (Some(x), Flow::Continue) => self.stack.push(x),
-                        (None, Flow::Continue) => {}
-                        (x, Flow::Return) => { return Ok((x, Flow::Return)); }
-                        _ => return Err(module.error(arg.source_range(),
-                                        &format!("{}\nExpected something. \
-                                        Check that expression returns a value.",
-                                        self.stack_trace()), self))
-                    };
-                }
+                self.call_loaded(call, new_index, None, module)
+            }
+            FnIndex::None => {
+                return Err(module.error(call.source_range,
+                    &format!("{}\nUnknown function `{}`", self.stack_trace(), call.name), self))
+            }
+        }
+    }
 
-                // Look for variable in current stack.
-                if f.currents.len() > 0 {
-                    for current in &f.currents {
-                        let mut res = None;
-                        for &(ref cname, ind) in self.current_stack.iter().rev() {
-                            if cname == &current.name {
-                                res = Some(ind);
-                                break;
-                            }
-                        }
-                        if let Some(ind) = res {
-                            self.local_stack.push((current.name.clone(), self.stack.len()));
-                            self.stack.push(Variable::Ref(ind));
-                        } else {
-                            return Err(module.error(call.source_range, &format!(
-                                "{}\nCould not find current variable `{}`",
-                                    self.stack_trace(), current.name), self));
-                        }
-                    }
-                }
-
-                self.push_fn(call.name.clone(), new_index, Some(f.file.clone()), st, lc, cu);
-                if f.returns() {
-                    self.local_stack.push((self.ret.clone(), st - 1));
-                }
-                for (i, arg) in f.args.iter().enumerate() {
-                    // Do not resolve locals to keep fixed length from end of stack.
-                    self.local_stack.push((arg.name.clone(), st + i));
-                }
-                let (x, flow) = try!(self.block(&f.block, module));
-                match flow {
-                    Flow::Break(None) =>
-                        return Err(module.error(call.source_range,
-                                   &format!("{}\nCan not break from function",
-                                        self.stack_trace()), self)),
-                    Flow::ContinueLoop(None) =>
-                        return Err(module.error(call.source_range,
-                                   &format!("{}\nCan not continue from function",
-                                        self.stack_trace()), self)),
-                    Flow::Break(Some(ref label)) =>
-                        return Err(module.error(call.source_range,
-                            &format!("{}\nThere is no loop labeled `{}`",
-                                     self.stack_trace(), label), self)),
-                    Flow::ContinueLoop(Some(ref label)) =>
-                        return Err(module.error(call.source_range,
-                            &format!("{}\nThere is no loop labeled `{}`",
-                                    self.stack_trace(), label), self)),
-                    _ => {}
-                }
-                self.pop_fn(call.name.clone());
-                match (f.returns(), x) {
-                    (true, None) => {
-                        match self.stack.pop().expect(TINVOTS) {
-                            Variable::Return => {
-                                let source = call.custom_source.as_ref().unwrap_or(
-                                    &module.functions[
-                                        self.call_stack.last().unwrap().index
-                                    ].source
-                                );
-                                return Err(module.error_source(
-                                call.source_range, &format!(
-                                "{}\nFunction `{}` did not return a value",
-                                self.stack_trace(),
-                                f.name), source))
-                            }
-                            x => {
-                                // This happens when return is only
-                                // assigned to `return = x`.
-                                return Ok((Some(x), Flow::Continue))
-                            }
-                        };
-                    }
-                    (false, Some(_)) => {
-                        let source = call.custom_source.as_ref().unwrap_or(
-                            &module.functions[self.call_stack.last().unwrap().index].source
-                        );
-                        return Err(module.error_source(call.source_range,
-                            &format!(
-                                "{}\nFunction `{}` should not return a value",
-                                self.stack_trace(),
-                                f.name), source))
+    /// Calls loaded function at `new_index` in the module.
+    ///
+    /// When `pushed` is set, the bytecode has already pushed
+    /// the return value slot and this number of arguments to the stack.
+    fn call_loaded(
+        &mut self,
+        call: &ast::Call,
+        new_index: usize,
+        pushed: Option<usize>,
+        module: &Arc<Module>
+    ) -> Result<(Option<Variable>, Flow), String> {
+        let f = &module.functions[new_index];
+        let arg_len = pushed.unwrap_or_else(|| call.arg_len());
+        if arg_len != f.args.len() {
+            return Err(module.error(call.source_range,
+                &format!("{}\nExpected {} arguments but found {}",
+                self.stack_trace(),
+                f.args.len(),
+                arg_len), self));
+        }
+        if let Some(max_call_depth) = self.limits.max_call_depth {
+            if self.call_stack.len() >= max_call_depth {
+                return Err(self.limit_error(call.source_range,
+                    "Maximum call depth exceeded", module));
+            }
+        }
+        // Arguments must be computed.
+        if f.returns() && pushed.is_none() {
+            // Add return value before arguments on the stack.
+            // The stack value should remain, but the local should not.
+            self.stack.push(Variable::Return);
+        }
+        let st = self.stack.len() - pushed.unwrap_or(0);
+        let lc = self.local_stack.len();
+        let cu = self.current_stack.len();
+        if pushed.is_none() {
+            for arg in &call.args {
+                match try!(self.expression(arg, Side::Right, module)) {
+                    (Some(x), Flow::Continue) => self.stack.push(x),
+                    (None, Flow::Continue) => {}
+                    (x, Flow::Return) => { return Ok((x, Flow::Return)); }
+                    _ => return Err(module.error(arg.source_range(),
+                                    &format!("{}\nExpected something. \
+                                    Check that expression returns a value.",
+                                    self.stack_trace()), self))
+                };
+            }
+        }
+
+        // Look for variable in current stack.
+        if f.currents.len() > 0 {
+            for current in &f.currents {
+                let mut res = None;
+                for &(ref cname, ind) in self.current_stack.iter().rev() {
+                    if cname == &current.name {
+                        res = Some(ind);
+                        break;
                     }
-                    (true, Some(Variable::Return)) => {
-                        // TODO: Could return the last value on the stack.
-                        //       Requires .pop_fn delayed after.
+                }
+                if let Some(ind) = res {
+                    self.local_stack.push((current.name.clone(), self.stack.len()));
+                    self.stack.push(Variable::Ref(ind));
+                } else {
+                    return Err(module.error(call.source_range, &format!(
+                        "{}\nCould not find current variable `{}`",
+                            self.stack_trace(), current.name), self));
+                }
+            }
+        }
+
+        self.push_fn(call.name.clone(), new_index, Some(f.file.clone()), st, lc, cu);
+        if f.returns() {
+            self.local_stack.push((self.ret.clone(), st - 1));
+        }
+        for (i, arg) in f.args.iter().enumerate() {
+            // Do not resolve locals to keep fixed length from end of stack.
+            self.local_stack.push((arg.name.clone(), st + i));
+        }
+        let (x, flow) = match f.code {
+            Some(ref code) if self.debugger.is_none() =>
+                try!(self.run_code(code, bytecode::Node::Block(&f.block), module)),
+            _ => try!(self.block(&f.block, module)),
+        };
+        match flow {
+            Flow::Break(None) =>
+                return Err(module.error(call.source_range,
+                           &format!("{}\nCan not break from function",
+                                self.stack_trace()), self)),
+            Flow::ContinueLoop(None) =>
+                return Err(module.error(call.source_range,
+                           &format!("{}\nCan not continue from function",
+                                self.stack_trace()), self)),
+            Flow::Break(Some(ref label)) =>
+                return Err(module.error(call.source_range,
+                    &format!("{}\nThere is no loop labeled `{}`",
+                             self.stack_trace(), label), self)),
+            Flow::ContinueLoop(Some(ref label)) =>
+                return Err(module.error(call.source_range,
+                    &format!("{}\nThere is no loop labeled `{}`",
+                            self.stack_trace(), label), self)),
+            _ => {}
+        }
+        self.pop_call(&call.name);
+        match (f.returns(), x) {
+            (true, None) => {
+                match self.stack.pop().expect(TINVOTS) {
+                    Variable::Return => {
                         let source = call.custom_source.as_ref().unwrap_or(
-                            &module.functions[self.call_stack.last().unwrap().index].source
+                            &module.functions[
+                                self.call_stack.last().unwrap().index
+                            ].source
                         );
-                        return Err(module.error_source(call.source_range,
-                            &format!(
-                            "{}\nFunction `{}` did not return a value. \
-                            Did you forget a `return`?",
-                                self.stack_trace(),
-                                f.name), source))
+                        return Err(module.error_source(
+                        call.source_range, &format!(
+                        "{}\nFunction `{}` did not return a value",
+                        self.stack_trace(),
+                        f.name), source))
                     }
-                    (returns, b) => {
-                        if returns { self.stack.pop(); }
-                        return Ok((b, Flow::Continue))
+                    x => {
+                        // This happens when return is only
+                        // assigned to `return = x`.
+                        return Ok((Some(x), Flow::Continue))
                     }
-                }
+                };
             }
-            FnIndex::None => {
-                return Err(module.error(call.source_range,
-                    &format!("{}\nUnknown function `{}`", self.stack_trace(), call.name), self))
+            (false, Some(_)) => {
+                let source = call.custom_source.as_ref().unwrap_or(
+                    &module.functions[self.call_stack.last().unwrap().index].source
+                );
+                return Err(module.error_source(call.source_range,
+                    &format!(
+                        "{}\nFunction `{}` should not return a value",
+                        self.stack_trace(),
+                        f.name), source))
+            }
+            (true, Some(Variable::Return)) => {
+                // TODO: Could return the last value on the stack.
+                //       Requires .pop_fn delayed after.
+                let source = call.custom_source.as_ref().unwrap_or(
+                    &module.functions[self.call_stack.last().unwrap().index].source
+                );
+                return Err(module.error_source(call.source_range,
+                    &format!(
+                    "{}\nFunction `{}` did not return a value. \
+                    Did you forget a `return`?",
+                        self.stack_trace(),
+                        f.name), source))
+            }
+            (returns, b) => {
+                if returns { self.stack.pop(); }
+                return Ok((b, Flow::Continue))
             }
         }
     }
@@ -1059,26 +2182,15 @@ impl Runtime {
         function: &str,
         args: &[Variable],
         module: &Arc<Module>
-    ) -> Result<(), String> {
-        use std::cell::Cell;
-
+    ) -> Result<(), Error> {
         let name: Arc<String> = Arc::new(function.into());
         match module.find_function(&name, 0) {
             FnIndex::Loaded(f_index) => {
-                let call = ast::Call {
-                    alias: None,
-                    name: name.clone(),
-                    f_index: Cell::new(FnIndex::Loaded(f_index)),
-                    args: args.iter()
-                            .map(|arg| ast::Expression::Variable(Range::empty(0), arg.clone()))
-                            .collect(),
-                    custom_source: None,
-                    source_range: Range::empty(0),
-                };
-                try!(self.call(&call, &module));
+                try!(self.call_index(f_index as usize, args.to_vec(), module));
                 Ok(())
             }
-            _ => return Err(format!("Could not find function `{}`",function))
+            _ => return Err(Error::new(ErrorKind::Runtime,
+                            format!("Could not find function `{}`",function)))
         }
     }
 
@@ -1120,6 +2232,7 @@ impl Runtime {
             let lc = self.local_stack.len();
             let cu = self.current_stack.len();
             let mut new_link = Link::new();
+            let mut len = 0;
             for item in &link.items {
                 let v = match try!(self.expression(item, Side::Right, module)) {
                     (Some(x), Flow::Continue) => x,
@@ -1134,6 +2247,9 @@ impl Runtime {
                     }
                     Ok(()) => {}
                 }
+                if let Some(msg) = self.check_link_push(&mut len, self.resolve(&v)) {
+                    return Err(self.limit_error(item.source_range(), msg, module));
+                }
             }
             self.stack.truncate(st);
             self.local_stack.truncate(lc);
@@ -1162,6 +2278,9 @@ impl Runtime {
                     &format!("{}\nDuplicate key in object `{}`",
                         self.stack_trace(), key), self))
             }
+            if let Some(msg) = self.alloc(key.len() + size_of::<Variable>()) {
+                return Err(self.limit_error(expr.source_range(), msg, module));
+            }
         }
         Ok((Some(Variable::Object(Arc::new(object))), Flow::Continue))
     }
@@ -1181,7 +2300,12 @@ impl Runtime {
                         self.stack_trace()), self))
             });
         }
-        Ok((Some(Variable::Array(Arc::new(array))), Flow::Continue))
+        let bytes = array.len() * size_of::<Variable>();
+        let v = Variable::Array(Arc::new(array));
+        if let Some(msg) = self.check_alloc(&v, bytes) {
+            return Err(self.limit_error(arr.source_range, msg, module));
+        }
+        Ok((Some(v), Flow::Continue))
     }
 
     fn array_fill(
@@ -1203,6 +2327,17 @@ impl Runtime {
                             &format!("{}\nExpected something",
                                 self.stack_trace()), self))
         };
+        // Check limits before allocating memory.
+        if let &Variable::F64(n, _) = self.resolve(&n) {
+            let n = n as usize;
+            if self.limits.max_array_len.map(|max| n > max).unwrap_or(false) {
+                return Err(self.limit_error(array_fill.n.source_range(),
+                    "Array length exceeds limit", module));
+            }
+            if let Some(msg) = self.alloc(n.saturating_mul(size_of::<Variable>())) {
+                return Err(self.limit_error(array_fill.n.source_range(), msg, module));
+            }
+        }
         let v = match (self.resolve(&fill), self.resolve(&n)) {
             (x, &Variable::F64(n, _)) => {
                 Variable::Array(Arc::new(vec![x.clone(); n as usize]))
@@ -1242,464 +2377,495 @@ impl Runtime {
                         &format!("{}\nExpected something from the left side",
                             self.stack_trace()), self))
             };
-            let mut r = match a {
-                Variable::UnsafeRef(mut r) => {
-                    // If reference, use a shallow clone to type check,
-                    // without affecting the original object.
-                    unsafe {
-                        if let Variable::Ref(ind) = *r.0 {
-                            *r.0 = self.stack[ind].clone()
+            try!(self.assign_ref(op, a, &b, left.source_range(), module));
+            Ok((None, Flow::Continue))
+        } else {
+            return match *left {
+                Expression::Item(ref item) => {
+                    let x = match try!(self.expression(right, Side::Right, module)) {
+                        (x, Flow::Return) => return Ok((x, Flow::Return)),
+                        (Some(x), Flow::Continue) => x,
+                        _ => return Err(module.error(right.source_range(),
+                                    &format!("{}\nExpected something from the right side",
+                                        self.stack_trace()), self))
+                    };
+                    let v = match x {
+                        // Use a shallow clone of a reference.
+                        Variable::Ref(ind) => self.stack[ind].clone(),
+                        x => x
+                    };
+                    if item.ids.len() != 0 {
+                        let x = match try!(self.expression(left, Side::LeftInsert(true),
+                                                   module)) {
+                            (Some(x), Flow::Continue) => x,
+                            (x, Flow::Return) => return Ok((x, Flow::Return)),
+                            _ => return Err(module.error(left.source_range(),
+                                    &format!("{}\nExpected something from the left side",
+                                        self.stack_trace()), self))
+                        };
+                        match x {
+                            Variable::UnsafeRef(mut r) => {
+                                unsafe { *r.0 = v }
+                            }
+                            _ => panic!("Expected unsafe reference")
                         }
+                    } else {
+                        self.local_stack.push((item.name.clone(), self.stack.len()));
+                        if item.current {
+                            self.current_stack.push((item.name.clone(), self.stack.len()));
+                        }
+                        self.stack.push(v);
+                    }
+                    Ok((None, Flow::Continue))
+                }
+                _ => return Err(module.error(left.source_range(),
+                                &format!("{}\nExpected item",
+                                    self.stack_trace()), self))
+            }
+        }
+    }
+    /// Assigns value to the left side of an assignment,
+    /// using assignment operator other than `:=`.
+    fn assign_ref(
+        &mut self,
+        op: ast::AssignOp,
+        a: Variable,
+        b: &Variable,
+        source_range: Range,
+        module: &Arc<Module>
+    ) -> Result<(), String> {
+        let r = match a {
+            Variable::UnsafeRef(mut r) => {
+                // If reference, use a shallow clone to type check,
+                // without affecting the original object.
+                unsafe {
+                    if let Variable::Ref(ind) = *r.0 {
+                        *r.0 = self.stack[ind].clone()
                     }
-                    r
-                }
-                Variable::Ref(ind) => {
-                    UnsafeRef(&mut self.stack[ind] as *mut Variable)
                 }
-                x => panic!("Expected reference, found `{}`", self.typeof_var(&x))
-            };
+                r
+            }
+            Variable::Ref(ind) => {
+                UnsafeRef(&mut self.stack[ind] as *mut Variable)
+            }
+            x => panic!("Expected reference, found `{}`", self.typeof_var(&x))
+        };
+        self.assign_op(op, r, b, source_range, module)
+    }
 
-            match *self.resolve(&b) {
-                Variable::F64(b, ref sec) => {
-                    unsafe {
-                        match *r.0 {
-                            Variable::F64(ref mut n, ref mut n_sec) => {
-                                match op {
-                                    Set => *n = b,
-                                    Add => *n += b,
-                                    Sub => *n -= b,
-                                    Mul => *n *= b,
-                                    Div => *n /= b,
-                                    Rem => *n %= b,
-                                    Pow => *n = n.powf(b),
-                                    Assign => {}
-                                };
-                                *n_sec = sec.clone()
-                            }
-                            Variable::Return => {
-                                if let Set = op {
-                                    *r.0 = Variable::F64(b, sec.clone())
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nReturn has no value",
-                                            self.stack_trace()), self))
-                                }
+    /// Assigns value to reference using assignment operator other than `:=`.
+    fn assign_op(
+        &self,
+        op: ast::AssignOp,
+        r: UnsafeRef,
+        b: &Variable,
+        source_range: Range,
+        module: &Arc<Module>
+    ) -> Result<(), String> {
+        use ast::AssignOp::*;
+
+        match *self.resolve(b) {
+            Variable::F64(b, ref sec) => {
+                unsafe {
+                    match *r.0 {
+                        Variable::F64(ref mut n, ref mut n_sec) => {
+                            match op {
+                                Set => *n = b,
+                                Add => *n += b,
+                                Sub => *n -= b,
+                                Mul => *n *= b,
+                                Div => *n /= b,
+                                Rem => *n %= b,
+                                Pow => *n = n.powf(b),
+                                Assign => {}
+                            };
+                            *n_sec = sec.clone()
+                        }
+                        Variable::Return => {
+                            if let Set = op {
+                                *r.0 = Variable::F64(b, sec.clone())
+                            } else {
+                                return Err(module.error(
+                                    source_range,
+                                    &format!("{}\nReturn has no value",
+                                        self.stack_trace()), self))
                             }
-                            Variable::Link(ref mut n) => {
-                                if let Add = op {
-                                    try!(n.push(&Variable::f64(b)));
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nCan not use this assignment \
-                                        operator with `link` and `number`",
-                                            self.stack_trace()), self));
-                                }
+                        }
+                        Variable::Link(ref mut n) => {
+                            if let Add = op {
+                                try!(n.push(&Variable::f64(b)));
+                            } else {
+                                return Err(module.error(
+                                    source_range,
+                                    &format!("{}\nCan not use this assignment \
+                                    operator with `link` and `number`",
+                                        self.stack_trace()), self));
                             }
-                            _ => return Err(module.error(
-                                    left.source_range(),
-                                    &format!("{}\nExpected assigning to a number",
-                                        self.stack_trace()), self))
-                        };
-                    }
+                        }
+                        _ => return Err(module.error(
+                                source_range,
+                                &format!("{}\nExpected assigning to a number",
+                                    self.stack_trace()), self))
+                    };
                 }
-                Variable::Vec4(b) => {
-                    unsafe {
-                        match *r.0 {
-                            Variable::Vec4(ref mut n) => {
-                                match op {
-                                    Set => *n = b,
-                                    Add => *n = [n[0] + b[0], n[1] + b[1],
-                                                 n[2] + b[2], n[3] + b[3]],
-                                    Sub => *n = [n[0] - b[0], n[1] - b[1],
-                                                 n[2] - b[2], n[3] - b[3]],
-                                    Mul => *n = [n[0] * b[0], n[1] * b[1],
-                                                 n[2] * b[2], n[3] * b[3]],
-                                    Div => *n = [n[0] / b[0], n[1] / b[1],
-                                                 n[2] / b[2], n[3] / b[3]],
-                                    Rem => *n = [n[0] % b[0], n[1] % b[1],
-                                                 n[2] % b[2], n[3] % b[3]],
-                                    Pow => *n = [n[0].powf(b[0]), n[1].powf(b[1]),
-                                                 n[2].powf(b[2]), n[3].powf(b[3])],
-                                    Assign => {}
-                                }
-                            }
-                            Variable::Return => {
-                                if let Set = op {
-                                    *r.0 = Variable::Vec4(b)
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nReturn has no value",
-                                            self.stack_trace()), self))
-                                }
+            }
+            Variable::Vec4(b) => {
+                unsafe {
+                    match *r.0 {
+                        Variable::Vec4(ref mut n) => {
+                            match op {
+                                Set => *n = b,
+                                Add => *n = [n[0] + b[0], n[1] + b[1],
+                                             n[2] + b[2], n[3] + b[3]],
+                                Sub => *n = [n[0] - b[0], n[1] - b[1],
+                                             n[2] - b[2], n[3] - b[3]],
+                                Mul => *n = [n[0] * b[0], n[1] * b[1],
+                                             n[2] * b[2], n[3] * b[3]],
+                                Div => *n = [n[0] / b[0], n[1] / b[1],
+                                             n[2] / b[2], n[3] / b[3]],
+                                Rem => *n = [n[0] % b[0], n[1] % b[1],
+                                             n[2] % b[2], n[3] % b[3]],
+                                Pow => *n = [n[0].powf(b[0]), n[1].powf(b[1]),
+                                             n[2].powf(b[2]), n[3].powf(b[3])],
+                                Assign => {}
                             }
-                            _ => return Err(module.error(
-                                    left.source_range(),
-                                    &format!("{}\nExpected assigning to a vec4",
+                        }
+                        Variable::Return => {
+                            if let Set = op {
+                                *r.0 = Variable::Vec4(b)
+                            } else {
+                                return Err(module.error(
+                                    source_range,
+                                    &format!("{}\nReturn has no value",
                                         self.stack_trace()), self))
-                        };
-                    }
+                            }
+                        }
+                        _ => return Err(module.error(
+                                source_range,
+                                &format!("{}\nExpected assigning to a vec4",
+                                    self.stack_trace()), self))
+                    };
                 }
-                Variable::Bool(b, ref sec) => {
-                    unsafe {
-                        match *r.0 {
-                            Variable::Bool(ref mut n, ref mut n_sec) => {
-                                match op {
-                                    Set => *n = b,
-                                    _ => unimplemented!()
-                                };
-                                *n_sec = sec.clone();
-                            }
-                            Variable::Return => {
-                                if let Set = op {
-                                    *r.0 = Variable::Bool(b, sec.clone())
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nReturn has no value",
-                                            self.stack_trace()), self))
-                                }
+            }
+            Variable::Bool(b, ref sec) => {
+                unsafe {
+                    match *r.0 {
+                        Variable::Bool(ref mut n, ref mut n_sec) => {
+                            match op {
+                                Set => *n = b,
+                                _ => unimplemented!()
+                            };
+                            *n_sec = sec.clone();
+                        }
+                        Variable::Return => {
+                            if let Set = op {
+                                *r.0 = Variable::Bool(b, sec.clone())
+                            } else {
+                                return Err(module.error(
+                                    source_range,
+                                    &format!("{}\nReturn has no value",
+                                        self.stack_trace()), self))
                             }
-                            Variable::Link(ref mut n) => {
-                                if let Add = op {
-                                    try!(n.push(&Variable::bool(b)));
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nCan not use this assignment \
-                                        operator with `link` and `bool`",
-                                            self.stack_trace()), self));
-                                }
+                        }
+                        Variable::Link(ref mut n) => {
+                            if let Add = op {
+                                try!(n.push(&Variable::bool(b)));
+                            } else {
+                                return Err(module.error(
+                                    source_range,
+                                    &format!("{}\nCan not use this assignment \
+                                    operator with `link` and `bool`",
+                                        self.stack_trace()), self));
                             }
-                            _ => return Err(module.error(
-                                    left.source_range(),
-                                    &format!("{}\nExpected assigning to a bool",
-                                        self.stack_trace()), self))
-                        };
-                    }
+                        }
+                        _ => return Err(module.error(
+                                source_range,
+                                &format!("{}\nExpected assigning to a bool",
+                                    self.stack_trace()), self))
+                    };
                 }
-                Variable::Text(ref b) => {
-                    unsafe {
-                        match *r.0 {
-                            Variable::Text(ref mut n) => {
-                                match op {
-                                    Set => *n = b.clone(),
-                                    Add => Arc::make_mut(n).push_str(b),
-                                    _ => unimplemented!()
-                                }
+            }
+            Variable::Text(ref b) => {
+                unsafe {
+                    match *r.0 {
+                        Variable::Text(ref mut n) => {
+                            match op {
+                                Set => *n = b.clone(),
+                                Add => Arc::make_mut(n).push_str(b),
+                                _ => unimplemented!()
                             }
-                            Variable::Return => {
-                                if let Set = op {
-                                    *r.0 = Variable::Text(b.clone())
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nReturn has no value",
-                                            self.stack_trace()), self))
-                                }
+                        }
+                        Variable::Return => {
+                            if let Set = op {
+                                *r.0 = Variable::Text(b.clone())
+                            } else {
+                                return Err(module.error(
+                                    source_range,
+                                    &format!("{}\nReturn has no value",
+                                        self.stack_trace()), self))
                             }
-                            Variable::Link(ref mut n) => {
-                                if let Add = op {
-                                    try!(n.push(&Variable::Text(b.clone())));
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nCan not use this assignment \
-                                        operator with `link` and `text`",
-                                            self.stack_trace()), self));
-                                }
+                        }
+                        Variable::Link(ref mut n) => {
+                            if let Add = op {
+                                try!(n.push(&Variable::Text(b.clone())));
+                            } else {
+                                return Err(module.error(
+                                    source_range,
+                                    &format!("{}\nCan not use this assignment \
+                                    operator with `link` and `text`",
+                                        self.stack_trace()), self));
                             }
-                            _ => return Err(module.error(
-                                left.source_range(),
-                                &format!("{}\nExpected assigning to text",
-                                    self.stack_trace()), self))
                         }
+                        _ => return Err(module.error(
+                            source_range,
+                            &format!("{}\nExpected assigning to text",
+                                self.stack_trace()), self))
                     }
                 }
-                Variable::Object(ref b) => {
-                    unsafe {
-                        match *r.0 {
-                            Variable::Object(ref mut n) => {
-                                if let Set = op {
-                                    // Check address to avoid unsafe
-                                    // reading and writing to same memory.
-                                    let n_addr = n as *const _ as usize;
-                                    let b_addr = b as *const _ as usize;
-                                    if n_addr != b_addr {
-                                        *r.0 = Variable::Object(b.clone())
-                                    }
-                                    // *n = obj.clone()
-                                } else {
-                                    unimplemented!()
-                                }
-                            }
-                            Variable::Return => {
-                                if let Set = op {
+            }
+            Variable::Object(ref b) => {
+                unsafe {
+                    match *r.0 {
+                        Variable::Object(ref mut n) => {
+                            if let Set = op {
+                                // Check address to avoid unsafe
+                                // reading and writing to same memory.
+                                let n_addr = n as *const _ as usize;
+                                let b_addr = b as *const _ as usize;
+                                if n_addr != b_addr {
                                     *r.0 = Variable::Object(b.clone())
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nReturn has no value",
-                                            self.stack_trace()), self))
                                 }
+                                // *n = obj.clone()
+                            } else {
+                                unimplemented!()
+                            }
+                        }
+                        Variable::Return => {
+                            if let Set = op {
+                                *r.0 = Variable::Object(b.clone())
+                            } else {
+                                return Err(module.error(
+                                    source_range,
+                                    &format!("{}\nReturn has no value",
+                                        self.stack_trace()), self))
                             }
-                            _ => return Err(module.error(
-                                left.source_range(),
-                                &format!("{}\nExpected assigning to object",
-                                    self.stack_trace()), self))
                         }
+                        _ => return Err(module.error(
+                            source_range,
+                            &format!("{}\nExpected assigning to object",
+                                self.stack_trace()), self))
                     }
                 }
-                Variable::Array(ref b) => {
-                    unsafe {
-                        match *r.0 {
-                            Variable::Array(ref mut n) => {
-                                if let Set = op {
-                                    // Check address to avoid unsafe
-                                    // reading and writing to same memory.
-                                    let n_addr = n as *const _ as usize;
-                                    let b_addr = b as *const _ as usize;
-                                    if n_addr != b_addr {
-                                        *r.0 = Variable::Array(b.clone())
-                                    }
-                                    // *n = arr.clone();
-                                } else {
-                                    unimplemented!()
-                                }
-                            }
-                            Variable::Return => {
-                                if let Set = op {
+            }
+            Variable::Array(ref b) => {
+                unsafe {
+                    match *r.0 {
+                        Variable::Array(ref mut n) => {
+                            if let Set = op {
+                                // Check address to avoid unsafe
+                                // reading and writing to same memory.
+                                let n_addr = n as *const _ as usize;
+                                let b_addr = b as *const _ as usize;
+                                if n_addr != b_addr {
                                     *r.0 = Variable::Array(b.clone())
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nReturn has no value",
-                                            self.stack_trace()), self))
                                 }
+                                // *n = arr.clone();
+                            } else {
+                                unimplemented!()
+                            }
+                        }
+                        Variable::Return => {
+                            if let Set = op {
+                                *r.0 = Variable::Array(b.clone())
+                            } else {
+                                return Err(module.error(
+                                    source_range,
+                                    &format!("{}\nReturn has no value",
+                                        self.stack_trace()), self))
                             }
-                            _ => return Err(module.error(
-                                left.source_range(),
-                                &format!("{}\nExpected assigning to array",
-                                    self.stack_trace()), self))
                         }
+                        _ => return Err(module.error(
+                            source_range,
+                            &format!("{}\nExpected assigning to array",
+                                self.stack_trace()), self))
                     }
                 }
-                Variable::Link(ref b) => {
-                    unsafe {
-                        match *r.0 {
-                            Variable::Link(ref mut n) => {
-                                match op {
-                                    Set => *n = b.clone(),
-                                    Add => **n = n.add(b),
-                                    Sub => **n = b.add(n),
-                                    _ => unimplemented!()
-                                }
+            }
+            Variable::Link(ref b) => {
+                unsafe {
+                    match *r.0 {
+                        Variable::Link(ref mut n) => {
+                            match op {
+                                Set => *n = b.clone(),
+                                Add => **n = n.add(b),
+                                Sub => **n = b.add(n),
+                                _ => unimplemented!()
                             }
-                            Variable::Return => {
-                                if let Set = op {
-                                    *r.0 = Variable::Link(b.clone())
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nReturn has no value",
-                                            self.stack_trace()), self))
-                                }
+                        }
+                        Variable::Return => {
+                            if let Set = op {
+                                *r.0 = Variable::Link(b.clone())
+                            } else {
+                                return Err(module.error(
+                                    source_range,
+                                    &format!("{}\nReturn has no value",
+                                        self.stack_trace()), self))
                             }
-                            _ => return Err(module.error(
-                                left.source_range(),
-                                &format!("{}\nExpected assigning to link",
-                                    self.stack_trace()), self))
                         }
+                        _ => return Err(module.error(
+                            source_range,
+                            &format!("{}\nExpected assigning to link",
+                                self.stack_trace()), self))
                     }
                 }
-                Variable::Option(ref b) => {
-                    unsafe {
-                        match *r.0 {
-                            Variable::Option(ref mut n) => {
-                                if let Set = op {
-                                    // Check address to avoid unsafe
-                                    // reading and writing to same memory.
-                                    let n_addr = n as *const _ as usize;
-                                    let b_addr = b as *const _ as usize;
-                                    if n_addr != b_addr {
-                                        *r.0 = Variable::Option(b.clone())
-                                    }
-                                } else {
-                                    unimplemented!()
-                                }
-                            }
-                            Variable::Return => {
-                                if let Set = op {
+            }
+            Variable::Option(ref b) => {
+                unsafe {
+                    match *r.0 {
+                        Variable::Option(ref mut n) => {
+                            if let Set = op {
+                                // Check address to avoid unsafe
+                                // reading and writing to same memory.
+                                let n_addr = n as *const _ as usize;
+                                let b_addr = b as *const _ as usize;
+                                if n_addr != b_addr {
                                     *r.0 = Variable::Option(b.clone())
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nReturn has no value",
-                                            self.stack_trace()), self))
                                 }
+                            } else {
+                                unimplemented!()
+                            }
+                        }
+                        Variable::Return => {
+                            if let Set = op {
+                                *r.0 = Variable::Option(b.clone())
+                            } else {
+                                return Err(module.error(
+                                    source_range,
+                                    &format!("{}\nReturn has no value",
+                                        self.stack_trace()), self))
                             }
-                            _ => return Err(module.error(
-                                left.source_range(),
-                                &format!("{}\nExpected assigning to option",
-                                    self.stack_trace()), self))
                         }
+                        _ => return Err(module.error(
+                            source_range,
+                            &format!("{}\nExpected assigning to option",
+                                self.stack_trace()), self))
                     }
                 }
-                Variable::Result(ref b) => {
-                    unsafe {
-                        match *r.0 {
-                            Variable::Result(ref mut n) => {
-                                if let Set = op {
-                                    // Check address to avoid unsafe
-                                    // reading and writing to same memory.
-                                    let n_addr = n as *const _ as usize;
-                                    let b_addr = b as *const _ as usize;
-                                    if n_addr != b_addr {
-                                        *r.0 = Variable::Result(b.clone())
-                                    }
-                                } else {
-                                    unimplemented!()
-                                }
-                            }
-                            Variable::Return => {
-                                if let Set = op {
+            }
+            Variable::Result(ref b) => {
+                unsafe {
+                    match *r.0 {
+                        Variable::Result(ref mut n) => {
+                            if let Set = op {
+                                // Check address to avoid unsafe
+                                // reading and writing to same memory.
+                                let n_addr = n as *const _ as usize;
+                                let b_addr = b as *const _ as usize;
+                                if n_addr != b_addr {
                                     *r.0 = Variable::Result(b.clone())
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nReturn has no value",
-                                            self.stack_trace()), self))
                                 }
+                            } else {
+                                unimplemented!()
+                            }
+                        }
+                        Variable::Return => {
+                            if let Set = op {
+                                *r.0 = Variable::Result(b.clone())
+                            } else {
+                                return Err(module.error(
+                                    source_range,
+                                    &format!("{}\nReturn has no value",
+                                        self.stack_trace()), self))
                             }
-                            _ => return Err(module.error(
-                                left.source_range(),
-                                &format!("{}\nExpected assigning to result",
-                                    self.stack_trace()), self))
                         }
+                        _ => return Err(module.error(
+                            source_range,
+                            &format!("{}\nExpected assigning to result",
+                                self.stack_trace()), self))
                     }
                 }
-                Variable::RustObject(ref b) => {
-                    unsafe {
-                        match *r.0 {
-                            Variable::RustObject(ref mut n) => {
-                                if let Set = op {
-                                    // Check address to avoid unsafe
-                                    // reading and writing to same memory.
-                                    let n_addr = n as *const _ as usize;
-                                    let b_addr = b as *const _ as usize;
-                                    if n_addr != b_addr {
-                                        *r.0 = Variable::RustObject(b.clone())
-                                    }
-                                } else {
-                                    unimplemented!()
-                                }
-                            }
-                            Variable::Return => {
-                                if let Set = op {
+            }
+            Variable::RustObject(ref b) => {
+                unsafe {
+                    match *r.0 {
+                        Variable::RustObject(ref mut n) => {
+                            if let Set = op {
+                                // Check address to avoid unsafe
+                                // reading and writing to same memory.
+                                let n_addr = n as *const _ as usize;
+                                let b_addr = b as *const _ as usize;
+                                if n_addr != b_addr {
                                     *r.0 = Variable::RustObject(b.clone())
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nReturn has no value",
-                                            self.stack_trace()), self))
                                 }
+                            } else {
+                                unimplemented!()
+                            }
+                        }
+                        Variable::Return => {
+                            if let Set = op {
+                                *r.0 = Variable::RustObject(b.clone())
+                            } else {
+                                return Err(module.error(
+                                    source_range,
+                                    &format!("{}\nReturn has no value",
+                                        self.stack_trace()), self))
                             }
-                            _ => return Err(module.error(
-                                left.source_range(),
-                                &format!(
-                                    "{}\nExpected assigning to rust_object",
-                                    self.stack_trace()), self))
                         }
+                        _ => return Err(module.error(
+                            source_range,
+                            &format!(
+                                "{}\nExpected assigning to rust_object",
+                                self.stack_trace()), self))
                     }
                 }
-                Variable::Closure(ref b, ref env) => {
-                    unsafe {
-                        match *r.0 {
-                            Variable::Closure(ref mut n, _) => {
-                                if let Set = op {
-                                    // Check address to avoid unsafe
-                                    // reading and writing to same memory.
-                                    let n_addr = n as *const _ as usize;
-                                    let b_addr = b as *const _ as usize;
-                                    if n_addr != b_addr {
-                                        *r.0 = Variable::Closure(b.clone(), env.clone())
-                                    }
-                                } else {
-                                    unimplemented!()
-                                }
-                            }
-                            Variable::Return => {
-                                if let Set = op {
+            }
+            Variable::Closure(ref b, ref env) => {
+                unsafe {
+                    match *r.0 {
+                        Variable::Closure(ref mut n, _) => {
+                            if let Set = op {
+                                // Check address to avoid unsafe
+                                // reading and writing to same memory.
+                                let n_addr = n as *const _ as usize;
+                                let b_addr = b as *const _ as usize;
+                                if n_addr != b_addr {
                                     *r.0 = Variable::Closure(b.clone(), env.clone())
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nReturn has no value",
-                                            self.stack_trace()), self))
                                 }
+                            } else {
+                                unimplemented!()
                             }
-                            _ => return Err(module.error(
-                                left.source_range(),
-                                &format!(
-                                    "{}\nExpected assigning to closure",
-                                    self.stack_trace()), self))
                         }
-                    }
-                }
-                ref x => {
-                    return Err(module.error(
-                        left.source_range(),
-                        &format!("{}\nCan not use this assignment operator with `{}`",
-                            self.stack_trace(), self.typeof_var(x)), self));
-                }
-            };
-            Ok((None, Flow::Continue))
-        } else {
-            return match *left {
-                Expression::Item(ref item) => {
-                    let x = match try!(self.expression(right, Side::Right, module)) {
-                        (x, Flow::Return) => return Ok((x, Flow::Return)),
-                        (Some(x), Flow::Continue) => x,
-                        _ => return Err(module.error(right.source_range(),
-                                    &format!("{}\nExpected something from the right side",
-                                        self.stack_trace()), self))
-                    };
-                    let v = match x {
-                        // Use a shallow clone of a reference.
-                        Variable::Ref(ind) => self.stack[ind].clone(),
-                        x => x
-                    };
-                    if item.ids.len() != 0 {
-                        let x = match try!(self.expression(left, Side::LeftInsert(true),
-                                                   module)) {
-                            (Some(x), Flow::Continue) => x,
-                            (x, Flow::Return) => return Ok((x, Flow::Return)),
-                            _ => return Err(module.error(left.source_range(),
-                                    &format!("{}\nExpected something from the left side",
+                        Variable::Return => {
+                            if let Set = op {
+                                *r.0 = Variable::Closure(b.clone(), env.clone())
+                            } else {
+                                return Err(module.error(
+                                    source_range,
+                                    &format!("{}\nReturn has no value",
                                         self.stack_trace()), self))
-                        };
-                        match x {
-                            Variable::UnsafeRef(mut r) => {
-                                unsafe { *r.0 = v }
                             }
-                            _ => panic!("Expected unsafe reference")
-                        }
-                    } else {
-                        self.local_stack.push((item.name.clone(), self.stack.len()));
-                        if item.current {
-                            self.current_stack.push((item.name.clone(), self.stack.len()));
                         }
-                        self.stack.push(v);
+                        _ => return Err(module.error(
+                            source_range,
+                            &format!(
+                                "{}\nExpected assigning to closure",
+                                self.stack_trace()), self))
                     }
-                    Ok((None, Flow::Continue))
                 }
-                _ => return Err(module.error(left.source_range(),
-                                &format!("{}\nExpected item",
-                                    self.stack_trace()), self))
             }
+            ref x => {
+                return Err(module.error(
+                    source_range,
+                    &format!("{}\nCan not use this assignment operator with `{}`",
+                        self.stack_trace(), self.typeof_var(x)), self));
+            }
+        };
+        // Assignment shares memory, while other operators copy the right side.
+        let bytes = if let Set = op { 0 } else { heap_size(self.resolve(b)) };
+        if let Some(msg) = self.check_alloc(unsafe { &*r.0 }, bytes) {
+            return Err(self.limit_error(source_range, msg, module));
         }
+        Ok(())
     }
     // `insert` is true for `:=` and false for `=`.
     // This works only on objects, but does not have to check since it is
@@ -1851,6 +3017,20 @@ impl Runtime {
                 };
             }
         }
+        self.item_ids(item, stack_id, start_stack_len, side, module)
+    }
+
+    /// Looks up the ids of an item, starting with the variable at `stack_id`.
+    ///
+    /// Expects the computed ids to be on the stack, starting at `start_stack_len`.
+    fn item_ids(
+        &mut self,
+        item: &ast::Item,
+        stack_id: usize,
+        start_stack_len: usize,
+        side: Side,
+        module: &Arc<Module>
+    ) -> Result<(Option<Variable>, Flow), String> {
         let &mut Runtime {
             ref mut stack,
             ref mut call_stack,
@@ -2018,6 +3198,7 @@ impl Runtime {
             &Variable::Option(_) => self.option_type.clone(),
             &Variable::Result(_) => self.result_type.clone(),
             &Variable::Thread(_) => self.thread_type.clone(),
+            &Variable::Channel(_) => self.channel_type.clone(),
             &Variable::Closure(_, _) => self.closure_type.clone(),
         };
         match v {
@@ -2031,9 +3212,36 @@ impl Runtime {
         compare: &ast::Compare,
         module: &Arc<Module>
     ) -> Result<(Option<Variable>, Flow), String> {
+        let left = match try!(self.expression(&compare.left, Side::Right, module)) {
+            (Some(x), Flow::Continue) => x,
+            (x, Flow::Return) => { return Ok((x, Flow::Return)); }
+            _ => return Err(module.error(compare.left.source_range(),
+                &format!("{}\nExpected something from the left argument",
+                    self.stack_trace()), self))
+        };
+        let right = match try!(self.expression(&compare.right, Side::Right, module)) {
+            (Some(x), Flow::Continue) => x,
+            (x, Flow::Return) => return Ok((x, Flow::Return)),
+            _ => return Err(module.error(compare.right.source_range(),
+                &format!("{}\nExpected something from the right argument",
+                    self.stack_trace()), self))
+        };
+        Ok((Some(try!(self.compare_values(compare.op, compare.source_range,
+                                          &left, &right, module))), Flow::Continue))
+    }
+    /// Compares two values.
+    fn compare_values(
+        &self,
+        op: ast::CompareOp,
+        source_range: Range,
+        a: &Variable,
+        b: &Variable,
+        module: &Module
+    ) -> Result<Variable, String> {
         fn sub_compare(
             rt: &Runtime,
-            compare: &ast::Compare,
+            op: ast::CompareOp,
+            source_range: Range,
             module: &Module,
             a: &Variable,
             b: &Variable
@@ -2042,7 +3250,7 @@ impl Runtime {
 
             match (rt.resolve(&b), rt.resolve(&a)) {
                 (&Variable::F64(b, _), &Variable::F64(a, ref sec)) => {
-                    Ok(Variable::Bool(match compare.op {
+                    Ok(Variable::Bool(match op {
                         Less => a < b,
                         LessOrEqual => a <= b,
                         Greater => a > b,
@@ -2052,7 +3260,7 @@ impl Runtime {
                     }, sec.clone()))
                 }
                 (&Variable::Text(ref b), &Variable::Text(ref a)) => {
-                    Ok(Variable::bool(match compare.op {
+                    Ok(Variable::bool(match op {
                         Less => a < b,
                         LessOrEqual => a <= b,
                         Greater => a > b,
@@ -2062,33 +3270,33 @@ impl Runtime {
                     }))
                 }
                 (&Variable::Bool(b, _), &Variable::Bool(a, ref sec)) => {
-                    Ok(Variable::Bool(match compare.op {
+                    Ok(Variable::Bool(match op {
                         Equal => a == b,
                         NotEqual => a != b,
-                        x => return Err(module.error(compare.source_range,
+                        x => return Err(module.error(source_range,
                             &format!("{}\n`{}` can not be used with bools",
                                 rt.stack_trace(),
                                 x.symbol()), rt))
                     }, sec.clone()))
                 }
                 (&Variable::Vec4(ref b), &Variable::Vec4(ref a)) => {
-                    Ok(Variable::bool(match compare.op {
+                    Ok(Variable::bool(match op {
                         Equal => a == b,
                         NotEqual => a != b,
-                        x => return Err(module.error(compare.source_range,
+                        x => return Err(module.error(source_range,
                             &format!("{}\n`{}` can not be used with vec4s",
                                 rt.stack_trace(),
                                 x.symbol()), rt))
                     }))
                 }
                 (&Variable::Object(ref b), &Variable::Object(ref a)) => {
-                    Ok(Variable::bool(match compare.op {
+                    Ok(Variable::bool(match op {
                         Equal => {
                             a.len() == b.len() &&
                             a.iter().all(|a| {
                                 if let Some(b_val) = b.get(a.0) {
                                     if let Ok(Variable::Bool(true, _)) =
-                                        sub_compare(rt, compare, module, &a.1, b_val) {true}
+                                        sub_compare(rt, op, source_range, module, &a.1, b_val) {true}
                                     else {false}
                                 } else {false}
                             })
@@ -2098,64 +3306,64 @@ impl Runtime {
                             a.iter().any(|a| {
                                 if let Some(b_val) = b.get(a.0) {
                                     if let Ok(Variable::Bool(false, _)) =
-                                        sub_compare(rt, compare, module, &a.1, b_val) {false}
+                                        sub_compare(rt, op, source_range, module, &a.1, b_val) {false}
                                     else {true}
                                 } else {true}
                             })
                         }
-                        x => return Err(module.error(compare.source_range,
+                        x => return Err(module.error(source_range,
                             &format!("{}\n`{}` can not be used with objects",
                                 rt.stack_trace(),
                                 x.symbol()), rt))
                     }))
                 }
                 (&Variable::Array(ref b), &Variable::Array(ref a)) => {
-                    Ok(Variable::bool(match compare.op {
+                    Ok(Variable::bool(match op {
                         Equal => {
                             a.len() == b.len() &&
                             a.iter().zip(b.iter()).all(|(a, b)| {
                                 if let Ok(Variable::Bool(true, _)) =
-                                    sub_compare(rt, compare, module, a, b) {true} else {false}
+                                    sub_compare(rt, op, source_range, module, a, b) {true} else {false}
                             })
                         }
                         NotEqual => {
                             a.len() != b.len() ||
                             a.iter().zip(b.iter()).any(|(a, b)| {
                                 if let Ok(Variable::Bool(false, _)) =
-                                    sub_compare(rt, compare, module, a, b) {false} else {true}
+                                    sub_compare(rt, op, source_range, module, a, b) {false} else {true}
                             })
                         }
-                        x => return Err(module.error(compare.source_range,
+                        x => return Err(module.error(source_range,
                             &format!("{}\n`{}` can not be used with arrays",
                                 rt.stack_trace(),
                                 x.symbol()), rt))
                     }))
                 }
                 (&Variable::Option(None), &Variable::Option(None)) => {
-                    Ok(Variable::bool(match compare.op {
+                    Ok(Variable::bool(match op {
                         Equal => true,
                         NotEqual => false,
-                        x => return Err(module.error(compare.source_range,
+                        x => return Err(module.error(source_range,
                             &format!("{}\n`{}` can not be used with options",
                                 rt.stack_trace(),
                                 x.symbol()), rt))
                     }))
                 }
                 (&Variable::Option(None), &Variable::Option(_)) => {
-                    Ok(Variable::bool(match compare.op {
+                    Ok(Variable::bool(match op {
                         Equal => false,
                         NotEqual => true,
-                        x => return Err(module.error(compare.source_range,
+                        x => return Err(module.error(source_range,
                             &format!("{}\n`{}` can not be used with options",
                                 rt.stack_trace(),
                                 x.symbol()), rt))
                     }))
                 }
                 (&Variable::Option(_), &Variable::Option(None)) => {
-                    Ok(Variable::bool(match compare.op {
+                    Ok(Variable::bool(match op {
                         Equal => false,
                         NotEqual => true,
-                        x => return Err(module.error(compare.source_range,
+                        x => return Err(module.error(source_range,
                             &format!("{}\n`{}` can not be used with options",
                                 rt.stack_trace(),
                                 x.symbol()), rt))
@@ -2163,33 +3371,20 @@ impl Runtime {
                 }
                 (&Variable::Option(Some(ref b)),
                  &Variable::Option(Some(ref a))) => {
-                    sub_compare(rt, compare, module, a, b)
+                    sub_compare(rt, op, source_range, module, a, b)
                 }
-                (b, a) => return Err(module.error(compare.source_range,
+                (b, a) => return Err(module.error(source_range,
                     &format!(
                     "{}\n`{}` can not be used with `{}` and `{}`",
                     rt.stack_trace(),
-                    compare.op.symbol(),
+                    op.symbol(),
                     rt.typeof_var(a),
                     rt.typeof_var(b)), rt))
             }
         }
 
-        let left = match try!(self.expression(&compare.left, Side::Right, module)) {
-            (Some(x), Flow::Continue) => x,
-            (x, Flow::Return) => { return Ok((x, Flow::Return)); }
-            _ => return Err(module.error(compare.left.source_range(),
-                &format!("{}\nExpected something from the left argument",
-                    self.stack_trace()), self))
-        };
-        let right = match try!(self.expression(&compare.right, Side::Right, module)) {
-            (Some(x), Flow::Continue) => x,
-            (x, Flow::Return) => return Ok((x, Flow::Return)),
-            _ => return Err(module.error(compare.right.source_range(),
-                &format!("{}\nExpected something from the right argument",
-                    self.stack_trace()), self))
-        };
-        Ok((Some(try!(sub_compare(self, compare, module, &left, &right))), Flow::Continue))
+
+        sub_compare(self, op, source_range, module, a, b)
     }
     fn if_expr(
         &mut self,
@@ -3433,6 +4628,7 @@ impl Runtime {
 
         fn sub_link_for_n_expr(
             res: &mut Link,
+            len: &mut usize,
             rt: &mut Runtime,
             for_n_expr: &ast::ForN,
             module: &Arc<Module>
@@ -3503,6 +4699,11 @@ impl Runtime {
                                         }
                                         Ok(()) => {}
                                     }
+                                    if let Some(msg) = rt.check_link_push(len,
+                                                                          rt.resolve(x)) {
+                                        return Err(rt.limit_error(item.source_range(),
+                                                                  msg, module));
+                                    }
                                 }
                                 (x, Flow::Return) => { return Ok((x, Flow::Return)); }
                                 (None, Flow::Continue) => {}
@@ -3545,7 +4746,7 @@ impl Runtime {
                     }
                     ast::Expression::LinkFor(ref for_n) => {
                         // Pass on control to next link loop.
-                        match sub_link_for_n_expr(res, rt, for_n, module) {
+                        match sub_link_for_n_expr(res, len, rt, for_n, module) {
                             Ok((None, Flow::Continue)) => {}
                             Ok((_, Flow::Break(x))) => {
                                 match x {
@@ -3602,7 +4803,8 @@ impl Runtime {
         }
 
         let mut res: Link = Link::new();
-        match sub_link_for_n_expr(&mut res, self, for_n_expr, module) {
+        let mut len = 0;
+        match sub_link_for_n_expr(&mut res, &mut len, self, for_n_expr, module) {
             Ok((None, Flow::Continue)) =>
                 Ok((Some(Variable::Link(Box::new(res))), Flow::Continue)),
             x => x
@@ -3665,7 +4867,17 @@ impl Runtime {
                                 &self.expected(x, "number"), self))
             };
             match try!(self.block(&for_n_expr.block, module)) {
-                (Some(x), Flow::Continue) => res.push(x),
+                (Some(x), Flow::Continue) => {
+                    res.push(x);
+                    if self.limits.max_array_len.map(|max| res.len() > max).unwrap_or(false) {
+                        return Err(self.limit_error(for_n_expr.block.source_range,
+                            "Array length exceeds limit", module));
+                    }
+                    if let Some(msg) = self.alloc(size_of::<Variable>()) {
+                        return Err(self.limit_error(for_n_expr.block.source_range,
+                            msg, module));
+                    }
+                }
                 (x, Flow::Return) => { return Ok((x, Flow::Return)); }
                 (None, Flow::Continue) => {
                     return Err(module.error(for_n_expr.block.source_range,
@@ -3797,27 +5009,37 @@ impl Runtime {
                 &format!("{}\nExpected something from unary argument",
                     self.stack_trace()), self))
         };
-        let v = match self.resolve(&val) {
+        let v = try!(self.unop_value(unop.op, &val, unop.source_range, module));
+        Ok((Some(v), Flow::Continue))
+    }
+    /// Computes unary operator on value.
+    fn unop_value(
+        &self,
+        op: ast::UnOp,
+        val: &Variable,
+        source_range: Range,
+        module: &Arc<Module>
+    ) -> Result<Variable, String> {
+        Ok(match self.resolve(val) {
             &Variable::Bool(b, ref sec) => {
-                Variable::Bool(match unop.op {
+                Variable::Bool(match op {
                     ast::UnOp::Not => !b,
-                    _ => return Err(module.error(unop.source_range,
+                    _ => return Err(module.error(source_range,
                                     &format!("{}\nUnknown boolean unary operator",
                                              self.stack_trace()), self))
                 }, sec.clone())
             }
             &Variable::F64(v, ref sec) => {
-                Variable::F64(match unop.op {
+                Variable::F64(match op {
                     ast::UnOp::Neg => -v,
-                    _ => return Err(module.error(unop.source_range,
+                    _ => return Err(module.error(source_range,
                                     &format!("{}\nUnknown number unary operator",
                                              self.stack_trace()), self))
                 }, sec.clone())
             }
-            _ => return Err(module.error(unop.source_range,
+            _ => return Err(module.error(source_range,
                 &format!("{}\nInvalid type, expected bool", self.stack_trace()), self))
-        };
-        Ok((Some(v), Flow::Continue))
+        })
     }
     fn binop(
         &mut self,
@@ -3857,23 +5079,37 @@ impl Runtime {
                 &format!("{}\nExpected something from right argument",
                     self.stack_trace()), self))
         };
-        let v = match (self.resolve(&left), self.resolve(&right)) {
+        let v = try!(self.binop_values(binop.op, &left, &right, binop.source_range, module));
+        Ok((Some(v), Flow::Continue))
+    }
+    /// Computes binary operator on values.
+    fn binop_values(
+        &self,
+        op: ast::BinOp,
+        left: &Variable,
+        right: &Variable,
+        source_range: Range,
+        module: &Arc<Module>
+    ) -> Result<Variable, String> {
+        use ast::BinOp::*;
+
+        let v = match (self.resolve(left), self.resolve(right)) {
             (&Variable::F64(a, ref sec), &Variable::F64(b, _)) => {
-                Variable::F64(match binop.op {
+                Variable::F64(match op {
                     Add => a + b,
                     Sub => a - b,
                     Mul => a * b,
                     Div => a / b,
                     Rem => a % b,
                     Pow => a.powf(b),
-                    _ => return Err(module.error(binop.source_range,
+                    _ => return Err(module.error(source_range,
                         &format!("{}\nUnknown number operator `{:?}`",
                             self.stack_trace(),
-                            binop.op.symbol()), self))
+                            op.symbol()), self))
                 }, sec.clone())
             }
             (&Variable::Vec4(a), &Variable::Vec4(b)) => {
-                match binop.op {
+                match op {
                     Add => Variable::Vec4([a[0] + b[0], a[1] + b[1], a[2] + b[2], a[3] + b[3]]),
                     Sub => Variable::Vec4([a[0] - b[0], a[1] - b[1], a[2] - b[2], a[3] - b[3]]),
                     Mul => Variable::Vec4([a[0] * b[0], a[1] * b[1], a[2] * b[2], a[3] * b[3]]),
@@ -3886,36 +5122,36 @@ impl Runtime {
                     Rem => Variable::Vec4([a[0] % b[0], a[1] % b[1], a[2] % b[2], a[3] % b[3]]),
                     Pow => Variable::Vec4([a[0].powf(b[0]), a[1].powf(b[1]),
                                            a[2].powf(b[2]), a[3].powf(b[3])]),
-                    AndAlso | OrElse => return Err(module.error(binop.source_range,
+                    AndAlso | OrElse => return Err(module.error(source_range,
                         &format!("{}\nUnknown operator `{:?}` for `vec4` and `vec4`",
                             self.stack_trace(),
-                            binop.op.symbol_bool()), self)),
+                            op.symbol_bool()), self)),
                 }
             }
             (&Variable::Vec4(a), &Variable::F64(b, _)) => {
                 let b = b as f32;
-                match binop.op {
+                match op {
                     Add => Variable::Vec4([a[0] + b, a[1] + b, a[2] + b, a[3] + b]),
                     Sub => Variable::Vec4([a[0] - b, a[1] - b, a[2] - b, a[3] - b]),
                     Mul => Variable::Vec4([a[0] * b, a[1] * b, a[2] * b, a[3] * b]),
                     Dot => Variable::f64((a[0] * b + a[1] * b +
                                           a[2] * b + a[3] * b) as f64),
-                    Cross => return Err(module.error(binop.source_range,
+                    Cross => return Err(module.error(source_range,
                         &format!("{}\nExpected two vec4 for `{:?}`",
-                            self.stack_trace(), binop.op.symbol()), self)),
+                            self.stack_trace(), op.symbol()), self)),
                     Div => Variable::Vec4([a[0] / b, a[1] / b, a[2] / b, a[3] / b]),
                     Rem => Variable::Vec4([a[0] % b, a[1] % b, a[2] % b, a[3] % b]),
                     Pow => Variable::Vec4([a[0].powf(b), a[1].powf(b),
                                            a[2].powf(b), a[3].powf(b)]),
-                    AndAlso | OrElse => return Err(module.error(binop.source_range,
+                    AndAlso | OrElse => return Err(module.error(source_range,
                         &format!("{}\nUnknown operator `{:?}` for `vec4` and `f64`",
                             self.stack_trace(),
-                            binop.op.symbol_bool()), self)),
+                            op.symbol_bool()), self)),
                 }
             }
             (&Variable::F64(a, _), &Variable::Vec4(b)) => {
                 let a = a as f32;
-                match binop.op {
+                match op {
                     Add => Variable::Vec4([a + b[0], a + b[1], a + b[2], a + b[3]]),
                     Sub => Variable::Vec4([a - b[0], a - b[1], a - b[2], a - b[3]]),
                     Mul => Variable::Vec4([a * b[0], a * b[1], a * b[2], a * b[3]]),
@@ -3925,63 +5161,65 @@ impl Runtime {
                     Rem => Variable::Vec4([a % b[0], a % b[1], a % b[2], a % b[3]]),
                     Pow => Variable::Vec4([a.powf(b[0]), a.powf(b[1]),
                                            a.powf(b[2]), a.powf(b[3])]),
-                    Cross => return Err(module.error(binop.source_range,
+                    Cross => return Err(module.error(source_range,
                         &format!("{}\nExpected two vec4 for `{:?}`",
-                            self.stack_trace(), binop.op.symbol()), self)),
-                    AndAlso | OrElse => return Err(module.error(binop.source_range,
+                            self.stack_trace(), op.symbol()), self)),
+                    AndAlso | OrElse => return Err(module.error(source_range,
                         &format!("{}\nUnknown operator `{:?}` for `f64` and `vec4`",
                             self.stack_trace(),
-                            binop.op.symbol_bool()), self)),
+                            op.symbol_bool()), self)),
                 }
             }
             (&Variable::Bool(a, ref sec), &Variable::Bool(b, _)) => {
-                Variable::Bool(match binop.op {
+                Variable::Bool(match op {
                     Add | OrElse => a || b,
                     // Boolean subtraction with lazy precedence.
                     Sub => a && !b,
                     Mul | AndAlso => a && b,
                     Pow => a ^ b,
-                    _ => return Err(module.error(binop.source_range,
+                    _ => return Err(module.error(source_range,
                         &format!("{}\nUnknown boolean operator `{:?}`",
                             self.stack_trace(),
-                            binop.op.symbol_bool()), self))
+                            op.symbol_bool()), self))
                 }, sec.clone())
             }
             (&Variable::Text(ref a), &Variable::Text(ref b)) => {
-                match binop.op {
+                match op {
                     Add => {
                         let mut res = String::with_capacity(a.len() + b.len());
                         res.push_str(a);
                         res.push_str(b);
                         Variable::Text(Arc::new(res))
                     }
-                    _ => return Err(module.error(binop.source_range,
+                    _ => return Err(module.error(source_range,
                         &format!("{}\nThis operation can not be used with strings",
                             self.stack_trace()), self))
                 }
             }
             (&Variable::Text(_), _) =>
-                return Err(module.error(binop.source_range,
+                return Err(module.error(source_range,
                 &format!("{}\nThe right argument must be a string. \
                 Try the `str` function", self.stack_trace()), self)),
             (&Variable::Link(ref a), &Variable::Link(ref b)) => {
-                match binop.op {
+                match op {
                     Add => {
                         Variable::Link(Box::new(a.add(b)))
                     }
-                    _ => return Err(module.error(binop.source_range,
+                    _ => return Err(module.error(source_range,
                         &format!("{}\nThis operation can not be used with links",
                             self.stack_trace()), self))
                 }
             }
-            _ => return Err(module.error(binop.source_range, &format!(
+            _ => return Err(module.error(source_range, &format!(
                 "{}\nInvalid type for binary operator `{:?}`, \
                 expected numbers, vec4s, bools or strings",
                 self.stack_trace(),
-                binop.op.symbol()), self))
+                op.symbol()), self))
         };
-
-        Ok((Some(v), Flow::Continue))
+        if let Some(msg) = self.check_alloc(&v, heap_size(&v)) {
+            return Err(self.limit_error(source_range, msg, module));
+        }
+        Ok(v)
     }
     pub fn stack_trace(&self) -> String {
         stack_trace(&self.call_stack)
@@ -0,0 +1,129 @@
+//! Runs tests written in Dyon.
+//!
+//! A test is a function without arguments with a name starting with `test_`.
+//! It fails when it returns an error, e.g. from `assert` or `assert_eq`,
+//! or when it returns `err(_)`.
+//! Each test runs in a new `Runtime`, so tests do not share state,
+//! and a panic in one test does not stop the others.
+
+use std::fs;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use error::Error;
+use load;
+use Module;
+use Runtime;
+use Variable;
+
+/// The outcome of a test.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    Passed,
+    /// The test returned an error.
+    Failed(Error),
+    /// The test panicked, with the panic message.
+    Panicked(String),
+}
+
+/// The result of running a test.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TestResult {
+    /// The name of the source file.
+    pub file: Arc<String>,
+    /// The name of the test function.
+    pub name: Arc<String>,
+    pub outcome: Outcome,
+}
+
+impl TestResult {
+    /// Returns `true` if the test passed.
+    pub fn passed(&self) -> bool {
+        self.outcome == Outcome::Passed
+    }
+}
+
+/// Returns `true` if a function is a test.
+pub fn is_test(f: &::ast::Function) -> bool {
+    f.name.starts_with("test_") && f.args.len() == 0
+}
+
+/// Finds Dyon source files in a directory and its sub directories, sorted by path.
+///
+/// When the path is a file, it is the only file found.
+pub fn find_files(path: &Path) -> Result<Vec<PathBuf>, io::Error> {
+    fn visit(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), io::Error> {
+        for entry in try!(fs::read_dir(dir)) {
+            let path = try!(entry).path();
+            if path.is_dir() {
+                try!(visit(&path, files));
+            } else if path.extension().map(|ext| ext == "dyon").unwrap_or(false) {
+                files.push(path);
+            }
+        }
+        Ok(())
+    }
+
+    let mut files = vec![];
+    if path.is_dir() {
+        try!(visit(path, &mut files));
+        files.sort();
+    } else {
+        files.push(path.to_path_buf());
+    }
+    Ok(files)
+}
+
+/// Runs the tests of a loaded module, in the order they are declared.
+///
+/// Only tests with a name containing `filter` are run.
+pub fn run_tests(module: &Arc<Module>, filter: &str) -> Vec<TestResult> {
+    let mut results = vec![];
+    for (i, f) in module.functions.iter().enumerate() {
+        if !is_test(f) || !f.name.contains(filter) { continue; }
+        results.push(TestResult {
+            file: f.file.clone(),
+            name: f.name.clone(),
+            outcome: run_test(module, i),
+        });
+    }
+    results
+}
+
+/// Loads a source file into a new module and runs its tests.
+///
+/// Returns an error if the source does not load.
+pub fn run_file(file: &str, filter: &str) -> Result<Vec<TestResult>, Error> {
+    let mut module = Module::new();
+    try!(load(file, &mut module));
+    Ok(run_tests(&Arc::new(module), filter))
+}
+
+/// Runs a test function by index in module.
+fn run_test(module: &Arc<Module>, f_index: usize) -> Outcome {
+    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut rt = Runtime::new();
+        match rt.call_index(f_index, vec![], module) {
+            Ok(Some(Variable::Result(Err(ref err)))) => {
+                Outcome::Failed(rt.returned_error(err, f_index, module))
+            }
+            Ok(_) => Outcome::Passed,
+            Err(err) => Outcome::Failed(err),
+        }
+    }));
+    match res {
+        Ok(outcome) => outcome,
+        Err(payload) => {
+            let msg = if let Some(msg) = payload.downcast_ref::<&str>() {
+                msg.to_string()
+            } else if let Some(msg) = payload.downcast_ref::<String>() {
+                msg.clone()
+            } else {
+                "Box<Any>".into()
+            };
+            Outcome::Panicked(msg)
+        }
+    }
+}
@@ -24,6 +24,7 @@ pub enum Type {
     Result(Box<Type>),
     Secret(Box<Type>),
     Thread(Box<Type>),
+    Channel(Box<Type>),
     AdHoc(Arc<String>, Box<Type>),
     Closure(Box<Dfn>),
 }
@@ -89,6 +90,16 @@ impl Type {
                     res
                 }
             }
+            &Channel(ref ty) => {
+                if let Any = **ty {
+                    "chan".into()
+                } else {
+                    let mut res = String::from("chan[");
+                    res.push_str(&ty.description());
+                    res.push(']');
+                    res
+                }
+            }
             &AdHoc(ref ad, ref ty) => {
                 (&**ad).clone() + " " + &ty.description()
             }
@@ -128,6 +139,10 @@ impl Type {
         Type::Thread(Box::new(Type::Any))
     }
 
+    pub fn channel() -> Type {
+        Type::Channel(Box::new(Type::Any))
+    }
+
     /// Returns `true` if a type goes with another type (directional check).
     ///
     /// - `bool` (argument) goes with `sec[bool]` (value)
@@ -203,6 +218,15 @@ impl Type {
                     false
                 }
             }
+            &Channel(ref ch) => {
+                if let &Channel(ref other_ch) = other {
+                    ch.goes_with(other_ch)
+                } else if let &Any = other {
+                    true
+                } else {
+                    false
+                }
+            }
             &Closure(ref cl) => {
                 if let &Closure(ref other_cl) = other {
                     if cl.tys.len() != other_cl.tys.len() { return false; }
@@ -395,6 +419,9 @@ impl Type {
             } else if let Ok((range, _)) = convert.meta_bool("thr_any") {
                 convert.update(range);
                 ty = Some(Type::Thread(Box::new(Type::Any)));
+            } else if let Ok((range, _)) = convert.meta_bool("chan_any") {
+                convert.update(range);
+                ty = Some(Type::Channel(Box::new(Type::Any)));
             } else if let Ok((range, val)) = Type::from_meta_data(
                     "opt", convert, ignored) {
                 convert.update(range);
@@ -411,6 +438,10 @@ impl Type {
                     "thr", convert, ignored) {
                 convert.update(range);
                 ty = Some(Type::Thread(Box::new(val)));
+            } else if let Ok((range, val)) = Type::from_meta_data(
+                    "chan", convert, ignored) {
+                convert.update(range);
+                ty = Some(Type::Channel(Box::new(val)));
             } else if let Ok((range, val)) = convert.meta_string("ad_hoc") {
                 convert.update(range);
                 let inner_ty = if let Ok((range, val)) = Type::from_meta_data(
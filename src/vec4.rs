@@ -1,6 +1,8 @@
-use embed::{ConvertVec4, PopVariable, PushVariable};
+use embed::{ConvertVec4, PopVariable, PushVariable, TypeOf};
+use error;
 use {
     Runtime,
+    Type,
     Variable,
 };
 
@@ -14,11 +16,11 @@ impl ConvertVec4 for Vec4 {
 }
 
 impl PopVariable for Vec4 {
-    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, String> {
+    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, error::Error> {
         if let &Variable::Vec4(v) = var {
             Ok(Vec4(v))
         } else {
-            Err(rt.expected(var, "vec4"))
+            Err(rt.expected_error(var, "vec4"))
         }
     }
 }
@@ -27,6 +29,10 @@ impl PushVariable for Vec4 {
     fn push_var(&self) -> Variable { Variable::Vec4(self.0) }
 }
 
+impl TypeOf for Vec4 {
+    fn type_of() -> Type { Type::Vec4 }
+}
+
 impl From<[f32; 2]> for Vec4 {
     fn from(val: [f32; 2]) -> Vec4 {
         Vec4([val[0], val[1], 0.0, 0.0])
@@ -0,0 +1,78 @@
+//! Reloads script files when they change, while the host keeps running.
+
+use std::fs;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use error::Error;
+use load;
+use Module;
+
+/// Modification time and length, used to detect changes without reading the file.
+type Stamp = Option<(SystemTime, u64)>;
+
+fn stamp(file: &str) -> Stamp {
+    fs::metadata(file).ok().and_then(|m| m.modified().ok().map(|t| (t, m.len())))
+}
+
+/// Watches source files and reloads them into a new module when they change.
+///
+/// All files are loaded again in order, into a copy of the base module,
+/// since a file can call functions loaded from the files before it.
+/// External functions added to the base module are kept.
+///
+/// Call `reload` between frames and use `module` for the next call into the runtime.
+/// Running scripts keep the module they started with.
+pub struct ModuleWatcher {
+    base: Module,
+    files: Vec<(String, Stamp)>,
+    module: Arc<Module>,
+}
+
+impl ModuleWatcher {
+    /// Loads files in order into a copy of the base module.
+    pub fn new(base: Module, files: Vec<String>) -> Result<ModuleWatcher, Error> {
+        let files: Vec<(String, Stamp)> = files.into_iter()
+            .map(|file| { let s = stamp(&file); (file, s) })
+            .collect();
+        let module = try!(load_files(&base, &files));
+        Ok(ModuleWatcher {
+            base: base,
+            files: files,
+            module: Arc::new(module),
+        })
+    }
+
+    /// Returns the last module that loaded without errors.
+    pub fn module(&self) -> &Arc<Module> {
+        &self.module
+    }
+
+    /// Returns `true` if any file changed since it was last loaded.
+    pub fn changed(&self) -> bool {
+        self.files.iter().any(|&(ref file, s)| stamp(file) != s)
+    }
+
+    /// Reloads the files if any of them changed.
+    ///
+    /// Returns `Ok(true)` when a new module was loaded.
+    /// On error, the previous module is kept and the same error is not reported again
+    /// until a file changes.
+    pub fn reload(&mut self) -> Result<bool, Error> {
+        if !self.changed() { return Ok(false); }
+        for &mut (ref file, ref mut s) in &mut self.files {
+            *s = stamp(file);
+        }
+        let module = try!(load_files(&self.base, &self.files));
+        self.module = Arc::new(module);
+        Ok(true)
+    }
+}
+
+fn load_files(base: &Module, files: &[(String, Stamp)]) -> Result<Module, Error> {
+    let mut module = base.clone();
+    for &(ref file, _) in files {
+        try!(load(file, &mut module));
+    }
+    Ok(module)
+}
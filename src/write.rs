@@ -1,7 +1,9 @@
 use piston_meta::json;
 use std::io;
+use std::sync::Arc;
 use ast;
 use Runtime;
+use Type;
 use Variable;
 
 #[derive(Copy, Clone)]
@@ -132,6 +134,7 @@ pub fn write_variable<W>(
             }
         }
         Variable::Thread(_) => try!(write!(w, "_thread")),
+        Variable::Channel(_) => try!(write!(w, "_channel")),
         Variable::Return => try!(write!(w, "_return")),
         Variable::UnsafeRef(_) => try!(write!(w, "_unsafe_ref")),
         Variable::RustObject(_) => try!(write!(w, "_rust_object")),
@@ -144,7 +147,6 @@ pub fn write_variable<W>(
 pub fn print_variable(rt: &Runtime, v: &Variable, escape_string: EscapeString) {
     write_variable(&mut io::stdout(), rt, v, escape_string, 0).unwrap();
 }
-
 fn write_tabs<W: io::Write>(w: &mut W, tabs: u32) -> Result<(), io::Error> {
     for _ in 0..tabs {
         try!(write!(w, "    "));
@@ -152,11 +154,29 @@ fn write_tabs<W: io::Write>(w: &mut W, tabs: u32) -> Result<(), io::Error> {
     Ok(())
 }
 
+/// Characters that can not be used in names.
+const SEPS: &'static str = "(){}[],.:;=<>*·+-/%^?~|&∧∨!¬∑∃∀\n\"\\";
+
+/// Returns `true` if text can be written as a name without quotes.
+pub fn is_name(text: &str) -> bool {
+    text.len() > 0 && text.chars().all(|c| !c.is_whitespace() && !SEPS.contains(c))
+}
+
 pub fn write_closure<W: io::Write>(
     w: &mut W,
     rt: &Runtime,
     closure: &ast::Closure,
     tabs: u32
+) -> Result<(), io::Error> {
+    try!(write_closure_head(w, closure));
+    try!(write_expr(w, rt, &closure.expr, tabs));
+    Ok(())
+}
+
+/// Writes the arguments and current objects of a closure, up to the expression.
+pub fn write_closure_head<W: io::Write>(
+    w: &mut W,
+    closure: &ast::Closure
 ) -> Result<(), io::Error> {
     try!(write!(w, "\\("));
     for (i, arg) in closure.args.iter().enumerate() {
@@ -165,8 +185,9 @@ pub fn write_closure<W: io::Write>(
             try!(write!(w, ", "));
         }
     }
-    try!(write!(w, ") = "));
-    try!(write_expr(w, rt, &closure.expr, tabs));
+    try!(write!(w, ") "));
+    try!(write_currents(w, &closure.currents));
+    try!(write!(w, "= "));
     Ok(())
 }
 
@@ -174,7 +195,165 @@ pub fn write_arg<W: io::Write>(
     w: &mut W,
     arg: &ast::Arg
 ) -> Result<(), io::Error> {
-    write!(w, "{}: {}", arg.name, arg.ty.description())
+    if arg.mutable {
+        try!(write!(w, "mut "));
+    }
+    try!(write!(w, "{}", arg.name));
+    match (&arg.lifetime, &arg.ty) {
+        (&None, &Type::Any) => {}
+        (&Some(ref lt), &Type::Any) => try!(write!(w, ": '{}", lt)),
+        (&Some(ref lt), ty) => {
+            try!(write!(w, ": '{} ", lt));
+            try!(write_type(w, ty));
+        }
+        (&None, ty) => {
+            try!(write!(w, ": "));
+            try!(write_type(w, ty));
+        }
+    }
+    Ok(())
+}
+
+/// Writes current objects followed by a space, or nothing if there are none.
+pub fn write_currents<W: io::Write>(
+    w: &mut W,
+    currents: &[ast::Current]
+) -> Result<(), io::Error> {
+    if currents.len() == 0 { return Ok(()); }
+    try!(write!(w, "~ "));
+    for (i, current) in currents.iter().enumerate() {
+        if current.mutable {
+            try!(write!(w, "mut "));
+        }
+        try!(write!(w, "{}", current.name));
+        if let Type::Any = current.ty {} else {
+            try!(write!(w, ": "));
+            try!(write_type(w, &current.ty));
+        }
+        if i + 1 < currents.len() {
+            try!(write!(w, ", "));
+        }
+    }
+    try!(write!(w, " "));
+    Ok(())
+}
+
+/// Writes a type the way it is declared in source.
+///
+/// Unlike `Type::description`, ad-hoc types without an inner type
+/// are written without the object type.
+pub fn write_type<W: io::Write>(
+    w: &mut W,
+    ty: &Type
+) -> Result<(), io::Error> {
+    fn inner<W: io::Write>(
+        w: &mut W,
+        name: &str,
+        ty: &Type
+    ) -> Result<(), io::Error> {
+        if let Type::Any = *ty {
+            try!(write!(w, "{}", name));
+        } else {
+            try!(write!(w, "{}[", name));
+            try!(write_type(w, ty));
+            try!(write!(w, "]"));
+        }
+        Ok(())
+    }
+
+    match *ty {
+        Type::Array(ref ty) => {
+            if let Type::Any = **ty {
+                try!(write!(w, "[]"));
+            } else {
+                try!(write!(w, "["));
+                try!(write_type(w, ty));
+                try!(write!(w, "]"));
+            }
+        }
+        Type::Option(ref ty) => try!(inner(w, "opt", ty)),
+        Type::Result(ref ty) => try!(inner(w, "res", ty)),
+        Type::Thread(ref ty) => try!(inner(w, "thr", ty)),
+        Type::Channel(ref ty) => try!(inner(w, "chan", ty)),
+        Type::AdHoc(ref name, ref ty) => {
+            try!(write!(w, "{}", name));
+            if let Type::Object = **ty {} else {
+                try!(write!(w, " "));
+                try!(write_type(w, ty));
+            }
+        }
+        Type::Closure(ref dfn) => {
+            try!(write!(w, "\\("));
+            for (i, ty) in dfn.tys.iter().enumerate() {
+                try!(write_type(w, ty));
+                if i + 1 < dfn.tys.len() {
+                    try!(write!(w, ", "));
+                }
+            }
+            try!(write!(w, ") -> "));
+            try!(write_type(w, &dfn.ret));
+        }
+        _ => try!(write!(w, "{}", ty.description())),
+    }
+    Ok(())
+}
+
+/// Returns how tightly an expression binds.
+///
+/// Expressions with lower precedence than required by their parent need parentheses.
+fn precedence(expr: &ast::Expression) -> u8 {
+    use ast::BinOp::*;
+    use ast::Expression as E;
+
+    match *expr {
+        E::Assign(_) | E::Return(_) | E::ReturnVoid(_) | E::Break(_) |
+        E::Continue(_) | E::For(_) | E::ForN(_) | E::If(_) | E::Closure(_) |
+        E::Swizzle(_) => 0,
+        E::Compare(_) => 1,
+        E::BinOp(ref binop) => match binop.op {
+            Add | Sub | OrElse => 2,
+            Pow => 4,
+            Mul | Dot | Cross | Div | Rem | AndAlso => 3,
+        },
+        E::UnOp(ref unop) => match unop.op {
+            ast::UnOp::Neg => 3,
+            ast::UnOp::Not => 5,
+        },
+        E::Number(ref number) if number.num.is_sign_negative() => 3,
+        _ => 5,
+    }
+}
+
+/// Returns `true` if expression starts with a minus sign.
+///
+/// A negation takes the rest of a multiplication as operand,
+/// so it needs parentheses inside one.
+fn is_negation(expr: &ast::Expression) -> bool {
+    match *expr {
+        ast::Expression::UnOp(ref unop) => {
+            if let ast::UnOp::Neg = unop.op { true } else { false }
+        }
+        ast::Expression::Number(ref number) => number.num.is_sign_negative(),
+        _ => false
+    }
+}
+
+/// Writes an operand, with parentheses if needed.
+fn write_operand<W: io::Write>(
+    w: &mut W,
+    rt: &Runtime,
+    expr: &ast::Expression,
+    parens: bool,
+    tabs: u32,
+) -> Result<(), io::Error> {
+    if parens {
+        try!(write!(w, "("));
+    }
+    try!(write_expr(w, rt, expr, tabs));
+    if parens {
+        try!(write!(w, ")"));
+    }
+    Ok(())
 }
 
 pub fn write_expr<W: io::Write>(
@@ -189,8 +368,9 @@ pub fn write_expr<W: io::Write>(
         &E::BinOp(ref binop) => try!(write_binop(w, rt, binop, tabs)),
         &E::Item(ref item) => try!(write_item(w, rt, item, tabs)),
         &E::Number(ref number) => try!(write!(w, "{}", number.num)),
-        &E::Text(ref text) => try!(json::write_string(w, &text.text)),
+        &E::Text(ref text) => try!(write_string(w, &text.text)),
         &E::Bool(ref b) => try!(write!(w, "{}", b.val)),
+        &E::Variable(_, Variable::Vec4(v)) => try!(write_color(w, v)),
         &E::Variable(_, ref var) => try!(write_variable(w, rt, var, EscapeString::Json, tabs)),
         &E::Link(ref link) => try!(write_link(w, rt, link, tabs)),
         &E::Object(ref obj) => try!(write_obj(w, rt, obj, tabs)),
@@ -225,55 +405,24 @@ pub fn write_expr<W: io::Write>(
         &E::Vec4(ref vec4) => try!(write_vec4(w, rt, vec4, tabs)),
         &E::For(ref f) => try!(write_for(w, rt, f, tabs)),
         &E::Compare(ref comp) => try!(write_compare(w, rt, comp, tabs)),
-        &E::ForN(ref for_n) => {
-            try!(write!(w, "for "));
-            try!(write_for_n(w, rt, for_n, tabs));
-        }
-        &E::Sum(ref for_n) => {
-            try!(write!(w, "sum "));
-            try!(write_for_n(w, rt, for_n, tabs));
-        }
-        &E::SumVec4(ref for_n) => {
-            try!(write!(w, "sum_vec4 "));
-            try!(write_for_n(w, rt, for_n, tabs));
-        }
-        &E::Prod(ref for_n) => {
-            try!(write!(w, "prod "));
-            try!(write_for_n(w, rt, for_n, tabs));
-        }
-        &E::ProdVec4(ref for_n) => {
-            try!(write!(w, "prod_vec4 "));
-            try!(write_for_n(w, rt, for_n, tabs));
-        }
-        &E::Min(ref for_n) => {
-            try!(write!(w, "min "));
-            try!(write_for_n(w, rt, for_n, tabs));
-        }
-        &E::Max(ref for_n) => {
-            try!(write!(w, "max "));
-            try!(write_for_n(w, rt, for_n, tabs));
-        }
-        &E::Sift(ref for_n) => {
-            try!(write!(w, "sift "));
-            try!(write_for_n(w, rt, for_n, tabs));
-        }
-        &E::Any(ref for_n) => {
-            try!(write!(w, "any "));
-            try!(write_for_n(w, rt, for_n, tabs));
-        }
-        &E::All(ref for_n) => {
-            try!(write!(w, "all "));
-            try!(write_for_n(w, rt, for_n, tabs));
-        }
-        &E::LinkFor(ref for_n) => {
-            try!(write!(w, "link "));
-            try!(write_for_n(w, rt, for_n, tabs));
-        }
+        &E::ForN(ref for_n) => try!(write_for_n(w, rt, "for", for_n, tabs)),
+        &E::Sum(ref for_n) => try!(write_for_n(w, rt, "sum", for_n, tabs)),
+        &E::SumVec4(ref for_n) => try!(write_for_n(w, rt, "sum_vec4", for_n, tabs)),
+        &E::Prod(ref for_n) => try!(write_for_n(w, rt, "prod", for_n, tabs)),
+        &E::ProdVec4(ref for_n) => try!(write_for_n(w, rt, "prod_vec4", for_n, tabs)),
+        &E::Min(ref for_n) => try!(write_for_n(w, rt, "min", for_n, tabs)),
+        &E::Max(ref for_n) => try!(write_for_n(w, rt, "max", for_n, tabs)),
+        &E::Sift(ref for_n) => try!(write_for_n(w, rt, "sift", for_n, tabs)),
+        &E::Any(ref for_n) => try!(write_for_n(w, rt, "any", for_n, tabs)),
+        &E::All(ref for_n) => try!(write_for_n(w, rt, "all", for_n, tabs)),
+        &E::LinkFor(ref for_n) => try!(write_for_n(w, rt, "link", for_n, tabs)),
         &E::If(ref if_expr) => try!(write_if(w, rt, if_expr, tabs)),
         &E::Norm(ref norm) => try!(write_norm(w, rt, norm, tabs)),
         &E::UnOp(ref unop) => try!(write_unop(w, rt, unop, tabs)),
         &E::Try(ref expr) => {
-            try!(write_expr(w, rt, expr, tabs));
+            // An item followed by `?` is parsed as part of the item.
+            let parens = precedence(expr) < 5 || if let E::Item(_) = **expr { true } else { false };
+            try!(write_operand(w, rt, expr, parens, tabs));
             try!(write!(w, "?"));
         }
         &E::Swizzle(ref swizzle) => try!(write_swizzle(w, rt, swizzle, tabs)),
@@ -286,6 +435,26 @@ pub fn write_expr<W: io::Write>(
     Ok(())
 }
 
+/// Writes a color as hex code, or as a vector if it has no exact hex code.
+pub fn write_color<W: io::Write>(
+    w: &mut W,
+    v: [f32; 4]
+) -> Result<(), io::Error> {
+    let mut bytes = [0; 4];
+    for i in 0..4 {
+        let x = v[i] * 255.0;
+        if x < 0.0 || x > 255.0 || x.round() != x {
+            return write!(w, "({}, {}, {}, {})", v[0], v[1], v[2], v[3]);
+        }
+        bytes[i] = x as u8;
+    }
+    try!(write!(w, "#{:02x}{:02x}{:02x}", bytes[0], bytes[1], bytes[2]));
+    if bytes[3] != 255 {
+        try!(write!(w, "{:02x}", bytes[3]));
+    }
+    Ok(())
+}
+
 pub fn write_block<W: io::Write>(
     w: &mut W,
     rt: &Runtime,
@@ -315,39 +484,26 @@ pub fn write_block<W: io::Write>(
     Ok(())
 }
 
-fn binop_needs_parent(expr: &ast::Expression) -> bool {
-    use ast::Expression as E;
-
-    match *expr {
-        E::Compare(_) => true,
-        _ => false
-    }
-}
-
 pub fn write_binop<W: io::Write>(
     w: &mut W,
     rt: &Runtime,
     binop: &ast::BinOpExpression,
     tabs: u32,
 ) -> Result<(), io::Error> {
-    let left_needs_parens = binop_needs_parent(&binop.left);
-    let right_needs_parens = binop_needs_parent(&binop.right);
+    use ast::BinOp::*;
 
-    if left_needs_parens {
-        try!(write!(w, "("));
-    }
-    try!(write_expr(w, rt, &binop.left, tabs));
-    if left_needs_parens {
-        try!(write!(w, ")"));
-    }
+    let (left, right) = match binop.op {
+        Add | Sub | OrElse => (2, 3),
+        Pow => (5, 5),
+        Mul | Dot | Cross | Div | Rem | AndAlso => (3, 4),
+    };
+    let left_needs_parens = precedence(&binop.left) < left ||
+        left == 3 && is_negation(&binop.left);
+    let right_needs_parens = precedence(&binop.right) < right;
+
+    try!(write_operand(w, rt, &binop.left, left_needs_parens, tabs));
     try!(write!(w, " {} ", binop.op.symbol()));
-    if right_needs_parens {
-        try!(write!(w, "("));
-    }
-    try!(write_expr(w, rt, &binop.right, tabs));
-    if right_needs_parens {
-        try!(write!(w, ")"));
-    }
+    try!(write_operand(w, rt, &binop.right, right_needs_parens, tabs));
     Ok(())
 }
 
@@ -374,11 +530,13 @@ pub fn write_unop<W: io::Write>(
     match unop.op {
         Not => {
             try!(write!(w, "!"));
-            try!(write_expr(w, rt, &unop.expr, tabs));
+            let parens = precedence(&unop.expr) < 5;
+            try!(write_operand(w, rt, &unop.expr, parens, tabs));
         }
         Neg => {
             try!(write!(w, "-"));
-            try!(write_expr(w, rt, &unop.expr, tabs));
+            let parens = precedence(&unop.expr) < 3;
+            try!(write_operand(w, rt, &unop.expr, parens, tabs));
         }
     }
     Ok(())
@@ -396,9 +554,20 @@ pub fn write_item<W: io::Write>(
         try!(write!(w, "~ "));
     }
     try!(write!(w, "{}", item.name));
+    if item.try {
+        try!(write!(w, "?"));
+    }
     for (i, id) in item.ids.iter().enumerate() {
         match id {
-            &Id::String(_, ref prop) => try!(write!(w, ".{}", prop)),
+            &Id::String(_, ref prop) => {
+                if is_name(prop) {
+                    try!(write!(w, ".{}", prop));
+                } else {
+                    try!(write!(w, "["));
+                    try!(write_string(w, prop));
+                    try!(write!(w, "]"));
+                }
+            }
             &Id::F64(_, ind) => try!(write!(w, "[{}]", ind)),
             &Id::Expression(ref expr) => {
                 try!(write!(w, "["));
@@ -419,7 +588,19 @@ pub fn write_link<W: io::Write>(
     link: &ast::Link,
     tabs: u32,
 ) -> Result<(), io::Error> {
-    try!(write!(w, "link {{ "));
+    try!(write!(w, "link "));
+    try!(write_link_body(w, rt, link, tabs));
+    Ok(())
+}
+
+/// Writes the items of a link between braces.
+pub fn write_link_body<W: io::Write>(
+    w: &mut W,
+    rt: &Runtime,
+    link: &ast::Link,
+    tabs: u32,
+) -> Result<(), io::Error> {
+    try!(write!(w, "{{ "));
     for item in &link.items {
         try!(write_expr(w, rt, item, tabs));
         try!(write!(w, " "));
@@ -436,12 +617,7 @@ pub fn write_obj<W: io::Write>(
 ) -> Result<(), io::Error> {
     try!(write!(w, "{{"));
     for (i, key_value) in obj.key_values.iter().enumerate() {
-        if key_value.0.chars().all(|c| c.is_alphanumeric()) {
-            try!(write!(w, "{}: ", key_value.0));
-        } else {
-            try!(json::write_string(w, &key_value.0));
-            try!(write!(w, ": "));
-        }
+        try!(write_key(w, &key_value.0));
         try!(write_expr(w, rt, &key_value.1, tabs + 1));
         if i + 1 < obj.key_values.len() {
             try!(write!(w, ", "));
@@ -451,14 +627,46 @@ pub fn write_obj<W: io::Write>(
     Ok(())
 }
 
+/// Writes a string literal, escaping characters that change the layout of source.
+fn write_string<W: io::Write>(w: &mut W, val: &str) -> Result<(), io::Error> {
+    try!(write!(w, "\""));
+    for c in val.chars() {
+        match c {
+            '\\' => try!(write!(w, "\\\\")),
+            '"' => try!(write!(w, "\\\"")),
+            '\n' => try!(write!(w, "\\n")),
+            '\r' => try!(write!(w, "\\r")),
+            '\t' => try!(write!(w, "\\t")),
+            c => try!(write!(w, "{}", c)),
+        }
+    }
+    try!(write!(w, "\""));
+    Ok(())
+}
+
+/// Writes an object key followed by a colon.
+pub fn write_key<W: io::Write>(
+    w: &mut W,
+    key: &str
+) -> Result<(), io::Error> {
+    if is_name(key) {
+        try!(write!(w, "{}: ", key));
+    } else {
+        try!(write_string(w, key));
+        try!(write!(w, ": "));
+    }
+    Ok(())
+}
+
 pub fn write_call<W: io::Write>(
     w: &mut W,
     rt: &Runtime,
     call: &ast::Call,
     tabs: u32,
 ) -> Result<(), io::Error> {
-    try!(write!(w, "{}(", call.name));
+    let prefixes = try!(write_call_head(w, &call.alias, &call.name, call.args.len()));
     for (i, arg) in call.args.iter().enumerate() {
+        try!(write!(w, "{}", prefixes[i]));
         try!(write_expr(w, rt, arg, tabs));
         if i + 1 < call.args.len() {
             try!(write!(w, ", "));
@@ -468,18 +676,101 @@ pub fn write_call<W: io::Write>(
     Ok(())
 }
 
+/// Splits the argument names of a named call from the function name.
+///
+/// For example, `foo__bar_baz` with two arguments becomes `foo` and `[bar, baz]`.
+fn named_call(name: &str, n: usize) -> Option<(&str, Vec<&str>)> {
+    let i = match name.find("__") {
+        None => return None,
+        Some(i) => i
+    };
+    let words: Vec<&str> = name[i + 2..].split('_').collect();
+    if i == 0 || n == 0 || words.len() != n || words.iter().any(|w| w.len() == 0) {
+        None
+    } else {
+        Some((&name[..i], words))
+    }
+}
+
+/// Writes the alias, name and opening parenthesis of a call.
+///
+/// Returns text to write before each argument,
+/// such as argument names of named calls and `mut`,
+/// which the parser moves into the function name.
+pub fn write_call_head<W: io::Write>(
+    w: &mut W,
+    alias: &Option<Arc<String>>,
+    name: &str,
+    n: usize,
+) -> Result<Vec<String>, io::Error> {
+    if let Some(ref alias) = *alias {
+        try!(write!(w, "{}::", alias));
+    }
+    let mut mutable = vec![false; n];
+    let mut name = name;
+    if name.ends_with(')') {
+        if let Some(i) = name.find('(') {
+            let args: Vec<&str> = name[i + 1..name.len() - 1].split(',').collect();
+            if args.len() == n && args.iter().all(|&a| a == "mut" || a == "_") {
+                for (j, &a) in args.iter().enumerate() {
+                    mutable[j] = a == "mut";
+                }
+                name = &name[..i];
+            }
+        }
+    }
+    let mut prefixes: Vec<String> = vec![String::new(); n];
+    if let Some((base, words)) = named_call(name, n) {
+        for (prefix, word) in prefixes.iter_mut().zip(words.iter()) {
+            prefix.push_str(word);
+            prefix.push_str(": ");
+        }
+        name = base;
+    }
+    for (prefix, &m) in prefixes.iter_mut().zip(mutable.iter()) {
+        if m {
+            prefix.push_str("mut ");
+        }
+    }
+    try!(write!(w, "{}(", name));
+    Ok(prefixes)
+}
+
 pub fn write_call_closure<W: io::Write>(
     w: &mut W,
     rt: &Runtime,
     call: &ast::CallClosure,
     tabs: u32,
 ) -> Result<(), io::Error> {
+    use ast::Id;
+
+    let n = call.args.len();
+    // Named closure calls append argument names to the item.
+    let mut item = call.item.clone();
+    let mut words = None;
+    if item.ids.len() == 0 {
+        if let Some((base, names)) = named_call(&call.item.name, n) {
+            item.name = Arc::new(base.into());
+            words = Some(names);
+        }
+    } else if let Some(&Id::String(_, ref prop)) = call.item.ids.last() {
+        if let Some((base, names)) = named_call(prop, n) {
+            let last = item.ids.len() - 1;
+            if let Id::String(_, ref mut prop) = item.ids[last] {
+                *prop = Arc::new(base.into());
+            }
+            words = Some(names);
+        }
+    }
     try!(write!(w, "\\"));
-    try!(write_item(w, rt, &call.item, tabs));
+    try!(write_item(w, rt, &item, tabs));
     try!(write!(w, "("));
     for (i, arg) in call.args.iter().enumerate() {
+        if let Some(ref words) = words {
+            try!(write!(w, "{}: ", words[i]));
+        }
         try!(write_expr(w, rt, arg, tabs + 1));
-        if i + 1 < call.args.len() {
+        if i + 1 < n {
             try!(write!(w, ", "));
         }
     }
@@ -512,7 +803,7 @@ pub fn write_arr_fill<W: io::Write>(
 ) -> Result<(), io::Error> {
     try!(write!(w, "["));
     try!(write_expr(w, rt, &arr_fill.fill, tabs + 1));
-    try!(write!(w, ", "));
+    try!(write!(w, "; "));
     try!(write_expr(w, rt, &arr_fill.n, tabs + 1));
     try!(write!(w, "]"));
     Ok(())
@@ -537,7 +828,8 @@ pub fn write_vec4<W: io::Write>(
     tabs: u32,
 ) -> Result<(), io::Error> {
     let mut n = vec4.args.len();
-    for expr in vec4.args.iter().rev() {
+    // Keep the first argument, since `()` is not a vector.
+    for expr in vec4.args[1..].iter().rev() {
         if let &ast::Expression::Number(ref num) = expr {
             if num.num == 0.0 {
                 n -= 1;
@@ -588,12 +880,35 @@ pub fn write_swizzle<W: io::Write>(
     Ok(())
 }
 
+fn write_label<W: io::Write>(
+    w: &mut W,
+    label: &Option<Arc<String>>
+) -> Result<(), io::Error> {
+    if let Some(ref label) = *label {
+        try!(write!(w, "'{}: ", label));
+    }
+    Ok(())
+}
+
 pub fn write_for<W: io::Write>(
     w: &mut W,
     rt: &Runtime,
     f: &ast::For,
     tabs: u32,
 ) -> Result<(), io::Error> {
+    try!(write_for_head(w, rt, f, tabs));
+    try!(write_block(w, rt, &f.block, tabs));
+    Ok(())
+}
+
+/// Writes a `for` or `loop` up to the block.
+pub fn write_for_head<W: io::Write>(
+    w: &mut W,
+    rt: &Runtime,
+    f: &ast::For,
+    tabs: u32,
+) -> Result<(), io::Error> {
+    try!(write_label(w, &f.label));
     if let ast::Expression::Block(ref b) = f.init {
         if b.expressions.len() == 0 {
             if let ast::Expression::Bool(ref b) = f.cond {
@@ -601,7 +916,6 @@ pub fn write_for<W: io::Write>(
                     if let ast::Expression::Block(ref b) = f.step {
                         if b.expressions.len() == 0 {
                             try!(write!(w, "loop "));
-                            try!(write_block(w, rt, &f.block, tabs + 1));
                             return Ok(());
                         }
                     }
@@ -617,67 +931,111 @@ pub fn write_for<W: io::Write>(
     try!(write!(w, "; "));
     try!(write_expr(w, rt, &f.step, tabs));
     try!(write!(w, " "));
-    try!(write_block(w, rt, &f.block, tabs + 1));
     Ok(())
 }
 
-fn compare_needs_parent(expr: &ast::Expression) -> bool {
-    use ast::Expression as E;
-
-    match *expr {
-        E::BinOp(_) => true,
-        _ => false
-    }
-}
-
 pub fn write_compare<W: io::Write>(
     w: &mut W,
     rt: &Runtime,
     comp: &ast::Compare,
     tabs: u32,
 ) -> Result<(), io::Error> {
-    let left_needs_parens = compare_needs_parent(&comp.left);
-    let right_needs_parens = compare_needs_parent(&comp.right);
+    let left_needs_parens = precedence(&comp.left) < 5;
+    let right_needs_parens = precedence(&comp.right) < 1;
 
-    if left_needs_parens {
-        try!(write!(w, "("));
-    }
-    try!(write_expr(w, rt, &comp.left, tabs));
-    if left_needs_parens {
-        try!(write!(w, ")"));
-    }
+    try!(write_operand(w, rt, &comp.left, left_needs_parens, tabs));
     try!(write!(w, " {} ", comp.op.symbol()));
-    if right_needs_parens {
-        try!(write!(w, "("));
-    }
-    try!(write_expr(w, rt, &comp.right, tabs));
-    if right_needs_parens {
-        try!(write!(w, ")"));
-    }
+    try!(write_operand(w, rt, &comp.right, right_needs_parens, tabs));
     Ok(())
 }
 
+/// Returns the inner loop of a loop over several indices.
+///
+/// The parser turns `for i, j { ... }` into nested loops with the same source range.
+fn inner_for_n(for_n: &ast::ForN) -> Option<&ast::ForN> {
+    use ast::Expression as E;
+
+    if for_n.block.expressions.len() != 1 { return None; }
+    let inner = match for_n.block.expressions[0] {
+        E::ForN(ref x) | E::Sum(ref x) | E::SumVec4(ref x) | E::Prod(ref x) |
+        E::ProdVec4(ref x) | E::Min(ref x) | E::Max(ref x) | E::Sift(ref x) |
+        E::Any(ref x) | E::All(ref x) | E::LinkFor(ref x) => x,
+        _ => return None
+    };
+    let (a, b) = (inner.source_range, for_n.source_range);
+    if inner.label.is_none() && a.offset == b.offset && a.length == b.length {
+        Some(inner)
+    } else {
+        None
+    }
+}
+
 pub fn write_for_n<W: io::Write>(
     w: &mut W,
     rt: &Runtime,
+    keyword: &str,
     for_n: &ast::ForN,
     tabs: u32
 ) -> Result<(), io::Error> {
-    try!(write!(w, "{} ", for_n.name));
-    if let Some(ref start) = for_n.start {
-        try!(write!(w, "["));
-        try!(write_expr(w, rt, start, tabs));
-        try!(write!(w, ", "));
-        try!(write_expr(w, rt, &for_n.end, tabs));
-        try!(write!(w, ") "));
+    let body = try!(write_for_n_head(w, rt, keyword, for_n, tabs));
+    if let Some(link) = link_body(body) {
+        try!(write_link_body(w, rt, link, tabs));
     } else {
-        try!(write_expr(w, rt, &for_n.end, tabs));
-        try!(write!(w, " "));
+        try!(write_block(w, rt, &body.block, tabs));
     }
-    try!(write_block(w, rt, &for_n.block, tabs + 1));
     Ok(())
 }
 
+/// Returns the items of a `link` loop.
+pub fn link_body(for_n: &ast::ForN) -> Option<&ast::Link> {
+    if for_n.block.expressions.len() != 1 { return None; }
+    if let ast::Expression::Link(ref link) = for_n.block.expressions[0] {
+        Some(link)
+    } else {
+        None
+    }
+}
+
+/// Writes a loop over indices up to the block.
+///
+/// Nested loops created from several indices are written as one loop,
+/// and ends inferred from the body are left out.
+/// Returns the innermost loop, which contains the body.
+pub fn write_for_n_head<'a, W: io::Write>(
+    w: &mut W,
+    rt: &Runtime,
+    keyword: &str,
+    for_n: &'a ast::ForN,
+    tabs: u32
+) -> Result<&'a ast::ForN, io::Error> {
+    let mut loops = vec![for_n];
+    while let Some(inner) = inner_for_n(loops[loops.len() - 1]) {
+        loops.push(inner);
+    }
+    let body = loops[loops.len() - 1];
+
+    try!(write_label(w, &for_n.label));
+    try!(write!(w, "{} ", keyword));
+    for (i, for_n) in loops.iter().enumerate() {
+        try!(write!(w, "{}", for_n.name));
+        if let Some(ref start) = for_n.start {
+            try!(write!(w, " ["));
+            try!(write_expr(w, rt, start, tabs));
+            try!(write!(w, ", "));
+            try!(write_expr(w, rt, &for_n.end, tabs));
+            try!(write!(w, ")"));
+        } else if for_n.end.source_range().offset < body.block.source_range.offset {
+            try!(write!(w, " "));
+            try!(write_expr(w, rt, &for_n.end, tabs));
+        }
+        if i + 1 < loops.len() {
+            try!(write!(w, ", "));
+        }
+    }
+    try!(write!(w, " "));
+    Ok(body)
+}
+
 pub fn write_if<W: io::Write>(
     w: &mut W,
     rt: &Runtime,
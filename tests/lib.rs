@@ -1,7 +1,11 @@
 extern crate piston_meta;
 extern crate dyon;
+extern crate range;
+#[macro_use]
+extern crate dyon_derive;
 
 use dyon::*;
+use dyon::error::ErrorKind;
 
 pub fn test_src(source: &str) {
     let mut module = Module::new();
@@ -15,7 +19,7 @@ pub fn test_fail_src(source: &str) {
     match load(source, &mut module) {
         Ok(_) => panic!("`{}` should fail", source),
         Err(err) => {
-            if err.starts_with(&format!("Could not open `{}`", source)) {
+            if err.kind == ErrorKind::Io {
                 panic!("{}", err)
             }
         }
@@ -206,3 +210,900 @@ fn test_error() {
     test_src("source/error/unwrap_err.dyon");
     test_src("source/error/option.dyon");
 }
+
+#[test]
+fn test_step_budget() {
+    use std::sync::Arc;
+
+    let mut module = Module::new();
+    load("source/limits/loop.dyon", &mut module).unwrap();
+    let mut rt = Runtime::new();
+    rt.max_steps = Some(1000);
+    let err = rt.run(&Arc::new(module)).unwrap_err();
+    assert!(err.message.contains("Step budget exceeded"));
+}
+
+#[test]
+fn test_steps_per_call() {
+    use std::sync::Arc;
+
+    // Each call from Rust gets the whole step budget.
+    let mut module = Module::new();
+    load("source/limits/steps.dyon", &mut module).unwrap();
+    let module = Arc::new(module);
+    let mut rt = Runtime::new();
+    rt.call_str("count", &[], &module).unwrap();
+    rt.max_steps = Some(rt.steps * 3 / 2);
+    rt.call_str("count", &[], &module).unwrap();
+    rt.call_str("count", &[], &module).unwrap();
+}
+
+#[test]
+fn test_deadline() {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    let mut module = Module::new();
+    load("source/limits/loop.dyon", &mut module).unwrap();
+    let mut rt = Runtime::new();
+    rt.deadline = Some(Instant::now() + Duration::from_millis(10));
+    let err = rt.run(&Arc::new(module)).unwrap_err();
+    assert!(err.message.contains("Deadline exceeded"));
+    assert_eq!(&*err.trace[0].fn_name, "main");
+}
+
+#[test]
+fn test_cancel() {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    let mut module = Module::new();
+    load("source/limits/loop.dyon", &mut module).unwrap();
+    let mut rt = Runtime::new();
+    let cancel = rt.cancel_handle();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        cancel.cancel();
+    });
+    let err = rt.run(&Arc::new(module)).unwrap_err();
+    assert!(err.message.contains("Script was cancelled"));
+}
+
+#[test]
+fn test_limits() {
+    use std::sync::Arc;
+    use std::thread;
+
+    let mut module = Module::new();
+    load("source/limits/recursion.dyon", &mut module).unwrap();
+    let mut rt = Runtime::new();
+    rt.limits.max_call_depth = Some(100);
+    let err = rt.run(&Arc::new(module)).unwrap_err();
+    assert!(err.message.contains("Maximum call depth exceeded"));
+
+    let mut module = Module::new();
+    load("source/limits/array_fill.dyon", &mut module).unwrap();
+    let mut rt = Runtime::new();
+    rt.limits.max_array_len = Some(1000);
+    let err = rt.run(&Arc::new(module)).unwrap_err();
+    assert!(err.message.contains("Array length exceeds limit"));
+
+    let mut module = Module::new();
+    load("source/limits/link.dyon", &mut module).unwrap();
+    let mut rt = Runtime::new();
+    rt.limits.max_link_len = Some(1000);
+    let err = rt.run(&Arc::new(module)).unwrap_err();
+    assert!(err.message.contains("Link length exceeds limit"));
+
+    let mut module = Module::new();
+    load("source/limits/heap.dyon", &mut module).unwrap();
+    let source = module.functions[0].source.clone();
+    let mut rt = Runtime::new();
+    rt.limits.max_heap = Some(1_000_000);
+    let err = rt.run(&Arc::new(module)).unwrap_err();
+    assert!(err.message.contains("Heap budget exceeded"));
+    assert!(err.range.unwrap().offset > source.find("loop").unwrap());
+
+    // Recursion is limited by the sandbox limits.
+    // Debug builds use more native stack per call than release builds.
+    let res = thread::Builder::new().stack_size(32 << 20).spawn(|| {
+        let mut module = Module::new();
+        load("source/limits/recursion.dyon", &mut module).unwrap();
+        let mut rt = Runtime::new();
+        rt.limits = RuntimeLimits::sandbox();
+        rt.run(&Arc::new(module)).unwrap_err().message
+    }).unwrap().join().unwrap();
+    assert!(res.contains("Maximum call depth exceeded"));
+}
+
+#[test]
+fn test_capabilities() {
+    let mut module = Module::new();
+    load("source/capabilities/read_file.dyon", &mut module).unwrap();
+
+    let mut module = Module::with_capabilities(Capabilities::none());
+    let err = load("source/capabilities/read_file.dyon", &mut module).unwrap_err();
+    assert!(err.message.contains("`load_string__file` is disabled, requires capability `fs_read`"));
+
+    let mut module = Module::with_capabilities(Capabilities::none());
+    let err = load("source/capabilities/go.dyon", &mut module).unwrap_err();
+    assert!(err.message.contains("`go` is disabled, requires capability `threads`"));
+}
+
+#[test]
+fn test_diagnostics() {
+    use std::sync::Arc;
+    use std::fs::File;
+    use std::io::Read;
+
+    let file = "source/diagnostics/two_errors.dyon";
+    let mut source = String::new();
+    File::open(file).unwrap().read_to_string(&mut source).unwrap();
+    let source = Arc::new(source);
+
+    let mut module = Module::new();
+    let diagnostics = load_str_diagnostics(file, source.clone(), &mut module).unwrap_err();
+    assert_eq!(diagnostics.len(), 2);
+    assert!(diagnostics.iter().all(|d| d.severity == Severity::Error && &**d.file == file));
+    assert!(diagnostics[0].message.contains("Could not find declaration of `y`"));
+    assert!(diagnostics[1].message.contains("Could not find function `foo`"));
+
+    let mut module = Module::new();
+    let err = load_str(file, source, &mut module).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::Lifetime);
+    assert_eq!(err.range, Some(diagnostics[0].range));
+    assert!(err.message.contains("`y`") && err.message.contains("`foo`"));
+
+    // A returned local is reported once.
+    let file = "source/diagnostics/return_local.dyon";
+    let mut source = String::new();
+    File::open(file).unwrap().read_to_string(&mut source).unwrap();
+    let mut module = Module::new();
+    let diagnostics = load_str_diagnostics(file, Arc::new(source), &mut module).unwrap_err();
+    assert_eq!(diagnostics.len(), 1);
+    assert!(diagnostics[0].message.contains("`x` does not live long enough"));
+}
+
+#[test]
+fn test_error_kinds() {
+    use std::sync::Arc;
+
+    let mut module = Module::new();
+    let err = load("source/error/missing.dyon", &mut module).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::Io);
+
+    let mut module = Module::new();
+    let err = load("source/typechk/return.dyon", &mut module).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::Type);
+
+    let file = "source/error/runtime.dyon";
+    let mut module = Module::new();
+    load(file, &mut module).unwrap();
+    let mut rt = Runtime::new();
+    let err = rt.run(&Arc::new(module)).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::Runtime);
+    assert_eq!(err.file.as_ref().map(|f| &***f), Some(file));
+    assert!(err.range.is_some());
+    assert_eq!(&*err.trace[0].fn_name, "main");
+    assert!(format!("{}", err).contains("something wrong happened"));
+
+    // The location of an error handled by `try` is not used for later errors.
+    let file = "source/error/handled.dyon";
+    let mut module = Module::new();
+    load(file, &mut module).unwrap();
+    let source = module.functions[0].source.clone();
+    let mut rt = Runtime::new();
+    let err = rt.run(&Arc::new(module)).unwrap_err();
+    assert_eq!(err.range.map(|r| r.offset), source.find("a + b"));
+    assert_eq!(&*err.trace[1].fn_name, "add");
+}
+
+#[test]
+fn test_cache() {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::sync::Arc;
+
+    let file = "source/cache/main.dyon";
+    let dir = env::temp_dir().join("dyon-test-cache");
+    let _ = fs::remove_dir_all(&dir);
+    let dir = dir.to_str().unwrap();
+    let rets = |module: &Module| -> Vec<(Arc<String>, Type)> {
+        module.functions.iter().map(|f| (f.name.clone(), f.ret.clone())).collect()
+    };
+
+    let mut expected = Module::new();
+    load(file, &mut expected).unwrap();
+
+    // Write cache.
+    let mut module = Module::new();
+    load_cached(file, dir, &mut module).unwrap();
+    assert_eq!(rets(&module), rets(&expected));
+    let entries: Vec<_> = fs::read_dir(dir).unwrap().collect();
+    assert_eq!(entries.len(), 1);
+    let cache_file = entries[0].as_ref().unwrap().path();
+
+    // Read cache.
+    let mut module = Module::new();
+    load_cached(file, dir, &mut module).unwrap();
+    assert_eq!(rets(&module), rets(&expected));
+    Runtime::new().run(&Arc::new(module)).unwrap();
+
+    // Corrupt cache falls back to full load.
+    File::create(&cache_file).unwrap().write_all(b"dyon-cache").unwrap();
+    let mut module = Module::new();
+    load_cached(file, dir, &mut module).unwrap();
+    assert_eq!(rets(&module), rets(&expected));
+}
+
+#[test]
+fn test_watcher() {
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+    use std::sync::Arc;
+
+    fn ext(_rt: &mut Runtime) -> Result<(), String> { Ok(()) }
+
+    let file = env::temp_dir().join("dyon-test-watcher.dyon");
+    let write = |source: &str| {
+        File::create(&file).unwrap().write_all(source.as_bytes()).unwrap();
+    };
+    let run = |watcher: &ModuleWatcher| {
+        match Runtime::new().run_args(watcher.module(), &[]).unwrap() {
+            Some(Variable::F64(x, _)) => x,
+            x => panic!("Expected number, found {:?}", x),
+        }
+    };
+
+    let mut base = Module::new();
+    base.add(Arc::new("ext".into()), ext, Dfn { lts: vec![], tys: vec![], ret: Type::Void });
+    write("fn main() -> f64 {\n    ext()\n    return 1\n}\n");
+    let mut watcher = ModuleWatcher::new(base,
+        vec![file.to_str().unwrap().into()]).unwrap();
+    assert_eq!(run(&watcher), 1.0);
+    assert!(!watcher.reload().unwrap());
+
+    write("fn main() -> f64 {\n    ext()\n    return 22\n}\n");
+    assert!(watcher.reload().unwrap());
+    assert_eq!(run(&watcher), 22.0);
+    assert_eq!(watcher.module().ext_prelude.len(), 1);
+
+    // Keeps the working version on errors.
+    write("fn main() -> f64 {\n    return\n}\n");
+    assert!(watcher.reload().is_err());
+    assert!(!watcher.reload().unwrap());
+    assert_eq!(run(&watcher), 22.0);
+}
+
+#[test]
+fn test_call_closure_value() {
+    use std::sync::Arc;
+
+    let mut module = Module::new();
+    load("source/closure_value/adder.dyon", &mut module).unwrap();
+    let module = Arc::new(module);
+    let mut rt = Runtime::new();
+    let adder = rt.run_args(&module, &[]).unwrap().unwrap();
+
+    match rt.call_closure_value(&adder, vec![Variable::f64(3.0)], &module).unwrap() {
+        Some(Variable::F64(x, _)) => assert_eq!(x, 5.0),
+        x => panic!("Expected number, found {:?}", x),
+    }
+    let x: f64 = rt.call_closure_ret(&adder, &[&10.0], &module).unwrap();
+    assert_eq!(x, 12.0);
+    assert!(rt.call_closure_value(&adder, vec![], &module).is_err());
+    assert!(rt.call_closure_value(&Variable::f64(1.0), vec![], &module).is_err());
+}
+
+#[test]
+fn test_get_fn() {
+    use std::sync::Arc;
+
+    let mut module = Module::new();
+    load("source/get_fn/add.dyon", &mut module).unwrap();
+    let module = Arc::new(module);
+    let mut rt = Runtime::new();
+
+    let add = module.get_fn::<(f64, f64), f64>("add").unwrap();
+    assert_eq!(add.call(&mut rt, &module, (1.0, 2.0)).unwrap(), 3.0);
+    assert_eq!(add.call(&mut rt, &module, (3.0, 4.0)).unwrap(), 7.0);
+    let greet = module.get_fn::<(String,), String>("greet").unwrap();
+    assert_eq!(greet.call(&mut rt, &module, ("you".into(),)).unwrap(), "hi you");
+    let log = module.get_fn::<(f64,), ()>("log").unwrap();
+    log.call(&mut rt, &module, (1.0,)).unwrap();
+
+    assert_eq!(module.get_fn::<(f64, String), f64>("add").err().unwrap().kind,
+               ErrorKind::Type);
+    assert_eq!(module.get_fn::<(f64,), f64>("add").err().unwrap().kind, ErrorKind::Type);
+    assert_eq!(module.get_fn::<(f64, f64), String>("add").err().unwrap().kind,
+               ErrorKind::Type);
+    assert_eq!(module.get_fn::<(f64,), f64>("log").err().unwrap().kind, ErrorKind::Type);
+    assert_eq!(module.get_fn::<(), ()>("foo").err().unwrap().kind, ErrorKind::Runtime);
+}
+
+#[derive(Debug, PartialEq, PopVariable, PushVariable)]
+struct DerivePoint(f64, f64);
+
+#[derive(Debug, PartialEq, PopVariable, PushVariable)]
+#[dyon(vec4)]
+struct DeriveColor(f32, f32, f32);
+
+#[derive(Debug, PartialEq, PopVariable, PushVariable)]
+enum DeriveShape {
+    Empty,
+    Circle { center: DerivePoint, radius: f64 },
+    #[dyon(rename = "poly")]
+    Polygon(Vec<DerivePoint>),
+}
+
+fn derive_default_name() -> String { "unnamed".into() }
+
+#[derive(Debug, PartialEq, PopVariable, PushVariable)]
+struct DeriveItem {
+    #[dyon(default = "derive_default_name")]
+    name: String,
+    #[dyon(rename = "col")]
+    color: DeriveColor,
+    shape: DeriveShape,
+    tag: Option<String>,
+    #[dyon(skip)]
+    id: u32,
+}
+
+#[test]
+fn test_derive() {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use dyon::embed::{PopVariable, PushVariable};
+
+    let rt = Runtime::new();
+    let item = DeriveItem {
+        name: "a".into(),
+        color: DeriveColor(1.0, 0.5, 0.0),
+        shape: DeriveShape::Circle { center: DerivePoint(1.0, 2.0), radius: 3.0 },
+        tag: Some("b".into()),
+        id: 0,
+    };
+    let var = item.push_var();
+    if let Variable::Object(ref obj) = var {
+        assert!(obj.contains_key(&Arc::new("col".into())));
+        assert!(!obj.contains_key(&Arc::new("id".into())));
+    } else {
+        panic!("Expected object");
+    }
+    assert_eq!(DeriveItem::pop_var(&rt, &var).unwrap(), item);
+
+    let empty = DeriveShape::Empty.push_var();
+    assert_eq!(empty, Variable::Text(Arc::new("Empty".into())));
+    assert_eq!(DeriveShape::pop_var(&rt, &empty).unwrap(), DeriveShape::Empty);
+    let poly = DeriveShape::Polygon(vec![DerivePoint(0.0, 1.0)]);
+    assert_eq!(DeriveShape::pop_var(&rt, &poly.push_var()).unwrap(), poly);
+
+    // Missing keys use default values.
+    let mut obj = HashMap::new();
+    obj.insert(Arc::new("col".into()), Variable::Vec4([0.0; 4]));
+    obj.insert(Arc::new("shape".into()), empty.clone());
+    let item = DeriveItem::pop_var(&rt, &Variable::Object(Arc::new(obj.clone()))).unwrap();
+    assert_eq!(item.name, "unnamed");
+    assert_eq!(item.tag, None);
+
+    // Errors name the path of the failing field.
+    let mut circle = HashMap::new();
+    circle.insert(Arc::new("center".into()), Variable::f64(0.0));
+    circle.insert(Arc::new("radius".into()), Variable::f64(1.0));
+    let mut shape = HashMap::new();
+    shape.insert(Arc::new("Circle".into()), Variable::Object(Arc::new(circle)));
+    obj.insert(Arc::new("shape".into()), Variable::Object(Arc::new(shape)));
+    let err = DeriveItem::pop_var(&rt, &Variable::Object(Arc::new(obj))).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::Type);
+    assert!(err.message.starts_with("In field `shape.Circle.center`:"), "{}", err);
+
+    // `obj_field`, used by `dyon_obj!`, does not add the path.
+    let err = dyon::embed::obj_field::<f64>(&rt, &Arc::new(HashMap::new()), "x").unwrap_err();
+    assert_eq!(err.message, "Object has no key `x`");
+}
+
+#[test]
+fn test_add_closure() {
+    use std::sync::{Arc, Mutex};
+
+    let counter = Arc::new(Mutex::new(0.0));
+    let mut module = Module::new();
+    let c = counter.clone();
+    let next = move |rt: &mut Runtime| -> Result<(), String> {
+        let mut c = c.lock().unwrap();
+        *c += 1.0;
+        rt.push(*c);
+        Ok(())
+    };
+    module.add_closure(Arc::new("next".into()), Arc::new(next),
+                       Dfn { lts: vec![], tys: vec![], ret: Type::F64 });
+    load("source/add_closure/counter.dyon", &mut module).unwrap();
+    let module = Arc::new(module);
+    let mut rt = Runtime::new();
+    match rt.run_args(&module, &[]).unwrap() {
+        Some(Variable::F64(x, _)) => assert_eq!(x, 3.0),
+        x => panic!("Expected number, found {:?}", x),
+    }
+    assert_eq!(*counter.lock().unwrap(), 2.0);
+}
+
+/// Local to the runtime of the host.
+struct CtxRenderer;
+
+fn ctx_log(rt: &mut Runtime) -> Result<(), String> {
+    use std::sync::{Arc, Mutex};
+
+    let msg: String = try!(rt.pop());
+    rt.ctx_mut::<Vec<String>>().unwrap().push(msg.clone());
+    rt.ctx::<Arc<Mutex<Vec<String>>>>().unwrap().lock().unwrap().push(msg);
+    Ok(())
+}
+
+fn ctx_has_renderer(rt: &mut Runtime) -> Result<(), String> {
+    let has = rt.ctx::<CtxRenderer>().is_some();
+    rt.push(has);
+    Ok(())
+}
+
+#[test]
+fn test_ctx() {
+    use std::sync::{Arc, Mutex};
+
+    let mut module = Module::new();
+    module.add(Arc::new("log".into()), ctx_log,
+               Dfn { lts: vec![Lt::Default], tys: vec![Type::Text], ret: Type::Void });
+    module.add(Arc::new("has_renderer".into()), ctx_has_renderer,
+               Dfn { lts: vec![], tys: vec![], ret: Type::Bool });
+    load("source/ctx/log.dyon", &mut module).unwrap();
+    let module = Arc::new(module);
+
+    let shared = Arc::new(Mutex::new(Vec::<String>::new()));
+    let mut rt = Runtime::new();
+    // Each thread gets its own clone.
+    assert!(rt.set_ctx::<Vec<String>>(vec![]).is_none());
+    // Threads share the same log through the clone of `Arc`.
+    rt.set_ctx(shared.clone());
+    // Not passed on to threads.
+    rt.set_local_ctx(CtxRenderer);
+    match rt.run_args(&module, &[]).unwrap() {
+        Some(Variable::Bool(x, _)) => assert!(x),
+        x => panic!("Expected bool, found {:?}", x),
+    }
+    assert_eq!(rt.ctx::<Vec<String>>().unwrap(), &["main".to_string()]);
+    assert_eq!(*shared.lock().unwrap(), vec!["main".to_string(), "thread".to_string()]);
+    assert!(rt.remove_ctx::<CtxRenderer>().is_some());
+    assert!(rt.ctx::<CtxRenderer>().is_none());
+}
+
+#[test]
+fn test_pool() {
+    use std::sync::Arc;
+
+    // Creates more tasks than workers, which join each other.
+    let mut module = Module::new();
+    load("source/pool/fib.dyon", &mut module).unwrap();
+    let module = Arc::new(module);
+    let mut rt = Runtime::new();
+    match rt.run_args(&module, &[]).unwrap() {
+        Some(Variable::F64(x, _)) => assert_eq!(x, 144.0),
+        x => panic!("Expected number, found {:?}", x),
+    }
+}
+
+#[test]
+fn test_run_args() {
+    use std::sync::Arc;
+
+    let mut module = Module::new();
+    load("source/run/args.dyon", &mut module).unwrap();
+    let module = Arc::new(module);
+    let mut rt = Runtime::new();
+    let args = vec!["a".to_string(), "b".to_string()];
+    match rt.run_args(&module, &args).unwrap() {
+        Some(Variable::Result(Ok(ref x))) =>
+            assert_eq!(**x, Variable::Text(Arc::new("ab".into()))),
+        _ => panic!("Expected `ok(_)`")
+    }
+    match rt.run_args(&module, &[]).unwrap() {
+        Some(Variable::Result(Err(ref err))) => {
+            // The error points at where the value was created.
+            let source = &module.functions[0].source;
+            let err = rt.returned_error(err, 0, &module);
+            assert_eq!(err.range.unwrap().offset, source.find("err(").unwrap());
+            assert!(err.message.contains("Expected 2 arguments"));
+        }
+        _ => panic!("Expected `err(_)`")
+    }
+}
+
+#[test]
+fn test_run_args_signature() {
+    use std::sync::Arc;
+    use dyon::error::ErrorKind;
+
+    let mut module = Module::new();
+    load("source/run/args_wrong_type.dyon", &mut module).unwrap();
+    let module = Arc::new(module);
+    let err = Runtime::new().run_args(&module, &[]).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::Type);
+    assert!(err.message.contains("`main` should take `args: [str]`, found `str`"));
+
+    let mut module = Module::new();
+    load("source/run/no_args.dyon", &mut module).unwrap();
+    let module = Arc::new(module);
+    let args = vec!["a".to_string()];
+    let err = Runtime::new().run_args(&module, &args).unwrap_err();
+    assert!(err.message.contains("`main` takes no arguments, found 1 command line argument(s)"));
+    assert!(Runtime::new().run_args(&module, &[]).is_ok());
+}
+
+#[test]
+fn test_debugger() {
+    use std::sync::{Arc, Mutex};
+    use dyon::runtime::Call;
+    use range::Range;
+
+    struct Record(Arc<Mutex<Vec<Arc<String>>>>);
+
+    impl Debugger for Record {
+        fn before(
+            &mut self,
+            _rt: &Runtime,
+            _range: Range,
+            call: &Call,
+            _module: &Module
+        ) -> Result<(), String> {
+            self.0.lock().unwrap().push(call.fn_name.clone());
+            Ok(())
+        }
+    }
+
+    let mut module = Module::new();
+    load("source/debug/calls.dyon", &mut module).unwrap();
+    let calls = Arc::new(Mutex::new(vec![]));
+    let mut rt = Runtime::new();
+    rt.debugger = Some(Box::new(Record(calls.clone())));
+    rt.run(&Arc::new(module)).unwrap();
+    let calls = calls.lock().unwrap();
+    assert!(calls.iter().any(|name| &**name == "main"));
+    assert!(calls.iter().any(|name| &**name == "foo"));
+}
+
+/// Writes a message with a `Content-Length` header, as used by DAP and LSP.
+fn send_message<W: std::io::Write>(w: &mut W, msg: &str) {
+    write!(w, "Content-Length: {}\r\n\r\n{}", msg.len(), msg).unwrap();
+    w.flush().unwrap();
+}
+
+/// Reads a message with a `Content-Length` header, as used by DAP and LSP.
+fn receive_message<R: std::io::BufRead>(r: &mut R) -> String {
+    let mut len = 0;
+    loop {
+        let mut line = String::new();
+        r.read_line(&mut line).unwrap();
+        let line = line.trim();
+        if line.len() == 0 { break; }
+        len = line["Content-Length:".len()..].trim().parse().unwrap();
+    }
+    let mut buf = vec![0; len];
+    r.read_exact(&mut buf).unwrap();
+    String::from_utf8(buf).unwrap()
+}
+
+#[test]
+fn test_dap() {
+    use std::env;
+    use std::io::{BufRead, BufReader, Write};
+    use std::path::Path;
+    use std::process::{Command, Stdio};
+
+    fn send<W: Write>(w: &mut W, seq: u32, command: &str, args: &str) {
+        send_message(w, &format!(
+            r#"{{"seq":{},"type":"request","command":"{}","arguments":{}}}"#,
+            seq, command, args));
+    }
+
+    // Reads messages until one contains the pattern.
+    fn expect<R: BufRead>(r: &mut R, pattern: &str) -> String {
+        loop {
+            let msg = receive_message(r);
+            if msg.contains(pattern) { return msg; }
+        }
+    }
+
+    let exe = env::current_exe().unwrap();
+    let dap = exe.parent().unwrap().parent().unwrap().join("dyon-dap");
+    let program = Path::new("source/dap/breakpoint.dyon").canonicalize().unwrap();
+    let program = program.to_str().unwrap();
+    let mut child = Command::new(dap)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut w = child.stdin.take().unwrap();
+    let mut r = BufReader::new(child.stdout.take().unwrap());
+
+    send(&mut w, 1, "initialize", r#"{"adapterID":"dyon"}"#);
+    expect(&mut r, r#""event":"initialized""#);
+    send(&mut w, 2, "launch", &format!(r#"{{"program":"{}"}}"#, program));
+    expect(&mut r, r#""command":"launch""#);
+    send(&mut w, 3, "setBreakpoints",
+         &format!(r#"{{"source":{{"path":"{}"}},"breakpoints":[{{"line":3}}]}}"#, program));
+    let msg = expect(&mut r, r#""command":"setBreakpoints""#);
+    assert!(msg.contains(r#""verified":true"#));
+    send(&mut w, 4, "configurationDone", "{}");
+    let msg = expect(&mut r, r#""event":"stopped""#);
+    assert!(msg.contains(r#""reason":"breakpoint""#));
+    assert!(msg.contains(r#""threadId":1"#));
+
+    send(&mut w, 5, "threads", "{}");
+    let msg = expect(&mut r, r#""command":"threads""#);
+    assert!(msg.contains(r#""name":"main""#));
+    send(&mut w, 6, "stackTrace", r#"{"threadId":1}"#);
+    let msg = expect(&mut r, r#""command":"stackTrace""#);
+    assert!(msg.contains(r#""name":"main""#));
+    assert!(msg.contains(r#""line":3"#));
+    send(&mut w, 7, "scopes", r#"{"frameId":100000}"#);
+    let msg = expect(&mut r, r#""command":"scopes""#);
+    assert!(msg.contains(r#""variablesReference":200001"#));
+    send(&mut w, 8, "variables", r#"{"variablesReference":200001}"#);
+    let msg = expect(&mut r, r#""command":"variables""#);
+    assert!(msg.contains(r#""name":"a","value":"1""#));
+
+    send(&mut w, 9, "continue", r#"{"threadId":1}"#);
+    let msg = expect(&mut r, r#""event":"output""#);
+    assert!(msg.contains(r#""output":"2\n""#));
+    expect(&mut r, r#""event":"terminated""#);
+    send(&mut w, 10, "disconnect", "{}");
+    expect(&mut r, r#""command":"disconnect""#);
+    child.wait().unwrap();
+}
+
+#[test]
+fn test_lsp() {
+    use std::env;
+    use std::io::BufReader;
+    use std::process::{Command, Stdio};
+
+    let exe = env::current_exe().unwrap();
+    let lsp = exe.parent().unwrap().parent().unwrap().join("dyon-lsp");
+    let mut child = Command::new(lsp)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut w = child.stdin.take().unwrap();
+    let mut r = BufReader::new(child.stdout.take().unwrap());
+
+    send_message(&mut w, r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#);
+    assert!(receive_message(&mut r).contains(r#""hoverProvider":true"#));
+
+    send_message(&mut w, r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///a.dyon","text":"fn main() {\n    x := foo()\n}\n"}}}"#);
+    let msg = receive_message(&mut r);
+    assert!(msg.contains("publishDiagnostics"));
+    assert!(msg.contains("Could not find function `foo`"));
+
+    send_message(&mut w, r#"{"jsonrpc":"2.0","method":"textDocument/didChange","params":{"textDocument":{"uri":"file:///a.dyon"},"contentChanges":[{"text":"fn foo() -> f64 { return 1 }\nfn main() {\n    x := foo()\n}\n"}]}}"#);
+    let msg = receive_message(&mut r);
+    assert!(msg.contains(r#""diagnostics":[]"#));
+
+    // Hover and goto definition on `foo` in `main`.
+    send_message(&mut w, r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/hover","params":{"textDocument":{"uri":"file:///a.dyon"},"position":{"line":2,"character":10}}}"#);
+    let msg = receive_message(&mut r);
+    assert!(msg.contains("fn foo() -> f64"));
+    send_message(&mut w, r#"{"jsonrpc":"2.0","id":3,"method":"textDocument/definition","params":{"textDocument":{"uri":"file:///a.dyon"},"position":{"line":2,"character":10}}}"#);
+    let msg = receive_message(&mut r);
+    assert!(msg.contains(r#""start":{"line":0,"character":0}"#));
+
+    send_message(&mut w, r#"{"jsonrpc":"2.0","method":"exit"}"#);
+    child.wait().unwrap();
+}
+
+#[test]
+fn test_channel() {
+    use std::sync::Arc;
+
+    let mut module = Module::new();
+    load("source/channel/pipeline.dyon", &mut module).unwrap();
+    let module = Arc::new(module);
+    let mut rt = Runtime::new();
+    match rt.run_args(&module, &[]).unwrap() {
+        Some(Variable::F64(x, _)) => assert_eq!(x, 385.0),
+        x => panic!("Expected number, found {:?}", x),
+    }
+
+    // Waiting workers do not block the pool.
+    let mut module = Module::new();
+    load("source/channel/many.dyon", &mut module).unwrap();
+    let module = Arc::new(module);
+    let mut rt = Runtime::new();
+    match rt.run_args(&module, &[]).unwrap() {
+        Some(Variable::F64(x, _)) => assert_eq!(x, 120.0),
+        x => panic!("Expected number, found {:?}", x),
+    }
+
+    let mut module = Module::new();
+    let err = load("source/channel/send_ref.dyon", &mut module).unwrap_err();
+    assert!(err.message.contains(
+        "Can not use `send` because this value has a lifetime constraint"), "{}", err);
+}
+
+/// Collects the `.dyon` files in a directory and its subdirectories.
+fn dyon_files(dir: &std::path::Path, files: &mut Vec<std::path::PathBuf>) {
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            dyon_files(&path, files);
+        } else if path.extension().map(|ext| ext == "dyon").unwrap_or(false) {
+            files.push(path);
+        }
+    }
+}
+
+#[test]
+fn test_format() {
+    use std::io::Read;
+    use std::fs::File;
+    use dyon::format::{format_str, Config};
+
+    let read = |file: &std::path::Path| {
+        let mut source = String::new();
+        File::open(file).unwrap().read_to_string(&mut source).unwrap();
+        source
+    };
+    let config = Config::default();
+
+    let messy = read("source/format/messy.dyon".as_ref());
+    assert_eq!(format_str("messy.dyon", &messy, &config).unwrap(),
+               read("source/format/formatted.dyon".as_ref()));
+    assert_eq!(format_str("number.dyon", "fn main() { x := .3 }", &config).unwrap(),
+               "fn main() { x := 0.3 }\n");
+    assert_eq!(format_str("vec4.dyon", "fn main() { x := (0, 0) }", &config).unwrap(),
+               "fn main() { x := (0,) }\n");
+}
+
+#[test]
+fn test_format_idempotent() {
+    use std::io::Read;
+    use std::fs::File;
+    use std::sync::Arc;
+    use dyon::format::{format_str, Config};
+
+    let read = |file: &std::path::Path| {
+        let mut source = String::new();
+        File::open(file).unwrap().read_to_string(&mut source).unwrap();
+        source
+    };
+    let config = Config::default();
+
+    let mut files = vec![];
+    dyon_files("source".as_ref(), &mut files);
+    for file in &files {
+        let name = file.to_str().unwrap();
+        let source = read(file);
+        let formatted = match format_str(name, &source, &config) {
+            Ok(x) => x,
+            // Some sources test syntax errors.
+            Err(_) => continue,
+        };
+        assert_eq!(format_str(name, &formatted, &config).unwrap(), formatted,
+                   "Formatting `{}` twice gives different results", name);
+        assert_eq!(source.matches("//").count(), formatted.matches("//").count(),
+                   "Formatting `{}` lost comments", name);
+        assert_eq!(source.matches("/*").count(), formatted.matches("/*").count(),
+                   "Formatting `{}` lost comments", name);
+        if load_str(name, Arc::new(source), &mut Module::new()).is_ok() {
+            if let Err(err) = load_str(name, Arc::new(formatted), &mut Module::new()) {
+                panic!("Formatting `{}` gives a source that does not load:\n{}", name, err);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_lint() {
+    use std::io::Read;
+    use std::fs::File;
+    use dyon::lint::{apply_fixes, lint_str, Config, Rule};
+
+    let read = |file: &str| {
+        let mut source = String::new();
+        File::open(file).unwrap().read_to_string(&mut source).unwrap();
+        source
+    };
+    let mut module = Module::new();
+    load("source/namespace/math.dyon", &mut module).unwrap();
+    let config = Config::default();
+
+    let file = "source/lint/lints.dyon";
+    let source = read(file);
+    let lints = lint_str(file, &source, &module, &config).unwrap();
+    let rules: Vec<Rule> = lints.iter().map(|lint| lint.rule).collect();
+    assert_eq!(rules, vec![Rule::UnusedImport, Rule::UnusedVariable,
+                           Rule::DeclareInsteadOfSet, Rule::ShadowedLocal,
+                           Rule::UnreachableCode]);
+    assert_eq!(lints[1].message, "Unused variable `a`");
+    assert!(lints[4].diagnostic(std::sync::Arc::new(file.into())).severity == Severity::Warning);
+
+    // Fixes that change the behavior are only suggested.
+    assert!(!lints[2].fix.as_ref().unwrap().automatic);
+    let fixed = apply_fixes(&source, &lints);
+    assert!(!fixed.contains(" as alg") && fixed.contains("_a := 1") &&
+            fixed.contains("sum := sum + i") && fixed.contains("break\n    }") &&
+            !fixed.contains("never"));
+    let lints = lint_str(file, &fixed, &module, &config).unwrap();
+    let rules: Vec<Rule> = lints.iter().map(|lint| lint.rule).collect();
+    assert_eq!(rules, vec![Rule::DeclareInsteadOfSet, Rule::ShadowedLocal]);
+
+    let mut config = Config::default();
+    config.allow(Rule::ShadowedLocal);
+    let lints = lint_str(file, &fixed, &module, &config).unwrap();
+    assert_eq!(lints.len(), 1);
+    assert_eq!(lints[0].rule, Rule::DeclareInsteadOfSet);
+
+    let file = "source/lint/clean.dyon";
+    assert_eq!(lint_str(file, &read(file), &module, &config).unwrap(), vec![]);
+}
+
+#[test]
+fn test_testing() {
+    use dyon::testing::{run_file, Outcome};
+
+    let results = run_file("source/testing/pass.dyon", "").unwrap();
+    let names: Vec<&str> = results.iter().map(|res| &**res.name).collect();
+    assert_eq!(names, vec!["test_add", "test_objects", "test_result"]);
+    assert!(results.iter().all(|res| res.passed()));
+
+    let results = run_file("source/testing/fail.dyon", "").unwrap();
+    let names: Vec<&str> = results.iter().filter(|res| !res.passed())
+        .map(|res| &**res.name).collect();
+    assert_eq!(names, vec!["test_assert", "test_assert_eq", "test_err"]);
+    match results[1].outcome {
+        Outcome::Failed(ref err) => {
+            assert!(err.range.is_some());
+            assert!(err.message.contains("left: 2"));
+            assert!(err.message.contains("right: 3"));
+        }
+        ref x => panic!("Expected failure, found {:?}", x),
+    }
+    match results[2].outcome {
+        Outcome::Failed(ref err) => assert!(err.message.contains("oops")),
+        ref x => panic!("Expected failure, found {:?}", x),
+    }
+
+    let results = run_file("source/testing/fail.dyon", "assert_eq").unwrap();
+    assert_eq!(results.len(), 1);
+}
+
+#[test]
+fn test_profiler() {
+    use std::sync::Arc;
+
+    let mut module = Module::new();
+    load("source/profiler/fib.dyon", &mut module).unwrap();
+    let module = Arc::new(module);
+    let mut rt = Runtime::new();
+    let mut profiler = Profiler::new();
+    profiler.sample_interval = 1;
+    rt.profiler = Some(profiler);
+    rt.run(&module).unwrap();
+    let profiler = rt.profiler.take().unwrap();
+
+    let functions = profiler.functions(&module);
+    let calls = |name: &str| functions.iter().find(|&&(ref n, _)| n == name).map(|f| f.1.calls);
+    assert_eq!(calls("main"), Some(1));
+    assert_eq!(calls("fib"), Some(177));
+    assert_eq!(calls("main::\\f"), Some(10));
+    let main = functions.iter().find(|&&(ref n, _)| n == "main").unwrap().1;
+    let fib = functions.iter().find(|&&(ref n, _)| n == "fib").unwrap().1;
+    assert!(main.inclusive >= fib.inclusive && fib.inclusive >= fib.exclusive);
+
+    assert!(profiler.lines(&module).iter().any(|&(_, line, _)| line == 3));
+    let folded = profiler.folded(&module);
+    assert!(folded.lines().any(|line| line.starts_with("main;fib;fib ")));
+    assert!(profiler.report(&module, 10).contains("main::\\f"));
+}